@@ -1,10 +1,27 @@
-use fs_err as fs;
 use serde::{Deserialize, Serialize};
 use std::{
     io,
     ops::Deref,
     path::{Path, PathBuf},
 };
+use thiserror::Error;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to resolve '{path}': {source}{hint}", path = path.to_string_lossy(), hint = hint(source))]
+    Resolve { path: PathBuf, source: io::Error },
+}
+
+fn hint(e: &io::Error) -> &'static str {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => {
+            " (hint: every directory above it needs to be readable and searchable by you)"
+        }
+        _ => "",
+    }
+}
 
 fn remove_midcomps(p: &Path) -> PathBuf {
     let mut out = PathBuf::new();
@@ -23,23 +40,109 @@ fn remove_midcomps(p: &Path) -> PathBuf {
     out
 }
 
+/// Longest path windows will take without the verbatim prefix
+const MAX_PATH: usize = 260;
+
+/// Strip the `\\?\` prefix that windows puts on canonical paths, if the
+/// path means the same thing without it. Drive paths lose the prefix and
+/// `\\?\UNC\server\share` becomes `\\server\share`
+fn strip_verbatim(path: &str) -> Option<String> {
+    let plain = if let Some(rest) = path.strip_prefix(r"\\?\UNC\") {
+        format!(r"\\{rest}")
+    } else {
+        let rest = path.strip_prefix(r"\\?\")?;
+        let drive = rest.as_bytes();
+        if drive.len() < 3 || !drive[0].is_ascii_alphabetic() || &drive[1..3] != br":\" {
+            return None;
+        }
+        rest.to_owned()
+    };
+    // verbatim paths treat these literally, without the prefix they would be
+    // interpreted so the path is no longer the same
+    let literal = plain.contains('/')
+        || plain
+            .split('\\')
+            .filter(|c| !c.is_empty() && !c.ends_with(':'))
+            .any(|c| c == "." || c == ".." || c.ends_with('.') || c.ends_with(' '));
+    if literal || plain.len() >= MAX_PATH {
+        None
+    } else {
+        Some(plain)
+    }
+}
+
+fn simplify(path: PathBuf) -> PathBuf {
+    if cfg!(windows) {
+        if let Some(p) = path.to_str().and_then(strip_verbatim) {
+            return PathBuf::from(p);
+        }
+    }
+    path
+}
+
+/// [`std::fs::canonicalize`] without the verbatim prefix on windows, where
+/// that is possible
+pub fn canonicalize(path: impl AsRef<Path>) -> Result<PathBuf> {
+    let path = path.as_ref();
+    std::fs::canonicalize(path)
+        .map(simplify)
+        .map_err(|source| Error::Resolve {
+            path: path.to_owned(),
+            source,
+        })
+}
+
 /// It's an absolute file path, what more could you ask for
+///
+/// The path is canonicalized once on construction so comparisons are just
+/// comparisons, they never go to the filesystem
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 #[serde(transparent)]
 pub struct AbsPathBuf {
     path: PathBuf,
 }
 
+/// `base.join(path)`, made absolute against the cwd only if `base` isn't
+fn absolute_in(base: &Path, path: &Path) -> Result<PathBuf> {
+    let path = base.join(path);
+    if path.is_absolute() {
+        Ok(path)
+    } else {
+        let cwd = std::env::current_dir().map_err(|source| Error::Resolve {
+            path: path.clone(),
+            source,
+        })?;
+        Ok(cwd.join(path))
+    }
+}
+
 impl AbsPathBuf {
-    pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
-        let path = path.as_ref();
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new_in(Path::new(""), path)
+    }
+    /// Like [`AbsPathBuf::new`] but relative paths are relative to `base`
+    /// rather than the cwd
+    pub fn new_in(base: &Path, path: impl AsRef<Path>) -> Result<Self> {
+        let path = absolute_in(base, path.as_ref())?;
         let p = if !path.exists() {
-            remove_midcomps(&std::env::current_dir()?.join(path))
+            remove_midcomps(&path)
         } else {
-            fs::canonicalize(path)?
+            canonicalize(path)?
         };
         Ok(Self { path: p })
     }
+    /// Make `path` absolute and remove `.` and `..` without resolving any
+    /// symlinks along the way. Use this where the path the user wrote matters
+    /// more than the file it ends up at, e.g. where we deploy to
+    pub fn logical(path: impl AsRef<Path>) -> Result<Self> {
+        Self::logical_in(Path::new(""), path)
+    }
+    /// [`AbsPathBuf::logical`] with relative paths relative to `base`
+    pub fn logical_in(base: &Path, path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            path: simplify(remove_midcomps(&absolute_in(base, path.as_ref())?)),
+        })
+    }
 }
 impl Deref for AbsPathBuf {
     type Target = Path;
@@ -57,9 +160,9 @@ macro_rules! impl_try_from {
     ($($ts:ty),+) => {
         $(
         impl TryFrom<$ts> for AbsPathBuf {
-            type Error = std::io::Error;
+            type Error = Error;
 
-            fn try_from(value: $ts) -> Result<Self, Self::Error> {
+            fn try_from(value: $ts) -> Result<Self> {
                 AbsPathBuf::new(value)
             }
         })+
@@ -70,8 +173,9 @@ impl_try_from!(&str, &Path, PathBuf, String);
 
 #[cfg(test)]
 mod tests {
-    use super::AbsPathBuf;
+    use super::{canonicalize, strip_verbatim, AbsPathBuf, Error};
     use assert_matches::assert_matches;
+    use std::io;
 
     #[test]
     fn abspath_can_handle_non_existant_paths() {
@@ -85,4 +189,108 @@ mod tests {
             AbsPathBuf::new(".").unwrap()
         );
     }
+    #[test]
+    fn verbatim_prefixes_are_stripped() {
+        assert_eq!(
+            strip_verbatim(r"\\?\C:\Users\me\dots").as_deref(),
+            Some(r"C:\Users\me\dots")
+        );
+        assert_eq!(
+            strip_verbatim(r"\\?\UNC\server\share\dots").as_deref(),
+            Some(r"\\server\share\dots")
+        );
+    }
+    #[test]
+    fn verbatim_prefixes_are_kept_when_needed() {
+        assert_eq!(strip_verbatim(r"C:\Users"), None);
+        assert_eq!(strip_verbatim(r"\\server\share"), None);
+        assert_eq!(strip_verbatim(r"\\?\Volume{abc}\x"), None);
+        assert_eq!(strip_verbatim(r"\\?\C:\a\..\b"), None);
+        assert_eq!(strip_verbatim(r"\\?\C:\a/b"), None);
+        assert_eq!(strip_verbatim(r"\\?\C:\trailing."), None);
+        let long = format!(r"\\?\C:\{}", "a".repeat(300));
+        assert_eq!(strip_verbatim(&long), None);
+    }
+    #[cfg(unix)]
+    #[test]
+    fn logical_paths_keep_symlinks() {
+        let dir = tempdir::TempDir::new("dotloy-abspath").unwrap();
+        let root = AbsPathBuf::new(dir.path()).unwrap();
+        std::fs::create_dir(root.join("real")).unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("linked")).unwrap();
+        let p = root.join("linked").join(".").join("x").join("..").join("y");
+
+        assert_eq!(
+            AbsPathBuf::logical(&p).unwrap().as_ref(),
+            root.join("linked").join("y")
+        );
+        std::fs::write(root.join("real").join("y"), "").unwrap();
+        assert_eq!(
+            AbsPathBuf::new(root.join("linked").join("y"))
+                .unwrap()
+                .as_ref(),
+            root.join("real").join("y")
+        );
+        assert_eq!(
+            AbsPathBuf::logical(&p).unwrap().as_ref(),
+            root.join("linked").join("y")
+        );
+    }
+    #[test]
+    fn relative_paths_resolve_against_their_base() {
+        let dirs = (0..4)
+            .map(|_| tempdir::TempDir::new("dotloy-abspath").unwrap())
+            .collect::<Vec<_>>();
+        std::thread::scope(|s| {
+            for dir in &dirs {
+                s.spawn(move || {
+                    let base = AbsPathBuf::new(dir.path()).unwrap();
+                    std::fs::write(base.join("exists"), "").unwrap();
+                    assert_eq!(
+                        AbsPathBuf::new_in(&base, "exists").unwrap().as_ref(),
+                        base.join("exists")
+                    );
+                    assert_eq!(
+                        AbsPathBuf::new_in(&base, "a/../missing").unwrap().as_ref(),
+                        base.join("missing")
+                    );
+                    assert_eq!(
+                        AbsPathBuf::logical_in(&base, "./to").unwrap().as_ref(),
+                        base.join("to")
+                    );
+                });
+            }
+        });
+        let abs = AbsPathBuf::new(".").unwrap();
+        assert_eq!(AbsPathBuf::new_in(dirs[0].path(), &abs).unwrap(), abs);
+    }
+    #[cfg(unix)]
+    #[test]
+    fn resolve_errors_name_the_path() {
+        let dir = tempdir::TempDir::new("dotloy-abspath").unwrap();
+        let root = AbsPathBuf::new(dir.path()).unwrap();
+        std::os::unix::fs::symlink(root.join("a"), root.join("b")).unwrap();
+        std::os::unix::fs::symlink(root.join("b"), root.join("a")).unwrap();
+        let err = canonicalize(root.join("a")).unwrap_err();
+        assert!(err.to_string().starts_with(&format!(
+            "failed to resolve '{}': ",
+            root.join("a").display()
+        )));
+
+        let denied = Error::Resolve {
+            path: "/x".into(),
+            source: io::ErrorKind::PermissionDenied.into(),
+        };
+        assert!(denied.to_string().contains("(hint: "));
+    }
+    #[test]
+    fn equality_does_not_touch_the_filesystem() {
+        let dir = tempdir::TempDir::new("dotloy-abspath").unwrap();
+        let file = dir.path().join("f");
+        std::fs::write(&file, "").unwrap();
+        let a = AbsPathBuf::new(&file).unwrap();
+        let b = AbsPathBuf::new(dir.path().join(".").join("f")).unwrap();
+        drop(dir);
+        assert_eq!(a, b);
+    }
 }
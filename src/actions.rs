@@ -1,23 +1,127 @@
+use colored::Colorize;
 use fs_err as fs;
-use std::path::{Path, PathBuf};
+use itertools::Itertools;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use thiserror::Error;
 
 use crate::{
-    abspath::AbsPathBuf,
-    config::{self, DeployType, LinkType, Platform},
-    define_variables,
-    resources::{ResourceHandle, ResourceLocation, ResourceStore},
-    vars,
+    abspath::{self, AbsPathBuf},
+    apply_xdg_fallbacks, archive,
+    block::{self, Block},
+    config::{
+        self, ArrayMerge, DeployType, LinkType, MergeFormat, OnConflict, OneOrMany, Platform,
+        SymlinkFallback, VerifyFailure,
+    },
+    define_variables, diff,
+    display::{self, PathStyle},
+    history,
+    ignore::{self, Ignore},
+    merge,
+    plan::{self, Destination, Plan, Planned},
+    remote::Remote,
+    resources::{self, ResourceHandle, ResourceLocation, ResourceMeta, ResourceStore},
+    script, sha256, sys,
+    template::{self, Context, Variable},
+    timings::Timings,
+    vars::{self, Layer},
+};
+
+/// Records of past runs, as [`Actions::record_history`] writes them
+pub use crate::history::{
+    default_dir as default_history_dir, file_for as history_file, read as read_history, Change,
+    Entry, Record,
 };
-use handybars::{self};
 
+/// What an [`Action`] does, without the details
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ActionKind {
+    Link(LinkType),
+    Copy,
+    MkDir,
+    TemplateExpand,
+    Verify,
+    Extract,
+    CheckHash,
+    EnsureDir,
+    Block,
+    Merge,
+}
+
+impl ActionKind {
+    /// Short name, as shown in dry runs
+    pub fn name(self) -> &'static str {
+        match self {
+            ActionKind::Link(_) => "link",
+            ActionKind::Copy => "copy",
+            ActionKind::MkDir => "mkdir",
+            ActionKind::TemplateExpand => "expand",
+            ActionKind::Verify => "verify",
+            ActionKind::Extract => "extract",
+            ActionKind::CheckHash => "check",
+            ActionKind::EnsureDir => "ensure directory",
+            ActionKind::Block => "update block",
+            ActionKind::Merge => "merge",
+        }
+    }
+    fn color(self) -> colored::Color {
+        match self {
+            ActionKind::Link(_) => colored::Color::Cyan,
+            ActionKind::Copy => colored::Color::Blue,
+            ActionKind::MkDir => colored::Color::White,
+            ActionKind::TemplateExpand => colored::Color::Magenta,
+            ActionKind::Verify => colored::Color::Yellow,
+            ActionKind::Extract => colored::Color::Green,
+            ActionKind::CheckHash => colored::Color::BrightBlack,
+            ActionKind::EnsureDir => colored::Color::White,
+            ActionKind::Block => colored::Color::Blue,
+            ActionKind::Merge => colored::Color::Blue,
+        }
+    }
+}
+
+/// An action as the columns of a dry run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Row {
+    pub kind: ActionKind,
+    /// What it reads, empty if nothing
+    pub source: String,
+    /// What it writes, empty if nothing
+    pub destination: String,
+    /// Link type, command or anything else that sets it apart
+    pub detail: String,
+}
+
+/// Actions next to each other in the plan that came from the same target
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Group {
+    pub config: Option<PathBuf>,
+    /// Name of the target, or its `from` if it has none
+    pub target: String,
+    pub description: Option<String>,
+    pub rows: Vec<Row>,
+}
+
+/// One step of a deploy
 #[derive(Clone, PartialEq, Eq, Debug)]
-enum Action {
+pub enum Action {
     Link {
         ty: LinkType,
         from: AbsPathBuf,
         to: AbsPathBuf,
+        /// What to do if `to` is on a filesystem without links
+        fallback: SymlinkFallback,
+        /// Directory of sources, a symlink at `to` into which, or to
+        /// something that no longer exists, is pointed at `from` instead of
+        /// being an error. `None` if it's always an error
+        repoint: Option<AbsPathBuf>,
+        /// Whether a file at `to` with the same content as `from` is
+        /// replaced by the link rather than being an error
+        adopt: bool,
     },
     Copy {
         from: ResourceLocation,
@@ -26,92 +130,774 @@ enum Action {
     MkDir {
         path: AbsPathBuf,
     },
+    /// Make sure the directory `path` exists, for a target that deploys
+    /// nothing else, with its unix permissions set to `mode` if there is one
+    EnsureDir {
+        path: AbsPathBuf,
+        mode: Option<u32>,
+    },
     TemplateExpand {
-        ctx: handybars::Context<'static>,
+        ctx: Context,
         target: ResourceLocation,
         output: ResourceLocation,
     },
+    /// Put the content of `from` in `block` of the file `to`, leaving the
+    /// rest of it as it is. `to` is only created if `create` is set
+    Block {
+        from: ResourceLocation,
+        to: AbsPathBuf,
+        block: Block,
+        create: bool,
+    },
+    /// Merge the `format` document `from` into the one at `to`, or write it
+    /// there if there isn't one
+    Merge {
+        from: ResourceLocation,
+        to: AbsPathBuf,
+        format: MergeFormat,
+        arrays: ArrayMerge,
+    },
+    /// Unpack `archive` into the directory `to`, without the first `strip`
+    /// components of each entry
+    Extract {
+        archive: AbsPathBuf,
+        format: archive::Format,
+        to: AbsPathBuf,
+        strip: usize,
+    },
+    /// Check `file` has the hex SHA-256 `sha256` before it is deployed
+    CheckHash {
+        file: AbsPathBuf,
+        sha256: String,
+    },
+    /// Run `command` in `dir` to check `target` was deployed correctly
+    Verify {
+        command: String,
+        dir: PathBuf,
+        target: ResourceLocation,
+        on_failure: VerifyFailure,
+    },
 }
 
 impl Action {
     fn run(&self, res: &mut ResourceStore) -> Result<()> {
         match self {
+            Action::Extract {
+                archive,
+                format,
+                to,
+                strip,
+            } => {
+                archive::extract(archive, *format, to, *strip, &mut res.extracted()).map_err(|e| {
+                    match e {
+                        archive::Error::Exists { path } => Error::TargetExists { path },
+                        e => e.into(),
+                    }
+                })
+            }
+            Action::CheckHash { file, sha256 } => {
+                let actual = sha256::hex_digest(fs::File::open(file.to_path_buf())?)?;
+                if actual != *sha256 {
+                    return Err(Error::HashMismatch {
+                        file: file.to_string_lossy().into_owned(),
+                        expected: sha256.clone(),
+                        actual,
+                    });
+                }
+                Ok(())
+            }
+            Action::Verify {
+                command,
+                dir,
+                on_failure,
+                ..
+            } => match verify(command, dir) {
+                Err(e @ Error::VerifyFailed { .. }) if *on_failure == VerifyFailure::Warn => {
+                    log::warn!("{e}");
+                    Ok(())
+                }
+                r => r,
+            },
             Action::MkDir { path } => Ok(fs::create_dir_all(path)?),
-            Action::Link { ty, from, to } => {
+            Action::Block {
+                from,
+                to,
+                block,
+                create,
+            } => {
+                let content =
+                    String::from_utf8(res.get_content(from)?).map_err(|_| Error::BlockNotText {
+                        name: block.name.clone(),
+                        path: to.to_string_lossy().into_owned(),
+                        from: from.to_string(),
+                    })?;
+                let text = match fs::read_to_string(to) {
+                    Ok(text) => text,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound && *create => String::new(),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        return Err(Error::BlockFileMissing {
+                            path: to.to_string_lossy().into_owned(),
+                        })
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let updated = block
+                    .insert(&text, &content)
+                    .map_err(|source| Error::BlockIn {
+                        path: to.to_string_lossy().into_owned(),
+                        source,
+                    })?;
+                if updated != text {
+                    // keeps the permissions the file already has
+                    res.set_content(
+                        &ResourceLocation::Path(to.clone()),
+                        ResourceHandle::Mem(updated.into_bytes(), ResourceMeta::default()),
+                    )?;
+                }
+                Ok(())
+            }
+            Action::Merge {
+                from,
+                to,
+                format,
+                arrays,
+            } => {
+                let (text, merged) = merge_documents(from, to, *arrays, res)?;
+                if merged.changed() {
+                    if merged.had_comments {
+                        log::warn!(
+                            "the comments in '{}' are lost merging into it",
+                            to.to_string_lossy()
+                        );
+                    }
+                    let updated = merged.write(&text, &to.to_string_lossy(), *format)?;
+                    res.set_content(
+                        &ResourceLocation::Path(to.clone()),
+                        ResourceHandle::Mem(updated.into_bytes(), ResourceMeta::default()),
+                    )?;
+                }
+                Ok(())
+            }
+            Action::EnsureDir { path, mode } => {
+                fs::create_dir_all(path)?;
+                #[cfg(unix)]
+                if let Some(mode) = mode {
+                    use std::os::unix::fs::PermissionsExt;
+                    fs::set_permissions(path, std::fs::Permissions::from_mode(*mode))?;
+                }
+                #[cfg(not(unix))]
+                let _ = mode;
+                Ok(())
+            }
+            Action::Link {
+                ty,
+                from,
+                to,
+                fallback,
+                repoint,
+                adopt,
+            } => {
+                recreate_parent(to)?;
                 if let Ok(m) = fs::symlink_metadata(to) {
+                    if *ty == LinkType::Hard && m.is_file() && same_inode(from, to)? {
+                        log::debug!(
+                            "'{}' is already linked to '{}'",
+                            to.to_string_lossy(),
+                            from.to_string_lossy()
+                        );
+                        return Ok(());
+                    }
                     if !m.is_symlink() {
-                        return Err(Error::TargetExists {
-                            path: to.to_string_lossy().into_owned(),
-                        });
-                    } else if fs::canonicalize(to)? != fs::canonicalize(from)? {
-                        return Err(Error::TargetSymlinksDiffer {
-                            path: to.to_string_lossy().into_owned(),
-                            ours: fs::canonicalize(from)?.to_string_lossy().into_owned(),
-                            theirs: fs::canonicalize(to)?.to_string_lossy().into_owned(),
-                        });
+                        if *fallback == SymlinkFallback::Copy && !links_supported(to) {
+                            // copied by an earlier deploy
+                            return if same_tree(from, to)? {
+                                Ok(())
+                            } else {
+                                copy_tree(from, to)
+                            };
+                        }
+                        if !(*adopt && m.is_file() && from.is_file() && same_content(from, to)?) {
+                            return Err(Error::TargetExists {
+                                path: to.to_string_lossy().into_owned(),
+                            });
+                        }
+                        fs::remove_file(to)?;
+                        log::info!("adopted existing identical file '{}'", to.to_string_lossy());
                     } else {
-                        return Ok(());
+                        match stale_link(from, to, repoint.as_deref())? {
+                            None => return Ok(()),
+                            Some(StaleLink { old, repointable }) if repointable => {
+                                symlink::remove_symlink_auto(to)?;
+                                log::info!(
+                                    "repointed '{}': '{}' -> '{}'",
+                                    to.to_string_lossy(),
+                                    old.to_string_lossy(),
+                                    abspath::canonicalize(from)?.to_string_lossy()
+                                );
+                            }
+                            Some(StaleLink { old, .. }) => {
+                                return Err(Error::TargetSymlinksDiffer {
+                                    path: to.to_string_lossy().into_owned(),
+                                    ours: abspath::canonicalize(from)?
+                                        .to_string_lossy()
+                                        .into_owned(),
+                                    theirs: old.to_string_lossy().into_owned(),
+                                })
+                            }
+                        }
                     }
                 }
-                match ty {
-                    LinkType::Soft => Ok(symlink::symlink_auto(fs::canonicalize(from)?, to)?),
+                let linked = match ty {
+                    LinkType::Soft => symlink::symlink_auto(abspath::canonicalize(from)?, to),
                     LinkType::Hard => {
                         assert!(from.is_file(), "tried to hardlink directory");
-                        Ok(fs::hard_link(from, to)?)
+                        fs::hard_link(from, to)
+                    }
+                };
+                match linked {
+                    Err(e) if *fallback == SymlinkFallback::Copy && links_unsupported(&e) => {
+                        log::warn!(
+                            "the filesystem of '{}' can't hold links ({e}), copying '{}' there instead",
+                            to.to_string_lossy(),
+                            from.to_string_lossy()
+                        );
+                        copy_tree(from, to)
                     }
+                    linked => Ok(linked?),
                 }
             }
-            Action::Copy { from, to } => match from {
-                ResourceLocation::InMemory { id: fid } => match to {
-                    ResourceLocation::InMemory { id: tid } => {
-                        res.set(*tid, res.get(*fid).clone());
-                        Ok(())
-                    }
-                    loc => Ok(res.set_content(loc, res.get(*fid).clone())?),
-                },
-                ResourceLocation::Path(pf) => match to {
-                    ResourceLocation::Path(pt) => {
-                        fs::copy(pf, pt)?;
-                        Ok(())
-                    }
-                    loc => Ok(res.set_content(loc, ResourceHandle::File(pf.to_owned()))?),
-                },
-            },
+            Action::Copy { from, to } => {
+                if let Some(to) = to.as_path() {
+                    recreate_parent(to)?;
+                }
+                Self::copy(from, to, res)
+            }
             Action::TemplateExpand {
                 ctx,
                 target,
                 output,
             } => {
-                let from = ctx.render(&res.get_content(target)?)?;
-                res.set_content(output, ResourceHandle::MemStr(from))?;
+                let (from, meta) = expand(ctx, target, res)?;
+                res.set_content(output, ResourceHandle::Mem(from.into_bytes(), meta))?;
                 Ok(())
             }
         }
     }
+    /// Run [`Action::Copy`], once where it goes exists
+    fn copy(from: &ResourceLocation, to: &ResourceLocation, res: &mut ResourceStore) -> Result<()> {
+        match from {
+            ResourceLocation::InMemory { id: fid, .. } => match to {
+                ResourceLocation::InMemory { id: tid, .. } => {
+                    res.set(*tid, res.get(*fid)?.clone());
+                    Ok(())
+                }
+                loc => Ok(res.set_content(loc, res.get(*fid)?.clone())?),
+            },
+            ResourceLocation::Path(pf) => match to {
+                ResourceLocation::Path(pt) => {
+                    fs::copy(pf, pt)?;
+                    Ok(())
+                }
+                loc => Ok(res.set_content(loc, ResourceHandle::File(pf.to_owned()))?),
+            },
+            ResourceLocation::Remote(r) => Err(resources::Error::RemoteRead(r.clone()).into()),
+        }
+    }
+    /// Whether what this deploys is already in place, without changing it.
+    /// `None` if it only checks something, or if all it does is fill in an
+    /// in-memory resource, which happens in `res` so what reads it can tell
+    fn status(&self, res: &mut ResourceStore) -> Result<Option<Status>> {
+        if matches!(self, Action::CheckHash { .. } | Action::Verify { .. }) {
+            return Ok(None);
+        }
+        let to = match self.output() {
+            ResourceLocation::InMemory { .. } => {
+                self.run(res)?;
+                return Ok(None);
+            }
+            ResourceLocation::Path(to) => to,
+            ResourceLocation::Remote(_) => {
+                return Ok(Some(Status::Unknown(
+                    "remote files aren't checked".to_owned(),
+                )))
+            }
+        };
+        let conflicting = |what: &str| Ok(Some(Status::Conflicting(what.to_owned())));
+        let Ok(m) = fs::symlink_metadata(&to) else {
+            return Ok(Some(Status::Missing));
+        };
+        match self {
+            Action::MkDir { .. } | Action::EnsureDir { .. } if !to.is_dir() => {
+                conflicting("it isn't a directory")
+            }
+            Action::Link {
+                ty, from, fallback, ..
+            } => {
+                if m.is_symlink() {
+                    return match stale_link(from, &to, None)? {
+                        None => Ok(Some(Status::Deployed)),
+                        Some(StaleLink { old, .. }) => {
+                            conflicting(&format!("it links to '{}'", old.to_string_lossy()))
+                        }
+                    };
+                }
+                let linked = *ty == LinkType::Hard && m.is_file() && same_inode(from, &to)?;
+                // copied by an earlier deploy
+                let copied = *fallback == SymlinkFallback::Copy
+                    && !links_supported(&to)
+                    && same_tree(from, &to)?;
+                if linked || copied {
+                    Ok(Some(Status::Deployed))
+                } else {
+                    conflicting("it isn't a link to the source")
+                }
+            }
+            Action::Copy {
+                from: ResourceLocation::Path(from),
+                ..
+            } if !same_tree(from, &to)? => conflicting("its content differs"),
+            Action::Copy { from, .. }
+                if from.as_path().is_none() && !same_bytes(&res.get_content(from)?, &to)? =>
+            {
+                conflicting("its content differs")
+            }
+            Action::TemplateExpand { ctx, target, .. }
+                if !same_bytes(expand(ctx, target, res)?.0.as_bytes(), &to)? =>
+            {
+                conflicting("its content differs from the rendered template")
+            }
+            Action::Block { from, block, .. } => {
+                let content = String::from_utf8_lossy(&res.get_content(from)?).into_owned();
+                let text = fs::read_to_string(&to)?;
+                match block.insert(&text, &content) {
+                    Ok(updated) if updated == text => Ok(Some(Status::Deployed)),
+                    Ok(_) => conflicting(&format!("block '{}' differs", block.name)),
+                    Err(e) => conflicting(&e.to_string()),
+                }
+            }
+            Action::Merge { from, arrays, .. }
+                if merge_documents(from, &to, *arrays, res)?.1.changed() =>
+            {
+                conflicting("it's missing some of what's merged into it")
+            }
+            // unpacked archives are only checked for being there at all
+            _ => Ok(Some(Status::Deployed)),
+        }
+    }
+    /// What running this would change, found like [`Action::status`] but
+    /// as a diff for what writes text. Paths in it are shown in `style`
+    fn difference(&self, res: &mut ResourceStore, style: &PathStyle) -> Result<Option<Difference>> {
+        let written = match self {
+            Action::Copy {
+                from,
+                to: ResourceLocation::Path(to),
+            } if !from.as_path().is_some_and(|p| p.is_dir()) => Some((res.get_content(from)?, to)),
+            Action::TemplateExpand {
+                ctx,
+                target,
+                output: ResourceLocation::Path(to),
+            } => Some((expand(ctx, target, res)?.0.into_bytes(), to)),
+            Action::Block {
+                from,
+                to,
+                block,
+                create,
+            } if to.is_file() || *create => {
+                let content = String::from_utf8_lossy(&res.get_content(from)?).into_owned();
+                let text = if to.is_file() {
+                    fs::read_to_string(to)?
+                } else {
+                    String::new()
+                };
+                let updated = block
+                    .insert(&text, &content)
+                    .map_err(|source| Error::BlockIn {
+                        path: to.to_string_lossy().into_owned(),
+                        source,
+                    })?;
+                Some((updated.into_bytes(), to))
+            }
+            Action::Merge {
+                from,
+                to,
+                format,
+                arrays,
+            } => {
+                let (text, merged) = merge_documents(from, to, *arrays, res)?;
+                if !merged.changed() {
+                    return Ok(Some(Difference::Same));
+                }
+                let updated = merged.write(&text, &to.to_string_lossy(), *format)?;
+                Some((updated.into_bytes(), to))
+            }
+            _ => None,
+        };
+        let Some((new, to)) = written else {
+            return Ok(self.status(res)?.map(|status| match status {
+                Status::Deployed => Difference::Same,
+                Status::Missing => Difference::Other("it doesn't exist yet".to_owned()),
+                Status::Conflicting(what) | Status::Unknown(what) => Difference::Other(what),
+            }));
+        };
+        let old = match fs::metadata(to) {
+            Ok(m) if m.is_file() => Some(res.get_content(&ResourceLocation::Path(to.clone()))?),
+            Ok(_) => return Ok(Some(Difference::Other("it isn't a file".to_owned()))),
+            Err(_) => None,
+        };
+        if old.as_deref() == Some(&new[..]) {
+            return Ok(Some(Difference::Same));
+        }
+        let (Ok(before), Ok(after)) = (
+            std::str::from_utf8(old.as_deref().unwrap_or_default()),
+            std::str::from_utf8(&new),
+        ) else {
+            return Ok(Some(Difference::Other(match old {
+                Some(_) => "its content differs, and isn't text".to_owned(),
+                None => "it doesn't exist yet".to_owned(),
+            })));
+        };
+        let name = style.path(to);
+        let old_name = if old.is_some() {
+            name.as_str()
+        } else {
+            "/dev/null"
+        };
+        let lines = diff::unified(before, after, old_name, &name);
+        Ok(Some(if lines.is_empty() {
+            Difference::Other("only how its lines end differs".to_owned())
+        } else {
+            Difference::Text(lines)
+        }))
+    }
+    /// Shell commands doing what running this would, `None` if all it does
+    /// is fill in an in-memory resource, which happens in `res` instead
+    fn script(&self, res: &mut ResourceStore) -> Result<Option<String>> {
+        let quoted = |p: &Path| {
+            p.to_str()
+                .map(script::quote)
+                .ok_or_else(|| self.not_scriptable("its path is not valid UTF-8"))
+        };
+        let embed = |content: Vec<u8>, meta: ResourceMeta, to: &Path| {
+            let content = String::from_utf8(content)
+                .ok()
+                .filter(|c| !c.contains('\0'))
+                .ok_or_else(|| self.not_scriptable("its content isn't text"))?;
+            let to = quoted(to)?;
+            let mut cmds = script::write_file(&content, &to);
+            #[cfg(unix)]
+            if let Some(permissions) = meta.permissions {
+                use std::os::unix::fs::PermissionsExt;
+                cmds += &format!("\nchmod {:o} {to}", permissions.mode() & 0o7777);
+            }
+            #[cfg(not(unix))]
+            let _ = meta;
+            Ok::<_, Error>(cmds)
+        };
+        if let Action::Verify {
+            command,
+            dir,
+            on_failure,
+            ..
+        } = self
+        {
+            let verify = format!("(cd {} && {command})", quoted(dir)?);
+            return match on_failure {
+                VerifyFailure::Error => Ok(Some(verify)),
+                VerifyFailure::Warn => Ok(Some(format!(
+                    "{verify} || echo {} >&2",
+                    script::quote(&format!("verification `{command}` failed"))
+                ))),
+                VerifyFailure::Rollback => {
+                    Err(self.not_scriptable("it rolls back if verification fails"))
+                }
+            };
+        }
+        match self.output() {
+            ResourceLocation::InMemory { .. } => {
+                self.run(res)?;
+                return Ok(None);
+            }
+            ResourceLocation::Remote(_) => {
+                return Err(self.not_scriptable("it writes to another machine"))
+            }
+            ResourceLocation::Path(_) => {}
+        }
+        Ok(Some(match self {
+            Action::Block { .. } | Action::Merge { .. } => {
+                return Err(self.not_scriptable("it edits a file in place"))
+            }
+            Action::MkDir { path } => format!("mkdir -p {}", quoted(path)?),
+            Action::EnsureDir { path, mode } => {
+                let path = quoted(path)?;
+                match mode {
+                    Some(mode) => format!("mkdir -p {path}\nchmod {mode:o} {path}"),
+                    None => format!("mkdir -p {path}"),
+                }
+            }
+            Action::Link { ty, from, to, .. } => {
+                let from = quoted(&abspath::canonicalize(from)?)?;
+                let to = quoted(to)?;
+                match ty {
+                    LinkType::Soft => format!("[ -L {to} ] || ln -s {from} {to}"),
+                    LinkType::Hard => format!("[ {from} -ef {to} ] || ln {from} {to}"),
+                }
+            }
+            Action::Copy {
+                from: ResourceLocation::Path(from),
+                to,
+            } => format!("cp {} {}", quoted(from)?, quoted(written_path(to))?),
+            Action::Copy { from, to } => embed(
+                res.get_content(from)?,
+                res.get_meta(from)?,
+                written_path(to),
+            )?,
+            Action::TemplateExpand {
+                ctx,
+                target,
+                output,
+            } => {
+                let (content, meta) = expand(ctx, target, res)?;
+                embed(content.into_bytes(), meta, written_path(output))?
+            }
+            Action::Verify { .. } => unreachable!("verifications are scripted above"),
+            Action::CheckHash { file, sha256 } => {
+                let file = quoted(file)?;
+                format!(
+                    "[ \"$(sha256sum < {file} | cut -d ' ' -f 1)\" = {sha256} ] || {{ echo {} >&2; exit 1; }}",
+                    script::quote(&format!("{file} doesn't have sha256 {sha256}"))
+                )
+            }
+            Action::Extract {
+                archive,
+                format,
+                to,
+                strip,
+            } => {
+                let (archive, to) = (quoted(archive)?, quoted(to)?);
+                match (format, strip) {
+                    (archive::Format::Tar, _) => {
+                        format!(
+                            "mkdir -p {to} && tar -xf {archive} -C {to} --strip-components={strip}"
+                        )
+                    }
+                    (archive::Format::Zip, 0) => {
+                        format!("mkdir -p {to} && unzip -q -o {archive} -d {to}")
+                    }
+                    (archive::Format::Zip, _) => {
+                        return Err(self.not_scriptable("unzip can't strip leading components"))
+                    }
+                }
+            }
+        }))
+    }
+    fn not_scriptable(&self, reason: &'static str) -> Error {
+        Error::NotScriptable {
+            action: self.to_string(),
+            reason,
+        }
+    }
+    pub fn kind(&self) -> ActionKind {
+        match self {
+            Action::Link { ty, .. } => ActionKind::Link(*ty),
+            Action::Copy { .. } => ActionKind::Copy,
+            Action::MkDir { .. } => ActionKind::MkDir,
+            Action::EnsureDir { .. } => ActionKind::EnsureDir,
+            Action::Block { .. } => ActionKind::Block,
+            Action::Merge { .. } => ActionKind::Merge,
+            Action::TemplateExpand { .. } => ActionKind::TemplateExpand,
+            Action::Verify { .. } => ActionKind::Verify,
+            Action::Extract { .. } => ActionKind::Extract,
+            Action::CheckHash { .. } => ActionKind::CheckHash,
+        }
+    }
+    /// What the action reads, `None` if it doesn't read anything
     pub fn dependency(&self) -> Option<ResourceLocation> {
         match self {
-            Action::Link { from, .. } => Some(ResourceLocation::Path(from.to_owned())),
-            Action::Copy { from, .. } => Some(from.to_owned()),
-            Action::TemplateExpand { target, .. } => Some(target.to_owned()),
-            Action::MkDir { .. } => None,
+            Action::Link { from, .. }
+            | Action::Extract { archive: from, .. }
+            | Action::CheckHash { file: from, .. } => Some(ResourceLocation::Path(from.to_owned())),
+            Action::Copy { from, .. } | Action::Block { from, .. } | Action::Merge { from, .. } => {
+                Some(from.to_owned())
+            }
+            Action::TemplateExpand { target, .. } | Action::Verify { target, .. } => {
+                Some(target.to_owned())
+            }
+            Action::MkDir { .. } | Action::EnsureDir { .. } => None,
+        }
+    }
+    /// Everything this action reads, its [`dependency`](Self::dependency)
+    /// plus any files its template reads
+    pub fn dependencies(&self) -> Vec<ResourceLocation> {
+        let mut deps: Vec<_> = self.dependency().into_iter().collect();
+        if let Action::TemplateExpand {
+            ctx,
+            target: ResourceLocation::Path(file),
+            ..
+        } = self
+        {
+            if let Ok(content) = fs::read_to_string(file) {
+                deps.extend(
+                    ctx.dependencies(&content, Some(file))
+                        .into_iter()
+                        .filter_map(|p| AbsPathBuf::new(p).ok())
+                        .map(ResourceLocation::Path),
+                );
+            }
         }
+        deps
     }
+    /// What the action writes or creates, or for [`Action::Verify`] and
+    /// [`Action::CheckHash`] what it checks
     pub fn output(&self) -> ResourceLocation {
         match self {
-            Action::Link { to, .. } => ResourceLocation::Path(to.to_owned()),
+            Action::CheckHash { file, .. } => ResourceLocation::Path(file.to_owned()),
+            Action::Verify { target, .. } => target.to_owned(),
+            Action::Extract { to, .. } => ResourceLocation::Path(to.to_owned()),
+            Action::Link { to, .. } | Action::Block { to, .. } | Action::Merge { to, .. } => {
+                ResourceLocation::Path(to.to_owned())
+            }
             Action::Copy { to, .. } => to.to_owned(),
-            Action::MkDir { path } => ResourceLocation::Path(path.to_owned()),
+            Action::MkDir { path } | Action::EnsureDir { path, .. } => {
+                ResourceLocation::Path(path.to_owned())
+            }
             Action::TemplateExpand { output, .. } => output.to_owned(),
         }
     }
+    /// The file or remote path the action writes, if it writes one rather
+    /// than only checking it or keeping it in memory
+    pub fn written(&self) -> Option<ResourceLocation> {
+        match self {
+            Action::Extract { .. }
+            | Action::Link { .. }
+            | Action::Copy { .. }
+            | Action::Block { .. }
+            | Action::Merge { .. }
+            | Action::TemplateExpand { .. } => {
+                Some(self.output()).filter(|o| !matches!(o, ResourceLocation::InMemory { .. }))
+            }
+            Action::CheckHash { .. }
+            | Action::Verify { .. }
+            | Action::MkDir { .. }
+            | Action::EnsureDir { .. } => None,
+        }
+    }
 
-    pub fn configure_watcher(&self, watcher: &mut dyn notify::Watcher) -> notify::Result<()> {
-        let src = match self {
-            Action::Link { .. } | Action::MkDir { .. } => None,
-            Action::Copy { from, .. } => from.as_path(),
-            Action::TemplateExpand { target, .. } => target.as_path(),
+    /// Log the shell commands expanding this action's template would run
+    fn log_shell_commands(&self, res: &ResourceStore) {
+        if let Action::Verify {
+            command, target, ..
+        } = self
+        {
+            log::info!("would run `{command}` to verify {target}");
+            return;
+        }
+        if let Action::Copy {
+            to: ResourceLocation::Remote(remote),
+            ..
+        } = self
+        {
+            log::info!("would run `{}`", remote.ssh_command());
+            return;
+        }
+        let Action::TemplateExpand { ctx, target, .. } = self else {
+            return;
+        };
+        if let Some(cmd) = res.command(target) {
+            log::info!("would run `{cmd}` to get the template for {target}");
+            return;
+        }
+        if !ctx.shell_enabled() {
+            return;
+        }
+        match template_source(res, target).and_then(|c| Ok(template::shell_commands(&c)?)) {
+            Ok(cmds) => {
+                for cmd in cmds {
+                    log::info!("would run shell command `{cmd}`");
+                }
+            }
+            Err(e) => log::warn!("failed to read template {target}: {e}"),
+        }
+    }
+
+    /// Log the symlink this would point at its source instead, if any
+    fn log_repoint(&self) {
+        let Action::Link {
+            from,
+            to,
+            repoint: Some(within),
+            ..
+        } = self
+        else {
+            return;
+        };
+        if !fs::symlink_metadata(to).is_ok_and(|m| m.is_symlink()) {
+            return;
+        }
+        if let Ok(Some(StaleLink {
+            old,
+            repointable: true,
+        })) = stale_link(from, to, Some(within))
+        {
+            log::info!(
+                "would repoint '{}': '{}' -> '{}'",
+                to.to_string_lossy(),
+                old.to_string_lossy(),
+                from.to_string_lossy()
+            );
+        }
+    }
+    /// Log what merging would change, if what's merged in can be read
+    /// without running anything
+    fn log_merge_changes(&self, res: &ResourceStore) {
+        let Action::Merge {
+            from, to, arrays, ..
+        } = self
+        else {
+            return;
+        };
+        let to_name = to.to_string_lossy();
+        // template output is empty until the template is expanded
+        let readable = match from {
+            ResourceLocation::Path(_) => true,
+            ResourceLocation::InMemory { .. } => {
+                res.command(from).is_none() && res.get_content(from).is_ok_and(|c| !c.is_empty())
+            }
+            ResourceLocation::Remote(_) => false,
         };
-        if let Some(src) = src {
+        if !readable {
+            log::info!("would merge {from} into '{to_name}'");
+            return;
+        }
+        match merge_documents(from, to, *arrays, res) {
+            Ok((_, merged)) if !merged.changed() => {
+                log::info!("merging into '{to_name}' would change nothing")
+            }
+            Ok((_, merged)) => {
+                log::info!("merging into '{to_name}' would change:");
+                for line in merged.changes() {
+                    log::info!("  {line}");
+                }
+            }
+            Err(e) => log::warn!("can't preview merging into '{to_name}': {e}"),
+        }
+    }
+
+    pub fn configure_watcher(&self, watcher: &mut dyn notify::Watcher) -> notify::Result<()> {
+        // verifications read what was just deployed, which would trigger
+        // another deploy
+        if matches!(
+            self,
+            Action::Link { .. }
+                | Action::MkDir { .. }
+                | Action::EnsureDir { .. }
+                | Action::Verify { .. }
+        ) {
+            return Ok(());
+        }
+        for dep in self.dependencies() {
+            let Some(src) = dep.as_path() else {
+                continue;
+            };
             watcher.watch(
                 if !src.is_dir() {
                     src.parent().unwrap()
@@ -133,37 +919,292 @@ impl Action {
         matches!(self, Self::Copy { .. })
     }
 }
-impl std::fmt::Display for Action {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Action {
+    /// How this is shown in logs, split where the columns of a table of
+    /// actions should line up
+    fn columns(&self, style: &PathStyle) -> (String, String) {
+        let source = |r: &ResourceLocation| match r.as_path() {
+            Some(p) => style.source(p),
+            None => r.to_string(),
+        };
+        let dest = |r: &ResourceLocation| match r.as_path() {
+            Some(p) => style.path(p),
+            None => r.to_string(),
+        };
         match self {
-            Action::MkDir { path } => {
-                f.write_fmt(format_args!("mkdir {path}", path = path.to_string_lossy()))
+            Action::MkDir { path } => (format!("mkdir {}", style.path(path)), String::new()),
+            Action::EnsureDir { path, mode } => (
+                format!("ensure directory {}", style.path(path)),
+                mode.map(|m| format!(" [{m:o}]")).unwrap_or_default(),
+            ),
+            Action::Link { ty, from, to, .. } => (
+                style.source(from),
+                format!(
+                    " -> {to} [{typ}]",
+                    to = style.path(to),
+                    typ = match ty {
+                        LinkType::Hard => "hard",
+                        LinkType::Soft => "soft",
+                    },
+                ),
+            ),
+            Action::Copy { from, to } => {
+                (format!("[{}]", source(from)), format!(" -> [{}]", dest(to)))
             }
-            Action::Link { ty, from, to } => f.write_fmt(format_args!(
-                "{from} -> {to} [{typ}]",
-                typ = match ty {
+            Action::Block {
+                from, to, block, ..
+            } => (
+                format!("[{}]", source(from)),
+                format!(" -> block '{}' of [{}]", block.name, style.path(to)),
+            ),
+            Action::Merge { from, to, .. } => (
+                format!("[{}]", source(from)),
+                format!(" -> merge into [{}]", style.path(to)),
+            ),
+            Action::TemplateExpand { target, output, .. } => (
+                format!("expand {}", source(target)),
+                format!(" to {}", dest(output)),
+            ),
+            Action::Verify {
+                command, target, ..
+            } => (
+                format!("verify {}", dest(target)),
+                format!(" with `{command}`"),
+            ),
+            Action::CheckHash { file, sha256 } => (
+                format!("check {}", style.source(file)),
+                format!(" has sha256 {}...", &sha256[..12]),
+            ),
+            Action::Extract {
+                archive, to, strip, ..
+            } => (
+                format!("extract {}", style.source(archive)),
+                match strip {
+                    0 => format!(" -> {}", style.path(to)),
+                    n => format!(" -> {} (strip {n})", style.path(to)),
+                },
+            ),
+        }
+    }
+}
+impl Action {
+    fn row(&self, style: &PathStyle) -> Row {
+        let source = |r: &ResourceLocation| match r.as_path() {
+            Some(p) => style.source(p),
+            None => r.to_string(),
+        };
+        let dest = |r: &ResourceLocation| match r.as_path() {
+            Some(p) => style.path(p),
+            None => r.to_string(),
+        };
+        let (source, destination, detail) = match self {
+            Action::MkDir { path } => (String::new(), style.path(path), String::new()),
+            Action::EnsureDir { path, mode } => (
+                String::new(),
+                style.path(path),
+                mode.map(|m| format!("{m:o}")).unwrap_or_default(),
+            ),
+            Action::Link { ty, from, to, .. } => (
+                style.source(from),
+                style.path(to),
+                match ty {
                     LinkType::Hard => "hard",
                     LinkType::Soft => "soft",
+                }
+                .to_owned(),
+            ),
+            Action::Copy { from, to } => (source(from), dest(to), String::new()),
+            Action::Block {
+                from, to, block, ..
+            } => (
+                source(from),
+                style.path(to),
+                format!("block '{}'", block.name),
+            ),
+            Action::Merge {
+                from, to, arrays, ..
+            } => (
+                source(from),
+                style.path(to),
+                match arrays {
+                    ArrayMerge::Replace => String::new(),
+                    ArrayMerge::Union => "union arrays".to_owned(),
                 },
-                from = from.to_string_lossy(),
-                to = to.to_string_lossy(),
-            )),
-            Action::Copy { from, to } => f.write_fmt(format_args!("[{from}] -> [{to}]")),
+            ),
             Action::TemplateExpand { target, output, .. } => {
-                write!(f, "expand {target} to {output}")
+                (source(target), dest(output), String::new())
             }
+            Action::Verify {
+                command, target, ..
+            } => (String::new(), dest(target), format!("`{command}`")),
+            Action::CheckHash { file, sha256 } => (
+                style.source(file),
+                String::new(),
+                format!("sha256 {}...", &sha256[..12]),
+            ),
+            Action::Extract {
+                archive, to, strip, ..
+            } => (
+                style.source(archive),
+                style.path(to),
+                match strip {
+                    0 => String::new(),
+                    n => format!("strip {n}"),
+                },
+            ),
+        };
+        Row {
+            kind: self.kind(),
+            source,
+            destination,
+            detail,
+        }
+    }
+}
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (left, right) = self.columns(&PathStyle::default());
+        write!(f, "{left}{right}")
+    }
+}
+
+/// How running an [`Action`] went
+#[derive(Clone, Copy, Debug)]
+pub enum Outcome<'a> {
+    Done,
+    Failed(&'a Error),
+    /// Not run because this is a dry run, or because what the action reads
+    /// failed
+    Skipped,
+}
+
+/// Whether what an [`Action`] deploys is in place, as `dotloy status` shows
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    Deployed,
+    Missing,
+    /// Something else is there, with what
+    Conflicting(String),
+    /// Couldn't be checked, with why
+    Unknown(String),
+}
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Deployed => "deployed",
+            Status::Missing => "missing",
+            Status::Conflicting(_) => "conflicting",
+            Status::Unknown(_) => "unknown",
         }
     }
+    /// What's there or why it couldn't be checked, if anything needs saying
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            Status::Deployed | Status::Missing => None,
+            Status::Conflicting(why) | Status::Unknown(why) => Some(why),
+        }
+    }
+}
+
+/// What deploying an [`Action`] would change, as `dotloy diff` shows
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// Nothing, it's deployed
+    Same,
+    /// Unified diff of what's there against what would be written
+    Text(Vec<String>),
+    /// What would change, for what can't be shown as a diff of text
+    Other(String),
 }
 
-type Result<T, E = Error> = std::result::Result<T, E>;
+pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
-    Template(#[from] handybars::Error),
+    Resource(#[from] resources::Error),
+    #[error(transparent)]
+    Path(#[from] abspath::Error),
+    #[error("in target '{target}': {source}")]
+    PathIn {
+        target: String,
+        source: abspath::Error,
+    },
+    #[error(transparent)]
+    Template(#[from] template::Error),
+    #[error("in template '{file}': {source}")]
+    TemplateIn {
+        file: String,
+        source: template::Error,
+    },
+    #[error("in target '{target}', field '{field}': {source}")]
+    TemplateField {
+        target: String,
+        field: &'static str,
+        source: template::Error,
+    },
+    /// In the definition of variable `name` of `target`, or of the config
+    /// if there is no target
+    #[error("in {place}field 'variables.{name}': {source}", place = target_place(target))]
+    TemplateVariable {
+        target: Option<String>,
+        name: String,
+        source: Box<template::Error>,
+    },
+    #[error("in target '{target}': variable '{name}' clashes with the built-in 'target.{name}', rename it")]
+    ReservedTargetVariable { target: String, name: &'static str },
+    #[error("template '{file}' is not valid UTF-8, set `template: false` on its target to copy it as is")]
+    TemplateNotUtf8 { file: String },
+    #[error("target '{target}' has `ensure_dir: true`, which can't be combined with `{field}`")]
+    EnsureDirWith { target: String, field: &'static str },
+    #[error("target '{target}' has `dir_mode` but only `ensure_dir` targets can have one")]
+    DirModeWithoutEnsureDir { target: String },
+    #[error(
+        "in target '{target}': `dir_mode` should be octal permissions like \"700\", not '{mode}'"
+    )]
+    InvalidDirMode { target: String, mode: String },
+    #[error("target '{target}' has `content`, which can't be combined with `{field}`")]
+    ContentWith { target: String, field: &'static str },
+    #[error("target '{target}' has `{mode}`, which can't be combined with `{field}`")]
+    EditWith {
+        target: String,
+        mode: &'static str,
+        field: &'static str,
+    },
+    #[error("target '{target}' edits '{to}' on another machine, files can only be edited in place locally")]
+    RemoteEdit { target: String, to: String },
+    #[error("target '{target}' merges into '{to}' but can't tell if it's JSON or YAML, set `merge_format`")]
+    UnknownMergeFormat { target: String, to: String },
+    #[error(transparent)]
+    Merge(#[from] merge::Error),
+    #[error("'{path}' doesn't exist, set `block_create: true` on its target to create it")]
+    BlockFileMissing { path: String },
+    #[error("block '{name}' of '{path}' can only hold text, but {from} isn't")]
+    BlockNotText {
+        name: String,
+        path: String,
+        from: String,
+    },
+    #[error("in '{path}': {source}")]
+    BlockIn { path: String, source: block::Error },
+    #[error("target '{target}' has both `from` and `from_command`, it can only have one")]
+    FromAndFromCommand { target: String },
+    #[error("target '{target}' deploys to '{to}' on another machine, which only works with `link_type: copy` or templates")]
+    RemoteLink { target: String, to: String },
+    #[error("targets '{first}' and '{second}' both deploy to '{to}'")]
+    DuplicateDestination {
+        to: String,
+        first: String,
+        second: String,
+    },
+    #[error("target '{target}' has `extract: true` but '{path}' isn't a .tar(.gz, .xz, ...) or .zip file")]
+    NotAnArchive { target: String, path: String },
+    #[error("target '{target}' extracts to '{to}' on another machine, archives can only be extracted locally")]
+    RemoteExtract { target: String, to: String },
+    #[error(transparent)]
+    Archive(#[from] archive::Error),
     #[error("Source file does not exist: '{path}'")]
     SourceDoesNotExist { path: String },
     #[error("Target file '{path}' already exists")]
@@ -180,20 +1221,92 @@ pub enum Error {
     UnsupportedPlatform,
     #[error("No actions to perform, did you not define any targets in your config?")]
     NoActions,
+    #[error("failed to run verification `{command}`: {source}")]
+    VerifySpawn {
+        command: String,
+        source: std::io::Error,
+    },
+    #[error("verification `{command}` failed ({status}): {output}")]
+    VerifyFailed {
+        command: String,
+        status: String,
+        output: String,
+    },
+    #[error("{source}, rolled '{path}' back")]
+    RolledBack { path: String, source: Box<Error> },
+    #[error(
+        "target '{target}' has `sha256` but its source isn't a file, only files can be checked"
+    )]
+    UnhashableSource { target: String },
+    #[error("in target '{target}': `sha256` should be 64 hex digits, not '{sha256}'")]
+    InvalidSha256 { target: String, sha256: String },
+    #[error("'{file}' has sha256 {actual}, expected {expected}")]
+    HashMismatch {
+        file: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("can't write '{action}' into a plan, {reason}")]
+    NotPlannable {
+        action: String,
+        reason: &'static str,
+    },
+    #[error("plan has a relative path '{path}', plans only have absolute paths")]
+    RelativePlanPath { path: String },
+    #[error("can't write '{action}' as a script, {reason}")]
+    NotScriptable {
+        action: String,
+        reason: &'static str,
+    },
+}
+
+/// Where an action came from, to tell them apart in logs
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Origin {
+    config: Option<PathBuf>,
+    target: Option<String>,
+    /// `order` of the target, raised to that of anything it must come after
+    order: i32,
+    description: Option<String>,
+    /// What the target is called in dry runs, its name or else its `from`
+    label: String,
+}
+
+/// Whether `r` is `of` or, for paths, somewhere inside it
+fn within(r: &ResourceLocation, of: &ResourceLocation) -> bool {
+    match (r.as_path(), of.as_path()) {
+        (Some(r), Some(of)) => r.starts_with(of),
+        _ => r == of,
+    }
 }
 
 #[derive(Clone, Debug, Default)]
 struct ActionsBuilder {
     acts: Vec<Action>,
+    origins: Vec<Origin>,
+    /// Origin of actions added from now on
+    origin: Origin,
     res: ResourceStore,
+    /// Relative paths are relative to this, or the cwd if it's empty
+    base: PathBuf,
 }
 impl ActionsBuilder {
+    fn push(&mut self, act: Action) {
+        self.acts.push(act);
+        self.origins.push(self.origin.clone());
+    }
+    fn in_dir(base: impl Into<PathBuf>) -> Self {
+        Self {
+            base: base.into(),
+            ..Default::default()
+        }
+    }
     fn copy(
         &mut self,
         from: impl Into<ResourceLocation>,
         to: impl Into<ResourceLocation>,
     ) -> &mut Self {
-        self.acts.push(Action::Copy {
+        self.push(Action::Copy {
             from: from.into(),
             to: to.into(),
         });
@@ -204,61 +1317,109 @@ impl ActionsBuilder {
         from: impl AsRef<Path>,
         to: impl AsRef<Path>,
         ty: LinkType,
-    ) -> std::io::Result<&mut Self> {
-        self.acts.push(Action::Link {
+        fallback: SymlinkFallback,
+        on_conflict: OnConflict,
+        adopt: bool,
+    ) -> abspath::Result<&mut Self> {
+        let repoint = match on_conflict {
+            OnConflict::Error => None,
+            OnConflict::Repoint => Some(AbsPathBuf::new(&self.base)?),
+        };
+        self.push(Action::Link {
             ty,
-            from: AbsPathBuf::new(from)?,
-            to: AbsPathBuf::new(to)?,
+            from: AbsPathBuf::new_in(&self.base, from)?,
+            to: AbsPathBuf::logical_in(&self.base, to)?,
+            fallback,
+            repoint,
+            adopt,
         });
         Ok(self)
     }
     fn template(
         &mut self,
-        ctx: handybars::Context<'static>,
+        ctx: Context,
         src: impl Into<ResourceLocation>,
         dst: impl Into<ResourceLocation>,
     ) -> &mut Self {
-        self.acts.push(Action::TemplateExpand {
+        self.push(Action::TemplateExpand {
             ctx,
             target: src.into(),
             output: dst.into(),
         });
         self
     }
+    #[cfg(test)]
     fn template_expand(
         &mut self,
-        ctx: handybars::Context<'static>,
+        ctx: Context,
         src: impl AsRef<Path>,
         dst: impl AsRef<Path>,
-    ) -> std::io::Result<&mut Self> {
-        let resource = self.res.define_mem();
-        self.template(
-            ctx,
-            ResourceLocation::Path(AbsPathBuf::new(src)?),
-            resource.clone(),
-        )
-        .copy(resource, ResourceLocation::Path(AbsPathBuf::new(dst)?));
-        Ok(self)
+    ) -> abspath::Result<&mut Self> {
+        let src = AbsPathBuf::new_in(&self.base, src)?;
+        let dst = AbsPathBuf::logical_in(&self.base, dst)?;
+        Ok(self.expand_to(ctx, src, dst))
+    }
+    /// Expand `src` into an in-memory resource and copy that to `dst`
+    fn expand_to(
+        &mut self,
+        ctx: Context,
+        src: impl Into<ResourceLocation>,
+        dst: impl Into<ResourceLocation>,
+    ) -> &mut Self {
+        let dst = dst.into();
+        let resource = self.expand(ctx, src, &dst);
+        self.copy(resource, dst)
+    }
+    /// Expand `src` into an in-memory resource, which is returned, for
+    /// something that ends up in `dst`
+    fn expand(
+        &mut self,
+        ctx: Context,
+        src: impl Into<ResourceLocation>,
+        dst: &ResourceLocation,
+    ) -> ResourceLocation {
+        let resource = self
+            .res
+            .define_mem_labeled(format!("template output for {dst}"));
+        self.template(ctx, src, resource.clone());
+        resource
     }
-    fn mkdir(&mut self, dir: impl AsRef<Path>) -> std::io::Result<&mut Self> {
-        self.acts.push(Action::MkDir {
-            path: AbsPathBuf::new(dir)?,
+    fn mkdir(&mut self, dir: impl AsRef<Path>) -> abspath::Result<&mut Self> {
+        self.push(Action::MkDir {
+            path: AbsPathBuf::logical_in(&self.base, dir)?,
         });
         Ok(self)
     }
 
     fn build(self) -> Actions {
-        Actions {
+        let mut style = PathStyle::default();
+        if self.base.is_absolute() {
+            style.add_base(self.base);
+        }
+        let mut acts = Actions {
             acts: self.acts,
+            origins: self.origins,
             resources: self.res,
-        }
+            style,
+            history: None,
+            quiet_failures: false,
+        };
+        acts.sort_by_order();
+        acts
     }
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct Actions {
     acts: Vec<Action>,
+    /// Where each of `acts` came from
+    origins: Vec<Origin>,
     resources: ResourceStore,
+    style: PathStyle,
+    /// Where runs are recorded, if they are
+    history: Option<PathBuf>,
+    /// Leave logging failed actions to whoever runs them
+    quiet_failures: bool,
 }
 
 impl Actions {
@@ -267,236 +1428,3424 @@ impl Actions {
     }
     pub fn append(&mut self, other: &mut Actions) {
         self.acts.append(&mut other.acts);
+        self.origins.append(&mut other.origins);
         self.resources.append(&mut other.resources);
+        self.style.append(&other.style);
+        self.sort_by_order();
+    }
+    /// Stable sort of the actions by the `order` of their targets. An action
+    /// that reads or writes inside the output of one before it is never
+    /// moved ahead of it, its order is raised to match instead
+    fn sort_by_order(&mut self) {
+        if self.origins.iter().all(|o| o.order == 0) {
+            return;
+        }
+        let outputs = self.acts.iter().map(Action::output).collect::<Vec<_>>();
+        for (i, act) in self.acts.iter().enumerate() {
+            let dep = act.dependency();
+            let after = (0..i)
+                .filter(|j| {
+                    within(&outputs[i], &outputs[*j])
+                        || dep.as_ref().is_some_and(|d| within(d, &outputs[*j]))
+                })
+                .map(|j| self.origins[j].order)
+                .max();
+            if let Some(after) = after {
+                self.origins[i].order = self.origins[i].order.max(after);
+            }
+        }
+        let sorted = (0..self.acts.len())
+            .sorted_by_key(|i| self.origins[*i].order)
+            .collect::<Vec<_>>();
+        self.acts = sorted.iter().map(|i| self.acts[*i].clone()).collect();
+        self.origins = sorted.iter().map(|i| self.origins[*i].clone()).collect();
+    }
+    /// Attribute every action to the config read from `file` in logs
+    pub fn set_config(&mut self, file: &Path) {
+        for origin in &mut self.origins {
+            origin.config = Some(file.to_owned());
+        }
+    }
+    /// The configs set with [`Actions::set_config`] that actions came from,
+    /// each once
+    pub fn configs(&self) -> Vec<&Path> {
+        self.origins
+            .iter()
+            .filter_map(|o| o.config.as_deref())
+            .unique()
+            .collect()
+    }
+    /// Configs among [`Actions::configs`] inside a source of another one,
+    /// along with that config, which deploys the first along with the rest
+    /// of the directory
+    pub fn nested_configs(&self) -> Vec<(&Path, &Path)> {
+        self.configs()
+            .into_iter()
+            .filter_map(|config| {
+                self.acts
+                    .iter()
+                    .zip(&self.origins)
+                    .filter_map(|(act, origin)| {
+                        let by = origin.config.as_deref().filter(|by| *by != config)?;
+                        let source = act.dependency()?;
+                        config.starts_with(source.as_path()?).then_some(by)
+                    })
+                    .next()
+                    .map(|by| (config, by))
+            })
+            .collect()
+    }
+    /// How each action is logged, along with the config to announce before
+    /// it if it starts the actions of another config
+    fn log_lines(&self) -> Vec<(Option<String>, String)> {
+        let rows = self
+            .acts
+            .iter()
+            .zip(&self.origins)
+            .map(|(a, o)| {
+                let (left, mut right) = a.columns(&self.style);
+                if o.order != 0 {
+                    right += &format!(" (order {})", o.order);
+                }
+                match &o.target {
+                    Some(name) => (format!("[{name}] {left}"), right),
+                    None => (left, right),
+                }
+            })
+            .collect::<Vec<_>>();
+        let several = self.origins.iter().map(|o| &o.config).unique().count() > 1;
+        let mut prev = None;
+        self.origins
+            .iter()
+            .zip(display::align(&rows))
+            .map(|(o, shown)| {
+                let config = o.config.as_deref();
+                let header = config.filter(|c| several && prev != Some(*c));
+                prev = config;
+                (header.map(|c| self.style.path(c)), shown)
+            })
+            .collect()
+    }
+    /// The actions grouped by the target they came from, in the order they
+    /// run
+    pub fn groups(&self) -> Vec<Group> {
+        self.groups_in(&self.style)
+    }
+    fn groups_in(&self, style: &PathStyle) -> Vec<Group> {
+        let mut groups: Vec<Group> = Vec::new();
+        for (act, origin) in self.acts.iter().zip(&self.origins) {
+            let row = act.row(style);
+            match groups.last_mut() {
+                Some(g)
+                    if g.config == origin.config
+                        && g.target == origin.label
+                        && g.description == origin.description =>
+                {
+                    g.rows.push(row)
+                }
+                _ => groups.push(Group {
+                    config: origin.config.clone(),
+                    target: origin.label.clone(),
+                    description: origin.description.clone(),
+                    rows: vec![row],
+                }),
+            }
+        }
+        groups
+    }
+    /// How a dry run shows the actions, a table under a header for each
+    /// target and counts of what they do
+    fn dry_run_lines(&self) -> Vec<String> {
+        let groups = self.groups();
+        let cells = groups
+            .iter()
+            .flat_map(|g| &g.rows)
+            .map(|r| {
+                let both = !r.source.is_empty() && !r.destination.is_empty();
+                vec![
+                    r.kind.name().to_owned(),
+                    r.source.clone(),
+                    if both { "->" } else { "" }.to_owned(),
+                    r.destination.clone(),
+                    r.detail.clone(),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let mut padded = display::table(&cells).into_iter();
+        let several = groups.iter().map(|g| &g.config).unique().count() > 1;
+        let count = |n: usize| format!("{n} action{}", if n == 1 { "" } else { "s" });
+        let mut lines = Vec::new();
+        let mut prev = None;
+        for group in &groups {
+            if let Some(config) = group
+                .config
+                .as_ref()
+                .filter(|c| several && prev != Some(*c))
+            {
+                lines.push(format!("{}:", self.style.path(config)));
+            }
+            prev = group.config.as_ref();
+            let name = match group.target.as_str() {
+                "" => "(no target)",
+                name => name,
+            };
+            let mut header = name.bold().to_string();
+            if let Some(desc) = &group.description {
+                header += &format!(" {}", format!("({desc})").dimmed());
+            }
+            lines.push(header);
+            for row in &group.rows {
+                let mut cells = padded.next().unwrap_or_default();
+                if let Some(kind) = cells.first_mut() {
+                    *kind = kind.color(row.kind.color()).to_string();
+                }
+                lines.push(format!("  {}", cells.join(" ").trim_end()));
+            }
+            lines.push(format!("  {}", count(group.rows.len()).dimmed()));
+        }
+        lines.push(format!(
+            "{} from {} target{}",
+            count(self.acts.len()),
+            groups.len(),
+            if groups.len() == 1 { "" } else { "s" }
+        ));
+        lines
+    }
+    /// [`Actions::groups`] as JSON, with every path in full, along with
+    /// `timings` if there are any
+    pub fn groups_json(&self, timings: Option<&Timings>) -> String {
+        let string = |s: &str| display::json_string(s);
+        let optional = |s: Option<String>| s.map_or("null".to_owned(), |s| string(&s));
+        let groups = self
+            .groups_in(&PathStyle::default())
+            .into_iter()
+            .map(|g| {
+                let rows = g
+                    .rows
+                    .iter()
+                    .map(|r| {
+                        format!(
+                            r#"{{"kind":{},"source":{},"destination":{},"detail":{}}}"#,
+                            string(r.kind.name()),
+                            string(&r.source),
+                            string(&r.destination),
+                            string(&r.detail)
+                        )
+                    })
+                    .join(",");
+                format!(
+                    r#"{{"config":{},"target":{},"description":{},"count":{},"actions":[{rows}]}}"#,
+                    optional(g.config.map(|c| c.to_string_lossy().into_owned())),
+                    string(&g.target),
+                    optional(g.description),
+                    g.rows.len()
+                )
+            })
+            .join(",");
+        let timings = timings.map_or(String::new(), |t| format!(r#","timings":{}"#, t.json()));
+        format!(
+            r#"{{"count":{},"groups":[{groups}]{timings}}}"#,
+            self.acts.len()
+        )
+    }
+    /// Record what each run does to the history in `dir`, one record per
+    /// config the actions came from
+    pub fn record_history(&mut self, dir: impl Into<PathBuf>) {
+        self.history = Some(dir.into());
+    }
+    /// Don't log actions that fail when running them, they are still
+    /// returned and passed to the observer
+    pub fn set_quiet_failures(&mut self, quiet: bool) {
+        self.quiet_failures = quiet;
+    }
+    /// Abbreviate `home` as `~` in logs, or show every path in full
+    pub fn set_path_style(&mut self, home: Option<PathBuf>, full: bool) {
+        self.style.set_home(home, full);
+    }
+
+    /// The actions of the plan, in the order they run
+    pub fn iter(&self) -> impl Iterator<Item = &Action> + '_ {
+        self.acts.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.acts.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.acts.is_empty()
+    }
+    /// Let every link repoint a symlink in its way that points into `within`
+    /// or at nothing, like `on_conflict: repoint` on each of their targets
+    pub fn repoint_links(&mut self, within: &Path) -> abspath::Result<()> {
+        for act in &mut self.acts {
+            if let Action::Link {
+                repoint: repoint @ None,
+                ..
+            } = act
+            {
+                *repoint = Some(AbsPathBuf::new(within)?);
+            }
+        }
+        Ok(())
+    }
+    /// Just the actions checking sources against their `sha256`, to check
+    /// them without deploying anything
+    pub fn hash_checks(&self) -> Self {
+        let (acts, origins) = self
+            .acts
+            .iter()
+            .zip(&self.origins)
+            .filter(|(a, _)| a.kind() == ActionKind::CheckHash)
+            .map(|(a, o)| (a.clone(), o.clone()))
+            .unzip();
+        Self {
+            acts,
+            origins,
+            resources: self.resources.subset([]),
+            style: self.style.clone(),
+            history: self.history.clone(),
+            quiet_failures: self.quiet_failures,
+        }
+    }
+
+    /// What state each action deploys is in, with how it's shown in logs,
+    /// without changing anything. Templates are rendered like a deploy
+    /// would, to compare them with what's there
+    pub fn status(&mut self) -> Vec<(String, Status)> {
+        self.inspect(|action, res, _| action.status(res), Status::Unknown)
+    }
+    /// What deploying would change for each action, with how it's shown in
+    /// logs, without changing anything. Text is shown as a diff
+    pub fn differences(&mut self) -> Vec<(String, Difference)> {
+        self.inspect(Action::difference, |why| {
+            Difference::Other(format!("couldn't be checked: {why}"))
+        })
+    }
+    /// `check` each action, with how it's shown in logs, skipping those it
+    /// has nothing to say about. Those that couldn't be checked, or read
+    /// something that couldn't be, are `failed` with why
+    fn inspect<T>(
+        &mut self,
+        check: impl Fn(&Action, &mut ResourceStore, &PathStyle) -> Result<Option<T>>,
+        failed: impl Fn(String) -> T,
+    ) -> Vec<(String, T)> {
+        let lines = self.log_lines();
+        let res = &mut self.resources;
+        let mut unchecked = Vec::new();
+        let mut out = Vec::new();
+        for (action, (_, shown)) in self.acts.iter().zip(lines) {
+            let checked = if action.dependency().is_some_and(|d| unchecked.contains(&d)) {
+                Err("what it needs failed".to_owned())
+            } else {
+                check(action, res, &self.style).map_err(|e| e.to_string())
+            };
+            match checked {
+                Ok(Some(checked)) => out.push((shown, checked)),
+                Ok(None) => {}
+                Err(why) => {
+                    unchecked.push(action.output());
+                    out.push((shown, failed(why)));
+                }
+            }
+        }
+        out
+    }
+    /// Run every action, carrying on past the ones that fail. Those are
+    /// logged and returned, with why they failed
+    pub fn run(&mut self, dry: bool) -> Result<Vec<String>> {
+        self.run_observed(dry, |_, _| {})
+    }
+    /// [`Actions::run`], also passing how each action went to `observe` once
+    /// it has been logged
+    pub fn run_observed(
+        &mut self,
+        dry: bool,
+        mut observe: impl FnMut(&Action, Outcome),
+    ) -> Result<Vec<String>> {
+        if dry {
+            for line in self.dry_run_lines() {
+                log::info!("{line}");
+            }
+        }
+        let mut lines = self.log_lines().into_iter();
+        let mut origins = self.origins.clone().into_iter();
+        let style = self.style.clone();
+        let quiet_failures = self.quiet_failures;
+        let mut failed = Vec::new();
+        self.run_with(dry, |action, outcome| {
+            let (header, shown) = lines.next().unwrap_or_default();
+            let origin = origins.next().unwrap_or_default();
+            if dry {
+                // already shown as a table
+                observe(action, outcome);
+                return;
+            }
+            if let Some(config) = header {
+                log::info!("{config}:");
+            }
+            let described = |s: String| match &origin.description {
+                Some(desc) => format!("{s} ({desc})"),
+                None => s,
+            };
+            match outcome {
+                Outcome::Failed(e) => {
+                    if !quiet_failures {
+                        log::error!("{} failed. reason: {}", described(shown), e);
+                    }
+                    let (left, right) = action.columns(&style);
+                    failed.push(format!("{}: {e}", described(format!("{left}{right}"))));
+                }
+                Outcome::Done => log::info!("{shown}"),
+                Outcome::Skipped => {
+                    log::warn!("{} skipped, what it needs failed", described(shown))
+                }
+            }
+            observe(action, outcome);
+        })?;
+        Ok(failed)
+    }
+    /// Run every action, carrying on past the ones that fail, and pass how
+    /// each one went to `report` once it's done. Nothing is logged except
+    /// the shell commands a dry run would have run
+    pub fn run_with(&mut self, dry: bool, mut report: impl FnMut(&Action, Outcome)) -> Result<()> {
+        if self.acts.is_empty() {
+            return Err(Error::NoActions);
+        }
+        // Every in-memory resource is written before it is read, so outputs
+        // left over from a previous run are never seen
+        let res = &mut self.resources;
+        let rolled_back = self
+            .acts
+            .iter()
+            .filter_map(|a| match a {
+                Action::Verify {
+                    target: ResourceLocation::Path(p),
+                    on_failure: VerifyFailure::Rollback,
+                    ..
+                } => Some(p),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // what verified files held before they were first written this run
+        let mut backups: Vec<(&AbsPathBuf, Option<Vec<u8>>)> = Vec::new();
+        let mut failed = Vec::new();
+        // what happened to each output, for the history
+        let mut changes: Vec<(&Origin, history::Entry)> = Vec::new();
+        for (action, origin) in self.acts.iter().zip(&self.origins) {
+            let output = action.output();
+            let recorded = match action {
+                Action::Verify { .. } | Action::CheckHash { .. } => None,
+                _ => output.as_path().map(|p| fs::symlink_metadata(p).is_ok()),
+            };
+            let mut record = |change| {
+                if let Some(existed) = recorded {
+                    let change = match change {
+                        Change::Created if existed => Change::Updated,
+                        change => change,
+                    };
+                    changes.push((
+                        origin,
+                        history::Entry {
+                            output: output.to_string(),
+                            change,
+                        },
+                    ));
+                }
+            };
+            if dry {
+                record(Change::Created);
+                report(action, Outcome::Skipped);
+                action.log_shell_commands(res);
+                action.log_merge_changes(res);
+                action.log_repoint();
+                continue;
+            }
+            if action.dependency().is_some_and(|d| failed.contains(&d)) {
+                record(Change::Skipped);
+                report(action, Outcome::Skipped);
+                failed.push(output);
+                continue;
+            }
+            let written = (!matches!(action, Action::Verify { .. })).then_some(&output);
+            if let Some(path) = rolled_back
+                .iter()
+                .find(|p| written.and_then(|o| o.as_path()) == Some(p))
+            {
+                // only files can be put back
+                let before = if !path.exists() {
+                    Some(None)
+                } else if path.is_file() {
+                    fs::read(path).ok().map(Some)
+                } else {
+                    None
+                };
+                if let Some(before) = before.filter(|_| !backups.iter().any(|(p, _)| p == path)) {
+                    backups.push((path, before));
+                }
+            }
+            match action.run(res) {
+                Ok(_) => {
+                    record(Change::Created);
+                    report(action, Outcome::Done)
+                }
+                Err(e) => {
+                    record(Change::Failed);
+                    if let Action::Verify { target, .. } = action {
+                        let target = target.to_string();
+                        for (_, entry) in changes.iter_mut().filter(|(_, e)| e.output == target) {
+                            entry.change = Change::Failed;
+                        }
+                    }
+                    let backup = backups.iter().find(|(p, _)| output.as_path() == Some(p));
+                    let e = match (action, backup) {
+                        (Action::Verify { .. }, Some((path, before))) => {
+                            match restore(path, before.as_deref()) {
+                                Ok(()) => Error::RolledBack {
+                                    path: path.to_string_lossy().into_owned(),
+                                    source: Box::new(e),
+                                },
+                                Err(re) => {
+                                    log::error!(
+                                        "failed to roll '{}' back: {re}",
+                                        path.to_string_lossy()
+                                    );
+                                    e
+                                }
+                            }
+                        }
+                        _ => e,
+                    };
+                    report(action, Outcome::Failed(&e));
+                    failed.push(output);
+                }
+            }
+        }
+        if let Some(dir) = &self.history {
+            let by_config = changes
+                .into_iter()
+                .filter_map(|(o, entry)| Some((o.config.as_ref()?, entry)))
+                .into_group_map();
+            for (config, entries) in by_config {
+                let record = history::Record::now(config, dry, entries);
+                if let Err(e) = history::append(dir, &record) {
+                    log::warn!(
+                        "failed to record the deploy in '{}': {e}",
+                        dir.to_string_lossy()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+    /// A POSIX shell script doing what [`Actions::run`] would. Templates and
+    /// command outputs are rendered now and embedded in it, so it only needs
+    /// the files that are linked or copied
+    pub fn to_script(&self) -> Result<String> {
+        if self.acts.is_empty() {
+            return Err(Error::NoActions);
+        }
+        let mut res = self.resources.clone();
+        let mut out = script::HEADER.to_owned();
+        for action in &self.acts {
+            if let Some(cmds) = action.script(&mut res)? {
+                out += &format!("\necho {}\n{cmds}\n", script::quote(&action.to_string()));
+            }
+        }
+        Ok(out)
+    }
+    /// The plan as it would run now. Templates and command outputs are
+    /// rendered and embedded in it, so running it never renders anything
+    pub fn to_plan(&self) -> Result<Plan> {
+        if self.acts.is_empty() {
+            return Err(Error::NoActions);
+        }
+        let mut res = self.resources.clone();
+        let mut steps = Vec::new();
+        for (action, origin) in self.acts.iter().zip(&self.origins) {
+            let not_plannable = |reason| Error::NotPlannable {
+                action: action.to_string(),
+                reason,
+            };
+            let dest = |r: &ResourceLocation| match r {
+                ResourceLocation::Path(p) => Ok(Destination::Path(p.clone())),
+                ResourceLocation::Remote(r) => Ok(Destination::Remote(r.clone())),
+                ResourceLocation::InMemory { .. } => {
+                    Err(not_plannable("it writes to a resource in memory"))
+                }
+            };
+            let write = |content: Vec<u8>, meta: ResourceMeta, to: &ResourceLocation| {
+                let content = String::from_utf8(content)
+                    .map_err(|_| not_plannable("its content isn't text"))?;
+                #[cfg(unix)]
+                let mode = meta.permissions.map(|p| {
+                    use std::os::unix::fs::PermissionsExt;
+                    p.mode() & 0o7777
+                });
+                #[cfg(not(unix))]
+                let mode = {
+                    let _ = meta;
+                    None
+                };
+                Ok::<_, Error>(Planned::Write {
+                    content,
+                    mode,
+                    to: dest(to)?,
+                })
+            };
+            if let (ResourceLocation::InMemory { .. }, false) =
+                (action.output(), matches!(action, Action::Verify { .. }))
+            {
+                action.run(&mut res)?;
+                continue;
+            }
+            let planned = match action {
+                Action::MkDir { path } => Planned::MkDir { path: path.clone() },
+                Action::EnsureDir { path, mode } => Planned::EnsureDir {
+                    path: path.clone(),
+                    mode: *mode,
+                },
+                Action::Link {
+                    ty,
+                    from,
+                    to,
+                    fallback,
+                    repoint,
+                    adopt,
+                } => Planned::Link {
+                    ty: *ty,
+                    from: from.clone(),
+                    to: to.clone(),
+                    fallback: *fallback,
+                    repoint: repoint.clone(),
+                    adopt: *adopt,
+                },
+                Action::Copy {
+                    from: ResourceLocation::Path(from),
+                    to,
+                } => Planned::Copy {
+                    from: from.clone(),
+                    to: dest(to)?,
+                },
+                Action::Copy {
+                    from: ResourceLocation::Remote(_),
+                    ..
+                } => return Err(not_plannable("it reads from another machine")),
+                Action::Copy { from, to } => {
+                    write(res.get_content(from)?, res.get_meta(from)?, to)?
+                }
+                Action::TemplateExpand {
+                    ctx,
+                    target,
+                    output,
+                } => {
+                    let (content, meta) = expand(ctx, target, &res)?;
+                    write(content.into_bytes(), meta, output)?
+                }
+                Action::Block {
+                    from,
+                    to,
+                    block,
+                    create,
+                } => Planned::Block {
+                    content: String::from_utf8(res.get_content(from)?)
+                        .map_err(|_| not_plannable("its content isn't text"))?,
+                    to: to.clone(),
+                    block: block.clone(),
+                    create: *create,
+                },
+                Action::Merge {
+                    from,
+                    to,
+                    format,
+                    arrays,
+                } => Planned::Merge {
+                    content: String::from_utf8(res.get_content(from)?)
+                        .map_err(|_| not_plannable("its content isn't text"))?,
+                    to: to.clone(),
+                    format: *format,
+                    arrays: *arrays,
+                },
+                Action::Extract {
+                    archive,
+                    format,
+                    to,
+                    strip,
+                } => Planned::Extract {
+                    archive: archive.clone(),
+                    format: *format,
+                    to: to.clone(),
+                    strip: *strip,
+                },
+                Action::CheckHash { file, sha256 } => Planned::CheckHash {
+                    file: file.clone(),
+                    sha256: sha256.clone(),
+                },
+                Action::Verify {
+                    command,
+                    dir,
+                    target,
+                    on_failure,
+                } => Planned::Verify {
+                    command: command.clone(),
+                    dir: dir.clone(),
+                    target: dest(target)?,
+                    on_failure: *on_failure,
+                },
+            };
+            steps.push(plan::Step {
+                config: origin.config.clone(),
+                target: origin.target.clone(),
+                description: origin.description.clone(),
+                action: planned,
+            });
+        }
+        Ok(Plan::new(steps))
+    }
+    /// The actions of `plan`, which must be one [`plan::read`] accepted.
+    /// Sources it reads are checked to still exist
+    pub fn from_plan(plan: Plan) -> Result<Self> {
+        let mut acts = Self::default();
+        for step in plan.steps {
+            let absolute = |p: &AbsPathBuf| {
+                if p.is_absolute() {
+                    Ok(p.clone())
+                } else {
+                    Err(Error::RelativePlanPath {
+                        path: p.to_string_lossy().into_owned(),
+                    })
+                }
+            };
+            let source = |p: &AbsPathBuf| {
+                let p = absolute(p)?;
+                if !p.exists() {
+                    return Err(Error::SourceDoesNotExist {
+                        path: p.to_string_lossy().into_owned(),
+                    });
+                }
+                Ok(p)
+            };
+            let dest = |d: &Destination| match d {
+                Destination::Path(p) => absolute(p).map(ResourceLocation::Path),
+                Destination::Remote(r) => Ok(ResourceLocation::Remote(r.clone())),
+            };
+            let action = match &step.action {
+                Planned::MkDir { path } => Action::MkDir {
+                    path: absolute(path)?,
+                },
+                Planned::EnsureDir { path, mode } => Action::EnsureDir {
+                    path: absolute(path)?,
+                    mode: *mode,
+                },
+                Planned::Link {
+                    ty,
+                    from,
+                    to,
+                    fallback,
+                    repoint,
+                    adopt,
+                } => Action::Link {
+                    ty: *ty,
+                    from: source(from)?,
+                    to: absolute(to)?,
+                    fallback: *fallback,
+                    repoint: repoint.as_ref().map(absolute).transpose()?,
+                    adopt: *adopt,
+                },
+                Planned::Copy { from, to } => Action::Copy {
+                    from: ResourceLocation::Path(source(from)?),
+                    to: dest(to)?,
+                },
+                Planned::Block {
+                    content,
+                    to,
+                    block,
+                    create,
+                } => {
+                    let to = absolute(to)?;
+                    let from = acts.resources.define_mem_labeled(format!(
+                        "planned content for block '{}' of {}",
+                        block.name,
+                        to.to_string_lossy()
+                    ));
+                    acts.resources.set_content(
+                        &from,
+                        ResourceHandle::Mem(content.clone().into_bytes(), Default::default()),
+                    )?;
+                    Action::Block {
+                        from,
+                        to,
+                        block: block.clone(),
+                        create: *create,
+                    }
+                }
+                Planned::Merge {
+                    content,
+                    to,
+                    format,
+                    arrays,
+                } => {
+                    let to = absolute(to)?;
+                    let from = acts.resources.define_mem_labeled(format!(
+                        "planned content to merge into {}",
+                        to.to_string_lossy()
+                    ));
+                    acts.resources.set_content(
+                        &from,
+                        ResourceHandle::Mem(content.clone().into_bytes(), Default::default()),
+                    )?;
+                    Action::Merge {
+                        from,
+                        to,
+                        format: *format,
+                        arrays: *arrays,
+                    }
+                }
+                Planned::Write { content, mode, to } => {
+                    let to = dest(to)?;
+                    let from = acts
+                        .resources
+                        .define_mem_labeled(format!("planned content for {to}"));
+                    #[cfg(unix)]
+                    let permissions = mode.map(|m| {
+                        use std::os::unix::fs::PermissionsExt;
+                        std::fs::Permissions::from_mode(m)
+                    });
+                    #[cfg(not(unix))]
+                    let permissions = {
+                        let _ = mode;
+                        None
+                    };
+                    let meta = ResourceMeta {
+                        permissions,
+                        modified: None,
+                    };
+                    acts.resources.set_content(
+                        &from,
+                        ResourceHandle::Mem(content.clone().into_bytes(), meta),
+                    )?;
+                    Action::Copy { from, to }
+                }
+                Planned::Extract {
+                    archive,
+                    format,
+                    to,
+                    strip,
+                } => Action::Extract {
+                    archive: source(archive)?,
+                    format: *format,
+                    to: absolute(to)?,
+                    strip: *strip,
+                },
+                Planned::CheckHash { file, sha256 } => {
+                    // a plan may have been edited since it was written
+                    if !sha256::is_hex_digest(sha256) {
+                        return Err(Error::InvalidSha256 {
+                            target: step
+                                .target
+                                .clone()
+                                .unwrap_or_else(|| file.to_string_lossy().into_owned()),
+                            sha256: sha256.clone(),
+                        });
+                    }
+                    Action::CheckHash {
+                        file: source(file)?,
+                        sha256: sha256.to_ascii_lowercase(),
+                    }
+                }
+                Planned::Verify {
+                    command,
+                    dir,
+                    target,
+                    on_failure,
+                } => Action::Verify {
+                    command: command.clone(),
+                    dir: dir.clone(),
+                    target: dest(target)?,
+                    on_failure: *on_failure,
+                },
+            };
+            acts.acts.push(action);
+            acts.origins.push(Origin {
+                config: step.config,
+                label: step.target.clone().unwrap_or_default(),
+                target: step.target,
+                order: 0,
+                description: step.description,
+            });
+        }
+        Ok(acts)
+    }
+    /// Make templates expanded from now on see the current time as `now`,
+    /// the current contents of files used by `lookup` and the current output
+    /// of `shell` commands
+    pub fn start_run(&self) {
+        for act in &self.acts {
+            if let Action::TemplateExpand { ctx, .. } = act {
+                ctx.start_run();
+            }
+        }
+    }
+    pub fn configure_watcher(&self, watcher: &mut dyn notify::Watcher) -> notify::Result<()> {
+        for act in &self.acts {
+            if let Some(cmd) = act
+                .dependency()
+                .as_ref()
+                .and_then(|d| self.resources.command(d))
+            {
+                log::info!("can't watch the output of `{cmd}`, it will only be deployed once");
+            } else if let Some(content) = act.dependency().filter(|d| self.is_inline(d)) {
+                log::info!("{content} is written in the config, it has no file to watch");
+            }
+            act.configure_watcher(watcher)?;
+        }
+        Ok(())
+    }
+    /// Whether `res` is in memory from the start, like a target's `content`,
+    /// rather than filled in by a command or another action
+    fn is_inline(&self, res: &ResourceLocation) -> bool {
+        matches!(res, ResourceLocation::InMemory { .. })
+            && self.resources.command(res).is_none()
+            && !self.acts.iter().any(|a| a.output() == *res)
+    }
+    /// Get all the paths that the filesystem uses
+    pub fn file_roots(&self) -> impl Iterator<Item = AbsPathBuf> + '_ {
+        self.acts
+            .iter()
+            .filter_map(|act| Some(act.dependency()?.as_path()?.to_owned()))
+    }
+    /// `changed` without the paths these actions write themselves or that
+    /// are inside them, so watching doesn't redeploy because of its own
+    /// deploy
+    pub fn external_changes(&self, changed: Vec<ResourceLocation>) -> Vec<ResourceLocation> {
+        let written = self
+            .acts
+            .iter()
+            .filter_map(Action::written)
+            .filter(|o| o.as_path().is_some())
+            .collect::<Vec<_>>();
+        changed
+            .into_iter()
+            .filter(|c| !written.iter().any(|w| within(c, w)))
+            .collect()
+    }
+    pub fn dependents_of(&self, roots: Vec<ResourceLocation>) -> Self {
+        // dependencies() reads template sources and canonicalizes partials,
+        // do that once per call rather than once per resource we visit
+        let deps = self
+            .acts
+            .iter()
+            .map(|a| a.dependencies())
+            .collect::<Vec<_>>();
+        let mut todo = roots;
+        // indices into acts, so each keeps its origin
+        let mut dependents: Vec<usize> = Vec::new();
+        while let Some(resource) = todo.pop() {
+            let to_add = deps
+                .iter()
+                .enumerate()
+                .filter(|(i, ds)| {
+                    ds.contains(&resource)
+                        && !dependents.iter().any(|d| self.acts[*d] == self.acts[*i])
+                })
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+            for dep in to_add {
+                todo.push(self.acts[dep].output());
+                dependents.push(dep);
+            }
+        }
+        let used = dependents
+            .iter()
+            .map(|i| &self.acts[*i])
+            .flat_map(|a| a.dependency().into_iter().chain([a.output()]))
+            .filter_map(|r| match r {
+                ResourceLocation::InMemory { id, .. } => Some(id),
+                ResourceLocation::Path(_) | ResourceLocation::Remote(_) => None,
+            });
+        Self {
+            resources: self.resources.subset(used),
+            acts: dependents.iter().map(|i| self.acts[*i].clone()).collect(),
+            origins: dependents
+                .iter()
+                .map(|i| self.origins[*i].clone())
+                .collect(),
+            style: self.style.clone(),
+            history: self.history.clone(),
+            quiet_failures: self.quiet_failures,
+        }
+    }
+    /// Plan `cfg`, with relative paths in it relative to the cwd
+    pub fn from_config(cfg: &config::Root, engine: &Context) -> Result<Self> {
+        Self::from_config_in(cfg, &std::env::current_dir()?, engine)
+    }
+    /// Plan `cfg`, with relative paths in it relative to `config_dir`
+    pub fn from_config_in(cfg: &config::Root, config_dir: &Path, engine: &Context) -> Result<Self> {
+        let mut engine = engine.clone();
+        if cfg.allow_shell {
+            engine.enable_shell();
+        }
+        let config_dir = config_dir.to_owned();
+        if let Some(dir) = &cfg.partials {
+            engine.set_partials_dir(config_dir.join(dir));
+        }
+        engine.set_base_dir(config_dir.clone());
+        apply_xdg_fallbacks(&mut engine, cfg)?;
+        let mut builder = ActionsBuilder::in_dir(&config_dir);
+        let curr_os = Platform::current().ok_or(Error::UnsupportedPlatform)?;
+        let wsl = sys::is_wsl();
+        let session = sys::session().ok();
+        if !cfg.shared.is_platform_supported(curr_os)
+            || !cfg.shared.is_wsl_supported(wsl)
+            || !cfg.shared.is_session_supported(session)
+        {
+            return Err(Error::ConfigDoesNotSupportPlatform);
+        }
+        define_variables(
+            &mut engine,
+            &vars::config_level(),
+            Layer::Config,
+            cfg.shared.variables.iter(),
+            cfg.allow_shadowing,
+        )
+        .map_err(|e| variables_error(None, e))?;
+        let engine = Arc::new(engine);
+        // where each destination is deployed from, to catch targets clobbering
+        // each other
+        let mut destinations = HashMap::new();
+        let ignore = Ignore::new(&config_dir);
+        for target in &cfg.targets {
+            if !target.shared.is_platform_supported(curr_os) {
+                log::info!("skipping target that deploys '{tname}' since it doesn't support the current platform", tname = target.label());
+                continue;
+            }
+            if !target.shared.is_wsl_supported(wsl) {
+                log::info!(
+                    "skipping target that deploys '{tname}' since it's only for {where}",
+                    tname = target.label(),
+                    where = if wsl { "outside of WSL" } else { "WSL" }
+                );
+                continue;
+            }
+            if !target.shared.is_session_supported(session) {
+                let sessions = match &target.shared.session {
+                    Some(OneOrMany::One(s)) => s.name().to_owned(),
+                    Some(OneOrMany::Many(ss)) => ss.iter().map(|s| s.name()).join(" or "),
+                    None => unreachable!("targets without a session run in all of them"),
+                };
+                if session.is_some() {
+                    log::info!(
+                        "skipping target that deploys '{tname}' since it's only for {sessions} sessions",
+                        tname = target.label()
+                    );
+                } else {
+                    log::debug!(
+                        "skipping target that deploys '{tname}' since it's only for {sessions} sessions, which aren't told apart here",
+                        tname = target.label()
+                    );
+                }
+                continue;
+            }
+            let items = match &target.foreach {
+                Some(items) => items
+                    .iter()
+                    .map(|item| item.render(&engine).map(Some))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|source| Error::TemplateField {
+                        target: target.label().to_owned(),
+                        field: "foreach",
+                        source,
+                    })?,
+                None => vec![None],
+            };
+            for item in items {
+                let mut label = match &item {
+                    Some(item) => format!("{} [{item}]", target.label()),
+                    None => target.label().to_owned(),
+                };
+                let unnamed = label.clone();
+                if let Some(desc) = &target.description {
+                    label += &format!(" ({desc})");
+                }
+                let label = &label;
+                let in_field = |field| {
+                    move |source| Error::TemplateField {
+                        target: label.clone(),
+                        field,
+                        source,
+                    }
+                };
+                let in_target = |source| Error::PathIn {
+                    target: label.clone(),
+                    source,
+                };
+                builder.origin.order = target.order;
+                builder.origin.description = target.description.clone();
+                builder.origin.target = match (&target.name, &item) {
+                    (Some(name), Some(item)) => Some(format!("{name}: {item}")),
+                    (None, Some(item)) => Some(item.to_string()),
+                    (name, None) => name.clone(),
+                };
+                builder.origin.label = match &target.name {
+                    Some(_) => builder.origin.target.clone().unwrap_or(unnamed),
+                    None => unnamed,
+                };
+                let mut engine = engine.scope();
+                if let Some(item) = item {
+                    engine.define(vars::target_level().join(Variable::single("item")), item);
+                }
+                define_variables(
+                    &mut engine,
+                    &vars::target_level(),
+                    Layer::Target,
+                    target.shared.variables.iter(),
+                    cfg.allow_shadowing,
+                )
+                .map_err(|e| variables_error(Some(label.as_str()), e))?;
+                if let Some(probe) = &target.if_path_exists {
+                    let probe = probe
+                        .render_path(&engine)
+                        .map_err(in_field("if_path_exists"))?;
+                    if !probe.exists() {
+                        log::info!(
+                            "skipping target '{label}' since its if_path_exists '{}' doesn't exist",
+                            probe.to_string_lossy()
+                        );
+                        continue;
+                    }
+                }
+                if let Some(probe) = &target.if_path_missing {
+                    let probe = probe
+                        .render_path(&engine)
+                        .map_err(in_field("if_path_missing"))?;
+                    if probe.exists() {
+                        log::info!(
+                            "skipping target '{label}' since its if_path_missing '{}' exists",
+                            probe.to_string_lossy()
+                        );
+                        continue;
+                    }
+                }
+                if let Some(missing) = target
+                    .requires_command
+                    .as_ref()
+                    .and_then(|cmds| cmds.missing(|c| sys::find_command(c).is_some()))
+                {
+                    log::info!("skipping target '{label}' since {missing}");
+                    continue;
+                }
+                // the path as written is kept for `target.from` and `target.to`
+                let path_field = |field, t: &template::Templated<String>, engine: &Context| {
+                    let written = t.render(engine).map_err(in_field(field))?;
+                    let path = engine.path(&written).map_err(in_field(field))?;
+                    Ok::<_, Error>((PathBuf::from(written), path))
+                };
+                if target.ensure_dir {
+                    let mode = ensured_dir_mode(target, label)?;
+                    let (dst_path, _) = path_field("to", &target.target_location, &engine)?;
+                    let path = AbsPathBuf::logical_in(&config_dir, &dst_path).map_err(in_target)?;
+                    let dst = ResourceLocation::Path(path.clone());
+                    if let Some(first) = destinations.insert(dst.to_string(), label.clone()) {
+                        return Err(Error::DuplicateDestination {
+                            to: dst.to_string(),
+                            first,
+                            second: label.clone(),
+                        });
+                    }
+                    builder.push(Action::EnsureDir { path, mode });
+                    continue;
+                }
+                if target.dir_mode.is_some() {
+                    return Err(Error::DirModeWithoutEnsureDir {
+                        target: label.clone(),
+                    });
+                }
+                let command = match &target.from_command {
+                    Some(_) if !target.path.source().is_empty() => {
+                        return Err(Error::FromAndFromCommand {
+                            target: target.label().to_owned(),
+                        })
+                    }
+                    Some(cmd) => Some(cmd.render(&engine).map_err(in_field("from_command"))?),
+                    None => None,
+                };
+                if target.content.is_some() {
+                    let with = [
+                        ("extract", target.extract),
+                        (
+                            "link_type",
+                            !matches!(target.link_type, DeployType::Auto | DeployType::Merge),
+                        ),
+                    ];
+                    if let Some((field, _)) = with.iter().find(|(_, set)| *set) {
+                        return Err(Error::ContentWith {
+                            target: label.clone(),
+                            field,
+                        });
+                    }
+                }
+                // read from a file, rather than a command or `content`
+                let from_file = command.is_none() && target.content.is_none();
+                let src_path = match &command {
+                    Some(cmd) => PathBuf::from(cmd),
+                    None if !from_file => PathBuf::new(),
+                    None => path_field("from", &target.path, &engine)?.0,
+                };
+                if from_file && !config_dir.join(&src_path).exists() {
+                    return Err(Error::SourceDoesNotExist {
+                        path: src_path.to_string_lossy().into_owned(),
+                    });
+                }
+                if from_file && ignore.is_ignored(&config_dir.join(&src_path)) {
+                    log::warn!(
+                        "target '{label}' deploys '{}', which {} ignores. It's deployed anyway as it's named explicitly",
+                        src_path.to_string_lossy(),
+                        ignore::FILE_NAME
+                    );
+                }
+                if let Some(expected) = &target.sha256 {
+                    let expected = expected.trim().to_lowercase();
+                    if !sha256::is_hex_digest(&expected) {
+                        return Err(Error::InvalidSha256 {
+                            target: label.clone(),
+                            sha256: expected,
+                        });
+                    }
+                    let file = AbsPathBuf::new_in(&config_dir, &src_path)
+                        .ok()
+                        .filter(|f| from_file && f.is_file())
+                        .ok_or_else(|| Error::UnhashableSource {
+                            target: label.clone(),
+                        })?;
+                    builder.push(Action::CheckHash {
+                        file,
+                        sha256: expected,
+                    });
+                }
+                let (dst_path, dst) = path_field("to", &target.target_location, &engine)?;
+                define_target_builtins(&mut engine, target, &src_path, &dst_path)?;
+                let dst: ResourceLocation = match dst_path.to_str().and_then(Remote::parse) {
+                    // directories above it are made over ssh as it's written
+                    Some(remote) => ResourceLocation::Remote(remote),
+                    None => {
+                        if let Some(p) = dst_path.parent() {
+                            if !config_dir.join(p).exists() {
+                                builder.mkdir(p).map_err(in_target)?;
+                            }
+                        }
+                        dst.into()
+                    }
+                };
+                // blocks of the same file don't get in each other's way
+                let written = match &target.block {
+                    Some(name) => format!("{dst} (block '{name}')"),
+                    None => dst.to_string(),
+                };
+                if let Some(first) = destinations.insert(written.clone(), label.clone()) {
+                    return Err(Error::DuplicateDestination {
+                        to: written,
+                        first,
+                        second: label.clone(),
+                    });
+                }
+                let verify = match &target.verify_command {
+                    Some(cmd) => Some(cmd.render(&engine).map_err(in_field("verify_command"))?),
+                    None => None,
+                };
+                let verified = dst.clone();
+                let inline = match &target.content {
+                    Some(content) => {
+                        engine.set_undefined(target.undefined);
+                        engine.set_render_depth(target.render_depth);
+                        let text = match target.is_template {
+                            Some(false) => content.source().to_owned(),
+                            _ => content.render(&engine).map_err(in_field("content"))?,
+                        };
+                        let label = content_label(&text);
+                        Some(builder.res.define_labeled(
+                            ResourceHandle::Mem(text.into_bytes(), ResourceMeta::default()),
+                            label,
+                        ))
+                    }
+                    None => None,
+                };
+                // both edit what's already at `to` rather than replacing it
+                if target.block.is_some() || target.link_type == DeployType::Merge {
+                    let (mode, with) = match target.block {
+                        Some(_) => (
+                            "block",
+                            vec![
+                                ("extract", target.extract),
+                                ("link_type", target.link_type != DeployType::default()),
+                            ],
+                        ),
+                        None => ("link_type: merge", vec![("extract", target.extract)]),
+                    };
+                    if let Some((field, _)) = with.iter().find(|(_, set)| *set) {
+                        return Err(Error::EditWith {
+                            target: label.clone(),
+                            mode,
+                            field,
+                        });
+                    }
+                    let ResourceLocation::Path(to) = dst else {
+                        return Err(Error::RemoteEdit {
+                            target: label.clone(),
+                            to: dst.to_string(),
+                        });
+                    };
+
+                    let (from, is_template) = match (inline, command) {
+                        (Some(content), _) => (content, false),
+                        (None, Some(command)) => (
+                            builder.res.define_labeled(
+                                ResourceHandle::Command {
+                                    command: command.clone(),
+                                    dir: config_dir.clone(),
+                                },
+                                format!("output of `{command}`"),
+                            ),
+                            target.is_template == Some(true),
+                        ),
+                        (None, None) => (
+                            AbsPathBuf::new_in(&config_dir, &src_path)
+                                .map_err(in_target)?
+                                .into(),
+                            target
+                                .is_template
+                                .unwrap_or_else(|| src_path.extension() == Some("in".as_ref())),
+                        ),
+                    };
+                    let from = if is_template {
+                        engine.set_undefined(target.undefined);
+                        engine.set_render_depth(target.render_depth);
+                        builder.expand(engine, from, &ResourceLocation::Path(to.clone()))
+                    } else {
+                        from
+                    };
+                    let edit = match &target.block {
+                        Some(name) => Action::Block {
+                            from,
+                            to,
+                            block: Block::new(name, &target.block_comment),
+                            create: target.block_create,
+                        },
+                        None => Action::Merge {
+                            format: target
+                                .merge_format
+                                .or_else(|| config::MergeFormat::of(&to))
+                                .ok_or_else(|| Error::UnknownMergeFormat {
+                                    target: label.clone(),
+                                    to: to.to_string_lossy().into_owned(),
+                                })?,
+                            from,
+                            to,
+                            arrays: target.merge_arrays,
+                        },
+                    };
+                    builder.push(edit);
+                } else if let Some(content) = inline {
+                    builder.copy(content, dst);
+                } else if let Some(command) = command {
+                    if target.extract {
+                        return Err(Error::NotAnArchive {
+                            target: label.clone(),
+                            path: command,
+                        });
+                    }
+                    let output = builder.res.define_labeled(
+                        ResourceHandle::Command {
+                            command: command.clone(),
+                            dir: config_dir.clone(),
+                        },
+                        format!("output of `{command}`"),
+                    );
+                    if target.is_template == Some(true) {
+                        engine.set_undefined(target.undefined);
+                        engine.set_render_depth(target.render_depth);
+                        builder.expand_to(engine, output, dst);
+                    } else {
+                        builder.copy(output, dst);
+                    }
+                } else {
+                    let is_template = target
+                        .is_template
+                        .unwrap_or_else(|| src_path.extension() == Some("in".as_ref()));
+                    if target.extract {
+                        let archive =
+                            AbsPathBuf::new_in(&config_dir, &src_path).map_err(in_target)?;
+                        let format = archive::Format::of(&archive)
+                            .filter(|_| archive.is_file())
+                            .ok_or_else(|| Error::NotAnArchive {
+                                target: label.clone(),
+                                path: src_path.to_string_lossy().into_owned(),
+                            })?;
+                        let ResourceLocation::Path(to) = dst else {
+                            return Err(Error::RemoteExtract {
+                                target: label.clone(),
+                                to: dst.to_string(),
+                            });
+                        };
+                        builder.push(Action::Extract {
+                            archive,
+                            format,
+                            to,
+                            strip: target.strip_components,
+                        });
+                    } else if is_template {
+                        engine.set_undefined(target.undefined);
+                        engine.set_render_depth(target.render_depth);
+                        let src = AbsPathBuf::new_in(&config_dir, src_path).map_err(in_target)?;
+                        builder.expand_to(engine, src, dst);
+                    } else if let ResourceLocation::Remote(remote) = dst {
+                        if target.link_type != DeployType::Copy {
+                            return Err(Error::RemoteLink {
+                                target: target.label().to_owned(),
+                                to: remote.to_string(),
+                            });
+                        }
+                        let src = AbsPathBuf::new_in(&config_dir, src_path).map_err(in_target)?;
+                        builder.copy(src, remote);
+                    } else {
+                        let fallback = target.symlink_fallback.unwrap_or(cfg.symlink_fallback);
+                        let adopt = target.adopt_identical.unwrap_or(cfg.adopt_identical);
+                        match target.link_type {
+                            DeployType::Copy => {
+                                builder.copy(
+                                    AbsPathBuf::new_in(&config_dir, src_path).map_err(in_target)?,
+                                    dst,
+                                );
+                            }
+                            DeployType::Auto => {
+                                let ty = if config_dir.join(&src_path).is_dir() {
+                                    LinkType::Soft
+                                } else {
+                                    LinkType::Hard
+                                };
+                                builder
+                                    .link(
+                                        src_path,
+                                        dst_path,
+                                        ty,
+                                        fallback,
+                                        target.on_conflict,
+                                        adopt,
+                                    )
+                                    .map_err(in_target)?;
+                            }
+                            DeployType::Link(ty) => {
+                                builder
+                                    .link(
+                                        src_path,
+                                        dst_path,
+                                        ty,
+                                        fallback,
+                                        target.on_conflict,
+                                        adopt,
+                                    )
+                                    .map_err(in_target)?;
+                            }
+                            DeployType::Merge => unreachable!("merges are planned above"),
+                        }
+                    }
+                }
+                if let Some(command) = verify {
+                    builder.push(Action::Verify {
+                        command,
+                        dir: config_dir.clone(),
+                        target: verified,
+                        on_failure: target.verify_failure,
+                    });
+                }
+            }
+        }
+        Ok(builder.build())
+    }
+}
+
+/// Run verification `command` in `dir`, failing with what it printed if it
+/// fails
+fn verify(command: &str, dir: &Path) -> Result<()> {
+    log::debug!("running `{command}` to verify");
+    let out = resources::shell(command)
+        .current_dir(dir)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|source| Error::VerifySpawn {
+            command: command.to_owned(),
+            source,
+        })?;
+    let printed = [&out.stdout, &out.stderr]
+        .into_iter()
+        .map(|o| String::from_utf8_lossy(o).trim_end().to_owned())
+        .filter(|o| !o.is_empty())
+        .join("\n");
+    if !out.status.success() {
+        return Err(Error::VerifyFailed {
+            command: command.to_owned(),
+            status: out.status.to_string(),
+            output: printed,
+        });
+    }
+    if !printed.is_empty() {
+        log::debug!("`{command}` printed: {printed}");
+    }
+    Ok(())
+}
+
+/// Put `path` back to holding `content`, or not existing if it's `None`
+fn restore(path: &Path, content: Option<&[u8]>) -> std::io::Result<()> {
+    match content {
+        Some(content) => fs::write(path, content),
+        None => fs::remove_file(path),
+    }
+}
+
+fn target_place(target: &Option<String>) -> String {
+    target
+        .as_ref()
+        .map(|t| format!("target '{t}', "))
+        .unwrap_or_default()
+}
+
+/// `err` from defining the variables of `target`, or of the config if it is
+/// `None`, with the field it came from
+pub fn variables_error(target: Option<&str>, err: template::Error) -> Error {
+    match (err, target) {
+        (template::Error::InVariable { var, source }, _) => Error::TemplateVariable {
+            target: target.map(str::to_owned),
+            // without the `config.` or `target.` it's defined under
+            name: var.segments().skip(1).join("."),
+            source,
+        },
+        (source, Some(target)) => Error::TemplateField {
+            target: target.to_owned(),
+            field: "variables",
+            source,
+        },
+        (source, None) => Error::Template(source),
+    }
+}
+
+/// The `dir_mode` of an `ensure_dir` target, checking it has nothing that
+/// would deploy something there
+fn ensured_dir_mode(target: &config::Target, label: &str) -> Result<Option<u32>> {
+    let with = [
+        ("from", !target.path.source().is_empty()),
+        ("from_command", target.from_command.is_some()),
+        ("content", target.content.is_some()),
+        ("block", target.block.is_some()),
+        ("template", target.is_template.is_some()),
+        ("link_type", target.link_type != DeployType::default()),
+        ("extract", target.extract),
+        ("sha256", target.sha256.is_some()),
+    ];
+    if let Some((field, _)) = with.iter().find(|(_, set)| *set) {
+        return Err(Error::EnsureDirWith {
+            target: label.to_owned(),
+            field,
+        });
+    }
+    target
+        .dir_mode
+        .as_ref()
+        .map(|mode| {
+            let digits = mode.trim().trim_start_matches("0o");
+            u32::from_str_radix(digits, 8)
+                .ok()
+                .filter(|m| *m <= 0o7777)
+                .ok_or_else(|| Error::InvalidDirMode {
+                    target: label.to_owned(),
+                    mode: mode.clone(),
+                })
+        })
+        .transpose()
+}
+
+/// A symlink being deployed over that points somewhere other than its source
+struct StaleLink {
+    /// Where it points, resolved if that exists
+    old: PathBuf,
+    /// Whether it can be pointed at its source instead
+    repointable: bool,
+}
+
+/// The symlink `to` as a [`StaleLink`], `None` if it already points at
+/// `from`. It's repointable if there's a `within` and it points into that or
+/// at something that doesn't exist
+fn stale_link(from: &Path, to: &Path, within: Option<&Path>) -> Result<Option<StaleLink>> {
+    let ours = abspath::canonicalize(from)?;
+    // `to` is usually logical and `from` canonical, so resolve both all the
+    // way before comparing
+    let theirs = match abspath::canonicalize(to) {
+        Ok(theirs) => theirs,
+        Err(abspath::Error::Resolve { source, .. })
+            if source.kind() == std::io::ErrorKind::NotFound =>
+        {
+            let old = fs::read_link(to)?;
+            return Ok(Some(StaleLink {
+                old: to
+                    .parent()
+                    .map_or_else(|| old.clone(), |dir| dir.join(&old)),
+                repointable: within.is_some(),
+            }));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if theirs == ours {
+        return Ok(None);
+    }
+    let repointable = within.is_some_and(|dir| {
+        theirs.starts_with(abspath::canonicalize(dir).unwrap_or_else(|_| dir.to_owned()))
+    });
+    Ok(Some(StaleLink {
+        old: theirs,
+        repointable,
+    }))
+}
+
+/// The text of `to`, empty if there's no such file, and what merging `from`
+/// into it makes of it
+fn merge_documents(
+    from: &ResourceLocation,
+    to: &Path,
+    arrays: ArrayMerge,
+    res: &ResourceStore,
+) -> Result<(String, merge::Merged)> {
+    let source = res.get_content(from)?;
+    let text = match fs::read_to_string(to) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let merged = merge::Merged::new(
+        &text,
+        &to.to_string_lossy(),
+        &String::from_utf8_lossy(&source),
+        &from.to_string(),
+        arrays,
+    )?;
+    Ok((text, merged))
+}
+
+/// How inline `content` is shown in dry runs and logs, its first line
+fn content_label(text: &str) -> String {
+    const MAX: usize = 40;
+    let first = text.lines().next().unwrap_or_default();
+    let shown = first.chars().take(MAX).collect::<String>();
+    let more = shown.len() < first.len() || text.trim_end().lines().nth(1).is_some();
+    format!("content \"{shown}{}\"", if more { "…" } else { "" })
+}
+
+/// Whether `e` says the filesystem can't hold links at all, rather than that
+/// we aren't allowed to make one there
+fn links_unsupported(e: &std::io::Error) -> bool {
+    let codes: &[i32] = if cfg!(windows) {
+        // ERROR_INVALID_FUNCTION, ERROR_NOT_SUPPORTED
+        &[1, 50]
+    } else if cfg!(target_os = "linux") {
+        // EOPNOTSUPP
+        &[95]
+    } else if cfg!(target_vendor = "apple") {
+        // ENOTSUP, EOPNOTSUPP
+        &[45, 102]
+    } else {
+        // EOPNOTSUPP on the BSDs
+        &[45]
+    };
+    e.kind() == std::io::ErrorKind::Unsupported
+        || e.raw_os_error().is_some_and(|c| codes.contains(&c))
+}
+
+/// Whether links can be made next to `path`, found by trying
+fn links_supported(path: &Path) -> bool {
+    let Some(dir) = path.parent() else {
+        return true;
+    };
+    let probe = dir.join(format!(".dotloy-link-probe-{}", uuid::Uuid::new_v4()));
+    match symlink::symlink_file(path, &probe) {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            true
+        }
+        Err(e) => !links_unsupported(&e),
+    }
+}
+
+/// Copy the file or directory `from` to `to`, over whatever is there
+pub fn copy_tree(from: &Path, to: &Path) -> Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Whether `to` already holds everything in `from`, with the same content
+pub fn same_tree(from: &Path, to: &Path) -> Result<bool> {
+    if from.is_dir() {
+        if !to.is_dir() {
+            return Ok(false);
+        }
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            if !same_tree(&entry.path(), &to.join(entry.file_name()))? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    } else {
+        Ok(to.is_file() && same_content(from, to)?)
+    }
+}
+
+/// Whether `a` and `b` are the same file, as hard links to it are
+#[cfg(unix)]
+fn same_inode(a: &Path, b: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+/// Whether `a` and `b` are the same file, as hard links to it are. Stable
+/// std has no file IDs here, so this can't tell
+#[cfg(not(unix))]
+fn same_inode(_a: &Path, _b: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+/// Whether the file `to` holds exactly `content`
+fn same_bytes(content: &[u8], to: &Path) -> Result<bool> {
+    Ok(to.is_file() && fs::metadata(to)?.len() == content.len() as u64 && fs::read(to)? == content)
+}
+
+/// Whether the files `a` and `b` have the same content, read a chunk at a
+/// time so large ones aren't held in memory
+fn same_content(a: &Path, b: &Path) -> Result<bool> {
+    use std::io::Read;
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    let (mut a, mut b) = (fs::File::open(a)?, fs::File::open(b)?);
+    let (mut buf_a, mut buf_b) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        let read = a.read(&mut buf_a)?;
+        if read == 0 {
+            // same length, so `b` is done too
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..read])?;
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Create the directory `path` goes in if it has gone missing since the
+/// deploy made it, e.g. removed while watching
+fn recreate_parent(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.exists() => {
+            log::debug!("recreating missing directory '{}'", dir.to_string_lossy());
+            Ok(fs::create_dir_all(dir)?)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Where an action writes to, for actions that don't write to memory
+fn written_path(to: &ResourceLocation) -> &Path {
+    to.as_path()
+        .expect("in-memory outputs are handled by running")
+}
+
+/// The template at `target` rendered with `ctx`, and the metadata the output
+/// gets
+fn expand(
+    ctx: &Context,
+    target: &ResourceLocation,
+    res: &ResourceStore,
+) -> Result<(String, ResourceMeta)> {
+    let content = template_source(res, target)?;
+    let rendered = match target.as_path() {
+        Some(file) => ctx.render_file(&content, file),
+        None => ctx.render(&content),
+    }
+    .map_err(|source| Error::TemplateIn {
+        file: target.to_string(),
+        source,
+    })?;
+    Ok((rendered, res.get_meta(target)?))
+}
+
+/// Text of the template at `target`
+fn template_source(res: &ResourceStore, target: &ResourceLocation) -> Result<String> {
+    String::from_utf8(res.get_content(target)?).map_err(|_| Error::TemplateNotUtf8 {
+        file: target.to_string(),
+    })
+}
+
+/// Define the built-in `target.from`, `target.to` and `target.name` for
+/// `target` once its paths are known. User variables may not use these names
+pub fn define_target_builtins(
+    engine: &mut Context,
+    target: &config::Target,
+    from: &Path,
+    to: &Path,
+) -> Result<()> {
+    const BUILTINS: [&str; 4] = ["from", "to", "name", "item"];
+    if let Some(name) = BUILTINS.into_iter().find(|n| {
+        target.shared.variables.contains_key(*n) && (*n != "item" || target.foreach.is_some())
+    }) {
+        return Err(Error::ReservedTargetVariable {
+            target: target.label().to_owned(),
+            name,
+        });
+    }
+    let var = |name| vars::target_level().join(Variable::single(name));
+    engine.define(var("from"), from.to_string_lossy().into_owned());
+    engine.define(var("to"), to.to_string_lossy().into_owned());
+    if let Some(name) = &target.name {
+        engine.define(var("name"), name.as_str());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use assert_matches::assert_matches;
+    use fs_err as fs;
+
+    use itertools::Itertools;
+    use tempdir::TempDir;
+    use uuid::Uuid;
+
+    use crate::{
+        abspath::{self, AbsPathBuf},
+        actions::{Action, ResourceLocation},
+        config::{
+            DeployType, LinkType, OnConflict, OneOrMany, Platform, Root, SymlinkFallback, Target,
+            VariableValue, VerifyFailure,
+        },
+        default_parse_context,
+        plan::Planned,
+        resources::{self, ResourceHandle},
+        template::{self, Context, Object, Templated, Variable},
+        test_data_path, xdg_context,
+    };
+
+    use super::{ActionKind, Actions, ActionsBuilder, Difference, Error, Outcome, Status};
+
+    #[test]
+    fn explicit_is_template_causes_expansion_even_if_not_ending_with_in() {
+        let src = test_data_path().join("softlinks.yaml");
+        let dst = test_data_path().join("softlinks-out.yaml");
+        let mut cfg = Root::default();
+        let mut tgt = Target::new(
+            src.to_string_lossy().into_owned(),
+            dst.to_string_lossy().into_owned(),
+        );
+        tgt.is_template = Some(true);
+        cfg.targets.push(tgt);
+        let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
+        assert_matches!(acts.acts.as_slice(), [Action::TemplateExpand { .. }, ..])
+    }
+
+    #[test]
+    fn resource_locations_are_equal_based_on_canonical_path() {
+        assert_eq!(
+            ResourceLocation::Path(AbsPathBuf::new("./src/..").unwrap()),
+            ResourceLocation::Path(AbsPathBuf::new(".").unwrap())
+        );
+    }
+    #[test]
+    fn templates_depend_on_their_partials() {
+        let dir = TempDir::new("partial_deps").unwrap();
+        fs::write(dir.path().join("main.in"), "{{> part }}").unwrap();
+        fs::write(dir.path().join("part"), "x").unwrap();
+        fs::write(dir.path().join("other"), "y").unwrap();
+        let mut b = ActionsBuilder::default();
+        b.template_expand(
+            default_parse_context(),
+            dir.path().join("main.in"),
+            dir.path().join("main"),
+        )
+        .unwrap();
+        let acts = b.build();
+        let path = |p: &str| ResourceLocation::Path(AbsPathBuf::new(dir.path().join(p)).unwrap());
+        assert_eq!(acts.dependents_of(vec![path("part")]).acts.len(), 2);
+        assert_eq!(acts.dependents_of(vec![path("other")]).acts.len(), 0);
+    }
+
+    #[test]
+    fn dependents_only_keep_the_resources_they_use() {
+        let mut b = ActionsBuilder::default();
+        let resources = (0..3).map(|_| b.res.define_mem()).collect::<Vec<_>>();
+        let mut dests = Vec::new();
+        for res in &resources {
+            let dst = b.res.define_mem();
+            dests.push(dst.clone());
+            b.copy(res.to_owned(), dst);
+        }
+        let acts = b.build();
+        let deps = acts.dependents_of(vec![resources[1].clone()]);
+        let id = |r: &ResourceLocation| match r {
+            ResourceLocation::InMemory { id, .. } => *id,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            deps.resources
+                .test_handles()
+                .keys()
+                .sorted()
+                .collect::<Vec<_>>(),
+            [id(&resources[1]), id(&dests[1])]
+                .iter()
+                .sorted()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn dependents_filters_all_actions_that_depend_on_resource() {
+        let mut b = ActionsBuilder::default();
+        let resources = (0..10).map(|_| b.res.define_mem()).collect::<Vec<_>>();
+        let mut dests = Vec::new();
+        for res in &resources {
+            let dst = b.res.define_mem();
+            dests.push(dst.clone());
+            b.copy(res.to_owned(), dst);
+        }
+        let acts = b.build();
+        for roots in resources.into_iter().powerset() {
+            let root_len = roots.len();
+            let deps = acts.dependents_of(roots);
+            assert_eq!(deps.acts.len(), root_len);
+            for act in deps.acts {
+                assert!(act.is_copy());
+            }
+        }
+    }
+
+    #[test]
+    fn actions_with_template_does_copy() {
+        let src = AbsPathBuf::new(test_data_path().join("actions_with_test_data.in")).unwrap();
+        let dst = AbsPathBuf::new(test_data_path().join("actions_with_test_data")).unwrap();
+        let mut cfg = Root::default();
+        cfg.targets.push(Target::new(
+            src.to_string_lossy().into_owned(),
+            dst.to_string_lossy().into_owned(),
+        ));
+        let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
+        let mut ctx = default_parse_context();
+        ctx.define(
+            "target.from".parse().unwrap(),
+            src.to_string_lossy().into_owned(),
+        );
+        ctx.define(
+            "target.to".parse().unwrap(),
+            dst.to_string_lossy().into_owned(),
+        );
+        let target = ResourceLocation::InMemory {
+            id: acts
+                .resources
+                .test_handles()
+                .keys()
+                .next()
+                .unwrap()
+                .to_owned(),
+            label: None,
+        };
+        assert_eq!(
+            &acts.acts,
+            &[
+                Action::TemplateExpand {
+                    target: ResourceLocation::Path(src),
+                    output: target.clone(),
+                    ctx,
+                },
+                Action::Copy {
+                    from: target,
+                    to: ResourceLocation::Path(dst)
+                }
+            ]
+        )
+    }
+
+    #[test]
+    fn variable_expansions_on_root_are_placed_in_config() {
+        let mut cfg: Root = Default::default();
+        let tgt = Target::new("src/actions.rs".to_string(), "{{ config.t1 }}".to_string());
+        let t1val = "{{ xdg.home }}/t".to_owned();
+        cfg.shared
+            .variables
+            .insert("t1".to_owned(), Templated::new(t1val.clone()).into());
+        cfg.targets.push(tgt);
+
+        let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
+        assert_eq!(
+            &acts.acts,
+            &[Action::Link {
+                ty: crate::config::LinkType::Hard,
+                to: AbsPathBuf::new(xdg_context().render(&t1val).unwrap()).unwrap(),
+                from: AbsPathBuf::new("src/actions.rs").unwrap(),
+                fallback: SymlinkFallback::Error,
+                repoint: None,
+                adopt: false,
+            }]
+        )
+    }
+
+    #[test]
+    fn config_field_errors_name_the_target_and_field() {
+        let mut cfg: Root = Default::default();
+        cfg.targets.push(Target::new(
+            "src/actions.rs".to_string(),
+            "{{ xdg.home }}/{{ target.font }}".to_string(),
+        ));
+        let err = Actions::from_config(&cfg, &default_parse_context()).unwrap_err();
+        assert_matches!(
+            &err,
+            Error::TemplateField { target, field: "to", source: template::Error::UndefinedVariable { .. } }
+                if target == "src/actions.rs"
+        );
+        assert_eq!(
+            err.to_string(),
+            "in target 'src/actions.rs', field 'to': unknown variable 'target.font' at line 1, column 16"
+        );
+    }
+
+    #[test]
+    fn variables_can_refer_to_later_variables_in_the_same_scope() {
+        let mut cfg: Root = Default::default();
+        let mut tgt = Target::new("src/actions.rs".to_string(), "{{ target.a }}".to_string());
+        for (name, val) in [
+            ("a", "{{ target.b }}a"),
+            ("b", "{{ config.c }}b"),
+            ("unused", "{{ target.b }}"),
+        ] {
+            tgt.shared
+                .variables
+                .insert(name.to_owned(), Templated::new(val.to_owned()).into());
+        }
+        for (name, val) in [("c", "{{ config.d }}c"), ("d", "{{ xdg.home }}/")] {
+            cfg.shared
+                .variables
+                .insert(name.to_owned(), Templated::new(val.to_owned()).into());
+        }
+        cfg.targets.push(tgt);
+
+        let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
+        assert_matches!(acts.acts.as_slice(), [Action::Link { to, .. }] => {
+            assert_eq!(
+                to.to_string_lossy(),
+                xdg_context().render("{{ xdg.home }}/cba").unwrap()
+            );
+        });
+    }
+
+    #[test]
+    fn variable_cycles_are_reported_with_the_chain() {
+        let mut cfg: Root = Default::default();
+        let mut tgt = Target::new("src/actions.rs".to_string(), "{{ target.a }}".to_string());
+        for (name, val) in [
+            ("a", "{{ target.b }}"),
+            ("b", "{{ config.c }}{{ target.c }}"),
+            ("c", "{{ target.a }}"),
+        ] {
+            tgt.shared
+                .variables
+                .insert(name.to_owned(), Templated::new(val.to_owned()).into());
+        }
+        cfg.shared
+            .variables
+            .insert("c".to_owned(), Templated::new("c".to_owned()).into());
+        cfg.targets.push(tgt);
+        assert_eq!(
+            Actions::from_config(&cfg, &default_parse_context())
+                .unwrap_err()
+                .to_string(),
+            "in target 'src/actions.rs', field 'variables': variable cycle: target.a -> target.b -> target.c -> target.a"
+        );
+
+        let mut cfg: Root = Default::default();
+        cfg.shared.variables.insert(
+            "a".to_owned(),
+            Templated::new("{{ config.a }}".to_owned()).into(),
+        );
+        assert_matches!(
+            Actions::from_config(&cfg, &default_parse_context()),
+            Err(Error::Template(template::Error::VariableCycle(c))) if c == "config.a -> config.a"
+        );
+    }
+
+    #[test]
+    fn variable_names_must_be_identifiers() {
+        let mut cfg: Root = Default::default();
+        let mut tgt = Target::new("src/actions.rs".to_string(), "{{ xdg.home }}".to_string());
+        tgt.shared.variables.insert(
+            "font size".to_owned(),
+            Templated::new("12".to_owned()).into(),
+        );
+        cfg.targets.push(tgt);
+        assert_eq!(
+            Actions::from_config(&cfg, &default_parse_context())
+                .unwrap_err()
+                .to_string(),
+            "in target 'src/actions.rs', field 'variables': invalid variable name 'target.font size', names must be identifiers separated by '.'"
+        );
+    }
+
+    #[test]
+    fn config_variables_cannot_refer_to_target_variables() {
+        let mut cfg: Root = Default::default();
+        let mut tgt = Target::new("src/actions.rs".to_string(), "{{ config.a }}".to_string());
+        tgt.shared
+            .variables
+            .insert("b".to_owned(), Templated::new("x".to_owned()).into());
+        cfg.shared.variables.insert(
+            "a".to_owned(),
+            Templated::new("{{ target.b }}".to_owned()).into(),
+        );
+        cfg.targets.push(tgt);
+        let err = Actions::from_config(&cfg, &default_parse_context()).unwrap_err();
+        assert_matches!(
+            &err,
+            Error::TemplateVariable { target: None, name, source }
+                if name == "a" && matches!(**source, template::Error::UndefinedVariable { .. })
+        );
+        assert_eq!(
+            err.to_string(),
+            "in field 'variables.a': unknown variable 'target.b' at line 1, column 1"
+        );
+    }
+
+    #[test]
+    fn dry_runs_group_actions_by_target() {
+        let mut cfg: Root = Default::default();
+        let mut named = Target::new("src/lib.rs".to_string(), "lib".to_string());
+        named.name = Some("library".to_owned());
+        named.description = Some("the \"lib\"".to_owned());
+        named.link_type = DeployType::Copy;
+        cfg.targets.push(named);
+        cfg.targets.push(Target::new(
+            "src/actions.rs".to_string(),
+            "sub/actions".to_string(),
+        ));
+        let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
+        let groups = acts.groups();
+        assert_eq!(
+            groups
+                .iter()
+                .map(|g| (g.target.as_str(), g.description.as_deref(), g.rows.len()))
+                .collect::<Vec<_>>(),
+            [
+                ("library", Some("the \"lib\""), 1),
+                ("src/actions.rs", None, 2)
+            ]
+        );
+        assert_eq!(
+            groups[1].rows.iter().map(|r| r.kind).collect::<Vec<_>>(),
+            [ActionKind::MkDir, ActionKind::Link(LinkType::Hard)]
+        );
+        let json = acts.groups_json(None);
+        assert!(json.starts_with(r#"{"count":3,"groups":[{"config":null,"target":"library","description":"the \"lib\"","count":1,"actions":[{"kind":"copy","source":"#));
+        assert!(json.contains(r#""kind":"link","#) && json.contains(r#""detail":"hard"}]}]}"#));
+    }
+
+    #[test]
+    fn variable_errors_name_the_variable() {
+        let mut cfg: Root = Default::default();
+        let mut tgt = Target::new("src/actions.rs".to_string(), "out".to_string());
+        tgt.shared.variables.insert(
+            "font".to_owned(),
+            Templated::new("{{ config.fnt }}".to_owned()).into(),
+        );
+        cfg.targets.push(tgt);
+        assert_eq!(
+            Actions::from_config(&cfg, &default_parse_context())
+                .unwrap_err()
+                .to_string(),
+            "in target 'src/actions.rs', field 'variables.font': unknown variable 'config.fnt' at line 1, column 1"
+        );
+    }
+
+    #[test]
+    fn unavailable_xdg_exec_uses_the_fallback_or_explains_itself() {
+        let engine = Context::new().with_define(
+            Variable::single("xdg"),
+            Object::new()
+                .with_property("home", "/h")
+                .with_property("exec", template::Value::Unavailable("no exec".to_owned())),
+        );
+        let mut cfg: Root = Default::default();
+        cfg.targets.push(Target::new(
+            "src/actions.rs".to_string(),
+            "{{ xdg.exec }}/tool".to_string(),
+        ));
+        assert_eq!(
+            Actions::from_config(&cfg, &engine).unwrap_err().to_string(),
+            "in target 'src/actions.rs', field 'to': variable 'xdg.exec' is not available: no exec"
+        );
+
+        cfg.xdg_exec_fallback = Some(Templated::new("{{ xdg.home }}/bin".to_owned()));
+        let acts = Actions::from_config(&cfg, &engine).unwrap();
+        assert_matches!(acts.acts.as_slice(), [.., Action::Link { to, .. }] => {
+            assert_eq!(to.to_string_lossy(), "/h/bin/tool");
+        });
+    }
+
+    #[test]
+    fn redeploys_recreate_directories_removed_since() {
+        let dir = TempDir::new("recreate").unwrap();
+        let src = dir.path().join("conf.in");
+        fs::write(&src, "a = 1").unwrap();
+        let app = dir.path().join("app");
+        let mut b = ActionsBuilder::default();
+        b.mkdir(&app).unwrap();
+        b.expand_to(
+            Context::new(),
+            AbsPathBuf::new(&src).unwrap(),
+            AbsPathBuf::new(app.join("conf")).unwrap(),
+        );
+        b.link(
+            &src,
+            app.join("link"),
+            LinkType::Soft,
+            SymlinkFallback::Error,
+            OnConflict::Error,
+            false,
+        )
+        .unwrap();
+        let mut acts = b.build();
+        assert!(acts.run(false).unwrap().is_empty());
+        fs::remove_dir_all(&app).unwrap();
+        // only what depends on the source is redeployed when it changes
+        let mut redeploy = acts.dependents_of(vec![AbsPathBuf::new(&src).unwrap().into()]);
+        assert!(redeploy.run(false).unwrap().is_empty());
+        assert_eq!(fs::read_to_string(app.join("conf")).unwrap(), "a = 1");
+        assert!(fs::symlink_metadata(app.join("link")).unwrap().is_symlink());
+    }
+
+    #[test]
+    fn stale_links_into_the_source_dir_are_repointed() {
+        let dir = TempDir::new("repoint").unwrap();
+        let repo = dir.path().join("repo");
+        fs::create_dir_all(repo.join("moved")).unwrap();
+        fs::write(repo.join("moved/vimrc"), "set nu").unwrap();
+        fs::write(repo.join("gitconfig"), "[user]").unwrap();
+        let foreign = dir.path().join("elsewhere");
+        fs::write(&foreign, "").unwrap();
+        // the old vimrc has moved, the old gitconfig is still there
+        symlink::symlink_file(repo.join("vimrc"), dir.path().join("vimrc")).unwrap();
+        fs::write(repo.join("gitconfig.old"), "").unwrap();
+        symlink::symlink_file(repo.join("gitconfig.old"), dir.path().join("gitconfig")).unwrap();
+        symlink::symlink_file(&foreign, dir.path().join("other")).unwrap();
+
+        let mut b = ActionsBuilder::default();
+        for (from, to) in [
+            ("moved/vimrc", "vimrc"),
+            ("gitconfig", "gitconfig"),
+            ("gitconfig", "other"),
+        ] {
+            b.link(
+                repo.join(from),
+                dir.path().join(to),
+                LinkType::Soft,
+                SymlinkFallback::Error,
+                OnConflict::Error,
+                false,
+            )
+            .unwrap();
+        }
+        let mut acts = b.build();
+        acts.repoint_links(&repo).unwrap();
+        let failed = acts.run(false).unwrap();
+        assert_eq!(failed.len(), 1, "{failed:?}");
+        assert!(failed[0].contains("elsewhere"), "{failed:?}");
+        for (link, to) in [("vimrc", "moved/vimrc"), ("gitconfig", "gitconfig")] {
+            assert_eq!(
+                abspath::canonicalize(dir.path().join(link)).unwrap(),
+                abspath::canonicalize(repo.join(to)).unwrap()
+            );
+        }
+        assert_eq!(fs::read_link(dir.path().join("other")).unwrap(), foreign);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn existing_hard_links_to_the_source_are_already_deployed() {
+        let dir = TempDir::new("hardlink").unwrap();
+        let src = dir.path().join("src");
+        fs::write(&src, "a").unwrap();
+        let mut b = ActionsBuilder::default();
+        b.link(
+            &src,
+            dir.path().join("dst"),
+            LinkType::Hard,
+            SymlinkFallback::Error,
+            OnConflict::Error,
+            false,
+        )
+        .unwrap();
+        let mut acts = b.build();
+        assert!(acts.run(false).unwrap().is_empty());
+        assert!(acts.run(false).unwrap().is_empty());
+
+        // a copy is another file, even with the same content
+        fs::remove_file(dir.path().join("dst")).unwrap();
+        fs::copy(&src, dir.path().join("dst")).unwrap();
+        assert_eq!(acts.run(false).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn status_tells_deployed_from_missing_and_conflicting() {
+        let dir = TempDir::new("status").unwrap();
+        let src = AbsPathBuf::new(dir.path().join("vimrc")).unwrap();
+        let tmpl = AbsPathBuf::new(dir.path().join("gitconfig.in")).unwrap();
+        let linked = dir.path().join(".vimrc");
+        let expanded = AbsPathBuf::new(dir.path().join(".gitconfig")).unwrap();
+        fs::write(&src, "set nu").unwrap();
+        fs::write(&tmpl, "name = {{ name }}").unwrap();
+        let mut b = ActionsBuilder::default();
+        b.link(
+            &src,
+            &linked,
+            LinkType::Soft,
+            SymlinkFallback::Error,
+            OnConflict::Error,
+            false,
+        )
+        .unwrap();
+        b.expand_to(
+            Context::new().with_define(Variable::single("name"), "me"),
+            tmpl,
+            expanded.clone(),
+        );
+        let mut acts = b.build();
+        let statuses = |acts: &mut Actions| {
+            acts.status()
+                .into_iter()
+                .map(|(_, status)| status)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(statuses(&mut acts), [Status::Missing, Status::Missing]);
+        assert!(acts.run(false).unwrap().is_empty());
+        assert_eq!(statuses(&mut acts), [Status::Deployed, Status::Deployed]);
+
+        fs::remove_file(&linked).unwrap();
+        symlink::symlink_file(&expanded, &linked).unwrap();
+        fs::write(&expanded, "name = you").unwrap();
+        assert_matches!(
+            statuses(&mut acts)[..],
+            [Status::Conflicting(ref link), Status::Conflicting(_)] if link.contains(".gitconfig")
+        );
+        // nothing was changed finding that out
+        assert_eq!(fs::read_to_string(&expanded).unwrap(), "name = you");
+    }
+
+    #[test]
+    fn differences_are_diffs_of_what_would_be_written() {
+        let dir = TempDir::new("differences").unwrap();
+        let tmpl = AbsPathBuf::new(dir.path().join("bashrc.in")).unwrap();
+        let out = AbsPathBuf::new(dir.path().join(".bashrc")).unwrap();
+        fs::write(&tmpl, "alias ll='ls -l'\nexport EDITOR={{ editor }}\n").unwrap();
+        let mut b = ActionsBuilder::default();
+        b.expand_to(
+            Context::new().with_define(Variable::single("editor"), "vim"),
+            tmpl,
+            out.clone(),
+        );
+        b.link(
+            dir.path().join("bashrc.in"),
+            dir.path().join("link"),
+            LinkType::Soft,
+            SymlinkFallback::Error,
+            OnConflict::Error,
+            false,
+        )
+        .unwrap();
+        let mut acts = b.build();
+        let differences = |acts: &mut Actions| {
+            acts.differences()
+                .into_iter()
+                .map(|(_, d)| d)
+                .collect::<Vec<_>>()
+        };
+        assert_matches!(
+            &differences(&mut acts)[..],
+            [Difference::Text(created), Difference::Other(_)] if created[0] == "--- /dev/null"
+        );
+        assert!(acts.run(false).unwrap().is_empty());
+        assert_eq!(differences(&mut acts), [Difference::Same, Difference::Same]);
+
+        fs::write(&out, "alias ll='ls -l'\nexport EDITOR=nano\n").unwrap();
+        let name = out.to_string_lossy();
+        assert_eq!(
+            differences(&mut acts)[0],
+            Difference::Text(
+                [
+                    format!("--- {name}"),
+                    format!("+++ {name}"),
+                    "@@ -1,2 +1,2 @@".to_owned(),
+                    " alias ll='ls -l'".to_owned(),
+                    "-export EDITOR=nano".to_owned(),
+                    "+export EDITOR=vim".to_owned(),
+                ]
+                .to_vec()
+            )
+        );
+    }
+
+    #[test]
+    fn identical_files_are_adopted_only_when_asked() {
+        let dir = TempDir::new("adopt").unwrap();
+        let src = dir.path().join("src");
+        fs::write(&src, "x".repeat(200_000)).unwrap();
+        let same = dir.path().join("same");
+        fs::write(&same, "x".repeat(200_000)).unwrap();
+        let differs = dir.path().join("differs");
+        fs::write(&differs, format!("{}y", "x".repeat(199_999))).unwrap();
+
+        let mut b = ActionsBuilder::default();
+        for (to, adopt) in [(&same, false), (&same, true), (&differs, true)] {
+            b.link(
+                &src,
+                to,
+                LinkType::Soft,
+                SymlinkFallback::Error,
+                OnConflict::Error,
+                adopt,
+            )
+            .unwrap();
+        }
+        let mut acts = b.build();
+        let errors = acts
+            .acts
+            .iter()
+            .map(|a| a.run(&mut acts.resources).err())
+            .collect_vec();
+        assert_matches!(
+            errors.as_slice(),
+            [
+                Some(Error::TargetExists { .. }),
+                None,
+                Some(Error::TargetExists { .. })
+            ]
+        );
+        assert!(fs::symlink_metadata(&same).unwrap().is_symlink());
+        assert!(!fs::symlink_metadata(&differs).unwrap().is_symlink());
+    }
+
+    #[test]
+    fn block_targets_only_touch_their_block() {
+        let dir = TempDir::new("block").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        fs::write(base.join("bashrc"), "export PATH=~/bin:$PATH\n").unwrap();
+        let mut tgt = Target::new(String::new(), "bashrc".to_owned());
+        tgt.content = Some(Templated::new("alias ll='ls -l'\n".to_owned()));
+        tgt.block = Some("aliases".to_owned());
+        let mut other = Target::new(String::new(), "bashrc".to_owned());
+        other.content = Some(Templated::new("set -o vi".to_owned()));
+        other.block = Some("vi".to_owned());
+        let mut cfg = Root {
+            targets: vec![tgt, other],
+            ..Default::default()
+        };
+        let expected = "export PATH=~/bin:$PATH\n\
+            # >>> dotloy managed: aliases >>>\nalias ll='ls -l'\n# <<< dotloy <<<\n\
+            # >>> dotloy managed: vi >>>\nset -o vi\n# <<< dotloy <<<\n";
+        for _ in 0..2 {
+            let mut acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+            assert!(acts.run(false).unwrap().is_empty());
+            assert_eq!(fs::read_to_string(base.join("bashrc")).unwrap(), expected);
+        }
+
+        cfg.targets.truncate(1);
+        cfg.targets[0].target_location = Templated::new("hosts".to_owned());
+        let mut acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+        let failed = acts.run(false).unwrap();
+        assert!(
+            failed[0]
+                .ends_with("doesn't exist, set `block_create: true` on its target to create it"),
+            "{failed:?}"
+        );
+        cfg.targets[0].block_create = true;
+        let mut acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+        assert!(acts.run(false).unwrap().is_empty());
+        assert!(fs::read_to_string(base.join("hosts"))
+            .unwrap()
+            .starts_with("# >>> dotloy managed: aliases >>>\n"));
+    }
+
+    #[test]
+    fn ensure_dir_targets_only_make_the_directory() {
+        let dir = TempDir::new("ensure_dir").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        let mut tgt = Target::new(String::new(), "state/myapp".to_owned());
+        tgt.ensure_dir = true;
+        tgt.dir_mode = Some("700".to_owned());
+        let mut cfg = Root {
+            targets: vec![tgt],
+            ..Default::default()
+        };
+        let mut acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+        assert_eq!(acts.groups()[0].rows[0].kind.name(), "ensure directory");
+        assert!(acts.run(false).unwrap().is_empty());
+        assert!(base.join("state/myapp").is_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let meta = fs::metadata(base.join("state/myapp")).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o7777, 0o700);
+        }
+
+        cfg.targets[0].dir_mode = Some("rwx".to_owned());
+        assert_matches!(
+            Actions::from_config_in(&cfg, &base, &default_parse_context()),
+            Err(Error::InvalidDirMode { .. })
+        );
+        cfg.targets[0].dir_mode = None;
+        cfg.targets[0].link_type = DeployType::Copy;
+        assert_eq!(
+            Actions::from_config_in(&cfg, &base, &default_parse_context())
+                .unwrap_err()
+                .to_string(),
+            "target 'state/myapp' has `ensure_dir: true`, which can't be combined with `link_type`"
+        );
+    }
+
+    #[test]
+    fn only_filesystems_without_links_fall_back_to_copies() {
+        use std::io::{Error as IoError, ErrorKind};
+        assert!(super::links_unsupported(&IoError::from(
+            ErrorKind::Unsupported
+        )));
+        #[cfg(target_os = "linux")]
+        assert!(super::links_unsupported(&IoError::from_raw_os_error(95)));
+        assert!(!super::links_unsupported(&IoError::from(
+            ErrorKind::PermissionDenied
+        )));
+        assert!(!super::links_unsupported(&IoError::from(
+            ErrorKind::AlreadyExists
+        )));
+
+        let dir = TempDir::new("fallback").unwrap();
+        let (from, to) = (dir.path().join("app"), dir.path().join("usb/app"));
+        fs::create_dir_all(from.join("themes")).unwrap();
+        fs::write(from.join("themes/dark.ini"), "bg = black").unwrap();
+        assert!(!super::same_tree(&from, &to).unwrap());
+        super::copy_tree(&from, &to).unwrap();
+        assert!(super::same_tree(&from, &to).unwrap());
+        fs::write(from.join("themes/dark.ini"), "bg = grey").unwrap();
+        assert!(!super::same_tree(&from, &to).unwrap());
+        assert!(super::links_supported(&to));
+    }
+
+    #[test]
+    fn outputs_next_to_their_sources_are_not_changes() {
+        let dir = TempDir::new("own_outputs").unwrap();
+        let src = AbsPathBuf::new(dir.path().join("colors.conf.in")).unwrap();
+        let out = AbsPathBuf::new(dir.path().join("colors.conf")).unwrap();
+        fs::write(&src, "bg = black").unwrap();
+        let mut b = ActionsBuilder::default();
+        b.expand_to(Context::new(), src.clone(), out.clone());
+        let mut acts = b.build();
+        assert!(acts.run(false).unwrap().is_empty());
+        // what the deploy writes is seen by the watcher like any other change
+        let own = acts.external_changes(vec![out.clone().into()]);
+        assert!(own.is_empty() && acts.dependents_of(own).is_empty());
+        let changes = acts.external_changes(vec![out.into(), src.clone().into()]);
+        assert_eq!(changes, vec![ResourceLocation::from(src)]);
+    }
+
+    #[test]
+    fn copies_through_the_store_stream_files() {
+        let dir = TempDir::new("large_copy").unwrap();
+        let src = dir.path().join("assets.bin");
+        let dst = dir.path().join("deployed.bin");
+        let content: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &content).unwrap();
+
+        let mut b = ActionsBuilder::default();
+        let mem = b.res.define_mem();
+        b.copy(AbsPathBuf::new(&src).unwrap(), mem.clone())
+            .copy(mem, AbsPathBuf::new(&dst).unwrap());
+        let acts = b.build();
+        let mut res = acts.resources.clone();
+        for act in &acts.acts {
+            act.run(&mut res).unwrap();
+        }
+        assert!(fs::read(&dst).unwrap() == content);
+        assert!(res.test_handles().values().all(|h| match h {
+            ResourceHandle::Mem(bytes, _) => bytes.is_empty(),
+            ResourceHandle::File(_) | ResourceHandle::Command { .. } => true,
+        }));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn template_outputs_keep_the_source_permissions_and_mtime() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("template_meta").unwrap();
+        let src = dir.path().join("script.sh.in");
+        let dst = dir.path().join("script.sh");
+        fs::write(&src, "#!/bin/sh\necho {{ name }}\n").unwrap();
+        fs::set_permissions(&src, std::fs::Permissions::from_mode(0o751)).unwrap();
+        let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        std::fs::File::options()
+            .write(true)
+            .open(&src)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let mut b = ActionsBuilder::default();
+        b.template_expand(
+            Context::new().with_define(Variable::single("name"), "hi"),
+            &src,
+            &dst,
+        )
+        .unwrap();
+        let acts = b.build();
+        let mut res = acts.resources.clone();
+        for act in &acts.acts {
+            act.run(&mut res).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "#!/bin/sh\necho hi\n");
+        let meta = fs::metadata(&dst).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o751);
+        assert_eq!(meta.modified().unwrap(), modified);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn template_outputs_are_displayed_by_their_destination() {
+        let mut b = ActionsBuilder::default();
+        b.template_expand(Context::new(), "/a/kitty.conf.in", "/b/kitty.conf")
+            .unwrap();
+        let acts = b.build();
+        assert_eq!(
+            acts.acts
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "expand /a/kitty.conf.in to (template output for /b/kitty.conf)",
+                "[(template output for /b/kitty.conf)] -> [/b/kitty.conf]",
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn command_output_is_deployed_and_failures_include_stderr() {
+        let dir = TempDir::new("from_command").unwrap();
+        let dst = dir.path().join("out");
+        let mut cfg: Root = Default::default();
+        let mut tgt = Target::new(String::new(), dst.to_string_lossy().into_owned());
+        tgt.from_command = Some(Templated::new("printf '%s\\n' {{ target.a }}".to_owned()));
+        tgt.shared.variables.insert(
+            "a".to_owned(),
+            Templated::new("generated".to_owned()).into(),
+        );
+        cfg.targets.push(tgt);
+        let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
+        assert_eq!(
+            acts.acts[0].to_string(),
+            format!(
+                "[(output of `printf '%s\\n' generated`)] -> [{}]",
+                dst.to_string_lossy()
+            )
+        );
+        let mut res = acts.resources.clone();
+        for act in &acts.acts {
+            act.run(&mut res).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "generated\n");
+
+        cfg.targets[0].from_command = Some(Templated::new("echo oops >&2; exit 3".to_owned()));
+        let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
+        assert_matches!(
+            acts.acts[0].run(&mut acts.resources.clone()),
+            Err(Error::Resource(resources::Error::CommandFailed { stderr, .. })) if stderr == "oops"
+        );
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "generated\n");
+
+        cfg.targets[0].path = Templated::new("src/actions.rs".to_owned());
+        assert_matches!(
+            Actions::from_config(&cfg, &default_parse_context()),
+            Err(Error::FromAndFromCommand { .. })
+        );
+    }
+
+    #[test]
+    fn inline_content_is_rendered_and_written() {
+        let dir = TempDir::new("content").unwrap();
+        let dst = dir.path().join("env.conf");
+        let mut cfg: Root = Default::default();
+        let mut tgt = Target::new(String::new(), dst.to_string_lossy().into_owned());
+        tgt.content = Some(Templated::new(
+            "EDITOR={{ target.editor }}\nPAGER=less\n".to_owned(),
+        ));
+        tgt.shared
+            .variables
+            .insert("editor".to_owned(), Templated::new("hx".to_owned()).into());
+        cfg.targets.push(tgt);
+        let mut acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
+        assert_eq!(
+            acts.acts[0].to_string(),
+            format!("[(content \"EDITOR=hx…\")] -> [{}]", dst.to_string_lossy())
+        );
+        acts.run(false).unwrap();
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "EDITOR=hx\nPAGER=less\n");
+
+        cfg.targets[0].extract = true;
+        assert_matches!(
+            Actions::from_config(&cfg, &default_parse_context()),
+            Err(Error::ContentWith {
+                field: "extract",
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn relative_paths_are_relative_to_the_config_dir() {
+        let dir = TempDir::new("config_dir").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        fs::write(base.join("dots"), "").unwrap();
+        let mut cfg: Root = Default::default();
+        let mut tgt = Target::new("dots".to_owned(), "out/dots".to_owned());
+        tgt.link_type = DeployType::Copy;
+        cfg.targets.push(tgt);
+        let acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+        assert_eq!(
+            acts.acts,
+            [
+                Action::MkDir {
+                    path: AbsPathBuf::new(base.join("out")).unwrap()
+                },
+                Action::Copy {
+                    from: AbsPathBuf::new(base.join("dots")).unwrap().into(),
+                    to: AbsPathBuf::new(base.join("out/dots")).unwrap().into(),
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn remote_destinations_are_only_copied_to() {
+        let dir = TempDir::new("remote").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        fs::write(base.join("dots"), "").unwrap();
+        let mut cfg: Root = Default::default();
+        let mut tgt = Target::new(
+            "dots".to_owned(),
+            "me@nowhere.invalid:~/out/dots".to_owned(),
+        );
+        tgt.link_type = DeployType::Copy;
+        cfg.targets.push(tgt);
+        let mut acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+        assert_eq!(
+            acts.acts,
+            [Action::Copy {
+                from: AbsPathBuf::new(base.join("dots")).unwrap().into(),
+                to: crate::remote::Remote::parse("me@nowhere.invalid:~/out/dots")
+                    .unwrap()
+                    .into(),
+            }]
+        );
+        // dry runs don't connect
+        acts.run(true).unwrap();
+        assert!(acts.to_script().is_err());
+
+        cfg.targets[0].link_type = DeployType::Auto;
+        assert_matches!(
+            Actions::from_config_in(&cfg, &base, &default_parse_context()),
+            Err(Error::RemoteLink { to, .. }) if to == "me@nowhere.invalid:~/out/dots"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn non_utf8_paths_are_used_as_is() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+        let dir = TempDir::new("non_utf8").unwrap();
+        let base = AbsPathBuf::new(dir.path())
+            .unwrap()
+            .join(OsStr::from_bytes(b"dots\xff"));
+        fs::create_dir(&base).unwrap();
+        fs::write(base.join("src"), "content").unwrap();
+        let mut cfg: Root = Default::default();
+        let mut tgt = Target::new("src".to_owned(), "out/dst".to_owned());
+        tgt.link_type = DeployType::Copy;
+        cfg.targets.push(tgt);
+        let mut acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+        acts.run(false).unwrap();
+        assert_eq!(
+            fs::read_to_string(base.join("out").join("dst")).unwrap(),
+            "content"
+        );
+
+        cfg.targets[0].target_location = Templated::new("{{ config.empty }}".to_owned());
+        cfg.shared
+            .variables
+            .insert("empty".to_owned(), Templated::new(String::new()).into());
+        assert_matches!(
+            Actions::from_config_in(&cfg, &base, &default_parse_context()),
+            Err(Error::TemplateField {
+                field: "to",
+                source: template::Error::EmptyPath,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn foreach_deploys_the_target_once_per_item() {
+        let dir = TempDir::new("foreach").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        fs::write(base.join("unit"), "").unwrap();
+        let item = |name: &str| {
+            VariableValue::Map([("name".to_owned(), Templated::new(name.to_owned()).into())].into())
+        };
+        let mut tgt = Target::new("unit".to_owned(), "{{ target.file }}".to_owned());
+        tgt.link_type = DeployType::Copy;
+        tgt.name = Some("units".to_owned());
+        tgt.shared.variables.insert(
+            "file".to_owned(),
+            Templated::new("{{ target.item.name }}.service".to_owned()).into(),
+        );
+        tgt.foreach = Some(vec![item("web"), item("db")]);
+        let mut cfg: Root = Default::default();
+        cfg.targets.push(tgt);
+        let acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+        let copy = |to: &str| Action::Copy {
+            from: AbsPathBuf::new(base.join("unit")).unwrap().into(),
+            to: AbsPathBuf::new(base.join(to)).unwrap().into(),
+        };
+        assert_eq!(acts.acts, [copy("web.service"), copy("db.service")]);
+        let lines = acts.log_lines();
+        assert!(lines[0].1.starts_with("[units: {name: web}] [unit] -> "));
+        assert!(lines[1].1.starts_with("[units: {name: db}] [unit]  -> "));
+
+        cfg.targets[0].shared.variables.clear();
+        cfg.targets[0].target_location = Templated::new("{{ target.item }}".to_owned());
+        cfg.targets[0].foreach = Some(vec![
+            Templated::new("a".to_owned()).into(),
+            Templated::new("a".to_owned()).into(),
+        ]);
+        assert_matches!(
+            Actions::from_config_in(&cfg, &base, &default_parse_context()),
+            Err(Error::DuplicateDestination { first, second, .. })
+                if first == "unit [a]" && second == "unit [a]"
+        );
+
+        cfg.targets[0].foreach = None;
+        cfg.targets[0]
+            .shared
+            .variables
+            .insert("item".to_owned(), Templated::new("b".to_owned()).into());
+        let acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+        assert_eq!(acts.acts, [copy("b")]);
+    }
+
+    #[test]
+    fn path_probes_skip_targets_before_their_source_is_checked() {
+        let dir = TempDir::new("probe").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        fs::create_dir(base.join("work")).unwrap();
+        let mut tgt = Target::new("overlay".to_owned(), "out".to_owned());
+        tgt.if_path_exists = Some(Templated::new("{{ config.dir }}/work/mnt".to_owned()));
+        let mut cfg: Root = Default::default();
+        cfg.shared.variables.insert(
+            "dir".to_owned(),
+            Templated::new(base.to_string_lossy().into_owned()).into(),
+        );
+        cfg.targets.push(tgt);
+        let plan = |cfg: &Root| Actions::from_config_in(cfg, &base, &default_parse_context());
+        assert!(plan(&cfg).unwrap().is_empty());
+
+        cfg.targets[0].if_path_exists = Some(Templated::new("work".to_owned()));
+        assert_matches!(plan(&cfg), Err(Error::SourceDoesNotExist { .. }));
+
+        cfg.targets[0].if_path_exists = None;
+        cfg.targets[0].if_path_missing = Some(Templated::new("work".to_owned()));
+        assert!(plan(&cfg).unwrap().is_empty());
+
+        cfg.targets[0].if_path_missing = Some(Templated::new("{{ config.nope }}".to_owned()));
+        assert_matches!(
+            plan(&cfg),
+            Err(Error::TemplateField {
+                field: "if_path_missing",
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn order_sorts_targets_without_breaking_what_they_need() {
+        let dir = TempDir::new("order").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        for src in ["rc", "a", "b"] {
+            fs::write(base.join(src), "").unwrap();
+        }
+        let target = |from: &str, to: &str, order| {
+            let mut tgt = Target::new(from.to_owned(), to.to_owned());
+            tgt.link_type = DeployType::Copy;
+            tgt.order = order;
+            tgt
+        };
+        let plan = |targets: Vec<Target>| {
+            let cfg = Root {
+                targets,
+                ..Default::default()
+            };
+            Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap()
+        };
+        let dests = |acts: &Actions| {
+            acts.iter()
+                .map(|a| a.output().to_string())
+                .map(|o| o.strip_prefix(&*base.to_string_lossy()).unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        let mut acts = plan(vec![target("rc", "rc.out", 10), target("a", "a.out", 0)]);
+        assert_eq!(dests(&acts), ["/a.out", "/rc.out"]);
+        acts.append(&mut plan(vec![target("b", "b.out", 5)]));
+        assert_eq!(dests(&acts), ["/a.out", "/b.out", "/rc.out"]);
+
+        let acts = plan(vec![
+            target("a", "out/a", 10),
+            target("b", "out/a/b", 0),
+            target("rc", "rc.out", 5),
+        ]);
+        assert_eq!(
+            dests(&acts),
+            ["/rc.out", "/out", "/out/a", "/out/a", "/out/a/b"]
+        );
+        assert!(acts.log_lines()[4].1.ends_with("/out/a/b] (order 10)"));
+    }
+
+    #[test]
+    fn descriptions_go_along_with_their_target_in_errors() {
+        let dir = TempDir::new("description").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        let mut tgt = Target::new(String::new(), "{{ config.nope }}".to_owned());
+        tgt.from_command = Some(Templated::new("exit 3".to_owned()));
+        tgt.description = Some("keeps tmux happy".to_owned());
+        let mut cfg: Root = Default::default();
+        cfg.targets.push(tgt);
+        let plan = |cfg: &Root| Actions::from_config_in(cfg, &base, &default_parse_context());
+        assert_matches!(
+            plan(&cfg),
+            Err(Error::TemplateField { target, .. }) if target == "exit 3 (keeps tmux happy)"
+        );
+
+        cfg.targets[0].target_location = Templated::new("out".to_owned());
+        let failed = plan(&cfg).unwrap().run_observed(false, |_, _| {}).unwrap();
+        assert_eq!(failed.len(), 1);
+        assert!(failed[0].starts_with(&format!(
+            "[(output of `exit 3`)] -> [{}] (keeps tmux happy): ",
+            base.join("out").to_string_lossy()
+        )));
     }
 
-    pub fn run(&self, dry: bool) -> Result<()> {
-        if self.acts.is_empty() {
-            return Err(Error::NoActions);
-        }
-        let mut res = self.resources.clone();
-        for action in &self.acts {
-            if !dry {
-                match action.run(&mut res) {
-                    Ok(_) => log::info!("{action}"),
-                    Err(e) => log::error!("{action} failed. reason: {}", e),
+    #[test]
+    #[cfg(unix)]
+    fn failed_verifications_fail_warn_or_roll_back() {
+        let dir = TempDir::new("verify").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        fs::write(base.join("conf"), "broken").unwrap();
+        let mut tgt = Target::new("conf".to_owned(), "out".to_owned());
+        tgt.link_type = DeployType::Copy;
+        tgt.verify_command = Some(Templated::new(
+            "grep -q valid {{ target.to }} || { echo \"invalid config\" >&2; exit 2; }".to_owned(),
+        ));
+        let mut cfg: Root = Default::default();
+        cfg.targets.push(tgt);
+        let deploy = |cfg: &Root| {
+            let mut acts = Actions::from_config_in(cfg, &base, &default_parse_context()).unwrap();
+            let mut failed = Vec::new();
+            acts.run_with(false, |_, outcome| {
+                if let Outcome::Failed(e) = outcome {
+                    failed.push(e.to_string());
                 }
-            } else {
-                log::info!("{action}");
-            }
-        }
-        Ok(())
+            })
+            .unwrap();
+            failed
+        };
+        let failed = deploy(&cfg);
+        assert_eq!(failed.len(), 1);
+        assert!(failed[0].starts_with("verification `grep"));
+        assert!(failed[0].ends_with(": invalid config"));
+        assert_eq!(fs::read_to_string(base.join("out")).unwrap(), "broken");
+
+        cfg.targets[0].verify_failure = VerifyFailure::Warn;
+        assert_eq!(deploy(&cfg), Vec::<String>::new());
+
+        cfg.targets[0].verify_failure = VerifyFailure::Rollback;
+        fs::write(base.join("out"), "valid").unwrap();
+        let failed = deploy(&cfg);
+        assert!(failed[0].ends_with(&format!(
+            ": invalid config, rolled '{}' back",
+            base.join("out").to_string_lossy()
+        )));
+        assert_eq!(fs::read_to_string(base.join("out")).unwrap(), "valid");
+        fs::remove_file(base.join("out")).unwrap();
+        assert_eq!(deploy(&cfg).len(), 1);
+        assert!(!base.join("out").exists());
+
+        // nothing to verify if deploying failed
+        fs::create_dir(base.join("out")).unwrap();
+        assert_eq!(deploy(&cfg).len(), 1);
     }
-    pub fn configure_watcher(&self, watcher: &mut dyn notify::Watcher) -> notify::Result<()> {
-        for act in &self.acts {
-            act.configure_watcher(watcher)?;
-        }
-        Ok(())
+
+    #[test]
+    #[cfg(unix)]
+    fn archives_are_extracted_into_their_destination() {
+        let dir = TempDir::new("extract").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        fs::create_dir(base.join("fonts")).unwrap();
+        fs::write(base.join("fonts/mono.ttf"), "glyphs").unwrap();
+        assert!(std::process::Command::new("tar")
+            .args(["-czf", "fonts.tar.gz", "fonts"])
+            .current_dir(&base)
+            .status()
+            .unwrap()
+            .success());
+        let mut tgt = Target::new("fonts.tar.gz".to_owned(), "out/fonts".to_owned());
+        tgt.extract = true;
+        tgt.strip_components = 1;
+        let mut cfg: Root = Default::default();
+        cfg.targets.push(tgt);
+        let mut acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+        assert_eq!(
+            acts.acts[1].to_string(),
+            format!(
+                "extract {} -> {} (strip 1)",
+                base.join("fonts.tar.gz").to_string_lossy(),
+                base.join("out/fonts").to_string_lossy()
+            )
+        );
+        acts.run(true).unwrap();
+        assert!(!base.join("out/fonts").exists());
+        acts.run(false).unwrap();
+        assert_eq!(
+            fs::read_to_string(base.join("out/fonts/mono.ttf")).unwrap(),
+            "glyphs"
+        );
+
+        cfg.targets[0].path = Templated::new("fonts/mono.ttf".to_owned());
+        assert_matches!(
+            Actions::from_config_in(&cfg, &base, &default_parse_context()),
+            Err(Error::NotAnArchive { .. })
+        );
     }
-    /// Get all the paths that the filesystem uses
-    pub fn file_roots(&self) -> impl Iterator<Item = AbsPathBuf> + '_ {
-        self.acts
-            .iter()
-            .filter_map(|act| Some(act.dependency()?.as_path()?.to_owned()))
+
+    #[test]
+    fn configs_inside_sources_of_others_are_nested() {
+        let dir = TempDir::new("nested").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        let nvim = base.join("apps/nvim");
+        fs::create_dir_all(&nvim).unwrap();
+        fs::write(nvim.join("init.lua"), "").unwrap();
+        let plan = |from: &str, dir: &Path, config: &Path| {
+            let cfg = Root {
+                targets: vec![Target::new(
+                    from.to_owned(),
+                    base.join("home").join(from).to_string_lossy().into_owned(),
+                )],
+                ..Default::default()
+            };
+            let mut acts = Actions::from_config_in(&cfg, dir, &default_parse_context()).unwrap();
+            acts.set_config(config);
+            acts
+        };
+        let outer = base.join("dotloy.yaml");
+        let inner = nvim.join("dotloy.yaml");
+        let mut acts = plan("apps", &base, &outer);
+        acts.append(&mut plan("init.lua", &nvim, &inner));
+        assert_eq!(acts.nested_configs(), [(inner.as_path(), outer.as_path())]);
     }
-    pub fn dependents_of(&self, roots: Vec<ResourceLocation>) -> Self {
-        let mut todo = roots;
-        let mut dependents: Vec<Action> = Vec::new();
-        while let Some(resource) = todo.pop() {
-            let to_add = self
-                .acts
+
+    #[test]
+    fn runs_are_recorded_in_the_history_of_their_config() {
+        let dir = TempDir::new("history").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        fs::write(base.join("bashrc"), "alias l=ls").unwrap();
+        let mut tgt = Target::new("bashrc".to_owned(), "home/.bashrc".to_owned());
+        tgt.link_type = DeployType::Copy;
+        let cfg = Root {
+            targets: vec![tgt],
+            ..Default::default()
+        };
+        let mut acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+        acts.set_config(&base.join("dotloy.yaml"));
+        acts.record_history(base.join("history"));
+        acts.run(true).unwrap();
+        acts.run(false).unwrap();
+        acts.run(false).unwrap();
+        let records = crate::history::read(&base.join("history")).unwrap();
+        assert_eq!(
+            records
                 .iter()
-                .filter(|a| a.dependency().as_ref() == Some(&resource) && !dependents.contains(a))
-                .cloned()
-                .collect::<Vec<_>>();
-            for dep in to_add {
-                todo.push(dep.output());
-                dependents.push(dep);
-            }
-        }
-        Self {
-            acts: dependents,
-            resources: self.resources.clone(),
-        }
+                .map(|r| (r.dry, r.summary()))
+                .collect::<Vec<_>>(),
+            [
+                (true, "2 created, 0 updated, 0 skipped, 0 failed".to_owned()),
+                (
+                    false,
+                    "2 created, 0 updated, 0 skipped, 0 failed".to_owned()
+                ),
+                (
+                    false,
+                    "0 created, 2 updated, 0 skipped, 0 failed".to_owned()
+                ),
+            ]
+        );
+        assert_eq!(
+            records[1].entries[1].output,
+            base.join("home/.bashrc").to_string_lossy()
+        );
     }
-    pub fn from_config(cfg: &config::Root, engine: &handybars::Context<'static>) -> Result<Self> {
-        let mut engine = engine.clone();
-        let mut builder = ActionsBuilder::default();
-        let curr_os = Platform::current().ok_or(Error::UnsupportedPlatform)?;
-        if !cfg.shared.is_platform_supported(curr_os) {
-            return Err(Error::ConfigDoesNotSupportPlatform);
-        }
-        define_variables(
-            &mut engine,
-            &vars::config_level(),
-            cfg.shared.variables.iter(),
-        )?;
-        for target in &cfg.targets {
-            if !target.shared.is_platform_supported(curr_os) {
-                log::info!("skipping target that deploys '{tname}' since it doesn't support the current platform", tname = target.path.0);
-                continue;
-            }
-            let mut engine = engine.clone();
-            define_variables(
-                &mut engine,
-                &vars::target_level(),
-                target.shared.variables.iter(),
-            )?;
-            let src_path: PathBuf = target.path.render(&engine)?.parse().unwrap();
-            if !src_path.exists() {
-                return Err(Error::SourceDoesNotExist {
-                    path: src_path.to_string_lossy().into_owned(),
-                });
-            }
-            let dst_path: PathBuf = target.target_location.render(&engine)?.parse().unwrap();
-            if let Some(p) = dst_path.parent() {
-                if !p.exists() {
-                    builder.mkdir(p)?;
-                }
-            }
-            let is_template = target
-                .is_template
-                .unwrap_or_else(|| src_path.extension() == Some("in".as_ref()));
-            if is_template {
-                builder.template_expand(engine, src_path, dst_path)?;
-            } else {
-                match target.link_type {
-                    DeployType::Copy => {
-                        builder.copy(AbsPathBuf::new(src_path)?, AbsPathBuf::new(dst_path)?);
-                    }
-                    DeployType::Auto => {
-                        let ty = if fs::canonicalize(&src_path)?.is_dir() {
-                            LinkType::Soft
-                        } else {
-                            LinkType::Hard
-                        };
-                        builder.link(src_path, dst_path, ty)?;
-                    }
-                    DeployType::Link(ty) => {
-                        builder.link(src_path, dst_path, ty)?;
-                    }
-                }
-            }
-        }
-        Ok(builder.build())
+
+    #[test]
+    fn plans_run_what_was_rendered_when_they_were_made() {
+        let dir = TempDir::new("plan").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        fs::write(base.join("greeting.in"), "hello {{ config.who }}").unwrap();
+        fs::write(base.join("plain"), "as is").unwrap();
+        let mut cfg = Root {
+            targets: vec![
+                Target::new("greeting.in".to_owned(), "out/greeting".to_owned()),
+                Target::new("plain".to_owned(), "out/plain".to_owned()),
+            ],
+            ..Default::default()
+        };
+        cfg.targets[1].link_type = DeployType::Copy;
+        cfg.shared
+            .variables
+            .insert("who".to_owned(), Templated::new("world".to_owned()).into());
+        let acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+        let plan = acts.to_plan().unwrap();
+        assert!(plan.steps.iter().any(
+            |s| matches!(&s.action, Planned::Write { content, .. } if content == "hello world")
+        ));
+
+        fs::write(base.join("greeting.in"), "changed {{ config.who }}").unwrap();
+        let mut planned = Actions::from_plan(plan.clone()).unwrap();
+        assert!(planned.run(false).unwrap().is_empty());
+        assert_eq!(
+            fs::read_to_string(base.join("out/greeting")).unwrap(),
+            "hello world"
+        );
+        assert_eq!(fs::read_to_string(base.join("out/plain")).unwrap(), "as is");
+
+        fs::remove_file(base.join("plain")).unwrap();
+        assert_matches!(
+            Actions::from_plan(plan),
+            Err(Error::SourceDoesNotExist { .. })
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::path::{Path, PathBuf};
+    #[test]
+    fn sources_not_matching_their_sha256_are_not_deployed() {
+        let dir = TempDir::new("sha256").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        fs::write(base.join("tool"), "abc").unwrap();
+        let mut tgt = Target::new("tool".to_owned(), "bin/tool".to_owned());
+        tgt.link_type = DeployType::Copy;
+        tgt.sha256 =
+            Some("BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD".to_owned());
+        let cfg = Root {
+            targets: vec![tgt],
+            ..Default::default()
+        };
+        let mut acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+        assert_eq!(acts.hash_checks().len(), 1);
+        assert!(acts.run(false).unwrap().is_empty());
+        assert_eq!(fs::read_to_string(base.join("bin/tool")).unwrap(), "abc");
 
-    use assert_matches::assert_matches;
-    use fs_err as fs;
+        fs::write(base.join("tool"), "abd").unwrap();
+        fs::write(base.join("bin/tool"), "old").unwrap();
+        let failed = acts.run(false).unwrap();
+        assert_eq!(failed.len(), 1);
+        assert!(
+            failed[0].contains(
+                "expected ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+            ),
+            "{failed:?}"
+        );
+        assert_eq!(fs::read_to_string(base.join("bin/tool")).unwrap(), "old");
 
-    use itertools::Itertools;
-    use tempdir::TempDir;
+        let mut plan = acts.to_plan().unwrap();
+        for step in &mut plan.steps {
+            if let Planned::CheckHash { sha256, .. } = &mut step.action {
+                sha256.truncate(8);
+            }
+        }
+        assert_matches!(
+            Actions::from_plan(plan),
+            Err(Error::InvalidSha256 { sha256, .. }) if sha256 == "ba7816bf"
+        );
 
-    use crate::{
-        abspath::AbsPathBuf,
-        actions::{Action, ResourceLocation},
-        config::{Root, Target},
-        default_parse_context, test_data_path, xdg_context, Templated,
-    };
-    use handybars::{Context, Variable};
+        let mut cfg = cfg;
+        cfg.targets[0].sha256 = Some("abc".to_owned());
+        assert_matches!(
+            Actions::from_config_in(&cfg, &base, &default_parse_context()),
+            Err(Error::InvalidSha256 { .. })
+        );
+        cfg.targets[0].path = Templated::new("bin".to_owned());
+        cfg.targets[0].sha256 = Some("0".repeat(64));
+        assert_matches!(
+            Actions::from_config_in(&cfg, &base, &default_parse_context()),
+            Err(Error::UnhashableSource { .. })
+        );
+    }
+
+    #[test]
+    fn paths_rendering_to_nothing_are_errors() {
+        let mut cfg: Root = Default::default();
+        cfg.shared
+            .variables
+            .insert("empty".to_owned(), Templated::new(String::new()).into());
+        cfg.shared
+            .variables
+            .insert("blank".to_owned(), Templated::new(" \t".to_owned()).into());
+        cfg.targets.push(Target::new(
+            "{{ config.empty }}".to_owned(),
+            "{{ xdg.home }}/a".to_owned(),
+        ));
+        let err = Actions::from_config(&cfg, &default_parse_context()).unwrap_err();
+        assert_matches!(
+            &err,
+            Error::TemplateField {
+                field: "from",
+                source: template::Error::EmptyPath,
+                ..
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "in target '{{ config.empty }}', field 'from': rendered to an empty path"
+        );
 
-    use super::{Actions, ActionsBuilder};
+        cfg.targets[0] = Target::new("src/actions.rs".to_owned(), "{{ config.blank }}".to_owned());
+        assert_matches!(
+            Actions::from_config(&cfg, &default_parse_context()),
+            Err(Error::TemplateField {
+                field: "to",
+                source: template::Error::BlankPath(_),
+                ..
+            })
+        );
+    }
 
     #[test]
-    fn explicit_is_template_causes_expansion_even_if_not_ending_with_in() {
-        let src = test_data_path().join("softlinks.yaml");
-        let dst = test_data_path().join("softlinks-out.yaml");
-        let mut cfg = Root::default();
-        let mut tgt = Target::new(
-            src.to_string_lossy().into_owned(),
-            dst.to_string_lossy().into_owned(),
+    fn logs_say_which_config_and_target_actions_are_from() {
+        let dir = TempDir::new("log_lines").unwrap();
+        let base = AbsPathBuf::new(dir.path()).unwrap();
+        fs::write(base.join("a"), "").unwrap();
+        let plan = |name: Option<&str>, config: &str| {
+            let mut cfg: Root = Default::default();
+            let mut tgt = Target::new("a".to_owned(), format!("{config}/a"));
+            tgt.link_type = DeployType::Copy;
+            tgt.name = name.map(str::to_owned);
+            cfg.targets.push(tgt);
+            let mut acts = Actions::from_config_in(&cfg, &base, &default_parse_context()).unwrap();
+            acts.set_config(&base.join(config));
+            acts
+        };
+        let mut acts = plan(Some("kitty"), "one");
+        acts.append(&mut plan(None, "two"));
+        let lines = acts.log_lines();
+        let out = |c| base.join(c).to_string_lossy().into_owned();
+        assert_eq!(
+            lines,
+            [
+                (Some(out("one")), format!("[kitty] mkdir {}", out("one"))),
+                (None, format!("[kitty] [a] -> [{}/a]", out("one"))),
+                (Some(out("two")), format!("mkdir {}", out("two"))),
+                (None, format!("[a]         -> [{}/a]", out("two"))),
+            ]
+        );
+
+        let only_one = acts.dependents_of(vec![AbsPathBuf::new(base.join("a")).unwrap().into()]);
+        assert_eq!(only_one.origins.len(), 2);
+        assert_eq!(
+            only_one.log_lines()[0].1,
+            format!("[kitty] [a] -> [{}/a]", out("one"))
         );
-        tgt.is_template = Some(true);
-        cfg.targets.push(tgt);
-        let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
-        assert_matches!(acts.acts.as_slice(), [Action::TemplateExpand { .. }, ..])
     }
 
     #[test]
-    fn resource_locations_are_equal_based_on_canonical_path() {
+    fn targets_share_the_config_variables_instead_of_copying_them() {
+        let mut cfg: Root = Default::default();
+        for i in 0..300 {
+            cfg.shared.variables.insert(
+                format!("v{i}"),
+                Templated::new(format!("{{{{ xdg.home }}}}/{i}")).into(),
+            );
+            let mut tgt = Target::new(
+                "src/actions.rs".to_string(),
+                format!("{{{{ config.v{i} }}}}/{{{{ target.t }}}}"),
+            );
+            tgt.is_template = Some(true);
+            tgt.shared
+                .variables
+                .insert("t".to_owned(), Templated::new(i.to_string()).into());
+            cfg.targets.push(tgt);
+        }
+        let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
+        let ctxs = acts
+            .acts
+            .iter()
+            .filter_map(|a| match a {
+                Action::TemplateExpand { ctx, .. } => Some(ctx),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ctxs.len(), 300);
+        assert!(ctxs.iter().all(|c| c.shares_parent_with(ctxs[0])));
         assert_eq!(
-            ResourceLocation::Path(AbsPathBuf::new("./src/..").unwrap()),
-            ResourceLocation::Path(AbsPathBuf::new(".").unwrap())
+            ctxs[7].render("{{ config.v7 }} {{ target.t }}").unwrap(),
+            xdg_context().render("{{ xdg.home }}/7 7").unwrap()
+        );
+    }
+
+    #[test]
+    fn dangling_resources_fail_only_their_action() {
+        let dir = TempDir::new("dangling").unwrap();
+        let mut b = ActionsBuilder::default();
+        let bogus = ResourceLocation::InMemory {
+            id: Uuid::new_v4(),
+            label: None,
+        };
+        b.copy(bogus, AbsPathBuf::new(dir.path().join("out")).unwrap())
+            .mkdir(dir.path().join("made"))
+            .unwrap();
+        let mut acts = b.build();
+        let mut res = acts.resources.clone();
+        assert_matches!(
+            acts.acts[0].run(&mut res),
+            Err(Error::Resource(resources::Error::UnknownResource { .. }))
+        );
+        let failed = acts.run(false).unwrap();
+        assert!(dir.path().join("made").is_dir());
+        assert_eq!(failed.len(), 1);
+        assert!(
+            failed[0].contains("unknown in-memory resource"),
+            "{failed:?}"
         );
     }
+
     #[test]
-    fn dependents_filters_all_actions_that_depend_on_resource() {
+    fn non_utf8_templates_are_reported_by_name() {
+        let dir = TempDir::new("non_utf8").unwrap();
+        let src = AbsPathBuf::new(dir.path().join("font.ttf.in")).unwrap();
+        fs::write(&src, [0x00, 0xff, 0xfe]).unwrap();
         let mut b = ActionsBuilder::default();
-        let resources = (0..10).map(|_| b.res.define_mem()).collect::<Vec<_>>();
-        let mut dests = Vec::new();
-        for res in &resources {
-            let dst = b.res.define_mem();
-            dests.push(dst.clone());
-            b.copy(res.to_owned(), dst);
-        }
+        b.template_expand(Context::new(), &src, dir.path().join("font.ttf"))
+            .unwrap();
         let acts = b.build();
-        for roots in resources.into_iter().powerset() {
-            let root_len = roots.len();
-            let deps = acts.dependents_of(roots);
-            assert_eq!(deps.acts.len(), root_len);
-            for act in deps.acts {
-                assert!(act.is_copy());
-            }
-        }
+        let err = acts.acts[0].run(&mut acts.resources.clone()).unwrap_err();
+        assert_matches!(&err, Error::TemplateNotUtf8 { file } if *file == src.to_string_lossy());
     }
 
     #[test]
-    fn actions_with_template_does_copy() {
-        let src = AbsPathBuf::new(test_data_path().join("actions_with_test_data.in")).unwrap();
-        let dst = AbsPathBuf::new(test_data_path().join("actions_with_test_data")).unwrap();
-        let mut cfg = Root::default();
-        cfg.targets.push(Target::new(
-            src.to_string_lossy().into_owned(),
-            dst.to_string_lossy().into_owned(),
-        ));
+    fn target_paths_are_available_to_its_template() {
+        let mut cfg: Root = Default::default();
+        let mut tgt = Target::new("src/actions.rs".to_string(), "{{ xdg.home }}/a".to_string());
+        tgt.is_template = Some(true);
+        tgt.name = Some("actions".to_owned());
+        cfg.targets.push(tgt);
         let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
-        let target = ResourceLocation::InMemory {
-            id: acts
-                .resources
-                .test_handles()
-                .keys()
-                .next()
-                .unwrap()
-                .to_owned(),
-        };
-        assert_eq!(
-            &acts.acts,
-            &[
-                Action::TemplateExpand {
-                    target: ResourceLocation::Path(src),
-                    output: target.clone(),
-                    ctx: default_parse_context(),
-                },
-                Action::Copy {
-                    from: target,
-                    to: ResourceLocation::Path(dst)
-                }
-            ]
-        )
+        assert_matches!(acts.acts.as_slice(), [Action::TemplateExpand { ctx, .. }, ..] => {
+            assert_eq!(
+                ctx.render("{{ target.name }}: {{ target.from }} -> {{ target.to }}").unwrap(),
+                xdg_context().render("actions: src/actions.rs -> {{ xdg.home }}/a").unwrap()
+            );
+        });
     }
 
     #[test]
-    fn variable_expansions_on_root_are_placed_in_config() {
+    fn target_variables_cannot_shadow_target_paths() {
         let mut cfg: Root = Default::default();
-        let tgt = Target::new("src/actions.rs".to_string(), "{{ config.t1 }}".to_string());
-        let t1val = "{{ xdg.home }}/t".to_owned();
-        cfg.shared
+        let mut tgt = Target::new("src/actions.rs".to_string(), "{{ xdg.home }}/a".to_string());
+        tgt.shared
             .variables
-            .insert("t1".to_owned(), Templated::new(t1val.clone()));
+            .insert("to".to_owned(), Templated::new("x".to_owned()).into());
+        cfg.targets.push(tgt);
+        assert_eq!(
+            Actions::from_config(&cfg, &default_parse_context())
+                .unwrap_err()
+                .to_string(),
+            "in target 'src/actions.rs': variable 'to' clashes with the built-in 'target.to', rename it"
+        );
+    }
+
+    #[test]
+    fn windows_only_targets_can_use_win_dirs() {
+        let mut cfg: Root = Default::default();
+        let mut tgt = Target::new(
+            "src/actions.rs".to_string(),
+            "{{ win.appdata }}/dotloy".to_string(),
+        );
+        tgt.shared.runs_on = Some(OneOrMany::One(Platform::Windows));
         cfg.targets.push(tgt);
+        let engine = default_parse_context().with_define(
+            Variable::single("win"),
+            Object::new().with_property("appdata", r"C:\Users\me\AppData\Roaming"),
+        );
+        let acts = Actions::from_config(&cfg, &engine).unwrap();
+        assert_eq!(acts.acts.is_empty(), !cfg!(windows));
+
+        // rendered where it isn't skipped, wherever this runs
+        cfg.targets[0].shared.runs_on = None;
+        let acts = Actions::from_config(&cfg, &engine).unwrap();
+        let links = acts
+            .acts
+            .iter()
+            .filter_map(|a| match a {
+                Action::Link { to, .. } => Some(to.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            [AbsPathBuf::try_from(r"C:\Users\me\AppData\Roaming/dotloy").unwrap()]
+        );
+    }
 
+    #[test]
+    fn sys_variables_are_available_in_config_fields() {
+        let mut cfg: Root = Default::default();
+        cfg.targets.push(Target::new(
+            "src/actions.rs".to_string(),
+            "./{{ sys.os }}-{{ sys.arch }}".to_string(),
+        ));
         let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
         assert_eq!(
             &acts.acts,
             &[Action::Link {
                 ty: crate::config::LinkType::Hard,
-                to: AbsPathBuf::new(xdg_context().render(&t1val).unwrap()).unwrap(),
-                from: AbsPathBuf::new("src/actions.rs").unwrap()
+                from: "src/actions.rs".try_into().unwrap(),
+                to: format!(
+                    "./{}-{}",
+                    crate::config::Platform::current().unwrap().name(),
+                    std::env::consts::ARCH
+                )
+                .try_into()
+                .unwrap(),
+                fallback: SymlinkFallback::Error,
+                repoint: None,
+                adopt: false,
             }]
         )
     }
@@ -520,7 +4869,10 @@ mod tests {
                 Action::Link {
                     ty: crate::config::LinkType::Hard,
                     from: "src/actions.rs".try_into().unwrap(),
-                    to: "/home/nonexistant/hello.txt".try_into().unwrap()
+                    to: "/home/nonexistant/hello.txt".try_into().unwrap(),
+                    fallback: SymlinkFallback::Error,
+                    repoint: None,
+                    adopt: false,
                 }
             ]
         )
@@ -532,7 +4884,7 @@ mod tests {
         let t1val = "{{ xdg.home }}/t".to_owned();
         tgt.shared
             .variables
-            .insert("t1".to_owned(), Templated::new(t1val.clone()));
+            .insert("t1".to_owned(), Templated::new(t1val.clone()).into());
         cfg.targets.push(tgt);
 
         let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
@@ -541,7 +4893,10 @@ mod tests {
             &[Action::Link {
                 ty: crate::config::LinkType::Hard,
                 to: xdg_context().render(&t1val).unwrap().try_into().unwrap(),
-                from: "src/actions.rs".try_into().unwrap()
+                from: "src/actions.rs".try_into().unwrap(),
+                fallback: SymlinkFallback::Error,
+                repoint: None,
+                adopt: false,
             }]
         )
     }
@@ -559,7 +4914,10 @@ mod tests {
             &[Action::Link {
                 ty: crate::config::LinkType::Hard,
                 from: "src/actions.rs".try_into().unwrap(),
-                to: "./dst".try_into().unwrap()
+                to: "./dst".try_into().unwrap(),
+                fallback: SymlinkFallback::Error,
+                repoint: None,
+                adopt: false,
             }]
         )
     }
@@ -568,7 +4926,7 @@ mod tests {
         let ns = Variable::single("test");
         let ctx = default_parse_context().with_define(
             ns,
-            handybars::Object::new()
+            Object::new()
                 .with_property("dir", dir.path().to_string_lossy().into_owned())
                 .with_property("data", "./test_data"),
         );
@@ -577,7 +4935,7 @@ mod tests {
 
     struct TestDataMgr {
         acts: Actions,
-        _ctx: Context<'static>,
+        _ctx: Context,
         dir: TempDir,
     }
     impl TestDataMgr {
@@ -602,6 +4960,34 @@ mod tests {
         (acts, ctx, dir)
     }
 
+    #[test]
+    fn list_variables_are_iterable_in_expanded_templates() {
+        let cfg: Root = serde_yaml::from_str(
+            r#"
+variables:
+  hosts:
+    - name: "{{ xdg.home }}"
+      port: "22"
+    - name: b
+      port: "2222"
+targets:
+  - from: test_data/actions_with_test_data.in
+    to: test_data/actions_with_test_data
+"#,
+        )
+        .unwrap();
+        let acts = Actions::from_config(&cfg, &default_parse_context()).unwrap();
+        let ctx = match &acts.acts[0] {
+            Action::TemplateExpand { ctx, .. } => ctx,
+            a => panic!("expected template expansion, got {a:?}"),
+        };
+        assert_eq!(
+            ctx.render("{{#each config.hosts}}{{ this.name }}:{{ this.port }} {{/each}}")
+                .unwrap(),
+            xdg_context().render("{{ xdg.home }}:22 b:2222 ").unwrap()
+        );
+    }
+
     #[test]
     fn non_matching_platform_causes_target_to_be_skipped() {
         const DATA: &str = include_str!("../test_data/nonmatch_platform.yaml");
@@ -616,7 +5002,7 @@ mod tests {
         const DATA: &str = include_str!("../test_data/softlinks.yaml");
         let cfg: Root = serde_yaml::from_str(DATA).unwrap();
         let (ctx, dir) = test_ctx_with_dir("softlinks");
-        let acts = Actions::from_config(&cfg, &ctx).unwrap();
+        let mut acts = Actions::from_config(&cfg, &ctx).unwrap();
         acts.run(false).unwrap();
         let created = fs::symlink_metadata(dir.path().join("softlink-folder")).unwrap();
         assert!(created.is_symlink());
@@ -624,14 +5010,14 @@ mod tests {
 
     #[test]
     fn explicit_copying_link_type() {
-        let mgr = TestDataMgr::new("copying");
+        let mut mgr = TestDataMgr::new("copying");
         mgr.acts.run(false).unwrap();
         let created = fs::symlink_metadata(mgr.resolve_path("actions.rs".as_ref())).unwrap();
         assert!(created.is_file());
     }
     #[test]
     fn trying_to_run_an_empty_actions_is_an_error() {
-        let acts = Actions::new();
+        let mut acts = Actions::new();
         assert_matches!(acts.run(false), Err(crate::actions::Error::NoActions));
     }
 }
@@ -0,0 +1,269 @@
+//! Adding targets to a config, as `dotloy add` and `dotloy adopt` do,
+//! leaving the rest of it as it was written
+use std::path::Path;
+
+use fs_err as fs;
+use thiserror::Error;
+
+use dotloy::{
+    actions::{self, copy_tree, same_tree},
+    config::{Root, Target},
+};
+
+use crate::import;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Action(#[from] actions::Error),
+    #[error("'{0}' is already a symlink, so it's probably deployed by something already")]
+    AlreadyLinked(String),
+    #[error("'{0}' already exists with different content, move one of them out of the way")]
+    Differs(String),
+}
+
+/// What follows the top-level `targets:` key if `line` is it, without any
+/// comment
+fn targets_key(line: &str) -> Option<&str> {
+    let rest = line.strip_prefix("targets:")?;
+    Some(rest.split('#').next().unwrap_or_default().trim())
+}
+
+/// Whether `line` starts a top-level key, which ends the one before it
+fn is_top_level(line: &str) -> bool {
+    !line.is_empty() && !line.starts_with([' ', '\t', '#', '-'])
+}
+
+/// `text` of the config `cfg` with `target` added to the end of its
+/// `targets`. When they're a block list, or `[]`, only the new target is
+/// written and the comments and layout of the rest are left alone. Otherwise
+/// the whole config is written again
+pub fn with_target(text: &str, mut cfg: Root, target: Target) -> Result<String, import::Error> {
+    let item = serde_yaml::to_string(std::slice::from_ref(&target))?;
+    let lines = text.lines().collect::<Vec<_>>();
+    let start = lines.iter().position(|l| targets_key(l).is_some());
+    let (last, indent) = match start.map(|s| (s, targets_key(lines[s]))) {
+        Some((start, Some(""))) => {
+            let end = lines[start + 1..]
+                .iter()
+                .position(|l| is_top_level(l))
+                .map_or(lines.len(), |i| start + 1 + i);
+            // comments and blank lines just before the next key are about it
+            let last = (start + 1..end)
+                .rev()
+                .find(|i| !lines[*i].trim().is_empty() && !lines[*i].starts_with('#'))
+                .unwrap_or(start);
+            let indent = lines[start + 1..end]
+                .iter()
+                .find(|l| l.trim_start().starts_with('-'))
+                .map_or(2, |l| l.len() - l.trim_start().len());
+            (last, indent)
+        }
+        Some((start, Some("[]"))) => {
+            let mut lines = lines.clone();
+            lines[start] = "targets:";
+            let text = lines.join("\n");
+            return with_target(&text, cfg, target);
+        }
+        _ => {
+            cfg.targets.push(target);
+            return import::to_yaml(&cfg);
+        }
+    };
+    let mut out = String::new();
+    for line in &lines[..=last] {
+        out += line;
+        out += "\n";
+    }
+    for line in item.lines() {
+        out += &" ".repeat(indent);
+        out += line;
+        out += "\n";
+    }
+    for line in &lines[last + 1..] {
+        out += line;
+        out += "\n";
+    }
+    Ok(out)
+}
+
+/// What [`move_into`] did, so [`move_back`] can undo it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Moved {
+    /// `deployed` was moved to `into`
+    Moved,
+    /// `into` already had the same content so `deployed` was just removed
+    Removed,
+}
+
+/// Move the deployed file or directory `deployed` to `into`, to be deployed
+/// from there. If `into` already has the same content `deployed` is just
+/// removed, if it has something else nothing is moved
+pub fn move_into(deployed: &Path, into: &Path) -> Result<Moved, Error> {
+    if fs::symlink_metadata(deployed)?.is_symlink() {
+        return Err(Error::AlreadyLinked(
+            deployed.to_string_lossy().into_owned(),
+        ));
+    }
+    if fs::symlink_metadata(into).is_ok() {
+        if !(same_tree(deployed, into)? && same_tree(into, deployed)?) {
+            return Err(Error::Differs(into.to_string_lossy().into_owned()));
+        }
+        log::info!(
+            "'{}' is already there, removing '{}'",
+            into.to_string_lossy(),
+            deployed.to_string_lossy()
+        );
+    } else {
+        if let Some(dir) = into.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        match fs::rename(deployed, into) {
+            Ok(()) => return Ok(Moved::Moved),
+            // most likely on another filesystem
+            Err(e) => log::debug!(
+                "couldn't move '{}', copying it: {e}",
+                deployed.to_string_lossy()
+            ),
+        }
+        copy_tree(deployed, into)?;
+        remove(deployed)?;
+        return Ok(Moved::Moved);
+    }
+    remove(deployed)?;
+    Ok(Moved::Removed)
+}
+
+/// Undo [`move_into`], putting what was at `deployed` back. Whatever a
+/// deploy left at `deployed` since is removed first
+pub fn move_back(deployed: &Path, into: &Path, moved: Moved) -> Result<(), Error> {
+    if fs::symlink_metadata(deployed).is_ok() {
+        remove(deployed)?;
+    }
+    match moved {
+        Moved::Moved => {
+            if fs::rename(into, deployed).is_err() {
+                copy_tree(into, deployed)?;
+                remove(into)?;
+            }
+        }
+        Moved::Removed => copy_tree(into, deployed)?,
+    }
+    Ok(())
+}
+
+/// Remove the file, link or directory at `path`
+fn remove(path: &Path) -> std::io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use fs_err as fs;
+    use tempdir::TempDir;
+
+    use dotloy::config::{self, DeployType, LinkType, OneOrMany, Platform, Root, Target};
+
+    use super::{move_back, move_into, with_target, Error, Moved};
+
+    fn added(text: &str) -> (String, Root) {
+        let mut target = Target::new("bashrc".to_owned(), "{{ xdg.home }}/.bashrc".to_owned());
+        target.link_type = DeployType::Link(LinkType::Soft);
+        let out = with_target(text, config::parse(text).unwrap(), target).unwrap();
+        let cfg = config::parse(&out).unwrap();
+        assert_eq!(cfg.targets.last().unwrap().path.source(), "bashrc");
+        (out, cfg)
+    }
+
+    #[test]
+    fn targets_are_added_without_disturbing_the_rest() {
+        let head = "\
+# my dotfiles
+variables:
+  editor: nvim
+targets:
+  - from: vimrc
+    to: ~/.vimrc # for vim
+    runs_on: linux
+";
+        let tail = "
+# only on unix
+runs_on: unix
+";
+        let (out, cfg) = added(&format!("{head}{tail}"));
+        assert!(out.starts_with(head) && out.ends_with(tail), "{out}");
+        assert_eq!(cfg.targets.len(), 2);
+        assert_eq!(
+            cfg.targets[0].shared.runs_on,
+            Some(OneOrMany::One(Platform::Linux))
+        );
+        assert_eq!(cfg.shared.runs_on, Some(OneOrMany::One(Platform::Unix)));
+        assert!(cfg.shared.variables.contains_key("editor"));
+
+        let (out, cfg) = added("targets: [] # none yet\nallow_shell: true\n");
+        assert!(out.starts_with("targets:\n  - from: bashrc\n"), "{out}");
+        assert!(out.ends_with("\nallow_shell: true\n"), "{out}");
+        assert!(cfg.allow_shell && cfg.targets.len() == 1);
+    }
+
+    #[test]
+    fn deployed_files_are_moved_unless_something_else_is_in_the_way() {
+        let dir = TempDir::new("move_into").unwrap();
+        let deployed = dir.path().join("home/.config/foo");
+        fs::create_dir_all(&deployed).unwrap();
+        fs::write(deployed.join("config.toml"), "a = 1").unwrap();
+        let repo = dir.path().join("repo/foo");
+        assert_eq!(move_into(&deployed, &repo).unwrap(), Moved::Moved);
+        assert!(!deployed.exists());
+        assert_eq!(
+            fs::read_to_string(repo.join("config.toml")).unwrap(),
+            "a = 1"
+        );
+
+        // the same again is only removed, something else is left alone
+        fs::create_dir_all(&deployed).unwrap();
+        fs::write(deployed.join("config.toml"), "a = 1").unwrap();
+        assert_eq!(move_into(&deployed, &repo).unwrap(), Moved::Removed);
+        assert!(!deployed.exists());
+        fs::create_dir_all(&deployed).unwrap();
+        fs::write(deployed.join("config.toml"), "a = 2").unwrap();
+        assert_matches!(move_into(&deployed, &repo), Err(Error::Differs(_)));
+        assert_eq!(
+            fs::read_to_string(deployed.join("config.toml")).unwrap(),
+            "a = 2"
+        );
+        assert_eq!(
+            fs::read_to_string(repo.join("config.toml")).unwrap(),
+            "a = 1"
+        );
+    }
+
+    #[test]
+    fn moves_are_undone_over_whatever_was_deployed() {
+        let dir = TempDir::new("move_back").unwrap();
+        let deployed = dir.path().join("home/.foorc");
+        let repo = dir.path().join("repo/foorc");
+        fs::create_dir_all(deployed.parent().unwrap()).unwrap();
+        fs::write(&deployed, "a = 1").unwrap();
+        let moved = move_into(&deployed, &repo).unwrap();
+        // a deploy that got as far as linking before failing
+        fs::write(&deployed, "partial").unwrap();
+        move_back(&deployed, &repo, moved).unwrap();
+        assert_eq!(fs::read_to_string(&deployed).unwrap(), "a = 1");
+        assert!(!repo.exists());
+
+        // the repository's copy stays when it was there already
+        fs::write(&repo, "a = 1").unwrap();
+        let moved = move_into(&deployed, &repo).unwrap();
+        move_back(&deployed, &repo, moved).unwrap();
+        assert_eq!(fs::read_to_string(&deployed).unwrap(), "a = 1");
+        assert_eq!(fs::read_to_string(&repo).unwrap(), "a = 1");
+    }
+}
@@ -0,0 +1,321 @@
+//! Archives deployed by extracting them, using the system's `tar` and `unzip`
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("failed to run `{command}`: {source}")]
+    Spawn {
+        command: String,
+        source: std::io::Error,
+    },
+    #[error("`{command}` failed ({status}): {stderr}")]
+    Failed {
+        command: String,
+        status: String,
+        stderr: String,
+    },
+    #[error("'{archive}' has an entry outside of where it is extracted to: '{entry}'")]
+    UnsafeEntry { archive: String, entry: String },
+    #[error("'{archive}' has a symlink '{entry}', which can't be extracted")]
+    Symlink { archive: String, entry: String },
+    #[error("can't extract '{entry}' to '{path}', there is a directory in the way")]
+    DirInTheWay { entry: String, path: String },
+    #[error("'{path}' already exists and differs from what would be extracted to it")]
+    Exists { path: String },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// Compressed or not, `tar` works out which
+    Tar,
+    Zip,
+}
+
+impl Format {
+    /// The format of `archive` going by its name
+    pub fn of(archive: &Path) -> Option<Self> {
+        let name = archive.file_name()?.to_str()?.to_lowercase();
+        const TAR: [&str; 9] = [
+            ".tar", ".tar.gz", ".tgz", ".tar.xz", ".txz", ".tar.bz2", ".tbz2", ".tar.zst", ".tzst",
+        ];
+        if TAR.iter().any(|ext| name.ends_with(ext)) {
+            Some(Self::Tar)
+        } else if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else {
+            None
+        }
+    }
+    /// Command listing the entries of `archive`, one per line
+    fn list(self, archive: &Path) -> Command {
+        match self {
+            // windows' tar is bsdtar, which reads zips too
+            Self::Zip if !cfg!(windows) => {
+                let mut cmd = Command::new("unzip");
+                cmd.arg("-Z1").arg(archive);
+                cmd
+            }
+            _ => {
+                let mut cmd = Command::new("tar");
+                cmd.arg("-tf").arg(archive);
+                cmd
+            }
+        }
+    }
+    /// Command extracting all of `archive` into `dir`
+    fn extract(self, archive: &Path, dir: &Path) -> Command {
+        match self {
+            Self::Zip if !cfg!(windows) => {
+                let mut cmd = Command::new("unzip");
+                cmd.args(["-q", "-o"]).arg(archive).arg("-d").arg(dir);
+                cmd
+            }
+            _ => {
+                let mut cmd = Command::new("tar");
+                cmd.arg("-xf").arg(archive).arg("-C").arg(dir);
+                cmd
+            }
+        }
+    }
+}
+
+fn run(mut cmd: Command) -> Result<String> {
+    let command = format!(
+        "{} {}",
+        cmd.get_program().to_string_lossy(),
+        cmd.get_args()
+            .map(|a| a.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    log::debug!("running `{command}`");
+    let out = cmd
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|source| Error::Spawn {
+            command: command.clone(),
+            source,
+        })?;
+    if !out.status.success() {
+        return Err(Error::Failed {
+            command,
+            status: out.status.to_string(),
+            stderr: String::from_utf8_lossy(&out.stderr).trim_end().to_owned(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Whether `entry` stays inside the directory it's extracted to
+fn is_safe(entry: &str) -> bool {
+    let entry = entry.replace('\\', "/");
+    let path = Path::new(&entry);
+    !entry.starts_with('/')
+        && path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Names of the entries in `archive`, failing if any would be extracted
+/// outside of where it's extracted to
+pub fn entries(archive: &Path, format: Format) -> Result<Vec<String>> {
+    let entries = run(format.list(archive))?
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    if let Some(entry) = entries.iter().find(|e| !is_safe(e)) {
+        return Err(Error::UnsafeEntry {
+            archive: archive.to_string_lossy().into_owned(),
+            entry: entry.clone(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Extract `archive` into `to`, creating it if needed, without the first
+/// `strip` components of each entry. A file already in `to` stops anything
+/// being extracted unless it's identical or in `ours`, the files extracted
+/// before (which the new ones are added to)
+pub fn extract(
+    archive: &Path,
+    format: Format,
+    to: &Path,
+    strip: usize,
+    ours: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    entries(archive, format)?;
+    let scratch = std::env::temp_dir().join(format!("dotloy-extract-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&scratch)?;
+    let extracted = run(format.extract(archive, &scratch))
+        .and_then(|_| copy_tree(archive, &scratch, to, strip, ours));
+    if let Err(e) = fs::remove_dir_all(&scratch) {
+        log::warn!(
+            "failed to clean up after extracting '{}': {e}",
+            archive.to_string_lossy()
+        );
+    }
+    extracted
+}
+
+/// Copy the files under `scratch` into `to`, dropping the first `strip`
+/// components of their paths. Every file is checked before any is copied,
+/// see [`extract`]
+fn copy_tree(
+    archive: &Path,
+    scratch: &Path,
+    to: &Path,
+    strip: usize,
+    ours: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    fs::create_dir_all(to)?;
+    let mut copies = Vec::new();
+    let mut todo = vec![scratch.to_owned()];
+    while let Some(dir) = todo.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let rel = path.strip_prefix(scratch).expect("walked from scratch");
+            let ty = entry.file_type()?;
+            if ty.is_symlink() {
+                return Err(Error::Symlink {
+                    archive: archive.to_string_lossy().into_owned(),
+                    entry: rel.to_string_lossy().into_owned(),
+                });
+            }
+            if ty.is_dir() {
+                todo.push(path);
+                continue;
+            }
+            let kept = rel.components().skip(strip).collect::<PathBuf>();
+            if kept.as_os_str().is_empty() {
+                continue;
+            }
+            let dest = to.join(&kept);
+            if dest.is_dir() {
+                return Err(Error::DirInTheWay {
+                    entry: rel.to_string_lossy().into_owned(),
+                    path: dest.to_string_lossy().into_owned(),
+                });
+            }
+            match fs::symlink_metadata(&dest) {
+                Ok(_) if ours.contains(&dest) => {}
+                Ok(m) if m.is_file() && fs::read(&dest)? == fs::read(&path)? => {
+                    ours.insert(dest);
+                    continue;
+                }
+                Ok(_) => {
+                    return Err(Error::Exists {
+                        path: dest.to_string_lossy().into_owned(),
+                    })
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            copies.push((path, dest));
+        }
+    }
+    for (path, dest) in copies {
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(&path, &dest)?;
+        ours.insert(dest);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, process::Command};
+
+    use std::collections::HashSet;
+
+    use fs_err as fs;
+    use tempdir::TempDir;
+
+    use super::{extract, is_safe, Error, Format};
+
+    #[test]
+    fn entries_must_stay_where_they_are_extracted() {
+        assert!(is_safe("fonts/Iosevka.ttf"));
+        assert!(is_safe("./plugin/init.lua"));
+        assert!(!is_safe("../.bashrc"));
+        assert!(!is_safe("plugin/../../.ssh/authorized_keys"));
+        assert!(!is_safe("/etc/passwd"));
+        assert!(!is_safe("..\\evil.dll"));
+    }
+
+    #[test]
+    fn formats_are_known_by_extension() {
+        assert_eq!(Format::of(Path::new("fonts.tar.gz")), Some(Format::Tar));
+        assert_eq!(Format::of(Path::new("a/plugin.TGZ")), Some(Format::Tar));
+        assert_eq!(Format::of(Path::new("fonts.zip")), Some(Format::Zip));
+        assert_eq!(Format::of(Path::new("fonts.gz")), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn tarballs_are_extracted_without_leading_components() {
+        let dir = TempDir::new("archive").unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("plugin-1.0/lua")).unwrap();
+        fs::write(src.join("plugin-1.0/lua/init.lua"), "return {}").unwrap();
+        fs::write(src.join("plugin-1.0/README"), "hi").unwrap();
+        let archive = dir.path().join("plugin.tar.gz");
+        let tar = |args: &[&str]| {
+            assert!(Command::new("tar")
+                .args(args)
+                .current_dir(&src)
+                .status()
+                .unwrap()
+                .success())
+        };
+        tar(&["-czf", archive.to_str().unwrap(), "plugin-1.0"]);
+
+        let out = dir.path().join("out/plugin");
+        fs::create_dir_all(&out).unwrap();
+        fs::write(out.join("README"), "old").unwrap();
+        let mut ours = HashSet::new();
+        assert!(matches!(
+            extract(&archive, Format::Tar, &out, 1, &mut ours),
+            Err(Error::Exists { path }) if path == out.join("README").to_string_lossy()
+        ));
+        assert!(!out.join("lua").exists());
+
+        // identical files are left be, and what was extracted can be again
+        fs::write(out.join("README"), "hi").unwrap();
+        extract(&archive, Format::Tar, &out, 1, &mut ours).unwrap();
+        assert_eq!(
+            fs::read_to_string(out.join("lua/init.lua")).unwrap(),
+            "return {}"
+        );
+        fs::write(src.join("plugin-1.0/README"), "hello").unwrap();
+        tar(&["-czf", archive.to_str().unwrap(), "plugin-1.0"]);
+        extract(&archive, Format::Tar, &out, 1, &mut ours).unwrap();
+        assert_eq!(fs::read_to_string(out.join("README")).unwrap(), "hello");
+
+        tar(&[
+            "-czPf",
+            archive.to_str().unwrap(),
+            "../src/plugin-1.0/README",
+        ]);
+        assert!(matches!(
+            extract(&archive, Format::Tar, &out, 0, &mut ours),
+            Err(Error::UnsafeEntry { entry, .. }) if entry == "../src/plugin-1.0/README"
+        ));
+    }
+}
@@ -1,5 +1,7 @@
 use clap::{Parser, Subcommand};
 
+use dotloy::template::Undefined;
+
 #[derive(Parser)]
 #[command(version = clap::crate_version!(), author = clap::crate_authors!("\n"))]
 pub struct Args {
@@ -20,8 +22,32 @@ pub enum Command {
     Expand(ExpandCmd),
     #[command(about = "Deploy a configuration", visible_alias = "d")]
     Deploy(DeployCmd),
+    #[command(about = "Check sources against their `sha256` without deploying anything")]
+    Check(CheckCmd),
+    #[command(about = "Show whether what each target deploys is in place")]
+    Status(StatusCmd),
+    #[command(about = "Show what deploying would change, as a diff")]
+    Diff(DiffCmd),
+    #[command(about = "Warn about parts of configs that are probably mistakes")]
+    Lint(LintCmd),
+    #[command(about = "List where each target of a config goes on this machine, and how")]
+    List(ListCmd),
+    #[command(about = "List past deploys, or show what one did")]
+    History(HistoryCmd),
+    #[command(about = "Check the environment dotloy runs in for common problems")]
+    Doctor,
+    #[command(about = "Write a starter dotloy.yaml")]
+    Init(InitCmd),
+    #[command(about = "Add a target to a config")]
+    Add(AddCmd),
+    #[command(about = "Move a deployed file into the config's directory and deploy it from there")]
+    Adopt(AdoptCmd),
     #[command(about = "Generate shell completions")]
     GenerateShellCompletions,
+    #[command(about = "Write a dotloy.yaml deploying the packages of a GNU stow directory")]
+    ImportStow(ImportStowCmd),
+    #[command(about = "Write a dotloy.yaml deploying the files of a chezmoi source directory")]
+    ImportChezmoi(ImportChezmoiCmd),
 }
 
 #[derive(clap::Args, Clone)]
@@ -38,18 +64,357 @@ pub struct ExpandCmd {
     #[arg(
         long,
         global = true,
-        help = "Config file to use. If not provided defaults to dotloy.yaml in cwd"
+        help = "Config file to use, `-` reads it from stdin. If not provided defaults to dotloy.yaml in cwd"
+    )]
+    pub config: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Directory paths in a config read from stdin are relative to. Defaults to cwd",
+        value_hint = clap::ValueHint::DirPath,
+    )]
+    pub source_dir: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        help = "What to do with references to undefined variables. Defaults to the `undefined` of the matching config target, or error"
+    )]
+    pub undefined: Option<UndefinedArg>,
+    #[arg(
+        long,
+        help = "Check that every variable the file uses is defined instead of expanding it"
+    )]
+    pub check: bool,
+    #[arg(
+        long = "target",
+        value_name = "NAME",
+        requires = "config",
+        help = "Render with the variables of this config target, given by its `name` or `from`, instead of the one that deploys the file"
+    )]
+    pub as_target: Option<String>,
+    #[arg(
+        long = "var-file",
+        value_name = "FILE",
+        help = "YAML file of variables to define under `config`, overriding those of the config. Can be given more than once, later files override earlier ones",
+        value_hint = clap::ValueHint::FilePath,
+    )]
+    pub var_files: Vec<std::path::PathBuf>,
+}
+#[derive(clap::Args, Clone)]
+pub struct InitCmd {
+    #[arg(help = "Directory to write dotloy.yaml in. Defaults to cwd", value_hint = clap::ValueHint::DirPath)]
+    pub dir: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_name = "FROM[=TO]",
+        help = "Add a target deploying FROM, relative to the directory, to TO. Without a TO dotfiles go in the home directory and everything else in the config directory. Can be given more than once"
+    )]
+    pub from: Vec<String>,
+    #[arg(long, help = "Overwrite a config already in the directory")]
+    pub force: bool,
+}
+#[derive(clap::Args, Clone)]
+pub struct AddCmd {
+    #[arg(help = "Path to deploy, relative to the config")]
+    pub from: String,
+    #[arg(help = "Where to deploy it, e.g. '{{ xdg.config }}/nvim'")]
+    pub to: String,
+    #[arg(
+        long,
+        value_enum,
+        help = "How to deploy it. Defaults to a hard link for files and a soft link for directories"
+    )]
+    pub link: Option<AddLink>,
+    #[arg(
+        long,
+        help = "Expand it as a template, which files ending in `.in` are anyway"
+    )]
+    pub template: bool,
+    #[arg(
+        long,
+        help = "Config to add to, or a directory with a dotloy.yaml. Defaults to cwd",
+        value_hint = clap::ValueHint::AnyPath
+    )]
+    pub config: Option<std::path::PathBuf>,
+    #[arg(long, help = "Deploy the target once it's added")]
+    pub deploy: bool,
+}
+#[derive(clap::Args, Clone)]
+pub struct AdoptCmd {
+    #[arg(help = "File or directory to adopt, e.g. ~/.config/foo", value_hint = clap::ValueHint::AnyPath)]
+    pub deployed: std::path::PathBuf,
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Directory within the config's to move it to. Defaults to the config's directory"
+    )]
+    pub into: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        help = "Config to add to, or a directory with a dotloy.yaml. Defaults to cwd",
+        value_hint = clap::ValueHint::AnyPath
     )]
     pub config: Option<std::path::PathBuf>,
 }
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum AddLink {
+    Soft,
+    Hard,
+    Copy,
+}
+#[derive(clap::Args, Clone)]
+pub struct ImportStowCmd {
+    #[arg(help = "Stow directory, the config is written to dotloy.yaml in it", value_hint = clap::ValueHint::DirPath)]
+    pub stow_dir: std::path::PathBuf,
+    #[arg(help = "Packages to import. Defaults to every package in the stow directory")]
+    pub packages: Vec<String>,
+    #[arg(
+        long,
+        short,
+        value_name = "DIR",
+        help = "Directory the packages are stowed into. Defaults to the home directory"
+    )]
+    pub target: Option<String>,
+    #[arg(long, help = "Print the config instead of writing it")]
+    pub dry_run: bool,
+}
+#[derive(clap::Args, Clone)]
+pub struct ImportChezmoiCmd {
+    #[arg(help = "chezmoi source directory, the config is written to dotloy.yaml in it", value_hint = clap::ValueHint::DirPath)]
+    pub source_dir: std::path::PathBuf,
+    #[arg(long, help = "Print the config instead of writing it")]
+    pub dry_run: bool,
+}
+#[derive(clap::Args, Clone)]
+pub struct HistoryCmd {
+    #[command(subcommand)]
+    pub show: Option<HistoryShow>,
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "How many of the latest deploys to list"
+    )]
+    pub limit: usize,
+}
+#[derive(Subcommand, Clone)]
+pub enum HistoryShow {
+    #[command(about = "Show every output a deploy touched")]
+    Show {
+        #[arg(help = "Id of the deploy, as listed by `history`")]
+        id: String,
+    },
+}
+#[derive(clap::Args, Clone)]
+pub struct LintCmd {
+    #[arg(
+        help = "Configs to lint, or directories with a dotloy.yaml. Defaults to cwd",
+        value_hint = clap::ValueHint::AnyPath
+    )]
+    pub targets: Vec<std::path::PathBuf>,
+    #[arg(long, help = "Exit with an error if there are any warnings")]
+    pub deny_warnings: bool,
+}
+#[derive(clap::Args, Clone)]
+pub struct ListCmd {
+    #[arg(
+        help = "Config to list, or a directory with a dotloy.yaml. Defaults to cwd",
+        value_hint = clap::ValueHint::AnyPath
+    )]
+    pub config: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = DryRunFormat::Text,
+        help = "How to show the targets, `json` prints an object with a `targets` array"
+    )]
+    pub format: DryRunFormat,
+}
+#[derive(clap::Args, Clone)]
+pub struct CheckCmd {
+    #[arg(help = "Targets to check, found the same way as for deploy")]
+    pub targets: Vec<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Directory paths in a config read from stdin are relative to. Defaults to cwd",
+        value_hint = clap::ValueHint::DirPath,
+    )]
+    pub source_dir: Option<std::path::PathBuf>,
+}
+#[derive(clap::Args, Clone)]
+pub struct StatusCmd {
+    #[arg(help = "Targets to show the status of, found the same way as for deploy")]
+    pub targets: Vec<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Directory paths in a config read from stdin are relative to. Defaults to cwd",
+        value_hint = clap::ValueHint::DirPath,
+    )]
+    pub source_dir: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        help = "Log full paths instead of abbreviating the home directory and sources"
+    )]
+    pub full_paths: bool,
+}
+#[derive(clap::Args, Clone)]
+pub struct DiffCmd {
+    #[arg(help = "Targets to diff, found the same way as for deploy")]
+    pub targets: Vec<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Directory paths in a config read from stdin are relative to. Defaults to cwd",
+        value_hint = clap::ValueHint::DirPath,
+    )]
+    pub source_dir: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        help = "Log full paths instead of abbreviating the home directory and sources"
+    )]
+    pub full_paths: bool,
+    #[arg(
+        long,
+        short,
+        help = "Also list the actions deploying would change nothing for"
+    )]
+    pub verbose: bool,
+}
 #[derive(clap::Args, Clone)]
 pub struct DeployCmd {
     #[arg(
-        help = "Targets to deploy. Directories are searched for dotloy.ya?ml's while files are treated as dotloy.yaml's directly"
+        help = "Targets to deploy. Directories are searched for dotloy.ya?ml's while files are treated as dotloy.yaml's directly, and `-` reads one from stdin"
     )]
     pub targets: Vec<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "from_plan",
+        help = "Also deploy the targets listed in FILE, one per line and relative to it. Blank lines and lines starting with `#` are skipped, and `-` reads the list from stdin",
+        value_hint = clap::ValueHint::FilePath,
+    )]
+    pub targets_from: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        short,
+        help = "Deploy every config found in the directories given as targets and the ones below them, skipping those a .dotloyignore ignores"
+    )]
+    pub recursive: bool,
+    #[arg(
+        long,
+        help = "On macOS, put xdg.config and friends under ~/.config and so on like on linux, as if every config set `xdg_on_macos: true`"
+    )]
+    pub xdg_on_macos: bool,
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Directory paths in a config read from stdin are relative to. Defaults to cwd",
+        value_hint = clap::ValueHint::DirPath,
+    )]
+    pub source_dir: Option<std::path::PathBuf>,
     #[arg(long, help = "Print actions but don't actually do them")]
     pub dry_run: bool,
     #[arg(long, short, help = "Watch directory and re-deploy on changes")]
     pub watch: bool,
+    #[arg(
+        long = "on-change",
+        value_name = "COMMAND",
+        requires = "watch",
+        help = "Shell command to run after each redeploy in watch mode that updated something and didn't fail, with the updated outputs in $DOTLOY_CHANGED, one per line. Can be given more than once"
+    )]
+    pub on_change: Vec<String>,
+    #[arg(
+        long,
+        help = "Log full paths instead of abbreviating the home directory and sources"
+    )]
+    pub full_paths: bool,
+    #[arg(
+        long,
+        help = "Fail if any config is skipped or any action fails, rather than only warning"
+    )]
+    pub strict: bool,
+    #[arg(
+        long,
+        help = "Show a progress bar. By default it's only shown for large deploys, when stderr is a terminal"
+    )]
+    pub progress: bool,
+    #[arg(
+        long,
+        help = "Wait for another dotloy run deploying the same config to finish, instead of failing"
+    )]
+    pub wait: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "watch",
+        help = "Write a shell script doing the deploy to PATH instead of deploying",
+        value_hint = clap::ValueHint::FilePath,
+    )]
+    pub emit_script: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        help = "Refuse to deploy sources with uncommitted changes, like `require_clean_git: true` in every config"
+    )]
+    pub require_clean: bool,
+    #[arg(
+        long,
+        help = "Repoint symlinks in the way that point elsewhere in the config's directory or at nothing, like `on_conflict: repoint` on every target"
+    )]
+    pub repoint: bool,
+    #[arg(
+        long,
+        help = "Print how long reading, planning and running the deploy took, and its slowest actions"
+    )]
+    pub timings: bool,
+    #[arg(long, help = "Don't record this deploy in the history")]
+    pub no_history: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["watch", "emit_script"],
+        help = "Write the deploy, with templates rendered, to a plan at PATH instead of deploying",
+        value_hint = clap::ValueHint::FilePath,
+    )]
+    pub emit_plan: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["targets", "watch", "emit_plan", "emit_script"],
+        help = "Deploy exactly what the plan at PATH says, without reading any config",
+        value_hint = clap::ValueHint::FilePath,
+    )]
+    pub from_plan: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = DryRunFormat::Text,
+        conflicts_with = "watch",
+        help = "How the actions are shown, `json` prints them grouped by target (with --timings, how long they took) and logs to stderr"
+    )]
+    pub format: DryRunFormat,
+}
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum DryRunFormat {
+    Text,
+    Json,
+}
+/// [`Undefined`], as `expand --undefined` takes it
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum UndefinedArg {
+    /// Fail to render
+    Error,
+    /// Leave the expression in the output exactly as it was written
+    Passthrough,
+    /// Replace the expression with nothing
+    Empty,
+}
+impl From<UndefinedArg> for Undefined {
+    fn from(value: UndefinedArg) -> Self {
+        match value {
+            UndefinedArg::Error => Undefined::Error,
+            UndefinedArg::Passthrough => Undefined::Passthrough,
+            UndefinedArg::Empty => Undefined::Empty,
+        }
+    }
 }
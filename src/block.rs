@@ -0,0 +1,156 @@
+//! Sections of files dotloy manages between two marker comments, leaving the
+//! rest of the file to whoever else edits it
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::CommentSyntax;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("block '{name}' starts with '{begin}' but has no '{end}' after it")]
+    Unterminated {
+        name: String,
+        begin: String,
+        end: String,
+    },
+}
+
+/// A managed block, known by the lines marking where it starts and ends
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub name: String,
+    pub begin: String,
+    pub end: String,
+}
+
+impl Block {
+    /// The block `name`, marked with comments in `comment` syntax
+    pub fn new(name: &str, comment: &CommentSyntax) -> Self {
+        let (begin, end) = match comment {
+            CommentSyntax::Line(start) => (
+                format!("{start} >>> dotloy managed: {name} >>>"),
+                format!("{start} <<< dotloy <<<"),
+            ),
+            CommentSyntax::Wrapped(start, close) => (
+                format!("{start} >>> dotloy managed: {name} >>> {close}"),
+                format!("{start} <<< dotloy <<< {close}"),
+            ),
+        };
+        Self {
+            name: name.to_owned(),
+            begin,
+            end,
+        }
+    }
+    /// `text` with the block holding `content`, in place of what it held if
+    /// it was there and at the end otherwise
+    pub fn insert(&self, text: &str, content: &str) -> Result<String, Error> {
+        let lines = text.split_inclusive('\n').collect::<Vec<_>>();
+        let mut block = format!("{}\n{content}", self.begin);
+        if !content.is_empty() && !content.ends_with('\n') {
+            block.push('\n');
+        }
+        block += &self.end;
+        Ok(match self.find(&lines)? {
+            Some((begin, end)) => {
+                let after = lines[end].strip_prefix(lines[end].trim_end()).unwrap_or("");
+                format!(
+                    "{}{block}{after}{}",
+                    lines[..begin].concat(),
+                    lines[end + 1..].concat()
+                )
+            }
+            None if text.is_empty() || text.ends_with('\n') => format!("{text}{block}\n"),
+            None => format!("{text}\n{block}\n"),
+        })
+    }
+    /// `text` without the block, markers and all, or as it is if the block
+    /// isn't there
+    pub fn remove(&self, text: &str) -> Result<String, Error> {
+        let lines = text.split_inclusive('\n').collect::<Vec<_>>();
+        Ok(match self.find(&lines)? {
+            Some((begin, end)) => {
+                format!("{}{}", lines[..begin].concat(), lines[end + 1..].concat())
+            }
+            None => text.to_owned(),
+        })
+    }
+    /// Indices into `lines` of the block's markers
+    fn find(&self, lines: &[&str]) -> Result<Option<(usize, usize)>, Error> {
+        let Some(begin) = lines.iter().position(|l| l.trim() == self.begin) else {
+            return Ok(None);
+        };
+        let end = lines[begin + 1..]
+            .iter()
+            .position(|l| l.trim() == self.end)
+            .ok_or_else(|| Error::Unterminated {
+                name: self.name.clone(),
+                begin: self.begin.clone(),
+                end: self.end.clone(),
+            })?;
+        Ok(Some((begin, begin + 1 + end)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Block, Error};
+    use crate::config::CommentSyntax;
+
+    #[test]
+    fn blocks_are_added_then_replaced_in_place() {
+        let block = Block::new("hosts", &CommentSyntax::default());
+        let hosts = "127.0.0.1 localhost";
+        let added = block.insert(hosts, "10.0.0.2 nas\n").unwrap();
+        assert_eq!(
+            added,
+            "127.0.0.1 localhost\n# >>> dotloy managed: hosts >>>\n10.0.0.2 nas\n# <<< dotloy <<<\n"
+        );
+        assert_eq!(block.insert(&added, "10.0.0.2 nas").unwrap(), added);
+
+        let edited = format!("{added}::1 localhost\n");
+        assert_eq!(
+            block.insert(&edited, "10.0.0.3 nas\n").unwrap(),
+            "127.0.0.1 localhost\n# >>> dotloy managed: hosts >>>\n10.0.0.3 nas\n# <<< dotloy <<<\n::1 localhost\n"
+        );
+        assert_eq!(
+            Block::new("a", &CommentSyntax::default())
+                .insert("", "")
+                .unwrap(),
+            "# >>> dotloy managed: a >>>\n# <<< dotloy <<<\n"
+        );
+    }
+
+    #[test]
+    fn removing_a_block_leaves_the_rest() {
+        let block = Block::new("hosts", &CommentSyntax::default());
+        let hosts = "127.0.0.1 localhost\n# >>> dotloy managed: hosts >>>\n10.0.0.2 nas\n# <<< dotloy <<<\n::1 localhost\n";
+        assert_eq!(
+            block.remove(hosts).unwrap(),
+            "127.0.0.1 localhost\n::1 localhost\n"
+        );
+        let added = block.insert("", "10.0.0.2 nas").unwrap();
+        assert_eq!(block.remove(&added).unwrap(), "");
+        assert_eq!(block.remove("::1 localhost").unwrap(), "::1 localhost");
+        assert!(matches!(
+            block.remove(&format!("{}\n", block.begin)),
+            Err(Error::Unterminated { .. })
+        ));
+    }
+
+    #[test]
+    fn markers_follow_the_comment_syntax() {
+        let html = Block::new(
+            "nav",
+            &CommentSyntax::Wrapped("<!--".to_owned(), "-->".to_owned()),
+        );
+        assert_eq!(html.begin, "<!-- >>> dotloy managed: nav >>> -->");
+        assert_eq!(html.end, "<!-- <<< dotloy <<< -->");
+
+        let unterminated = format!("{}\n<a/>\n", html.begin);
+        assert!(matches!(
+            html.insert(&unterminated, "<b/>"),
+            Err(Error::Unterminated { .. })
+        ));
+    }
+}
@@ -1,62 +1,528 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
 
-use super::Templated;
-use serde::Deserialize;
+use crate::{
+    abspath,
+    ignore::Ignore,
+    suggest,
+    template::{self, Context, Templated, Undefined, Value},
+};
+use fs_err as fs;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Default)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("unknown field `{field}` {place}{}", hint.as_ref().map(|h| format!(", {h}")).unwrap_or_default())]
+    UnknownField {
+        field: String,
+        place: String,
+        hint: Option<String>,
+    },
+    #[error("target {target} has both `content` and `{field}`, it can only have one")]
+    ContentWithSource { target: usize, field: &'static str },
+}
+
+#[derive(Error, Debug)]
+pub enum ReadError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Path(#[from] abspath::Error),
+    #[error("failed to parse config '{path}': {source}", path = path.to_string_lossy())]
+    Parse { path: PathBuf, source: Error },
+    #[error("failed to parse variables in '{path}': {source}", path = path.to_string_lossy())]
+    Variables {
+        path: PathBuf,
+        source: serde_yaml::Error,
+    },
+}
+
+/// Names a config in a directory can have, the first one found is used
+pub const FILE_NAMES: [&str; 2] = ["dotloy.yaml", "dotloy.yml"];
+
+/// The config file in `dir`, if it has one
+pub fn find_in_dir(dir: &Path) -> Option<PathBuf> {
+    assert!(dir.is_dir(), "tried to find config in non-directory");
+    FILE_NAMES
+        .into_iter()
+        .map(Path::new)
+        .map(|p| dir.join(p))
+        .find(|c| c.exists())
+}
+
+/// Every config in `dir` and the directories below it, each before those
+/// below it and otherwise sorted by path. Directories a `.dotloyignore`
+/// ignores are skipped, `.git` among them, and symlinks aren't followed
+pub fn find_below(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    fn walk(dir: &Path, ignore: &Ignore, found: &mut Vec<PathBuf>) -> std::io::Result<()> {
+        found.extend(find_in_dir(dir));
+        let mut entries = fs::read_dir(dir)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        for path in entries {
+            if fs::symlink_metadata(&path)?.is_dir() && !ignore.is_ignored(&path) {
+                walk(&path, ignore, found)?;
+            }
+        }
+        Ok(())
+    }
+    let mut found = Vec::new();
+    walk(dir, &Ignore::new(dir), &mut found)?;
+    Ok(found)
+}
+
+/// Directory of the config at or in `p`
+pub fn dir_of(p: &Path) -> Option<&Path> {
+    if p.is_dir() {
+        Some(p)
+    } else {
+        p.parent()
+    }
+}
+
+/// Find and parse the config at or in `p`, along with the absolute path of
+/// the file it was read from
+///
+/// ```no_run
+/// let (file, cfg) = dotloy::config::read("dotfiles".as_ref())?.expect("no config in dotfiles/");
+/// println!("{} has {} targets", file.display(), cfg.targets.len());
+/// # Ok::<_, dotloy::config::ReadError>(())
+/// ```
+pub fn read(p: &Path) -> Result<Option<(PathBuf, Root)>, ReadError> {
+    let p = if p.is_dir() {
+        find_in_dir(p)
+    } else {
+        Some(p.to_owned())
+    };
+    p.map(|p| {
+        let p = abspath::canonicalize(p)?;
+        let cfg = parse(&fs::read_to_string(&p)?).map_err(|source| ReadError::Parse {
+            path: p.clone(),
+            source,
+        })?;
+        Ok((p, cfg))
+    })
+    .transpose()
+}
+
+/// Variables defined by the YAML mapping in each of `files`, later files
+/// overriding earlier ones. Nested maps become dotted namespaces and are
+/// merged rather than replaced
+pub fn read_variables<P: AsRef<Path>>(
+    files: impl IntoIterator<Item = P>,
+) -> Result<HashMap<String, VariableValue>, ReadError> {
+    let mut vars = HashMap::new();
+    for path in files {
+        let path = path.as_ref();
+        let read: HashMap<String, VariableValue> = serde_yaml::from_str(&fs::read_to_string(path)?)
+            .map_err(|source| ReadError::Variables {
+                path: path.to_owned(),
+                source,
+            })?;
+        merge_variables(&mut vars, read);
+    }
+    Ok(vars)
+}
+
+/// Define `from` in `into`, maps in both are merged key by key
+pub fn merge_variables(
+    into: &mut HashMap<String, VariableValue>,
+    from: HashMap<String, VariableValue>,
+) {
+    fn merge(into: &mut VariableValue, from: VariableValue) {
+        match (into, from) {
+            (VariableValue::Map(into), VariableValue::Map(from)) => {
+                for (name, value) in from {
+                    match into.get_mut(&name) {
+                        Some(existing) => merge(existing, value),
+                        None => {
+                            into.insert(name, value);
+                        }
+                    }
+                }
+            }
+            (into, from) => *into = from,
+        }
+    }
+    for (name, value) in from {
+        match into.get_mut(&name) {
+            Some(existing) => merge(existing, value),
+            None => {
+                into.insert(name, value);
+            }
+        }
+    }
+}
+
+/// Given instead of a config path to read the config from stdin
+pub const STDIN: &str = "-";
+
+pub fn is_stdin(p: &Path) -> bool {
+    p == Path::new(STDIN)
+}
+
+/// Parse a config from stdin, along with what to know it by. There's no file
+/// so that's a hash of its content, the same config gets the same id
+pub fn read_stdin() -> Result<(String, Root), ReadError> {
+    let text = std::io::read_to_string(std::io::stdin())?;
+    let cfg = parse(&text).map_err(|source| ReadError::Parse {
+        path: "<stdin>".into(),
+        source,
+    })?;
+    Ok((content_id(&text), cfg))
+}
+
+fn content_id(text: &str) -> String {
+    format!("stdin-{:016x}", crate::stable_hash(text.as_bytes()))
+}
+
+/// Fields of [`Root`] as they are spelled in the config, keep in sync
+const ROOT_FIELDS: [&str; 13] = [
+    "targets",
+    "variables",
+    "runs_on",
+    "wsl",
+    "session",
+    "allow_shadowing",
+    "allow_shell",
+    "partials",
+    "xdg_exec_fallback",
+    "require_clean_git",
+    "symlink_fallback",
+    "xdg_on_macos",
+    "adopt_identical",
+];
+/// Fields of [`Target`] as they are spelled in the config, keep in sync
+const TARGET_FIELDS: [&str; 34] = [
+    "from",
+    "from_command",
+    "content",
+    "variables",
+    "runs_on",
+    "wsl",
+    "session",
+    "to",
+    "name",
+    "link_type",
+    "template",
+    "undefined",
+    "render_depth",
+    "foreach",
+    "if_path_exists",
+    "if_path_missing",
+    "requires_command",
+    "order",
+    "description",
+    "verify_command",
+    "verify_failure",
+    "extract",
+    "strip_components",
+    "sha256",
+    "symlink_fallback",
+    "ensure_dir",
+    "dir_mode",
+    "block",
+    "block_comment",
+    "block_create",
+    "merge_format",
+    "merge_arrays",
+    "on_conflict",
+    "adopt_identical",
+];
+/// What people tend to write instead of a field
+const ALIASES: [(&str, &str); 16] = [
+    ("source", "from"),
+    ("src", "from"),
+    ("path", "from"),
+    ("dest", "to"),
+    ("destination", "to"),
+    ("dst", "to"),
+    ("target", "to"),
+    ("vars", "variables"),
+    ("platform", "runs_on"),
+    ("platforms", "runs_on"),
+    ("os", "runs_on"),
+    ("type", "link_type"),
+    ("link", "link_type"),
+    ("is_template", "template"),
+    ("command", "from_command"),
+    ("cmd", "from_command"),
+];
+
+/// Parse a config, pointing out fields that don't exist rather than
+/// ignoring them
+pub fn parse(text: &str) -> Result<Root, Error> {
+    let value: serde_yaml::Value = serde_yaml::from_str(text)?;
+    check_fields(
+        &value,
+        &ROOT_FIELDS,
+        &TARGET_FIELDS,
+        "at the top level",
+        |f| format!("`{f}` belongs to a target, under `targets`"),
+    )?;
+    if let Some(targets) = value.get("targets").and_then(|t| t.as_sequence()) {
+        for (i, target) in targets.iter().enumerate() {
+            check_fields(
+                target,
+                &TARGET_FIELDS,
+                &ROOT_FIELDS,
+                &format!("in target {}", i + 1),
+                |f| format!("`{f}` can only be set at the top level of the config"),
+            )?;
+            if target.get("content").is_some() {
+                if let Some(field) = ["from", "from_command"]
+                    .into_iter()
+                    .find(|f| target.get(f).is_some())
+                {
+                    return Err(Error::ContentWithSource {
+                        target: i + 1,
+                        field,
+                    });
+                }
+            }
+        }
+    }
+    Ok(serde_yaml::from_str(text)?)
+}
+
+fn check_fields(
+    value: &serde_yaml::Value,
+    fields: &[&'static str],
+    elsewhere: &[&str],
+    place: &str,
+    misplaced: impl Fn(&str) -> String,
+) -> Result<(), Error> {
+    let Some(map) = value.as_mapping() else {
+        return Ok(());
+    };
+    for key in map.iter().filter_map(|(k, _)| k.as_str()) {
+        if fields.contains(&key) {
+            continue;
+        }
+        let hint = if elsewhere.contains(&key) {
+            Some(misplaced(key))
+        } else {
+            ALIASES
+                .iter()
+                .find(|(alias, field)| *alias == key && fields.contains(field))
+                .map(|(_, field)| *field)
+                .or_else(|| {
+                    suggest::closest(key, fields.iter().copied())
+                        .first()
+                        .copied()
+                })
+                .map(|field| format!("did you mean `{field}`?"))
+        };
+        return Err(Error::UnknownField {
+            field: key.to_owned(),
+            place: place.to_owned(),
+            hint,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
 pub struct Root {
     /// Global variables. Accessible under `config` namespace
     #[serde(default, flatten)]
     pub shared: MultiScopedOptions,
     /// Targets to deploy
     pub targets: Vec<Target>,
+    /// Don't warn about variables named after built-in namespaces like `xdg`
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub allow_shadowing: bool,
+    /// Allow templates to run commands with the `shell` helper
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub allow_shell: bool,
+    /// Directory to look for partials in when they aren't next to the
+    /// template including them. Relative to the config file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partials: Option<std::path::PathBuf>,
+    /// Used as `xdg.exec` on platforms without an executable directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xdg_exec_fallback: Option<Templated<String>>,
+    /// Check that sources of targets have no uncommitted changes before
+    /// deploying, when the config is in a git repository
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub require_clean_git: RequireCleanGit,
+    /// What to do when a destination's filesystem can't hold links, for
+    /// targets that don't say
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub symlink_fallback: SymlinkFallback,
+    /// Put `xdg.config`, `xdg.data`, `xdg.cache` and `xdg.state` where they
+    /// are on linux when on macOS, rather than under `~/Library`
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub xdg_on_macos: bool,
+    /// Let links replace a file already at their destination with the same
+    /// content as their source, for targets that don't say
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub adopt_identical: bool,
+}
+
+/// `require_clean_git`, `true` being the same as `refuse`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(untagged)]
+pub enum RequireCleanGit {
+    Enabled(bool),
+    Mode(DirtyGit),
+}
+impl Default for RequireCleanGit {
+    fn default() -> Self {
+        Self::Enabled(false)
+    }
+}
+impl RequireCleanGit {
+    /// What to do about uncommitted changes, `None` to not check
+    pub fn mode(self) -> Option<DirtyGit> {
+        match self {
+            Self::Enabled(true) => Some(DirtyGit::Refuse),
+            Self::Enabled(false) => None,
+            Self::Mode(m) => Some(m),
+        }
+    }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+/// What to do when sources of targets have uncommitted changes
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum DirtyGit {
+    Warn,
+    Refuse,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 pub enum Platform {
     Windows,
     #[serde(rename = "macos")]
     MacOs,
     Linux,
+    #[serde(rename = "freebsd")]
+    FreeBsd,
+    #[serde(rename = "openbsd")]
+    OpenBsd,
+    #[serde(rename = "netbsd")]
+    NetBsd,
+    /// Every platform but windows, only meaningful in `runs_on`
+    Unix,
     /// Testing platform that will never be matched by the current one
     #[cfg(test)]
     #[doc(hidden)]
     Test,
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
 #[serde(untagged)]
 pub enum OneOrMany<T> {
     One(T),
     Many(Vec<T>),
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Default, Clone)]
+/// Value of a user defined variable, strings are rendered as templates while
+/// lists and maps are made available to `#each`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(untagged, from = "ReadVariableValue")]
+pub enum VariableValue {
+    Single(Templated<String>),
+    List(Vec<VariableValue>),
+    Map(BTreeMap<String, VariableValue>),
+}
+
+/// A [`VariableValue`] as it is read. Numbers and booleans are strings like
+/// any other value, but once YAML has read them as what they are they're no
+/// longer the text they were written as, so `1.50` is `1.5`. Null is empty
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ReadVariableValue {
+    Single(Templated<String>),
+    Bool(bool),
+    Number(serde_yaml::Number),
+    Null(()),
+    List(Vec<VariableValue>),
+    Map(BTreeMap<String, VariableValue>),
+}
+impl From<ReadVariableValue> for VariableValue {
+    fn from(value: ReadVariableValue) -> Self {
+        let single = |s: String| Self::Single(Templated::new(s));
+        match value {
+            ReadVariableValue::Single(s) => Self::Single(s),
+            ReadVariableValue::Bool(b) => single(b.to_string()),
+            ReadVariableValue::Number(n) => single(n.to_string()),
+            ReadVariableValue::Null(()) => single(String::new()),
+            ReadVariableValue::List(l) => Self::List(l),
+            ReadVariableValue::Map(m) => Self::Map(m),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default, Clone)]
 pub struct MultiScopedOptions {
-    #[serde(default)]
-    pub variables: HashMap<String, Templated<String>>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub variables: HashMap<String, VariableValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub runs_on: Option<OneOrMany<Platform>>,
+    /// Only deploy under WSL if `true`, or only outside of it if `false`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wsl: Option<bool>,
+    /// Only deploy in these kinds of linux session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<OneOrMany<Session>>,
+}
+
+/// Kind of session a linux user is logged into
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Session {
+    Wayland,
+    X11,
+    /// No graphical session, like on a console or over ssh
+    Tty,
+}
+impl Session {
+    /// Name of the session as it is spelled in the config
+    pub fn name(self) -> &'static str {
+        match self {
+            Session::Wayland => "wayland",
+            Session::X11 => "x11",
+            Session::Tty => "tty",
+        }
+    }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum DeployType {
     #[default]
     Auto,
     Copy,
+    /// Merge into the JSON or YAML document already there
+    Merge,
     #[serde(untagged)]
     Link(LinkType),
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct Target {
     /// Local path
     ///
     /// Config name: `from`
-    #[serde(rename = "from")]
+    #[serde(rename = "from", default)]
     pub path: Templated<String>,
+    /// Shell command whose output is deployed, instead of a file at `from`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_command: Option<Templated<String>>,
+    /// Text deployed to `to`, rendered with the target's variables, instead
+    /// of a file at `from`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Templated<String>>,
     /// Target specific variables
     ///
     /// Accessible under `target` namespace
@@ -67,55 +533,598 @@ pub struct Target {
     /// Config name: `to`
     #[serde(rename = "to")]
     pub target_location: Templated<String>,
+    /// Name to refer to the target by, available to its template as
+    /// `target.name`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     /// Explicit link type to use.
     ///
     /// If not specified defaults to [`Hard`](LinkType::Hard) for files and
     /// [`Soft`](LinkType::Soft) for directories
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_default")]
     pub link_type: DeployType,
     /// Explicit option to expand template or not
     ///
     /// By default it will only be treated as a template if `from` ends with `.in`
-    #[serde(default, rename = "template")]
+    #[serde(default, rename = "template", skip_serializing_if = "Option::is_none")]
     pub is_template: Option<bool>,
+    /// What to do with references to undefined variables when expanding the
+    /// template. Config fields like `to` always error
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub undefined: Undefined,
+    /// How many times to render the template, so that template syntax
+    /// produced by variables is expanded too
+    #[serde(
+        default = "default_render_depth",
+        skip_serializing_if = "is_default_render_depth"
+    )]
+    pub render_depth: usize,
+    /// Deploy the target once per entry, with the entry available as
+    /// `target.item`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub foreach: Option<Vec<VariableValue>>,
+    /// Only deploy the target if this path exists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_path_exists: Option<Templated<String>>,
+    /// Only deploy the target if this path doesn't exist
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_path_missing: Option<Templated<String>>,
+    /// Only deploy the target if these commands are on `PATH`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires_command: Option<RequiredCommands>,
+    /// Targets with a higher order are deployed after those with a lower one,
+    /// unless that would deploy them before something they need
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub order: i32,
+    /// What the target is for, shown alongside it in dry runs and errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Command run once the target is deployed, which fails the target if it
+    /// fails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_command: Option<Templated<String>>,
+    /// What a failed `verify_command` does
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub verify_failure: VerifyFailure,
+    /// `from` is an archive to unpack into `to`
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub extract: bool,
+    /// Leading path components dropped from each entry when extracting
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub strip_components: usize,
+    /// Hex SHA-256 `from` must have, checked before it is deployed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// What to do when the destination's filesystem can't hold links,
+    /// defaults to the `symlink_fallback` of the config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink_fallback: Option<SymlinkFallback>,
+    /// Only make sure `to` is a directory, for targets without a `from`
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ensure_dir: bool,
+    /// Octal unix permissions of the directory of an `ensure_dir` target,
+    /// e.g. `"700"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir_mode: Option<String>,
+    /// Only manage a block of `to` with this name, between marker comments,
+    /// rather than the whole file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<String>,
+    /// Comment syntax of the markers around a `block`
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub block_comment: CommentSyntax,
+    /// Create `to` for a `block` if it doesn't exist, rather than failing
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub block_create: bool,
+    /// Format of the document a `link_type: merge` target merges into,
+    /// by default going by the extension of `to`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merge_format: Option<MergeFormat>,
+    /// How `link_type: merge` merges arrays
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub merge_arrays: ArrayMerge,
+    /// What to do when `to` is already a symlink to something else
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub on_conflict: OnConflict,
+    /// Replace a file at `to` with the link if it has the same content as
+    /// `from`, rather than failing. Defaults to the `adopt_identical` of the
+    /// config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adopt_identical: Option<bool>,
+}
+
+/// What a link does when its destination is a symlink to something else
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+    /// Fail the target
+    #[default]
+    Error,
+    /// Point it at the source instead, if what it points at no longer exists
+    /// or is in the same directory as the config, like a source that moved
+    Repoint,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeFormat {
+    Json,
+    Yaml,
+}
+
+impl MergeFormat {
+    /// Format of a file going by its extension
+    pub fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ArrayMerge {
+    /// The array being merged in replaces the one there
+    #[default]
+    Replace,
+    /// Items of the array being merged in are added to the end of the one
+    /// there, unless it already has them
+    Union,
+}
+
+/// How to write a comment in the file a managed block is in
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(untagged)]
+pub enum CommentSyntax {
+    /// Comments running to the end of the line, like `#` or `//`
+    Line(String),
+    /// Comments with a start and an end, like `["<!--", "-->"]`
+    Wrapped(String, String),
+}
+
+impl Default for CommentSyntax {
+    fn default() -> Self {
+        Self::Line("#".to_owned())
+    }
+}
+
+/// What to do when linking fails because the filesystem can't hold links at
+/// all, like FAT or some network shares
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkFallback {
+    /// Fail the target
+    #[default]
+    Error,
+    /// Copy the source there instead, with a warning
+    Copy,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyFailure {
+    /// Fail the target
+    #[default]
+    Error,
+    /// Log a warning and carry on
+    Warn,
+    /// Fail the target and put back what was there before it was deployed
+    Rollback,
+}
+
+/// Commands a target needs, either all of them or at least one
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(untagged)]
+pub enum RequiredCommands {
+    All(OneOrMany<String>),
+    AnyOf { any_of: Vec<String> },
+}
+
+impl RequiredCommands {
+    /// Why the target can't be deployed, if it can't, when `found` says
+    /// which commands are on `PATH`
+    pub fn missing(&self, found: impl Fn(&str) -> bool) -> Option<String> {
+        let quoted = |cmds: &[&String]| cmds.iter().map(|c| format!("`{c}`")).join(", ");
+        match self {
+            RequiredCommands::All(cmds) => {
+                let cmds = match cmds {
+                    OneOrMany::One(cmd) => std::slice::from_ref(cmd),
+                    OneOrMany::Many(cmds) => cmds.as_slice(),
+                };
+                match cmds
+                    .iter()
+                    .filter(|c| !found(c))
+                    .collect::<Vec<_>>()
+                    .as_slice()
+                {
+                    [] => None,
+                    [cmd] => Some(format!("`{cmd}` isn't on PATH")),
+                    missing => Some(format!("{} aren't on PATH", quoted(missing))),
+                }
+            }
+            RequiredCommands::AnyOf { any_of } => (!any_of.iter().any(|c| found(c))).then(|| {
+                format!(
+                    "none of {} are on PATH",
+                    quoted(&any_of.iter().collect::<Vec<_>>())
+                )
+            }),
+        }
+    }
+}
+
+fn default_render_depth() -> usize {
+    1
+}
+fn is_default_render_depth(depth: &usize) -> bool {
+    *depth == default_render_depth()
+}
+pub(crate) fn is_default<T: Default + PartialEq>(v: &T) -> bool {
+    *v == T::default()
+}
+fn is_false(b: &bool) -> bool {
+    !b
 }
 
 impl Target {
-    #[cfg(test)]
+    /// A target deploying `path` to `target_location`, with everything else
+    /// left as it would be if the config didn't set it
     pub fn new(path: String, target_location: String) -> Self {
         Self {
             path: Templated::new(path),
+            from_command: None,
+            content: None,
             shared: Default::default(),
             target_location: Templated::new(target_location),
+            name: None,
             link_type: Default::default(),
             is_template: None,
+            undefined: Default::default(),
+            render_depth: default_render_depth(),
+            foreach: None,
+            if_path_exists: None,
+            if_path_missing: None,
+            requires_command: None,
+            order: 0,
+            description: None,
+            verify_command: None,
+            verify_failure: Default::default(),
+            extract: false,
+            strip_components: 0,
+            sha256: None,
+            symlink_fallback: None,
+            ensure_dir: false,
+            dir_mode: None,
+            block: None,
+            block_comment: Default::default(),
+            block_create: false,
+            merge_format: None,
+            merge_arrays: Default::default(),
+            on_conflict: Default::default(),
+            adopt_identical: None,
         }
     }
+    /// How to refer to the target in messages, its `from` or, if it doesn't
+    /// have one, its `from_command` or for `ensure_dir` and `content` its
+    /// `to`
+    pub fn label(&self) -> &str {
+        match &self.from_command {
+            Some(cmd) if self.path.source().is_empty() => cmd.source(),
+            None if (self.ensure_dir || self.content.is_some())
+                && self.path.source().is_empty() =>
+            {
+                self.target_location.source()
+            }
+            _ => self.path.source(),
+        }
+    }
+}
+
+impl VariableValue {
+    /// Variables used by the templates in this value
+    pub fn references(&self) -> Result<Vec<template::Variable>, template::Error> {
+        match self {
+            VariableValue::Single(t) => Ok(t
+                .referenced_vars()?
+                .into_iter()
+                .map(|r| r.var)
+                .unique()
+                .collect()),
+            VariableValue::List(items) => items.iter().map(Self::references).flatten_ok().collect(),
+            VariableValue::Map(props) => {
+                props.values().map(Self::references).flatten_ok().collect()
+            }
+        }
+    }
+    pub fn render(&self, ctx: &Context) -> Result<Value, template::Error> {
+        Ok(match self {
+            VariableValue::Single(t) => Value::String(t.render(ctx)?),
+            VariableValue::List(items) => Value::List(
+                items
+                    .iter()
+                    .map(|v| v.render(ctx))
+                    .collect::<Result<_, _>>()?,
+            ),
+            VariableValue::Map(props) => Value::Object(
+                props
+                    .iter()
+                    .map(|(k, v)| Ok((k.to_owned(), v.render(ctx)?)))
+                    .collect::<Result<_, template::Error>>()?,
+            ),
+        })
+    }
+}
+impl From<Templated<String>> for VariableValue {
+    fn from(value: Templated<String>) -> Self {
+        Self::Single(value)
+    }
 }
 
 impl MultiScopedOptions {
     pub fn is_platform_supported(&self, target: Platform) -> bool {
         match &self.runs_on {
-            Some(OneOrMany::One(p)) => *p == target,
-            Some(OneOrMany::Many(ps)) => ps.iter().any(|p| *p == target),
+            Some(OneOrMany::One(p)) => p.matches(target),
+            Some(OneOrMany::Many(ps)) => ps.iter().any(|p| p.matches(target)),
             None => true,
         }
     }
+    /// Whether these options allow deploying on a machine that is (`wsl`)
+    /// or isn't running under WSL
+    pub fn is_wsl_supported(&self, wsl: bool) -> bool {
+        self.wsl.unwrap_or(wsl) == wsl
+    }
+    /// Whether these options allow deploying in `session`, `None` if there's
+    /// no telling sessions apart here
+    pub fn is_session_supported(&self, session: Option<Session>) -> bool {
+        match (&self.session, session) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(OneOrMany::One(s)), Some(session)) => *s == session,
+            (Some(OneOrMany::Many(ss)), Some(session)) => ss.contains(&session),
+        }
+    }
 }
 impl Platform {
+    /// Name of the platform as it is spelled in the config
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Windows => "windows",
+            Platform::MacOs => "macos",
+            Platform::Linux => "linux",
+            Platform::FreeBsd => "freebsd",
+            Platform::OpenBsd => "openbsd",
+            Platform::NetBsd => "netbsd",
+            Platform::Unix => "unix",
+            #[cfg(test)]
+            Platform::Test => "test",
+        }
+    }
     pub fn current() -> Option<Self> {
         match std::env::consts::OS {
             "linux" => Some(Self::Linux),
             "macos" => Some(Self::MacOs),
             "windows" => Some(Self::Windows),
+            "freebsd" => Some(Self::FreeBsd),
+            "openbsd" => Some(Self::OpenBsd),
+            "netbsd" => Some(Self::NetBsd),
             _ => None,
         }
     }
+    /// Whether `runs_on` with this in it includes `current`, which is what
+    /// [`Platform::current`] gave
+    pub fn matches(self, current: Platform) -> bool {
+        match self {
+            Platform::Unix => !matches!(current, Platform::Windows | Platform::Unix),
+            p => p == current,
+        }
+    }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 pub enum LinkType {
     Soft,
     Hard,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        content_id, find_below, parse, read, read_variables, DeployType, LinkType,
+        MultiScopedOptions, OneOrMany, Platform, RequiredCommands, Root, Target, VariableValue,
+    };
+    use crate::test_data_path;
+
+    #[test]
+    fn unix_runs_on_everything_but_windows_and_wsl_is_separate() {
+        let runs_on = |p| MultiScopedOptions {
+            runs_on: Some(OneOrMany::One(p)),
+            ..Default::default()
+        };
+        for bsd in [Platform::FreeBsd, Platform::OpenBsd, Platform::NetBsd] {
+            assert!(runs_on(Platform::Unix).is_platform_supported(bsd));
+            assert!(runs_on(bsd).is_platform_supported(bsd));
+            assert!(!runs_on(Platform::Linux).is_platform_supported(bsd));
+        }
+        assert!(runs_on(Platform::Unix).is_platform_supported(Platform::Linux));
+        assert!(runs_on(Platform::Unix).is_platform_supported(Platform::MacOs));
+        assert!(!runs_on(Platform::Unix).is_platform_supported(Platform::Windows));
+        let either = MultiScopedOptions {
+            runs_on: Some(OneOrMany::Many(vec![Platform::Windows, Platform::FreeBsd])),
+            ..Default::default()
+        };
+        assert!(either.is_platform_supported(Platform::FreeBsd));
+        assert!(!either.is_platform_supported(Platform::NetBsd));
+        assert!(either.is_wsl_supported(true) && either.is_wsl_supported(false));
+        let outside = MultiScopedOptions {
+            wsl: Some(false),
+            ..Default::default()
+        };
+        assert!(!outside.is_wsl_supported(true) && outside.is_wsl_supported(false));
+    }
+
+    #[test]
+    fn required_commands_say_what_is_missing() {
+        let found = |c: &str| c == "vim" || c == "tmux";
+        let all = |cmds: &[&str]| {
+            RequiredCommands::All(OneOrMany::Many(
+                cmds.iter().map(|c| c.to_string()).collect(),
+            ))
+            .missing(found)
+        };
+        assert_eq!(
+            RequiredCommands::All(OneOrMany::One("tmux".to_owned())).missing(found),
+            None
+        );
+        assert_eq!(all(&["tmux", "vim"]), None);
+        assert_eq!(all(&["tmux", "fzf"]).unwrap(), "`fzf` isn't on PATH");
+        assert_eq!(all(&["rg", "fzf"]).unwrap(), "`rg`, `fzf` aren't on PATH");
+        let any_of = |cmds: &[&str]| {
+            RequiredCommands::AnyOf {
+                any_of: cmds.iter().map(|c| c.to_string()).collect(),
+            }
+            .missing(found)
+        };
+        assert_eq!(any_of(&["nvim", "vim"]), None);
+        assert_eq!(
+            any_of(&["nvim", "hx"]).unwrap(),
+            "none of `nvim`, `hx` are on PATH"
+        );
+    }
+
+    #[test]
+    fn broken_configs_name_the_file_and_suggest_fixes() {
+        let error = |name: &str| {
+            let path = test_data_path().join("broken").join(name);
+            let msg = read(&path).unwrap_err().to_string();
+            assert!(
+                msg.starts_with(&format!(
+                    "failed to parse config '{}': ",
+                    path.canonicalize().unwrap().display()
+                )),
+                "{msg}"
+            );
+            msg
+        };
+        assert!(error("source_instead_of_from.yaml")
+            .ends_with("unknown field `source` in target 1, did you mean `from`?"));
+        assert!(error("typo.yaml")
+            .ends_with("unknown field `link_tpye` in target 1, did you mean `link_type`?"));
+        assert!(error("misplaced_allow_shell.yaml").ends_with(
+            "unknown field `allow_shell` in target 1, `allow_shell` can only be set at the top level of the config"
+        ));
+        assert!(error("to_at_top_level.yaml").ends_with(
+            "unknown field `to` at the top level, `to` belongs to a target, under `targets`"
+        ));
+        assert!(error("content_and_from.yaml")
+            .ends_with("target 2 has both `content` and `from`, it can only have one"));
+        assert!(error("syntax.yaml").contains("line 3"));
+    }
+
+    #[test]
+    fn configs_are_written_without_defaults() {
+        let mut cfg = Root::default();
+        let mut target = Target::new("vim/.vimrc".to_owned(), "~/.vimrc".to_owned());
+        target.link_type = DeployType::Link(LinkType::Soft);
+        cfg.targets.push(target);
+        let text = serde_yaml::to_string(&cfg).unwrap();
+        assert_eq!(
+            text,
+            "targets:\n- from: vim/.vimrc\n  to: ~/.vimrc\n  link_type: soft\n"
+        );
+        assert_eq!(parse(&text).unwrap(), cfg);
+    }
+    #[test]
+    fn numbers_and_booleans_are_variables_like_strings() {
+        let cfg = parse(
+            "variables:\n  port: 8080\n  enabled: true\n  ratio: 0.5\n  unset: ~\n  \
+             ports: [80, 443]\ntargets:\n- from: a\n  to: b\n  variables:\n    \
+             retries: 3\n    debug: false\n",
+        )
+        .unwrap();
+        let single = |s: &str| VariableValue::Single(crate::template::Templated::new(s.to_owned()));
+        let root = &cfg.shared.variables;
+        assert_eq!(root["port"], single("8080"));
+        assert_eq!(root["enabled"], single("true"));
+        assert_eq!(root["ratio"], single("0.5"));
+        assert_eq!(root["unset"], single(""));
+        assert_eq!(
+            root["ports"],
+            VariableValue::List(vec![single("80"), single("443")])
+        );
+        let target = &cfg.targets[0].shared.variables;
+        assert_eq!(target["retries"], single("3"));
+        assert_eq!(target["debug"], single("false"));
+    }
+    #[test]
+    fn stdin_configs_are_known_by_their_content() {
+        let a = content_id("targets: []");
+        assert_eq!(a, "stdin-c69ecc42e3cc461f");
+        assert_eq!(a, content_id("targets: []"));
+        assert_ne!(a, content_id("targets: [ ]"));
+    }
+
+    #[test]
+    fn configs_are_found_below_directories_in_order() {
+        let dir = tempdir::TempDir::new("find").unwrap();
+        let root = dir.path();
+        for config in [
+            "dotloy.yaml",
+            "b/dotloy.yml",
+            "a/nvim/dotloy.yaml",
+            "a/dotloy.yaml",
+            "build/dotloy.yaml",
+            ".git/dotloy.yaml",
+        ] {
+            let path = root.join(config);
+            fs_err::create_dir_all(path.parent().unwrap()).unwrap();
+            fs_err::write(path, "targets: []").unwrap();
+        }
+        fs_err::write(root.join(crate::ignore::FILE_NAME), "build/").unwrap();
+        assert_eq!(
+            find_below(root).unwrap(),
+            [
+                "dotloy.yaml",
+                "a/dotloy.yaml",
+                "a/nvim/dotloy.yaml",
+                "b/dotloy.yml"
+            ]
+            .map(|c| root.join(c))
+        );
+    }
+
+    #[test]
+    fn variable_files_merge_in_order() {
+        let dir = tempdir::TempDir::new("vars").unwrap();
+        let file = |name: &str, text: &str| {
+            let path = dir.path().join(name);
+            fs_err::write(&path, text).unwrap();
+            path
+        };
+        let files = [
+            file(
+                "a.yaml",
+                "name: me\ngit:\n  email: me@home\n  editor: vim\n",
+            ),
+            file("b.yaml", "git:\n  email: me@work\n"),
+        ];
+        let vars = read_variables(&files).unwrap();
+        let single = |s: &str| VariableValue::Single(crate::template::Templated::new(s.to_owned()));
+        assert_eq!(vars["name"], single("me"));
+        assert_eq!(
+            vars["git"],
+            VariableValue::Map(
+                [("editor", "vim"), ("email", "me@work")]
+                    .map(|(k, v)| (k.to_owned(), single(v)))
+                    .into()
+            )
+        );
+        let broken = file("broken.yaml", "a: b\nc: [d\n");
+        let err = read_variables([&broken]).unwrap_err().to_string();
+        assert!(
+            err.contains(&*broken.to_string_lossy()) && err.contains("line"),
+            "{err}"
+        );
+    }
+}
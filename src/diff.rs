@@ -0,0 +1,150 @@
+//! Line diffs of what a deploy would write against what's there, for
+//! `dotloy diff`
+use itertools::Itertools;
+
+/// Lines of context shown around each change
+const CONTEXT: usize = 3;
+
+/// One line of a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// The lines turning `old` into `new`, keeping the most lines they share.
+/// Lines both start or end with are set aside first, so the usual small edit
+/// to a large file stays cheap
+fn lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    // shared[i][j] is how many lines `a[i..]` and `b[j..]` have in common
+    let mut shared = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            shared[i][j] = if a[i] == b[j] {
+                shared[i + 1][j + 1] + 1
+            } else {
+                shared[i + 1][j].max(shared[i][j + 1])
+            };
+        }
+    }
+    let mut out = old[..prefix].iter().map(|l| Line::Same(l)).collect_vec();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out.push(Line::Same(a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || shared[i + 1][j] >= shared[i][j + 1]) {
+            out.push(Line::Removed(a[i]));
+            i += 1;
+        } else {
+            out.push(Line::Added(b[j]));
+            j += 1;
+        }
+    }
+    out.extend(old[old.len() - suffix..].iter().map(|l| Line::Same(l)));
+    out
+}
+
+/// `old` and `new` as a unified diff headed by their names, empty if they
+/// have the same lines
+pub fn unified(old: &str, new: &str, old_name: &str, new_name: &str) -> Vec<String> {
+    let diff = lines(&old.lines().collect_vec(), &new.lines().collect_vec());
+    // changes close enough together to share context are one hunk
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (k, _) in diff
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| !matches!(l, Line::Same(_)))
+    {
+        let (start, end) = (k.saturating_sub(CONTEXT), (k + 1 + CONTEXT).min(diff.len()));
+        match hunks.last_mut() {
+            Some((_, last)) if start <= *last => *last = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    if hunks.is_empty() {
+        return Vec::new();
+    }
+    let mut out = vec![format!("--- {old_name}"), format!("+++ {new_name}")];
+    // lines of `old` and `new` before each line of the diff
+    let (mut before_old, mut before_new) = (vec![0], vec![0]);
+    for line in &diff {
+        let (o, n) = (*before_old.last().unwrap(), *before_new.last().unwrap());
+        before_old.push(o + usize::from(!matches!(line, Line::Added(_))));
+        before_new.push(n + usize::from(!matches!(line, Line::Removed(_))));
+    }
+    for (start, end) in hunks {
+        let range = |before: &[usize]| {
+            let len = before[end] - before[start];
+            // an empty range is numbered by the line before it
+            let first = before[start] + usize::from(len > 0);
+            format!("{first},{len}")
+        };
+        out.push(format!(
+            "@@ -{} +{} @@",
+            range(&before_old),
+            range(&before_new)
+        ));
+        out.extend(diff[start..end].iter().map(|line| match line {
+            Line::Same(l) => format!(" {l}"),
+            Line::Removed(l) => format!("-{l}"),
+            Line::Added(l) => format!("+{l}"),
+        }));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unified;
+
+    #[test]
+    fn changes_far_apart_are_separate_hunks() {
+        let old = (1..=12).map(|n| format!("{n}\n")).collect::<String>();
+        let new = (1..=12)
+            .filter(|n| *n != 11)
+            .map(|n| match n {
+                2 => "two\n".to_owned(),
+                n => format!("{n}\n"),
+            })
+            .collect::<String>();
+        assert_eq!(
+            unified(&old, &new, "a", "b"),
+            [
+                "--- a",
+                "+++ b",
+                "@@ -1,5 +1,5 @@",
+                " 1",
+                "-2",
+                "+two",
+                " 3",
+                " 4",
+                " 5",
+                "@@ -8,5 +8,4 @@",
+                " 8",
+                " 9",
+                " 10",
+                "-11",
+                " 12",
+            ]
+        );
+        assert_eq!(
+            unified("", "x\n", "/dev/null", "b"),
+            ["--- /dev/null", "+++ b", "@@ -0,0 +1,1 @@", "+x"]
+        );
+        assert!(unified(&old, &old, "a", "b").is_empty());
+    }
+}
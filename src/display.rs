@@ -0,0 +1,200 @@
+//! Formatting shared by log lines, tables and JSON output
+use std::path::{Path, PathBuf};
+
+/// Widest the left column of a table gets, longer cells don't pad the rest
+const MAX_COLUMN: usize = 60;
+
+/// How paths are shown in log output. Only ever for showing, paths written
+/// like this are not something the filesystem would understand
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PathStyle {
+    home: Option<PathBuf>,
+    bases: Vec<PathBuf>,
+    full: bool,
+}
+
+impl PathStyle {
+    /// Show paths under `home` as `~/...`, unless `full` is set in which case
+    /// everything is shown as is
+    pub fn set_home(&mut self, home: Option<PathBuf>, full: bool) {
+        self.home = home;
+        self.full = full;
+    }
+    /// Sources under `dir` may be shown relative to it
+    pub fn add_base(&mut self, dir: PathBuf) {
+        if !self.bases.contains(&dir) {
+            self.bases.push(dir);
+        }
+    }
+    pub fn append(&mut self, other: &PathStyle) {
+        for dir in &other.bases {
+            self.add_base(dir.to_owned());
+        }
+    }
+
+    pub fn path(&self, p: &Path) -> String {
+        match self.home.as_ref().and_then(|h| p.strip_prefix(h).ok()) {
+            Some(rest) if !self.full && rest.as_os_str().is_empty() => "~".to_owned(),
+            Some(rest) if !self.full => Path::new("~").join(rest).to_string_lossy().into_owned(),
+            _ => p.to_string_lossy().into_owned(),
+        }
+    }
+    /// Like [`PathStyle::path`] but relative to a config directory if that is
+    /// shorter
+    pub fn source(&self, p: &Path) -> String {
+        let shown = self.path(p);
+        if self.full {
+            return shown;
+        }
+        self.bases
+            .iter()
+            .filter_map(|b| p.strip_prefix(b).ok())
+            .filter(|rel| !rel.as_os_str().is_empty())
+            .map(|rel| rel.to_string_lossy().into_owned())
+            .chain([shown])
+            .min_by_key(|s| s.chars().count())
+            .unwrap()
+    }
+}
+
+/// Join the two halves of each row, padding the left so the right ones line
+/// up
+pub fn align(rows: &[(String, String)]) -> Vec<String> {
+    let width = rows
+        .iter()
+        .filter(|(_, r)| !r.is_empty())
+        .map(|(l, _)| l.chars().count())
+        .filter(|w| *w <= MAX_COLUMN)
+        .max()
+        .unwrap_or(0);
+    rows.iter()
+        .map(|(l, r)| {
+            if r.is_empty() {
+                l.to_owned()
+            } else {
+                format!("{l:<width$}{r}")
+            }
+        })
+        .collect()
+}
+
+/// Pad every cell but the last of each row to the widest in its column, so
+/// the columns line up. Cells wider than [`MAX_COLUMN`] don't widen theirs
+pub fn table(rows: &[Vec<String>]) -> Vec<Vec<String>> {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths = (0..columns)
+        .map(|i| {
+            rows.iter()
+                .filter_map(|r| r.get(i))
+                .map(|c| c.chars().count())
+                .filter(|w| *w <= MAX_COLUMN)
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .enumerate()
+                .map(|(i, cell)| {
+                    if i + 1 == row.len() {
+                        cell.to_owned()
+                    } else {
+                        format!("{cell:<width$}", width = widths[i])
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// `s` as a JSON string, quotes included
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{align, json_string, table, PathStyle};
+    use std::path::{Path, PathBuf};
+
+    fn style(full: bool) -> PathStyle {
+        let mut s = PathStyle::default();
+        s.set_home(Some("/home/me".into()), full);
+        s.add_base("/home/me/dotfiles".into());
+        s
+    }
+
+    #[test]
+    fn home_is_abbreviated_and_sources_are_relative() {
+        let s = style(false);
+        assert_eq!(s.path(Path::new("/home/me/.vimrc")), "~/.vimrc");
+        assert_eq!(s.path(Path::new("/etc/hosts")), "/etc/hosts");
+        assert_eq!(s.path(Path::new("/home/me")), "~");
+        assert_eq!(
+            s.source(Path::new("/home/me/dotfiles/vim/vimrc")),
+            "vim/vimrc"
+        );
+        assert_eq!(s.source(Path::new("/home/me/dotfiles")), "~/dotfiles");
+        assert_eq!(s.source(Path::new("/home/me/.vimrc")), "~/.vimrc");
+    }
+    #[test]
+    fn full_paths_are_left_alone() {
+        let s = style(true);
+        assert_eq!(s.path(Path::new("/home/me/.vimrc")), "/home/me/.vimrc");
+        assert_eq!(
+            s.source(Path::new("/home/me/dotfiles/vim/vimrc")),
+            "/home/me/dotfiles/vim/vimrc"
+        );
+        let mut other = PathStyle::default();
+        other.append(&s);
+        assert_eq!(other.source(&PathBuf::from("/home/me/dotfiles/a")), "a");
+    }
+    #[test]
+    fn rows_are_aligned_on_their_right_half() {
+        let rows = [
+            ("a".to_owned(), " -> x".to_owned()),
+            ("mkdir ~/.config".to_owned(), String::new()),
+            ("abc".to_owned(), " -> y".to_owned()),
+            ("z".repeat(100), " -> w".to_owned()),
+        ];
+        assert_eq!(
+            align(&rows),
+            [
+                "a   -> x".to_owned(),
+                "mkdir ~/.config".to_owned(),
+                "abc -> y".to_owned(),
+                format!("{} -> w", "z".repeat(100)),
+            ]
+        );
+    }
+    #[test]
+    fn tables_line_up_every_column_and_json_is_escaped() {
+        let rows = vec![
+            vec!["link".to_owned(), "a".to_owned(), "~/a".to_owned()],
+            vec!["mkdir".to_owned(), String::new(), "~/.config".to_owned()],
+        ];
+        assert_eq!(
+            table(&rows),
+            [
+                ["link ", "a", "~/a"].map(str::to_owned).to_vec(),
+                ["mkdir", " ", "~/.config"].map(str::to_owned).to_vec(),
+            ]
+        );
+        assert_eq!(json_string("a \"b\"\\\n\u{1}"), r#""a \"b\"\\\n\u0001""#);
+    }
+}
@@ -0,0 +1,222 @@
+//! Checks of the environment dotloy runs in, for `dotloy doctor`
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use fs_err as fs;
+use notify::Watcher;
+
+use dotloy::{config, default_parse_context};
+
+use crate::lock;
+
+/// How long to wait for the watcher to report a change
+const EVENT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Below this many inotify watches, watching a large dotfiles repo may fail
+const FEW_INOTIFY_WATCHES: u64 = 8192;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// Result of one check
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    /// What was found
+    pub detail: String,
+    /// How to fix it, for checks that didn't pass
+    pub hint: Option<&'static str>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+    fn warn(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            status: Status::Warn,
+            hint: Some(hint),
+            ..Self::pass(name, detail)
+        }
+    }
+    fn fail(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            status: Status::Fail,
+            ..Self::warn(name, detail, hint)
+        }
+    }
+}
+
+/// Run every check, looking for a config in `dir`
+pub fn run(dir: &Path) -> Vec<Check> {
+    let scratch = std::env::temp_dir().join(format!("dotloy-doctor-{}", uuid::Uuid::new_v4()));
+    let mut checks = Vec::new();
+    match fs::create_dir_all(&scratch) {
+        Ok(()) => {
+            checks.push(symlinks(&scratch));
+            checks.push(watcher(&scratch));
+            let _ = fs::remove_dir_all(&scratch);
+        }
+        Err(e) => checks.push(Check::fail(
+            "temp dir",
+            format!("can't create '{}': {e}", scratch.to_string_lossy()),
+            "make sure the temp directory ($TMPDIR) exists and is writable",
+        )),
+    }
+    checks.extend(inotify_limit());
+    checks.extend(variables());
+    checks.push(find_config(dir));
+    checks.push(state_dir(&lock::default_dir()));
+    checks
+}
+
+fn symlinks(scratch: &Path) -> Check {
+    let (from, to) = (scratch.join("source"), scratch.join("link"));
+    match fs::write(&from, "").and_then(|_| symlink::symlink_auto(&from, &to)) {
+        Ok(()) => Check::pass("symlinks", "can create symlinks"),
+        Err(e) => Check::fail(
+            "symlinks",
+            format!("can't create a symlink: {e}"),
+            if cfg!(windows) {
+                "turn on Developer Mode or run as administrator, or use `link_type: copy`"
+            } else {
+                "check the filesystem supports symlinks, or use `link_type: copy`"
+            },
+        ),
+    }
+}
+
+fn watcher(scratch: &Path) -> Check {
+    const NAME: &str = "watcher";
+    const HINT: &str = "--watch won't notice changes, deploy by hand instead";
+    let backend = format!("{:?}", notify::RecommendedWatcher::kind());
+    let (tx, rx) = mpsc::channel();
+    let watched = notify::recommended_watcher(tx).and_then(|mut w| {
+        w.watch(scratch, notify::RecursiveMode::NonRecursive)?;
+        Ok(w)
+    });
+    let _watcher = match watched {
+        Ok(w) => w,
+        Err(e) => return Check::fail(NAME, format!("{backend} failed to start: {e}"), HINT),
+    };
+    if let Err(e) = fs::write(scratch.join("changed"), "") {
+        return Check::fail(NAME, format!("can't write to the temp dir: {e}"), HINT);
+    }
+    match rx.recv_timeout(EVENT_TIMEOUT) {
+        Ok(Ok(_)) => Check::pass(NAME, format!("{backend} delivers changes")),
+        Ok(Err(e)) => Check::warn(NAME, format!("{backend} reported an error: {e}"), HINT),
+        Err(_) => Check::warn(
+            NAME,
+            format!(
+                "{backend} didn't report a change within {}s",
+                EVENT_TIMEOUT.as_secs()
+            ),
+            HINT,
+        ),
+    }
+}
+
+/// How many inotify watches are allowed, on linux
+fn inotify_limit() -> Option<Check> {
+    const NAME: &str = "inotify watches";
+    let limit = fs::read_to_string("/proc/sys/fs/inotify/max_user_watches").ok()?;
+    let limit = limit.trim().parse::<u64>().ok()?;
+    Some(if limit < FEW_INOTIFY_WATCHES {
+        Check::warn(
+            NAME,
+            format!("only {limit} are allowed"),
+            "raise it with `sysctl fs.inotify.max_user_watches=524288`",
+        )
+    } else {
+        Check::pass(NAME, format!("{limit} are allowed"))
+    })
+}
+
+/// What the built-in variables resolve to here
+fn variables() -> Vec<Check> {
+    const VARIABLES: [&str; 10] = [
+        "xdg.home",
+        "xdg.config",
+        "xdg.data",
+        "xdg.cache",
+        "xdg.state",
+        "sys.os",
+        "sys.arch",
+        "sys.wsl",
+        "sys.hostname",
+        "sys.user",
+    ];
+    let ctx = default_parse_context();
+    VARIABLES
+        .into_iter()
+        .map(|name| match ctx.render(&format!("{{{{ {name} }}}}")) {
+            Ok(value) => Check::pass(name, value),
+            Err(e) => Check::warn(
+                name,
+                e.to_string(),
+                "targets using it will fail, define it in the config's variables instead",
+            ),
+        })
+        .collect()
+}
+
+fn find_config(dir: &Path) -> Check {
+    const NAME: &str = "config";
+    match config::read(dir) {
+        Ok(Some((file, _))) => Check::pass(NAME, format!("found '{}'", file.to_string_lossy())),
+        Ok(None) => Check::warn(
+            NAME,
+            format!("no dotloy.yaml in '{}'", dir.to_string_lossy()),
+            "run dotloy from the directory with your config, or pass it to deploy",
+        ),
+        Err(e) => Check::fail(NAME, e.to_string(), "fix the config so it can be read"),
+    }
+}
+
+/// Whether lock files can be written to `dir`
+fn state_dir(dir: &Path) -> Check {
+    const NAME: &str = "state dir";
+    let probe: PathBuf = dir.join(format!(".doctor-{}", uuid::Uuid::new_v4()));
+    match fs::create_dir_all(dir).and_then(|_| fs::write(&probe, "")) {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            Check::pass(NAME, format!("'{}' is writable", dir.to_string_lossy()))
+        }
+        Err(e) => Check::fail(
+            NAME,
+            format!("can't write to '{}': {e}", dir.to_string_lossy()),
+            "make it writable, deploys can't take their lock without it",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fs_err as fs;
+    use tempdir::TempDir;
+
+    use super::{find_config, state_dir, Status};
+
+    #[test]
+    fn missing_configs_warn_and_broken_ones_fail() {
+        let dir = TempDir::new("doctor").unwrap();
+        assert_eq!(find_config(dir.path()).status, Status::Warn);
+        assert_eq!(state_dir(&dir.path().join("locks")).status, Status::Pass);
+        fs::write(dir.path().join("dotloy.yaml"), "targets: [").unwrap();
+        let check = find_config(dir.path());
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.hint.is_some());
+    }
+}
@@ -0,0 +1,125 @@
+//! Failures while watching, so one that happens again on every change
+//! doesn't bury everything else in the log
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// How often a failure that keeps happening is mentioned again
+const REMIND_EVERY: Duration = Duration::from_secs(60);
+
+struct Failing {
+    error: String,
+    /// Times it has failed like this in a row
+    count: usize,
+    /// Wall clock time of the first of them, as shown
+    since: String,
+    /// When it was last logged
+    logged: Instant,
+}
+
+/// What to log about a failure
+#[derive(Debug, PartialEq, Eq)]
+pub enum Report {
+    /// It didn't fail like this last time, so all of it
+    New(String),
+    /// A reminder that it's still failing like it was
+    StillFailing(String),
+}
+impl Report {
+    pub fn line(&self) -> &str {
+        match self {
+            Report::New(line) | Report::StillFailing(line) => line,
+        }
+    }
+}
+
+/// The last way each thing being redeployed failed, until it stops failing
+#[derive(Default)]
+pub struct Failures {
+    failing: HashMap<String, Failing>,
+}
+
+impl Failures {
+    /// What to log now that `what` failed because of `error`: everything if
+    /// it didn't fail like this last time, otherwise a reminder that it's
+    /// still failing every so often and nothing in between
+    pub fn failed(&mut self, what: &str, error: &str, now: Instant) -> Option<Report> {
+        match self.failing.get_mut(what) {
+            Some(failing) if failing.error == error => {
+                failing.count += 1;
+                if now.duration_since(failing.logged) < REMIND_EVERY {
+                    return None;
+                }
+                failing.logged = now;
+                Some(Report::StillFailing(format!(
+                    "{what} still failing (seen {} times since {}): {}",
+                    failing.count,
+                    failing.since,
+                    error.lines().next().unwrap_or_default()
+                )))
+            }
+            _ => {
+                self.failing.insert(
+                    what.to_owned(),
+                    Failing {
+                        error: error.to_owned(),
+                        count: 1,
+                        since: chrono::Local::now().format("%H:%M").to_string(),
+                        logged: now,
+                    },
+                );
+                Some(Report::New(format!("{what} failed. reason: {error}")))
+            }
+        }
+    }
+    /// `what` worked, so if it fails again that's logged in full
+    pub fn succeeded(&mut self, what: &str) {
+        self.failing.remove(what);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Failures, Report, REMIND_EVERY};
+
+    #[test]
+    fn repeats_are_collapsed_until_the_error_changes_or_goes_away() {
+        let start = Instant::now();
+        let later = |secs| start + Duration::from_secs(secs);
+        let mut failures = Failures::default();
+        let error = "undefined variable `x`\n  --> a.in:1";
+        assert_eq!(
+            failures.failed("expand a.in", error, start),
+            Some(Report::New(format!("expand a.in failed. reason: {error}")))
+        );
+        assert_eq!(failures.failed("expand a.in", error, later(1)), None);
+        assert_eq!(failures.failed("expand a.in", error, later(2)), None);
+        let Some(Report::StillFailing(reminder)) =
+            failures.failed("expand a.in", error, later(REMIND_EVERY.as_secs() + 1))
+        else {
+            panic!("expected a reminder");
+        };
+        assert!(
+            reminder.starts_with("expand a.in still failing (seen 4 times since "),
+            "{reminder}"
+        );
+        assert!(
+            reminder.ends_with("): undefined variable `x`"),
+            "{reminder}"
+        );
+
+        // others are tracked on their own
+        assert!(failures.failed("expand b.in", error, later(3)).is_some());
+        assert_eq!(
+            failures.failed("expand a.in", "no such file", later(4)),
+            Some(Report::New(
+                "expand a.in failed. reason: no such file".to_owned()
+            ))
+        );
+        failures.succeeded("expand b.in");
+        assert!(failures.failed("expand b.in", error, later(5)).is_some());
+    }
+}
@@ -0,0 +1,117 @@
+//! Asking git about the repository dotfiles are kept in
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use thiserror::Error;
+
+use dotloy::abspath;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to run git: {0}")]
+    Spawn(#[from] std::io::Error),
+    #[error("`git status` failed: {0}")]
+    Status(String),
+    #[error(transparent)]
+    Path(#[from] abspath::Error),
+}
+
+/// Root of the git work tree `dir` is in, `None` if it isn't in one or git
+/// isn't installed
+pub fn work_tree(dir: &Path) -> Option<PathBuf> {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--show-toplevel"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+    let top = String::from_utf8(out.stdout).ok()?;
+    abspath::canonicalize(top.trim_end_matches(['\n', '\r'])).ok()
+}
+
+/// Files at or under `paths` that have uncommitted changes or are untracked,
+/// in the work tree rooted at `top`. Paths outside it are ignored
+pub fn uncommitted(top: &Path, paths: &[&Path]) -> Result<Vec<PathBuf>, Error> {
+    let paths = paths
+        .iter()
+        .filter(|p| p.starts_with(top))
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(top)
+        .args(["status", "--porcelain", "-z", "--untracked-files=all", "--"])
+        .args(&paths)
+        .output()?;
+    if !out.status.success() {
+        return Err(Error::Status(
+            String::from_utf8_lossy(&out.stderr).trim().to_owned(),
+        ));
+    }
+    Ok(parse_status(&String::from_utf8_lossy(&out.stdout))
+        .into_iter()
+        .map(|p| top.join(p))
+        .collect())
+}
+
+/// Paths in `git status --porcelain -z` output
+fn parse_status(out: &str) -> Vec<&str> {
+    let mut paths = Vec::new();
+    let mut entries = out.split('\0').filter(|e| !e.is_empty());
+    while let Some(entry) = entries.next() {
+        let (status, path) = entry.split_at(3.min(entry.len()));
+        paths.push(path);
+        // renames and copies are followed by the path they came from
+        if status.starts_with(['R', 'C']) {
+            entries.next();
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_status, uncommitted, work_tree};
+    use dotloy::abspath;
+    use std::{path::Path, process::Command};
+
+    #[test]
+    fn status_lists_every_changed_path() {
+        assert_eq!(
+            parse_status(" M vimrc\0?? new file\0R  to\0from\0"),
+            ["vimrc", "new file", "to"]
+        );
+        assert!(parse_status("").is_empty());
+    }
+    #[test]
+    fn only_changes_to_the_given_paths_count() {
+        let dir = tempdir::TempDir::new("dotloy-git").unwrap();
+        let root = abspath::canonicalize(dir.path()).unwrap();
+        if !Command::new("git")
+            .args(["init", "-q"])
+            .current_dir(&root)
+            .status()
+            .is_ok_and(|s| s.success())
+        {
+            return;
+        }
+        assert_eq!(work_tree(&root).as_deref(), Some(root.as_path()));
+        std::fs::write(root.join("vimrc"), "").unwrap();
+        std::fs::write(root.join("notes.txt"), "").unwrap();
+        assert_eq!(
+            uncommitted(&root, &[&root.join("vimrc")]).unwrap(),
+            [root.join("vimrc")]
+        );
+        assert!(uncommitted(&root, &[&root.join("zshrc")])
+            .unwrap()
+            .is_empty());
+        assert!(uncommitted(&root, &[Path::new("/elsewhere")])
+            .unwrap()
+            .is_empty());
+    }
+}
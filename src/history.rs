@@ -0,0 +1,248 @@
+//! Records of past deploys, kept in one file per config
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use fs_err as fs;
+
+use crate::stable_hash;
+
+/// Records kept per config, older ones are dropped
+pub const MAX_RECORDS: usize = 100;
+
+/// What a deploy did to one of its outputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Created,
+    Updated,
+    /// Not written because what it needed failed
+    Skipped,
+    Failed,
+}
+
+impl Change {
+    pub fn name(self) -> &'static str {
+        match self {
+            Change::Created => "created",
+            Change::Updated => "updated",
+            Change::Skipped => "skipped",
+            Change::Failed => "failed",
+        }
+    }
+    fn parse(s: &str) -> Option<Self> {
+        [
+            Change::Created,
+            Change::Updated,
+            Change::Skipped,
+            Change::Failed,
+        ]
+        .into_iter()
+        .find(|c| c.name() == s)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub output: String,
+    pub change: Change,
+}
+
+/// One deploy of one config
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub id: String,
+    /// Local time the deploy finished
+    pub time: String,
+    pub dry: bool,
+    pub config: String,
+    pub entries: Vec<Entry>,
+}
+
+impl Record {
+    /// A record of deploying `config` just now, with a new id
+    pub fn now(config: &Path, dry: bool, entries: Vec<Entry>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().simple().to_string()[..8].to_owned(),
+            time: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            dry,
+            config: config.to_string_lossy().into_owned(),
+            entries,
+        }
+    }
+    pub fn count(&self, change: Change) -> usize {
+        self.entries.iter().filter(|e| e.change == change).count()
+    }
+    /// Counts of each change, e.g. "2 created, 1 updated, 0 skipped, 0 failed"
+    pub fn summary(&self) -> String {
+        [
+            Change::Created,
+            Change::Updated,
+            Change::Skipped,
+            Change::Failed,
+        ]
+        .map(|c| format!("{} {}", self.count(c), c.name()))
+        .join(", ")
+    }
+    /// The record as one tab separated line
+    fn to_line(&self) -> String {
+        let mut fields = vec![
+            self.id.clone(),
+            self.time.clone(),
+            if self.dry { "dry" } else { "run" }.to_owned(),
+            self.config.clone(),
+        ];
+        for entry in &self.entries {
+            fields.push(entry.change.name().to_owned());
+            fields.push(entry.output.clone());
+        }
+        fields
+            .iter()
+            .map(|f| escape(f))
+            .collect::<Vec<_>>()
+            .join("\t")
+    }
+    fn parse(line: &str) -> Option<Self> {
+        let fields = line.split('\t').map(unescape).collect::<Vec<_>>();
+        let [id, time, mode, config, entries @ ..] = fields.as_slice() else {
+            return None;
+        };
+        if entries.len() % 2 != 0 {
+            return None;
+        }
+        Some(Self {
+            id: id.clone(),
+            time: time.clone(),
+            dry: mode == "dry",
+            config: config.clone(),
+            entries: entries
+                .chunks_exact(2)
+                .map(|e| {
+                    Some(Entry {
+                        change: Change::parse(&e[0])?,
+                        output: e[1].clone(),
+                    })
+                })
+                .collect::<Option<_>>()?,
+        })
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', r"\\")
+        .replace('\t', r"\t")
+        .replace('\n', r"\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Where history goes, the state dir as it should outlive a reboot. Not
+/// beside the config, as the repository it's in would then have changes
+/// after every deploy
+pub fn default_dir() -> PathBuf {
+    directories::BaseDirs::new()
+        .map(|d| d.state_dir().unwrap_or(d.data_local_dir()).to_owned())
+        .unwrap_or_else(std::env::temp_dir)
+        .join("dotloy")
+        .join("history")
+}
+
+/// The file in `dir` with the history of `config`
+pub fn file_for(dir: &Path, config: &str) -> PathBuf {
+    dir.join(format!("{:016x}.log", stable_hash(config.as_bytes())))
+}
+
+/// Add `record` to the history of its config in `dir`, dropping the oldest
+/// records past [`MAX_RECORDS`]
+pub fn append(dir: &Path, record: &Record) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let file = file_for(dir, &record.config);
+    let existing = match fs::read_to_string(&file) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let mut lines = existing
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    lines.push(record.to_line());
+    let kept = &lines[lines.len().saturating_sub(MAX_RECORDS)..];
+    // written beside it then moved over it, so a crash never leaves half
+    let tmp = file.with_extension(format!("tmp-{}", std::process::id()));
+    fs::write(&tmp, kept.join("\n") + "\n")?;
+    fs::rename(&tmp, &file)
+}
+
+/// Every record in `dir`, oldest first. Lines that can't be read are skipped
+pub fn read(dir: &Path) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(records),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() != Some("log".as_ref()) {
+            continue;
+        }
+        records.extend(fs::read_to_string(&path)?.lines().filter_map(Record::parse));
+    }
+    records.sort_by(|a, b| a.time.cmp(&b.time));
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tempdir::TempDir;
+
+    use super::{append, read, Change, Entry, Record, MAX_RECORDS};
+
+    #[test]
+    fn records_survive_a_round_trip_and_are_capped() {
+        let dir = TempDir::new("history").unwrap();
+        let entries = vec![
+            Entry {
+                output: "/home/me/odd\tname".to_owned(),
+                change: Change::Created,
+            },
+            Entry {
+                output: r"C:\Users\me".to_owned(),
+                change: Change::Failed,
+            },
+        ];
+        let first = Record::now(Path::new("/dots/dotloy.yaml"), false, entries);
+        append(dir.path(), &first).unwrap();
+        let other = Record::now(Path::new("/other/dotloy.yaml"), true, Vec::new());
+        append(dir.path(), &other).unwrap();
+        let records = read(dir.path()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records.contains(&first) && records.contains(&other));
+        assert_eq!(first.summary(), "1 created, 0 updated, 0 skipped, 1 failed");
+
+        for _ in 0..MAX_RECORDS {
+            append(dir.path(), &other).unwrap();
+        }
+        assert_eq!(read(dir.path()).unwrap().len(), MAX_RECORDS + 1);
+    }
+}
@@ -0,0 +1,252 @@
+//! `.dotloyignore` files, which keep paths out of anything dotloy finds by
+//! walking directories. They use gitignore syntax and apply to the directory
+//! they're in and everything below it
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+
+use fs_err as fs;
+
+pub const FILE_NAME: &str = ".dotloyignore";
+
+/// Ignored without being listed
+const DEFAULT: [&str; 1] = [".git"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Rule {
+    /// Path segments to match, `**` matching any number of them
+    segments: Vec<String>,
+    /// Matched against the path relative to the file rather than any name
+    /// in it
+    anchored: bool,
+    dir_only: bool,
+    negated: bool,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let segments = line
+            .trim_start_matches('/')
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        (!segments.is_empty()).then_some(Self {
+            segments,
+            anchored,
+            dir_only,
+            negated,
+        })
+    }
+    /// Whether the rule matches `path`, relative to the file it's from
+    fn matches(&self, path: &[&str], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            segments_match(&self.segments, path)
+        } else {
+            // without a `/` there's only the one
+            path.last()
+                .is_some_and(|name| wildcard(&self.segments[0], name))
+        }
+    }
+}
+
+/// Whether the pattern segments match every segment of `path`
+fn segments_match(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skip| segments_match(rest, &path[skip..]))
+        }
+        Some((first, rest)) => path
+            .split_first()
+            .is_some_and(|(seg, path)| wildcard(first, seg) && segments_match(rest, path)),
+    }
+}
+
+/// Whether `name` matches `pattern`, with `*`, `?` and `[...]`
+fn wildcard(pattern: &str, name: &str) -> bool {
+    fn go(p: &[char], n: &[char]) -> bool {
+        match p.split_first() {
+            None => n.is_empty(),
+            Some(('*', rest)) => (0..=n.len()).any(|skip| go(rest, &n[skip..])),
+            Some(('?', rest)) => !n.is_empty() && go(rest, &n[1..]),
+            Some(('[', rest)) => match (rest.iter().position(|c| *c == ']'), n.first()) {
+                (Some(end), Some(c)) => {
+                    let (negated, set) = match rest[..end].split_first() {
+                        Some(('!' | '^', set)) => (true, set),
+                        _ => (false, &rest[..end]),
+                    };
+                    in_set(set, *c) != negated && go(&rest[end + 1..], &n[1..])
+                }
+                // an unclosed `[` is just a `[`
+                (None, Some('[')) => go(rest, &n[1..]),
+                _ => false,
+            },
+            Some(('\\', [c, rest @ ..])) => n.first() == Some(c) && go(rest, &n[1..]),
+            Some((c, rest)) => n.first() == Some(c) && go(rest, &n[1..]),
+        }
+    }
+    let p = pattern.chars().collect::<Vec<_>>();
+    let n = name.chars().collect::<Vec<_>>();
+    go(&p, &n)
+}
+
+/// Whether `c` is in the contents of a `[...]`, ranges like `a-z` included
+fn in_set(set: &[char], c: char) -> bool {
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            if (set[i]..=set[i + 2]).contains(&c) {
+                return true;
+            }
+            i += 3;
+        } else {
+            if set[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+/// The ignore files of a tree, read as they're needed
+#[derive(Debug)]
+pub struct Ignore {
+    root: PathBuf,
+    /// Rules of the ignore file in each directory, relative to `root`
+    files: RefCell<HashMap<PathBuf, Vec<Rule>>>,
+}
+
+impl Ignore {
+    /// Ignore files in `root` and the directories below it
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            files: RefCell::default(),
+        }
+    }
+    fn rules(&self, dir: &Path) -> Vec<Rule> {
+        self.files
+            .borrow_mut()
+            .entry(dir.to_owned())
+            .or_insert_with(|| {
+                let defaults = DEFAULT.iter().filter(|_| dir.as_os_str().is_empty());
+                let read = fs::read_to_string(self.root.join(dir).join(FILE_NAME));
+                defaults
+                    .map(|p| p.to_string())
+                    .chain(
+                        read.ok()
+                            .into_iter()
+                            .flat_map(|text| text.lines().map(str::to_owned).collect::<Vec<_>>()),
+                    )
+                    .filter_map(|line| Rule::parse(&line))
+                    .collect()
+            })
+            .clone()
+    }
+    /// Whether `path`, relative to the root or absolute within it, is
+    /// ignored, either itself or as it's in an ignored directory. Paths
+    /// outside the root never are
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let rel = match path.strip_prefix(&self.root) {
+            Ok(rel) => rel,
+            Err(_) if path.is_relative() => path,
+            Err(_) => return false,
+        };
+        if rel.components().any(|c| c == Component::ParentDir) {
+            return false;
+        }
+        let segments = rel
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(s) => Some(s.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+        (1..=segments.len()).any(|end| {
+            let is_dir = end < segments.len() || self.root.join(rel).is_dir();
+            self.ignores(&segments[..end], is_dir)
+        })
+    }
+    /// Whether the last segment of `path` is ignored by the rules of the
+    /// directories above it, without looking at those directories themselves
+    fn ignores(&self, path: &[&str], is_dir: bool) -> bool {
+        let mut ignored = false;
+        // files deeper down override those above them
+        for depth in 0..path.len() {
+            let dir = path[..depth].iter().collect::<PathBuf>();
+            for rule in self.rules(&dir) {
+                if rule.matches(&path[depth..], is_dir) {
+                    ignored = !rule.negated;
+                }
+            }
+        }
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use fs_err as fs;
+    use tempdir::TempDir;
+
+    use super::{wildcard, Ignore, FILE_NAME};
+
+    #[test]
+    fn wildcards_follow_gitignore() {
+        assert!(wildcard("*.swp", ".vimrc.swp"));
+        assert!(wildcard("?.txt", "a.txt") && !wildcard("?.txt", "ab.txt"));
+        assert!(wildcard("[a-c]x", "bx") && !wildcard("[!a-c]x", "bx"));
+        assert!(!wildcard("*.swp", "vimrc"));
+    }
+
+    #[test]
+    fn ignore_files_apply_below_them_and_deeper_ones_win() {
+        let dir = TempDir::new("ignore").unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("nvim/build")).unwrap();
+        fs::create_dir_all(root.join("kitty")).unwrap();
+        fs::write(
+            root.join(FILE_NAME),
+            "# editor droppings\n*~\n/build/\n**/cache\n*.log\n",
+        )
+        .unwrap();
+        fs::write(root.join("nvim").join(FILE_NAME), "build/\n!keep.log\n").unwrap();
+        let ignore = Ignore::new(root);
+        for (path, ignored) in [
+            (".git/config", true),
+            ("kitty/kitty.conf~", true),
+            ("kitty/kitty.conf", false),
+            ("nvim/build/out.lua", true),
+            ("nvim/a/b/cache", true),
+            ("kitty/x.log", true),
+            ("nvim/keep.log", false),
+        ] {
+            assert_eq!(ignore.is_ignored(Path::new(path)), ignored, "{path}");
+        }
+        assert!(ignore.is_ignored(&root.join("kitty/x.log")));
+        assert!(!ignore.is_ignored(Path::new("/elsewhere/x.log")));
+    }
+}
@@ -0,0 +1,225 @@
+//! chezmoi source directories, where attributes of each target are written
+//! into the names of its source files, e.g. `private_dot_ssh`
+use std::path::{Path, PathBuf};
+
+use fs_err as fs;
+
+use super::{slashed, Error};
+use dotloy::{
+    config::{Root, Target},
+    ignore::Ignore,
+};
+
+/// Name prefixes of sources dotloy has nothing like, with why
+const UNSUPPORTED: [(&str, &str); 6] = [
+    (
+        "encrypted_",
+        "encrypted files aren't supported, decrypt it first",
+    ),
+    ("run_", "scripts aren't supported"),
+    ("modify_", "scripts modifying a file aren't supported"),
+    ("remove_", "removing files isn't supported"),
+    (
+        "create_",
+        "files only created when they're missing aren't supported",
+    ),
+    (
+        "symlink_",
+        "symlinks to the file's content aren't supported",
+    ),
+];
+/// Name prefixes that are imported without what they do, with why
+const DROPPED: [(&str, &str); 4] = [
+    ("private_", "dotloy doesn't set permissions"),
+    ("readonly_", "dotloy doesn't set permissions"),
+    ("executable_", "dotloy doesn't set permissions"),
+    ("exact_", "dotloy doesn't remove files it doesn't deploy"),
+];
+/// Name prefixes that don't need anything from dotloy
+const HARMLESS: [&str; 1] = ["empty_"];
+
+/// A config made from a chezmoi source directory
+#[derive(Debug)]
+pub struct Imported {
+    pub config: Root,
+    /// What couldn't be imported or was imported without some attribute,
+    /// with why
+    pub not_imported: Vec<String>,
+}
+
+/// Attributes of a source and the name it's deployed as
+#[derive(Debug, PartialEq, Eq)]
+struct Name {
+    attrs: Vec<&'static str>,
+    dest: String,
+    template: bool,
+}
+
+fn parse_name(name: &str) -> Name {
+    let mut attrs = Vec::new();
+    let mut rest = name;
+    let prefixes = UNSUPPORTED
+        .iter()
+        .chain(&DROPPED)
+        .map(|(p, _)| *p)
+        .chain(HARMLESS)
+        .collect::<Vec<_>>();
+    // `literal_` stops the rest of the name being read as attributes
+    let literal = loop {
+        if let Some(r) = rest.strip_prefix("literal_") {
+            rest = r;
+            break true;
+        }
+        match prefixes.iter().find(|p| rest.starts_with(**p)) {
+            Some(p) => {
+                attrs.push(*p);
+                rest = &rest[p.len()..];
+            }
+            None => break false,
+        }
+    };
+    let dest = match rest.strip_prefix("dot_") {
+        Some(r) if !literal => format!(".{r}"),
+        _ => rest.to_owned(),
+    };
+    let (dest, template) = match dest.strip_suffix(".tmpl") {
+        Some(d) => (d.to_owned(), true),
+        None => (
+            dest.strip_suffix(".literal").unwrap_or(&dest).to_owned(),
+            false,
+        ),
+    };
+    Name {
+        attrs,
+        dest,
+        template,
+    }
+}
+
+/// Targets deploying everything in the chezmoi `source_dir` to where chezmoi
+/// would put it under `to`, with `from` relative to `source_dir`
+pub fn import(source_dir: &Path, to: &str) -> Result<Imported, Error> {
+    let mut out = Imported {
+        config: Root::default(),
+        not_imported: Vec::new(),
+    };
+    // `.chezmoiroot` moves the source state into a subdirectory
+    let root = match fs::read_to_string(source_dir.join(".chezmoiroot")) {
+        Ok(sub) => PathBuf::from(sub.trim()),
+        Err(_) => PathBuf::new(),
+    };
+    let ignore = Ignore::new(source_dir);
+    walk(source_dir, &root, Path::new(""), to, &ignore, &mut out)?;
+    Ok(out)
+}
+
+fn walk(
+    source_dir: &Path,
+    rel: &Path,
+    dest: &Path,
+    to: &str,
+    ignore: &Ignore,
+    out: &mut Imported,
+) -> Result<(), Error> {
+    let mut entries = fs::read_dir(source_dir.join(rel))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let src = rel.join(&name);
+        if name.starts_with(".chezmoi") {
+            out.not_imported.push(format!(
+                "'{}': chezmoi's own configuration and scripts aren't imported",
+                slashed(&src)
+            ));
+            continue;
+        }
+        // chezmoi ignores these too, e.g. `.git`
+        if name.starts_with('.') || ignore.is_ignored(&src) {
+            continue;
+        }
+        let parsed = parse_name(&name);
+        if let Some((_, why)) = UNSUPPORTED.iter().find(|(p, _)| parsed.attrs.contains(p)) {
+            out.not_imported.push(format!("'{}': {why}", slashed(&src)));
+            continue;
+        }
+        for (attr, why) in DROPPED.iter().filter(|(p, _)| parsed.attrs.contains(p)) {
+            out.not_imported.push(format!(
+                "'{}': imported without `{attr}`, {why}",
+                slashed(&src)
+            ));
+        }
+        let target = dest.join(&parsed.dest);
+        if entry.file_type()?.is_dir() {
+            walk(source_dir, &src, &target, to, ignore, out)?;
+            continue;
+        }
+        let mut t = Target::new(slashed(&src), format!("{to}/{}", slashed(&target)));
+        if parsed.template {
+            t.is_template = Some(true);
+        }
+        out.config.targets.push(t);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import, parse_name, Name};
+
+    #[test]
+    fn attributes_are_read_from_names() {
+        assert_eq!(
+            parse_name("private_readonly_dot_netrc.tmpl"),
+            Name {
+                attrs: vec!["private_", "readonly_"],
+                dest: ".netrc".to_owned(),
+                template: true,
+            }
+        );
+        assert_eq!(parse_name("literal_dot_x.tmpl.literal").dest, "dot_x.tmpl");
+        assert_eq!(parse_name("run_once_install.sh").attrs, ["run_"]);
+    }
+    #[test]
+    fn what_dotloy_cant_do_is_reported() {
+        let dir = tempdir::TempDir::new("dotloy-chezmoi").unwrap();
+        for f in [
+            "dot_vimrc",
+            "dot_gitconfig.tmpl",
+            "private_dot_ssh/config",
+            "dot_local/bin/executable_tool",
+            "encrypted_dot_secret.age",
+            "run_once_install.sh",
+            ".chezmoiignore",
+            ".git/HEAD",
+        ] {
+            let path = dir.path().join(f);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let imported = import(dir.path(), "~").unwrap();
+        assert_eq!(
+            imported
+                .config
+                .targets
+                .iter()
+                .map(|t| (t.path.source(), t.target_location.source(), t.is_template))
+                .collect::<Vec<_>>(),
+            [
+                ("dot_gitconfig.tmpl", "~/.gitconfig", Some(true)),
+                ("dot_local/bin/executable_tool", "~/.local/bin/tool", None),
+                ("dot_vimrc", "~/.vimrc", None),
+                ("private_dot_ssh/config", "~/.ssh/config", None),
+            ]
+        );
+        assert_eq!(
+            imported.not_imported,
+            [
+                "'.chezmoiignore': chezmoi's own configuration and scripts aren't imported",
+                "'dot_local/bin/executable_tool': imported without `executable_`, dotloy doesn't set permissions",
+                "'encrypted_dot_secret.age': encrypted files aren't supported, decrypt it first",
+                "'private_dot_ssh': imported without `private_`, dotloy doesn't set permissions",
+                "'run_once_install.sh': scripts aren't supported",
+            ]
+        );
+    }
+}
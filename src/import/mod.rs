@@ -0,0 +1,41 @@
+//! Turning dotfiles managed by other tools into a dotloy config
+use std::path::Path;
+
+use thiserror::Error;
+
+use dotloy::config::Root;
+
+pub mod chezmoi;
+pub mod stow;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("failed to write the config: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("no package '{0}' in the stow directory")]
+    UnknownPackage(String),
+    #[error("nothing to import, there are no packages in the stow directory")]
+    NoPackages,
+    #[error("packages conflict, dotloy can only deploy one of each:{}", indented(.0))]
+    Conflicts(Vec<String>),
+}
+
+fn indented(lines: &[String]) -> String {
+    lines.iter().map(|l| format!("\n  {l}")).collect()
+}
+
+/// `cfg` as the text of a config file
+pub fn to_yaml(cfg: &Root) -> Result<String, Error> {
+    Ok(serde_yaml::to_string(cfg)?)
+}
+
+/// `path` with `/` between its components whatever the platform, so that it
+/// reads the same in a config
+pub fn slashed(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,243 @@
+//! GNU stow layouts, where each package is a directory mirroring the
+//! directory it's stowed into
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use fs_err as fs;
+
+use super::{slashed, Error};
+use dotloy::{
+    config::{DeployType, LinkType, Root, Target},
+    ignore::{self, Ignore},
+};
+
+/// Files stow doesn't stow by default
+const IGNORED: [&str; 4] = [".git", ".gitignore", ".stow-local-ignore", ".DS_Store"];
+
+/// What is at a path under the stowed directory
+#[derive(Debug, Default)]
+struct Entry {
+    /// Packages that have something here
+    packages: BTreeSet<String>,
+    /// Whether any of them has a file rather than a directory here
+    file: bool,
+}
+
+/// Targets deploying `packages` from `stow_dir` (every package if it's
+/// empty) into `to`, with `from` relative to `stow_dir`. Directories only one
+/// package has anything in become a single link, like stow's tree folding,
+/// unless they exist as real directories in `existing`
+pub fn import(
+    stow_dir: &Path,
+    packages: &[String],
+    to: &str,
+    existing: Option<&Path>,
+) -> Result<Root, Error> {
+    let ignore = Ignore::new(stow_dir);
+    let packages = if packages.is_empty() {
+        let mut all = Vec::new();
+        for entry in fs::read_dir(stow_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_dir()
+                && !name.starts_with('.')
+                && !ignore.is_ignored(&entry.path())
+            {
+                all.push(name);
+            }
+        }
+        all.sort();
+        all
+    } else {
+        packages.to_owned()
+    };
+    if packages.is_empty() {
+        return Err(Error::NoPackages);
+    }
+    let mut tree = BTreeMap::<PathBuf, Entry>::new();
+    for pkg in &packages {
+        let root = stow_dir.join(pkg);
+        if !root.is_dir() {
+            return Err(Error::UnknownPackage(pkg.to_owned()));
+        }
+        walk(&root, Path::new(""), pkg, &ignore, &mut tree)?;
+    }
+    let conflicts = tree
+        .iter()
+        .filter(|(_, e)| e.file && e.packages.len() > 1)
+        .map(|(path, e)| {
+            format!(
+                "'{}' is in '{}'",
+                slashed(path),
+                e.packages.iter().cloned().collect::<Vec<_>>().join("', '")
+            )
+        })
+        .collect::<Vec<_>>();
+    if !conflicts.is_empty() {
+        return Err(Error::Conflicts(conflicts));
+    }
+
+    let mut cfg = Root::default();
+    let mut folded: Option<&Path> = None;
+    for (path, entry) in &tree {
+        if folded.is_some_and(|dir| path.starts_with(dir)) {
+            continue;
+        }
+        let owners = tree
+            .range(path.to_owned()..)
+            .take_while(|(p, _)| p.starts_with(path))
+            .flat_map(|(_, e)| &e.packages)
+            .collect::<BTreeSet<_>>();
+        if !entry.file {
+            // a link to a directory doesn't count, it's what folding makes
+            let real = existing
+                .is_some_and(|e| std::fs::symlink_metadata(e.join(path)).is_ok_and(|m| m.is_dir()));
+            if owners.len() > 1 || real {
+                continue;
+            }
+            folded = Some(path);
+        }
+        let pkg = owners
+            .into_iter()
+            .next()
+            .expect("every entry has a package");
+        let rel = slashed(path);
+        let mut target = Target::new(format!("{pkg}/{rel}"), format!("{to}/{rel}"));
+        target.link_type = DeployType::Link(LinkType::Soft);
+        cfg.targets.push(target);
+    }
+    Ok(cfg)
+}
+
+/// Add everything under `root.join(rel)` to `tree`, as belonging to `pkg`
+fn walk(
+    root: &Path,
+    rel: &Path,
+    pkg: &str,
+    ignore: &Ignore,
+    tree: &mut BTreeMap<PathBuf, Entry>,
+) -> Result<(), Error> {
+    for entry in fs::read_dir(root.join(rel))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let ignored = IGNORED.contains(&name.to_string_lossy().as_ref())
+            || name == ignore::FILE_NAME
+            || ignore.is_ignored(&entry.path());
+        if ignored {
+            continue;
+        }
+        let path = rel.join(&name);
+        let is_dir = entry.file_type()?.is_dir();
+        let e = tree.entry(path.clone()).or_default();
+        e.packages.insert(pkg.to_owned());
+        e.file |= !is_dir;
+        if is_dir {
+            walk(root, &path, pkg, ignore, tree)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::import;
+    use crate::import::Error;
+    use assert_matches::assert_matches;
+    use dotloy::config::{DeployType, LinkType};
+    use std::path::Path;
+
+    fn stow_dir(files: &[&str]) -> tempdir::TempDir {
+        let dir = tempdir::TempDir::new("dotloy-stow").unwrap();
+        for f in files {
+            let path = dir.path().join(f);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        dir
+    }
+    fn targets(dir: &Path, packages: &[&str], existing: Option<&Path>) -> Vec<(String, String)> {
+        let packages = packages.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let cfg = import(dir, &packages, "~", existing).unwrap();
+        assert!(cfg
+            .targets
+            .iter()
+            .all(|t| t.link_type == DeployType::Link(LinkType::Soft)));
+        cfg.targets
+            .iter()
+            .map(|t| {
+                (
+                    t.path.source().to_owned(),
+                    t.target_location.source().to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dotloyignore_keeps_files_out() {
+        let dir = stow_dir(&[
+            ".dotloyignore",
+            "vim/.vimrc",
+            "vim/.vimrc.swp",
+            "vim/.dotloyignore",
+            "vim/.netrwhist",
+            "scratch/notes",
+        ]);
+        std::fs::write(dir.path().join(".dotloyignore"), "*.swp\n/scratch/\n").unwrap();
+        std::fs::write(dir.path().join("vim/.dotloyignore"), ".netrwhist\n").unwrap();
+        assert_eq!(
+            targets(dir.path(), &[], None),
+            [("vim/.vimrc", "~/.vimrc")].map(|(a, b)| (a.to_owned(), b.to_owned()))
+        );
+    }
+
+    #[test]
+    fn directories_with_one_package_are_folded() {
+        let dir = stow_dir(&[
+            "vim/.vimrc",
+            "vim/.vim/colors/x.vim",
+            "vim/.vim/ftplugin/rust.vim",
+            "nvim/.config/nvim/init.lua",
+            "kitty/.config/kitty/kitty.conf",
+            "kitty/.git/HEAD",
+        ]);
+        assert_eq!(
+            targets(dir.path(), &[], None),
+            [
+                ("kitty/.config/kitty", "~/.config/kitty"),
+                ("nvim/.config/nvim", "~/.config/nvim"),
+                ("vim/.vim", "~/.vim"),
+                ("vim/.vimrc", "~/.vimrc"),
+            ]
+            .map(|(a, b)| (a.to_owned(), b.to_owned()))
+        );
+        assert_eq!(
+            targets(dir.path(), &["nvim"], None),
+            [("nvim/.config", "~/.config")].map(|(a, b)| (a.to_owned(), b.to_owned()))
+        );
+    }
+    #[test]
+    fn existing_directories_are_not_folded() {
+        let dir = stow_dir(&["nvim/.config/nvim/init.lua"]);
+        let home = tempdir::TempDir::new("dotloy-stow-home").unwrap();
+        std::fs::create_dir(home.path().join(".config")).unwrap();
+        assert_eq!(
+            targets(dir.path(), &[], Some(home.path())),
+            [("nvim/.config/nvim", "~/.config/nvim")].map(|(a, b)| (a.to_owned(), b.to_owned()))
+        );
+    }
+    #[test]
+    fn packages_with_the_same_file_conflict() {
+        let dir = stow_dir(&["vim/.vimrc", "vim-minimal/.vimrc", "zsh/.zshrc"]);
+        assert_matches!(
+            import(dir.path(), &[], "~", None),
+            Err(Error::Conflicts(c)) if c == ["'.vimrc' is in 'vim', 'vim-minimal'"]
+        );
+        assert_matches!(
+            import(dir.path(), &["bash".to_owned()], "~", None),
+            Err(Error::UnknownPackage(p)) if p == "bash"
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! Starter configs, as written by `dotloy init`
+use std::path::Path;
+
+use dotloy::config::{Root, Target};
+
+use crate::import;
+
+/// Written above the targets
+const HEADER: &str = "\
+# dotloy config. Paths in `from` are relative to this file, and `to` can use
+# variables like {{ xdg.config }} and {{ xdg.home }}
+";
+
+/// Written below the targets, an example of the fields most configs use
+const EXAMPLE: &str = "
+# Variables defined here are available to templates as `config.<name>`:
+#
+# variables:
+#   editor: nvim
+#
+# Some example targets:
+#
+# targets:
+#   - from: nvim
+#     to: \"{{ xdg.config }}/nvim\"
+#     # soft or hard link, or copy. Files are hard linked and directories
+#     # soft linked by default
+#     link_type: soft
+#   - from: gitconfig.in
+#     to: \"{{ xdg.home }}/.gitconfig\"
+#     # expanded as a template, the default for files ending in `.in`
+#     template: true
+#     # only deployed on these platforms
+#     runs_on: [linux, macos]
+#     # available to the template as `target.<name>`
+#     variables:
+#       email: me@example.com
+";
+
+/// Where `from` is deployed when `dotloy init` isn't told: dotfiles like
+/// `.bashrc` in the home directory and anything else in the config
+/// directory, without the `.in` of a template
+fn default_to(from: &str) -> String {
+    let name = Path::new(from)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| from.to_owned());
+    let name = name.strip_suffix(".in").unwrap_or(&name);
+    if name.starts_with('.') {
+        format!("{{{{ xdg.home }}}}/{name}")
+    } else {
+        format!("{{{{ xdg.config }}}}/{name}")
+    }
+}
+
+/// Text of a starter config deploying each `from` to its `to`, or where
+/// [`default_to`] puts it if it has none, followed by a commented example
+pub fn starter(targets: &[(String, Option<String>)]) -> Result<String, import::Error> {
+    let cfg = Root {
+        targets: targets
+            .iter()
+            .map(|(from, to)| {
+                let to = to.clone().unwrap_or_else(|| default_to(from));
+                Target::new(from.clone(), to)
+            })
+            .collect(),
+        ..Default::default()
+    };
+    Ok(format!("{HEADER}{}{EXAMPLE}", import::to_yaml(&cfg)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_to, starter};
+    use dotloy::config::Root;
+
+    #[test]
+    fn starters_parse_and_guess_where_things_go() {
+        assert_eq!(default_to(".bashrc"), "{{ xdg.home }}/.bashrc");
+        assert_eq!(default_to("shell/.zshrc.in"), "{{ xdg.home }}/.zshrc");
+        assert_eq!(default_to("nvim/"), "{{ xdg.config }}/nvim");
+
+        let text = starter(&[
+            ("nvim".to_owned(), None),
+            (
+                "bin".to_owned(),
+                Some("{{ xdg.home }}/.local/bin".to_owned()),
+            ),
+        ])
+        .unwrap();
+        let cfg: Root = serde_yaml::from_str(&text).unwrap();
+        let targets = cfg
+            .targets
+            .iter()
+            .map(|t| (t.path.source(), t.target_location.source()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            targets,
+            [
+                ("nvim", "{{ xdg.config }}/nvim"),
+                ("bin", "{{ xdg.home }}/.local/bin")
+            ]
+        );
+        let empty: Root = serde_yaml::from_str(&starter(&[]).unwrap()).unwrap();
+        assert!(empty.targets.is_empty());
+    }
+}
@@ -0,0 +1,693 @@
+//! Deploy dotfiles from a `dotloy.yaml` config. This is the core the
+//! `dotloy` binary is a command line over
+//!
+//! A deploy reads a [`config::Root`], turns it into a plan of [`Actions`]
+//! rendered against [`default_parse_context`] and then runs it
+//!
+//! ```no_run
+//! use dotloy::{actions::Actions, config};
+//!
+//! let (file, cfg) = config::read("dotfiles".as_ref())?.expect("no config in dotfiles/");
+//! let mut ctx = dotloy::default_parse_context();
+//! dotloy::define_config_location(&mut ctx, &file);
+//! let mut plan = Actions::from_config_in(&cfg, file.parent().unwrap(), &ctx)?;
+//! for action in plan.iter() {
+//!     println!("{:?}: {}", action.kind(), action);
+//! }
+//! plan.run_with(false, |action, outcome| println!("{action}: {outcome:?}"))?;
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+#![deny(unused_must_use)]
+// what only the binary uses is behind `cli`
+#![cfg_attr(not(feature = "cli"), deny(unused_crate_dependencies))]
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use config::{Platform, Root, VariableValue};
+use itertools::Itertools;
+use template::{Context, Object, Value, Variable};
+
+pub use actions::Actions;
+use vars::Layer;
+
+pub mod abspath;
+pub mod actions;
+mod archive;
+mod block;
+pub mod config;
+mod diff;
+pub mod display;
+mod history;
+pub mod ignore;
+mod merge;
+pub mod plan;
+mod remote;
+pub mod resources;
+mod script;
+mod sha256;
+mod suggest;
+pub mod sys;
+pub mod template;
+pub mod timings;
+
+/// Namespaces variables defined by configs go under
+pub mod vars {
+    use crate::template::Variable;
+
+    pub fn target_level() -> Variable {
+        Variable::single("target")
+    }
+    pub fn config_level() -> Variable {
+        Variable::single("config")
+    }
+
+    /// Where a variable was defined. Each layer takes precedence over the
+    /// ones before it, whatever order they are defined in
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum Layer {
+        /// `xdg`, `config.dir` and the rest dotloy defines
+        Builtin,
+        /// `variables` at the top level of a config
+        Config,
+        /// `variables` of a target
+        Target,
+        /// Given when running dotloy, like `--var-file`
+        CommandLine,
+    }
+
+    impl std::fmt::Display for Layer {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(match self {
+                Layer::Builtin => "built-in",
+                Layer::Config => "config",
+                Layer::Target => "target",
+                Layer::CommandLine => "command line",
+            })
+        }
+    }
+}
+
+/// Templates can only hold text, so a path that isn't UTF-8 is unavailable
+/// rather than mangled into one that doesn't exist
+pub(crate) fn path_value(p: &Path) -> Value {
+    match p.to_str() {
+        Some(s) => Value::String(s.to_owned()),
+        None => Value::Unavailable(format!("'{}' is not valid UTF-8", p.to_string_lossy())),
+    }
+}
+
+fn optional_path(p: Option<&Path>, reason: &str) -> Value {
+    p.map(path_value)
+        .unwrap_or_else(|| Value::Unavailable(reason.to_owned()))
+}
+
+/// The `xdg` namespace, the platform's standard directories
+pub fn xdg_context() -> Context {
+    let dirs = directories::BaseDirs::new().expect("failed to get dirs on system");
+
+    let xdg = Object::new()
+        .with_property("home", path_value(dirs.home_dir()))
+        .with_property("config", path_value(dirs.config_dir()))
+        .with_property(
+            "local",
+            Object::new().with_property("config", path_value(dirs.config_local_dir())),
+        )
+        .with_property("cache", path_value(dirs.cache_dir()))
+        .with_property("data", path_value(dirs.data_dir()))
+        .with_property(
+            "state",
+            optional_path(dirs.state_dir(), "this platform has no state directory"),
+        )
+        .with_property(
+            "runtime",
+            optional_path(
+                dirs.runtime_dir(),
+                "no runtime directory, on linux it is only available when $XDG_RUNTIME_DIR is set",
+            ),
+        )
+        .with_property(
+            "exec",
+            optional_path(dirs.executable_dir(), XDG_EXEC_UNAVAILABLE),
+        );
+    Context::new().with_define(Variable::single("xdg"), xdg)
+}
+
+/// Windows known folders, only available on windows
+pub fn win_context() -> Context {
+    const PROPERTIES: [&str; 4] = ["appdata", "localappdata", "documents", "profile"];
+    let win = if cfg!(windows) {
+        let dirs = directories::BaseDirs::new().expect("failed to get dirs on system");
+        let user = directories::UserDirs::new();
+        Object::new()
+            .with_property("appdata", path_value(dirs.data_dir()))
+            .with_property("localappdata", path_value(dirs.data_local_dir()))
+            .with_property(
+                "documents",
+                optional_path(
+                    user.as_ref().and_then(|u| u.document_dir()),
+                    "failed to find the documents folder",
+                ),
+            )
+            .with_property("profile", path_value(dirs.home_dir()))
+    } else {
+        PROPERTIES
+            .into_iter()
+            .map(|p| {
+                (
+                    p.to_owned(),
+                    Value::Unavailable("only available on windows".to_owned()),
+                )
+            })
+            .collect()
+    };
+    Context::new().with_define(Variable::single("win"), win)
+}
+
+const XDG_EXEC_UNAVAILABLE: &str = "this platform has no executable directory, \
+consider setting `xdg_exec_fallback` in the config (e.g. to \"{{ xdg.home }}/.local/bin\")";
+
+/// Define `xdg.exec` using the config's fallback when the platform doesn't
+/// provide one, and use the linux layout on macOS if the config asks for it
+pub fn apply_xdg_fallbacks(on: &mut Context, cfg: &Root) -> Result<(), template::Error> {
+    if cfg.xdg_on_macos {
+        use_xdg_layout_on_macos(on);
+    }
+    let Some(fallback) = &cfg.xdg_exec_fallback else {
+        return Ok(());
+    };
+    let exec = Variable::single("xdg").join(Variable::single("exec"));
+    if matches!(on.lookup(&exec), Some(Value::Unavailable(_))) {
+        let dir = fallback.render(on)?;
+        on.define(exec, dir);
+    }
+    Ok(())
+}
+
+/// On macOS, point `xdg.config`, `xdg.data`, `xdg.cache` and `xdg.state`
+/// at `~/.config` and so on, where most command line programs look for them
+/// there too, rather than under `~/Library`. Does nothing elsewhere
+pub fn use_xdg_layout_on_macos(on: &mut Context) {
+    if cfg!(target_os = "macos") {
+        let dirs = directories::BaseDirs::new().expect("failed to get dirs on system");
+        define_xdg_layout(on, dirs.home_dir(), |var| std::env::var_os(var));
+    }
+}
+
+/// Define the directories of the XDG base directory spec as linux has them,
+/// below `home` unless `env` sets them to an absolute path. Only for macOS,
+/// where the directories crate has no way to ask for this layout
+fn define_xdg_layout(on: &mut Context, home: &Path, env: impl Fn(&str) -> Option<OsString>) {
+    const LAYOUT: [(&str, &str, &str); 4] = [
+        ("config", "XDG_CONFIG_HOME", ".config"),
+        ("data", "XDG_DATA_HOME", ".local/share"),
+        ("cache", "XDG_CACHE_HOME", ".cache"),
+        ("state", "XDG_STATE_HOME", ".local/state"),
+    ];
+    for (name, var, default) in LAYOUT {
+        let dir = env(var)
+            .map(PathBuf::from)
+            .filter(|d| d.is_absolute())
+            .unwrap_or_else(|| home.join(default));
+        on.define(
+            Variable::single("xdg").join(Variable::single(name)),
+            path_value(&dir),
+        );
+    }
+}
+
+/// The `sys` namespace, facts about the machine we're running on
+pub fn sys_context() -> Context {
+    let available = |r: Result<String, &str>| match r {
+        Ok(v) => Value::String(v),
+        Err(reason) => Value::Unavailable(reason.to_owned()),
+    };
+    let sys = Object::new()
+        .with_property("hostname", available(sys::hostname()))
+        .with_property("fqdn", available(sys::fqdn()))
+        .with_property("user", available(sys::user()))
+        .with_property(
+            "os",
+            available(
+                Platform::current()
+                    .map(|p| p.name().to_owned())
+                    .ok_or("dotloy doesn't support this platform"),
+            ),
+        )
+        .with_property("arch", std::env::consts::ARCH)
+        .with_property("wsl", sys::is_wsl().to_string())
+        .with_property(
+            "session",
+            available(sys::session().map(|s| s.name().to_owned())),
+        );
+    Context::new().with_define(Variable::single("sys"), sys)
+}
+
+/// Every built-in namespace that doesn't depend on the config, which is what
+/// configs are rendered against before their own variables are defined
+///
+/// ```
+/// let ctx = dotloy::default_parse_context();
+/// assert_eq!(ctx.render("{{ sys.arch }}")?, std::env::consts::ARCH);
+/// # Ok::<_, dotloy::template::Error>(())
+/// ```
+pub fn default_parse_context() -> Context {
+    let mut ctx = Context::new();
+    ctx.define(
+        Variable::single("cwd"),
+        path_value(&std::env::current_dir().unwrap()),
+    );
+    ctx.append(xdg_context());
+    ctx.append(win_context());
+    ctx.append(sys_context());
+    ctx
+}
+
+/// Roots of the variables that are always available to templates
+const BUILTIN_NAMESPACES: [&str; 7] = ["cwd", "xdg", "win", "sys", "now", "config", "target"];
+
+/// Render and define `vars` of `layer` under `namespace`. Variables may refer
+/// to each other regardless of the order they are given in. Ones already
+/// defined by a later [`Layer`] are left as they are, and those shadowing
+/// an earlier one are warned about unless `allow_shadowing`. Errors in one
+/// variable are [`template::Error::InVariable`]
+pub fn define_variables<'a>(
+    on: &mut Context,
+    namespace: &Variable,
+    layer: Layer,
+    vars: impl Iterator<Item = (&'a String, &'a VariableValue)>,
+    allow_shadowing: bool,
+) -> Result<(), template::Error> {
+    let mut defs = Vec::new();
+    for (name, val) in vars {
+        let var = Variable::definable(name)
+            .map_err(|_| template::parse::Error::InvalidVariable(format!("{namespace}.{name}")))?;
+        let root = var.segments().next().unwrap_or_default();
+        if !allow_shadowing && BUILTIN_NAMESPACES.contains(&root) {
+            log::warn!(
+                "variable '{namespace}.{name}' is named after the built-in '{root}' namespace, \
+                 which is easy to mix up. Set `allow_shadowing: true` in the config to silence this"
+            );
+        }
+        defs.push((namespace.clone().join(var), val));
+    }
+    defs.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut deps = Vec::with_capacity(defs.len());
+    let in_variable = |var: &Variable| {
+        let var = var.clone();
+        move |source| template::Error::InVariable {
+            var,
+            source: Box::new(source),
+        }
+    };
+    for (var, val) in &defs {
+        let refs = val.references().map_err(in_variable(var))?;
+        deps.push(
+            defs.iter()
+                .positions(|(var, _)| {
+                    refs.iter()
+                        .any(|r| r.starts_with(var) || var.starts_with(r))
+                })
+                .collect_vec(),
+        );
+    }
+    let names = defs.iter().map(|(var, _)| var).collect_vec();
+    let mut order = Vec::with_capacity(defs.len());
+    let mut visiting = Vec::new();
+    for i in 0..defs.len() {
+        definition_order(i, &names, &deps, &mut visiting, &mut order)?;
+    }
+    for i in order {
+        let (var, val) = &defs[i];
+        match on.layer_of(var) {
+            Some(above) if above > layer => {
+                log::debug!("variable '{var}' from the {layer} is overridden by the {above}");
+                continue;
+            }
+            Some(below) if below < layer && !allow_shadowing => {
+                warn_shadowed(&var.to_string(), layer, below)
+            }
+            _ => {}
+        }
+        let value = val.render(on).map_err(in_variable(var))?;
+        on.define_in(layer, var.clone(), value);
+    }
+    Ok(())
+}
+
+/// Merge `overrides` from the command line into the config variables
+/// `vars`, before they are defined so the config's own variables see them.
+/// Those replacing a variable of the config are warned about unless
+/// `allow_shadowing`
+pub fn override_variables(
+    vars: &mut HashMap<String, VariableValue>,
+    overrides: HashMap<String, VariableValue>,
+    allow_shadowing: bool,
+) {
+    /// Names of what `value` replaces in `existing`, which is called `name`.
+    /// Maps are merged so only their keys in both are
+    fn shadowed(
+        existing: Option<&VariableValue>,
+        value: &VariableValue,
+        name: String,
+        out: &mut Vec<String>,
+    ) {
+        match (existing, value) {
+            (Some(VariableValue::Map(existing)), VariableValue::Map(value)) => {
+                for (key, value) in value {
+                    shadowed(existing.get(key), value, format!("{name}.{key}"), out);
+                }
+            }
+            (Some(_), _) => out.push(name),
+            (None, _) => {}
+        }
+    }
+    if !allow_shadowing {
+        let mut names = Vec::new();
+        for (name, value) in &overrides {
+            shadowed(vars.get(name), value, name.clone(), &mut names);
+        }
+        for name in names.into_iter().sorted() {
+            let var = format!("{}.{name}", vars::config_level());
+            warn_shadowed(&var, Layer::CommandLine, Layer::Config);
+        }
+    }
+    config::merge_variables(vars, overrides);
+}
+
+fn warn_shadowed(var: &str, layer: Layer, below: Layer) {
+    log::warn!(
+        "variable '{var}' from the {layer} shadows the one from the {below}. \
+         Set `allow_shadowing: true` in the config to silence this"
+    );
+}
+
+/// Add `def` to `order` after everything it depends on, failing if it
+/// depends on itself
+fn definition_order(
+    def: usize,
+    names: &[&Variable],
+    deps: &[Vec<usize>],
+    visiting: &mut Vec<usize>,
+    order: &mut Vec<usize>,
+) -> Result<(), template::Error> {
+    if order.contains(&def) {
+        return Ok(());
+    }
+    if let Some(start) = visiting.iter().position(|v| *v == def) {
+        return Err(template::Error::VariableCycle(
+            visiting[start..]
+                .iter()
+                .chain([&def])
+                .map(|&i| names[i])
+                .join(" -> "),
+        ));
+    }
+    visiting.push(def);
+    for &dep in &deps[def] {
+        definition_order(dep, names, deps, visiting, order)?;
+    }
+    visiting.pop();
+    order.push(def);
+    Ok(())
+}
+
+/// Define `config.file` and `config.dir` for the config read from `file`
+pub fn define_config_location(on: &mut Context, file: &Path) {
+    let config = vars::config_level();
+    if let Some(dir) = file.parent() {
+        on.define(
+            config.clone().join(Variable::single("dir")),
+            path_value(dir),
+        );
+    }
+    on.define(config.join(Variable::single("file")), path_value(file));
+}
+
+/// [`define_config_location`] for a config read from stdin, which has no
+/// file. Its directory is `dir`, wherever it says its sources are
+pub fn define_stdin_config_location(on: &mut Context, dir: &Path) {
+    let config = vars::config_level();
+    on.define(
+        config.clone().join(Variable::single("dir")),
+        path_value(dir),
+    );
+    on.define(
+        config.join(Variable::single("file")),
+        Value::Unavailable("the config was read from stdin".to_owned()),
+    );
+}
+
+/// FNV-1a of `bytes`, which unlike std's hashers is the same across releases
+/// so it can be used in things that outlive a run
+pub(crate) fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+fn test_data_path() -> &'static std::path::Path {
+    "./test_data".as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use std::path::Path;
+
+    use std::collections::HashMap;
+
+    use crate::{
+        apply_xdg_fallbacks,
+        config::{Root, VariableValue},
+        define_config_location, define_stdin_config_location, define_variables, define_xdg_layout,
+        override_variables, path_value,
+        template::{self, Templated},
+        vars::{self, Layer},
+        win_context, xdg_context, Context, Variable,
+    };
+
+    fn value(v: &str) -> VariableValue {
+        VariableValue::Single(Templated::new(v.to_owned()))
+    }
+
+    #[test]
+    fn later_layers_take_precedence_whatever_order_they_are_defined_in() {
+        let layers = [Layer::Config, Layer::Target, Layer::CommandLine];
+        for (i, &low) in layers.iter().enumerate() {
+            for &high in &layers[i + 1..] {
+                for order in [[low, high], [high, low]] {
+                    let mut ctx = Context::new();
+                    ctx.define("config.a".parse().unwrap(), "built-in");
+                    assert_eq!(
+                        ctx.layer_of(&"config.a".parse().unwrap()),
+                        Some(Layer::Builtin)
+                    );
+                    for layer in order {
+                        let vars = HashMap::from([("a".to_owned(), value(&layer.to_string()))]);
+                        define_variables(
+                            &mut ctx,
+                            &vars::config_level(),
+                            layer,
+                            vars.iter(),
+                            false,
+                        )
+                        .unwrap();
+                    }
+                    assert_eq!(
+                        ctx.render("{{ config.a }}").unwrap(),
+                        high.to_string(),
+                        "{order:?}"
+                    );
+                    assert_eq!(ctx.layer_of(&"config.a".parse().unwrap()), Some(high));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn command_line_variables_override_the_config_before_it_is_defined() {
+        let mut vars = HashMap::from([
+            ("a".to_owned(), value("config")),
+            ("b".to_owned(), value("{{ config.a }}")),
+            (
+                "m".to_owned(),
+                VariableValue::Map(
+                    [("x", "1"), ("y", "2")]
+                        .map(|(k, v)| (k.to_owned(), value(v)))
+                        .into(),
+                ),
+            ),
+        ]);
+        let overrides = HashMap::from([
+            ("a".to_owned(), value("command line")),
+            (
+                "m".to_owned(),
+                VariableValue::Map([("x".to_owned(), value("3"))].into()),
+            ),
+        ]);
+        override_variables(&mut vars, overrides, false);
+        let mut ctx = Context::new();
+        define_variables(
+            &mut ctx,
+            &vars::config_level(),
+            Layer::Config,
+            vars.iter(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            ctx.render("{{ config.a }}, {{ config.b }}, {{ config.m.x }}{{ config.m.y }}")
+                .unwrap(),
+            "command line, command line, 32"
+        );
+    }
+
+    /// Set in the environment of a test run again by [`rerun_with_env`]
+    const RERUN: &str = "DOTLOY_TEST_RERUN";
+
+    /// Run the test `name` again in a child process, with `set` set and
+    /// `unset` removed from its environment, as the directories crate only
+    /// reads the real one. Returns whether this is that child, which should
+    /// go on to check the environment had the effect it should
+    fn rerun_with_env(name: &str, set: &[(&str, &str)], unset: &[&str]) -> bool {
+        if std::env::var_os(RERUN).is_some() {
+            return true;
+        }
+        let mut cmd = std::process::Command::new(std::env::current_exe().unwrap());
+        cmd.args([name, "--exact", "--nocapture"]).env(RERUN, "1");
+        for (var, val) in set {
+            cmd.env(var, val);
+        }
+        for var in unset {
+            cmd.env_remove(var);
+        }
+        let out = cmd.output().unwrap();
+        assert!(
+            out.status.success(),
+            "{name} failed in a child process:\n{}{}",
+            String::from_utf8_lossy(&out.stdout),
+            String::from_utf8_lossy(&out.stderr)
+        );
+        false
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn xdg_dirs_follow_the_environment() {
+        let env = [
+            ("XDG_CACHE_HOME", "/xdg/cache"),
+            ("XDG_DATA_HOME", "/xdg/data"),
+            ("XDG_STATE_HOME", "/xdg/state"),
+            ("XDG_RUNTIME_DIR", "/xdg/runtime"),
+        ];
+        if rerun_with_env("tests::xdg_dirs_follow_the_environment", &env, &[]) {
+            assert_eq!(
+                xdg_context()
+                    .render("{{ xdg.cache }} {{ xdg.data }} {{ xdg.state }} {{ xdg.runtime }}")
+                    .unwrap(),
+                "/xdg/cache /xdg/data /xdg/state /xdg/runtime"
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn xdg_runtime_is_unavailable_without_its_variable() {
+        let name = "tests::xdg_runtime_is_unavailable_without_its_variable";
+        if rerun_with_env(name, &[], &["XDG_RUNTIME_DIR"]) {
+            assert_matches!(
+                xdg_context().render("{{ xdg.runtime }}"),
+                Err(template::Error::Unavailable { var, .. }) if var.to_string() == "xdg.runtime"
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn xdg_layout_is_only_forced_when_asked_for() {
+        let dirs = directories::BaseDirs::new().unwrap();
+        let mut ctx = xdg_context();
+        apply_xdg_fallbacks(&mut ctx, &Root::default()).unwrap();
+        assert_eq!(
+            ctx.render("{{ xdg.config }}").unwrap(),
+            dirs.config_dir().to_string_lossy()
+        );
+
+        let env = |var: &str| match var {
+            "XDG_CONFIG_HOME" => Some("/xdg/config".into()),
+            // relative paths are ignored, as the spec says
+            "XDG_CACHE_HOME" => Some("cache".into()),
+            _ => None,
+        };
+        define_xdg_layout(&mut ctx, Path::new("/home/u"), env);
+        assert_eq!(
+            ctx.render("{{ xdg.config }} {{ xdg.data }} {{ xdg.cache }} {{ xdg.state }}")
+                .unwrap(),
+            "/xdg/config /home/u/.local/share /home/u/.cache /home/u/.local/state"
+        );
+    }
+
+    #[test]
+    fn each_config_sees_its_own_location() {
+        let base = xdg_context();
+        let render = |file: &str| {
+            let mut ctx = base.clone();
+            define_config_location(&mut ctx, Path::new(file));
+            ctx.render("{{ config.dir }} {{ config.file }}").unwrap()
+        };
+        assert_eq!(render("/a/dotloy.yaml"), "/a /a/dotloy.yaml");
+        assert_eq!(render("/b/c/.dotloy.yaml"), "/b/c /b/c/.dotloy.yaml");
+
+        let mut ctx = base.clone();
+        define_stdin_config_location(&mut ctx, Path::new("/src"));
+        assert_eq!(ctx.render("{{ config.dir }}").unwrap(), "/src");
+        assert_matches!(
+            ctx.render("{{ config.file }}"),
+            Err(template::Error::Unavailable { .. })
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn non_utf8_paths_are_unavailable_instead_of_mangled() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+        let ctx = Context::new()
+            .with_define(
+                Variable::single("bad"),
+                path_value(Path::new(OsStr::from_bytes(b"/h/\xff"))),
+            )
+            .with_define(Variable::single("good"), path_value(Path::new("/h/ok")));
+        assert_eq!(ctx.render("{{ good }}").unwrap(), "/h/ok");
+        assert_matches!(
+            ctx.render("{{ bad }}"),
+            Err(template::Error::Unavailable { reason, .. }) if reason == "'/h/\u{FFFD}' is not valid UTF-8"
+        );
+    }
+
+    #[test]
+    fn win_dirs_are_only_available_on_windows() {
+        let ctx = win_context();
+        #[cfg(windows)]
+        {
+            let dirs = directories::BaseDirs::new().unwrap();
+            assert_eq!(
+                ctx.render("{{ win.appdata }}").unwrap(),
+                dirs.data_dir().to_string_lossy()
+            );
+            assert_eq!(
+                ctx.render("{{ win.profile }}").unwrap(),
+                dirs.home_dir().to_string_lossy()
+            );
+        }
+        #[cfg(not(windows))]
+        assert_matches!(
+            ctx.render("{{ win.localappdata }}"),
+            Err(template::Error::Unavailable { reason, .. }) if reason == "only available on windows"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! Things in a config that are allowed but probably mistakes, for `dotloy lint`
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use fs_err as fs;
+use itertools::Itertools;
+
+use dotloy::{
+    config::{Platform, Root, Target, VariableValue},
+    default_parse_context, define_config_location, define_variables,
+    template::{self, Context, Templated, Variable},
+    vars::{self, Layer},
+};
+
+/// Platforms dotloy runs on, which `runs_on` should match at least one of
+const PLATFORMS: [Platform; 6] = [
+    Platform::Linux,
+    Platform::MacOs,
+    Platform::Windows,
+    Platform::FreeBsd,
+    Platform::OpenBsd,
+    Platform::NetBsd,
+];
+
+/// Something in a config that is probably a mistake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub config: PathBuf,
+    /// Label of the target it's in, `None` for the top level of the config
+    pub target: Option<String>,
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: ", self.config.to_string_lossy())?;
+        if let Some(target) = &self.target {
+            write!(f, "in target '{target}', ")?;
+        }
+        write!(f, "field '{}': {}", self.field, self.message)
+    }
+}
+
+/// Every warning about `cfg`, read from `file` with relative paths relative
+/// to `dir`
+pub fn lint(cfg: &Root, file: &Path, dir: &Path) -> Vec<Warning> {
+    let warn = |target: Option<&Target>, field, message| Warning {
+        config: file.to_owned(),
+        target: target.map(|t| t.label().to_owned()),
+        field,
+        message,
+    };
+    let mut engine = default_parse_context();
+    define_config_location(&mut engine, file);
+    if let Some(partials) = &cfg.partials {
+        engine.set_partials_dir(dir.join(partials));
+    }
+    let _ = define_variables(
+        &mut engine,
+        &vars::config_level(),
+        Layer::Config,
+        cfg.shared.variables.iter(),
+        true,
+    );
+    let engine = Arc::new(engine);
+
+    let mut warnings = Vec::new();
+    if !PLATFORMS
+        .iter()
+        .any(|p| cfg.shared.is_platform_supported(*p))
+    {
+        warnings.push(warn(
+            None,
+            "runs_on",
+            "never matches a platform dotloy runs on, so nothing is deployed".to_owned(),
+        ));
+    }
+    let mut config_refs = variable_refs(cfg.shared.variables.values());
+    config_refs.extend(templated_refs(cfg.xdg_exec_fallback.iter()));
+    for target in &cfg.targets {
+        let mut engine = engine.scope();
+        let _ = define_variables(
+            &mut engine,
+            &vars::target_level(),
+            Layer::Target,
+            target.shared.variables.iter(),
+            true,
+        );
+        let mut refs = variable_refs(target.shared.variables.values());
+        refs.extend(variable_refs(target.foreach.iter().flatten()));
+        refs.extend(templated_refs(
+            [
+                Some(&target.path),
+                Some(&target.target_location),
+                target.from_command.as_ref(),
+                target.if_path_exists.as_ref(),
+                target.if_path_missing.as_ref(),
+                target.verify_command.as_ref(),
+            ]
+            .into_iter()
+            .flatten(),
+        ));
+        match source(target, &engine, dir) {
+            Some(Ok(src)) => refs.extend(template_refs(target, &src, &engine)),
+            Some(Err(src)) => warnings.push(warn(
+                Some(target),
+                "from",
+                format!("'{}' doesn't exist", src.to_string_lossy()),
+            )),
+            None => {}
+        }
+        for name in unused(&target.shared.variables, &vars::target_level(), &refs) {
+            warnings.push(warn(
+                Some(target),
+                "variables",
+                format!("variable '{name}' is never used"),
+            ));
+        }
+        if !runs_anywhere(cfg, target) {
+            warnings.push(warn(
+                Some(target),
+                "runs_on",
+                "never matches a platform the config runs on, so it's never deployed".to_owned(),
+            ));
+        }
+        config_refs.extend(refs);
+    }
+    for name in unused(&cfg.shared.variables, &vars::config_level(), &config_refs) {
+        warnings.push(warn(
+            None,
+            "variables",
+            format!("variable '{name}' is never used"),
+        ));
+    }
+    warnings
+}
+
+/// Whether there is any machine both `cfg` and `target` would deploy on
+fn runs_anywhere(cfg: &Root, target: &Target) -> bool {
+    PLATFORMS
+        .iter()
+        .flat_map(|p| [(*p, false), (*p, true)])
+        .filter(|(p, wsl)| !wsl || *p == Platform::Linux)
+        .any(|(p, wsl)| {
+            [&cfg.shared, &target.shared]
+                .iter()
+                .all(|o| o.is_platform_supported(p) && o.is_wsl_supported(wsl))
+        })
+}
+
+/// Names of the variables in `defined` under `namespace` that nothing in
+/// `refs` uses
+fn unused<'a>(
+    defined: &'a std::collections::HashMap<String, VariableValue>,
+    namespace: &'a Variable,
+    refs: &'a [Variable],
+) -> impl Iterator<Item = String> + 'a {
+    defined.keys().sorted().filter_map(move |name| {
+        let var = namespace.clone().join(Variable::definable(name).ok()?);
+        let used = refs
+            .iter()
+            .any(|r| r.starts_with(&var) || var.starts_with(r));
+        (!used).then(|| var.to_string())
+    })
+}
+
+fn variable_refs<'a>(values: impl Iterator<Item = &'a VariableValue>) -> Vec<Variable> {
+    values
+        .filter_map(|v| v.references().ok())
+        .flatten()
+        .collect()
+}
+
+fn templated_refs<'a>(fields: impl Iterator<Item = &'a Templated<String>>) -> Vec<Variable> {
+    fields
+        .filter_map(|t| t.referenced_vars().ok())
+        .flatten()
+        .map(|r| r.var)
+        .collect()
+}
+
+/// The file `target` deploys if it can be worked out, or what it deploys if
+/// that doesn't exist. `None` for targets deploying the output of a command
+/// or whose `from` can't be rendered without deploying them
+fn source(target: &Target, engine: &Context, dir: &Path) -> Option<Result<PathBuf, PathBuf>> {
+    if target.from_command.is_some() {
+        return None;
+    }
+    let src = dir.join(target.path.render(engine).ok()?);
+    Some(if src.exists() { Ok(src) } else { Err(src) })
+}
+
+/// Variables the template `src` of `target` and its partials use, none if
+/// it isn't a template
+fn template_refs(target: &Target, src: &Path, engine: &Context) -> Vec<Variable> {
+    let is_template = target
+        .is_template
+        .unwrap_or_else(|| src.extension() == Some("in".as_ref()));
+    let Some(content) = is_template.then(|| fs::read_to_string(src).ok()).flatten() else {
+        return Vec::new();
+    };
+    engine
+        .dependencies(&content, Some(src))
+        .into_iter()
+        .filter_map(|dep| fs::read_to_string(dep).ok())
+        .chain([content])
+        .filter_map(|text| template::variables(&text).ok())
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use fs_err as fs;
+    use tempdir::TempDir;
+
+    use super::lint;
+    use dotloy::{
+        config::{OneOrMany, Platform, Root, Target},
+        template::Templated,
+    };
+
+    #[test]
+    fn unused_variables_and_impossible_targets_are_found() {
+        let dir = TempDir::new("lint").unwrap();
+        fs::write(dir.path().join("vimrc.in"), "set ts={{ config.tabs }}").unwrap();
+        let mut cfg = Root {
+            targets: vec![
+                Target::new("vimrc.in".to_owned(), "{{ target.home }}/.vimrc".to_owned()),
+                Target::new("missing".to_owned(), "b".to_owned()),
+            ],
+            ..Default::default()
+        };
+        for (name, value) in [("tabs", "4"), ("stale", "x")] {
+            cfg.shared
+                .variables
+                .insert(name.to_owned(), Templated::new(value.to_owned()).into());
+        }
+        for (name, value) in [("home", "/home/me"), ("unused", "x")] {
+            cfg.targets[0]
+                .shared
+                .variables
+                .insert(name.to_owned(), Templated::new(value.to_owned()).into());
+        }
+        cfg.shared.runs_on = Some(OneOrMany::One(Platform::Linux));
+        cfg.targets[1].shared.runs_on = Some(OneOrMany::One(Platform::Windows));
+
+        let file = dir.path().join("dotloy.yaml");
+        let warnings = lint(&cfg, &file, dir.path())
+            .iter()
+            .map(|w| w.to_string())
+            .collect::<Vec<_>>();
+        let file = file.to_string_lossy();
+        let missing = Path::new(dir.path()).join("missing");
+        assert_eq!(
+            warnings,
+            [
+                format!("{file}: in target 'vimrc.in', field 'variables': variable 'target.unused' is never used"),
+                format!("{file}: in target 'missing', field 'from': '{}' doesn't exist", missing.to_string_lossy()),
+                format!("{file}: in target 'missing', field 'runs_on': never matches a platform the config runs on, so it's never deployed"),
+                format!("{file}: field 'variables': variable 'config.stale' is never used"),
+            ]
+        );
+    }
+}
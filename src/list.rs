@@ -0,0 +1,339 @@
+//! What each target of a config resolves to on this machine, for `dotloy
+//! list`. Unlike planning a deploy nothing is an error once the config's own
+//! variables are defined, problems with a target are noted on it instead
+use std::{path::Path, sync::Arc};
+
+use itertools::Itertools;
+
+use dotloy::{
+    actions::{self, variables_error},
+    apply_xdg_fallbacks,
+    config::{DeployType, LinkType, OneOrMany, Platform, Root, Target},
+    define_variables,
+    display::{self, json_string},
+    sys,
+    template::{Context, Templated, Variable},
+    vars::{self, Layer},
+};
+
+/// A target as it would be deployed here, once for each item of its
+/// `foreach`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listed {
+    /// How the target is referred to, with its item if it has one
+    pub target: String,
+    /// `from` rendered, relative to the config, or what's deployed instead
+    /// of a file
+    pub from: String,
+    /// `to` rendered
+    pub to: String,
+    /// How it's deployed, like `soft link` or `copy`
+    pub deploy: &'static str,
+    pub template: bool,
+    /// Why it isn't deployed here, if it isn't
+    pub skipped: Option<String>,
+    /// Whether `from` is a file that doesn't exist
+    pub missing: bool,
+    /// Why the target couldn't be worked out, in which case what's above is
+    /// only as far as it got
+    pub error: Option<String>,
+}
+
+/// Why `target` of `cfg` isn't deployed on this machine, if it isn't
+fn skipped(cfg: &Root, target: &Target) -> Option<String> {
+    let os = Platform::current()?;
+    let wsl = sys::is_wsl();
+    let session = sys::session().ok();
+    if !cfg.shared.is_platform_supported(os)
+        || !cfg.shared.is_wsl_supported(wsl)
+        || !cfg.shared.is_session_supported(session)
+    {
+        return Some("the config doesn't run here".to_owned());
+    }
+    if !target.shared.is_platform_supported(os) {
+        return Some(format!("doesn't run on {}", os.name()));
+    }
+    if !target.shared.is_wsl_supported(wsl) {
+        return Some(if wsl { "not for WSL" } else { "only for WSL" }.to_owned());
+    }
+    if !target.shared.is_session_supported(session) {
+        let sessions = match &target.shared.session {
+            Some(OneOrMany::One(s)) => s.name().to_owned(),
+            Some(OneOrMany::Many(ss)) => ss.iter().map(|s| s.name()).join(" or "),
+            None => String::new(),
+        };
+        return Some(format!("only for {sessions} sessions"));
+    }
+    None
+}
+
+/// How `target` is deployed, going by `src` for what depends on whether it
+/// is a directory
+fn deploy_kind(target: &Target, src: &Path) -> &'static str {
+    if target.ensure_dir {
+        "ensure dir"
+    } else if target.block.is_some() {
+        "block"
+    } else if target.link_type == DeployType::Merge {
+        "merge"
+    } else if target.extract {
+        "extract"
+    } else if target.content.is_some() || target.from_command.is_some() {
+        "copy"
+    } else {
+        match target.link_type {
+            DeployType::Link(LinkType::Soft) => "soft link",
+            DeployType::Link(LinkType::Hard) => "hard link",
+            DeployType::Auto if src.is_dir() => "soft link",
+            DeployType::Auto => "hard link",
+            DeployType::Copy | DeployType::Merge => "copy",
+        }
+    }
+}
+
+/// Fill in the rest of `entry` for `target`, as far as it gets before an
+/// error rendering one of its fields
+fn resolve(
+    entry: &mut Listed,
+    target: &Target,
+    engine: &Context,
+    dir: &Path,
+) -> Result<(), String> {
+    let render = |field, t: &Templated<String>| {
+        t.render(engine)
+            .map_err(|e| format!("in field '{field}': {e}"))
+    };
+    entry.to = render("to", &target.target_location)?;
+    if let Ok(to) = engine.path(&entry.to) {
+        entry.to = to.to_string_lossy().into_owned();
+    }
+    if target.ensure_dir {
+        entry.from = String::new();
+        return Ok(());
+    }
+    match (&target.content, &target.from_command) {
+        (Some(_), _) => {
+            entry.template = target.is_template != Some(false);
+            entry.from = "content".to_owned();
+        }
+        (None, Some(cmd)) => {
+            entry.template = target.is_template == Some(true);
+            entry.from = format!("`{}`", render("from_command", cmd)?);
+        }
+        (None, None) => {
+            let from = render("from", &target.path)?;
+            entry.template = target
+                .is_template
+                .unwrap_or_else(|| Path::new(&from).extension() == Some("in".as_ref()));
+            let src = dir.join(&from);
+            entry.missing = !src.exists();
+            entry.deploy = deploy_kind(target, &src);
+            entry.from = from;
+        }
+    }
+    Ok(())
+}
+
+/// Every target of `cfg`, with relative paths relative to `config_dir`,
+/// rendered with `engine` and the variables of the config and each target
+/// like a deploy would
+pub fn list(cfg: &Root, config_dir: &Path, engine: &Context) -> actions::Result<Vec<Listed>> {
+    let mut engine = engine.clone();
+    if cfg.allow_shell {
+        engine.enable_shell();
+    }
+    if let Some(dir) = &cfg.partials {
+        engine.set_partials_dir(config_dir.join(dir));
+    }
+    engine.set_base_dir(config_dir.to_owned());
+    apply_xdg_fallbacks(&mut engine, cfg)?;
+    define_variables(
+        &mut engine,
+        &vars::config_level(),
+        Layer::Config,
+        cfg.shared.variables.iter(),
+        cfg.allow_shadowing,
+    )
+    .map_err(|e| variables_error(None, e))?;
+    let engine = Arc::new(engine);
+    let mut listed = Vec::new();
+    for target in &cfg.targets {
+        let items = match &target.foreach {
+            Some(items) => match items
+                .iter()
+                .map(|item| item.render(&engine).map(Some))
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(items) => items,
+                Err(e) => {
+                    listed.push(Listed {
+                        target: target.label().to_owned(),
+                        from: target.path.source().to_owned(),
+                        to: target.target_location.source().to_owned(),
+                        deploy: deploy_kind(target, Path::new("")),
+                        template: false,
+                        skipped: skipped(cfg, target),
+                        missing: false,
+                        error: Some(format!("in field 'foreach': {e}")),
+                    });
+                    continue;
+                }
+            },
+            None => vec![None],
+        };
+        for item in items {
+            let label = match &item {
+                Some(item) => format!("{} [{item}]", target.label()),
+                None => target.label().to_owned(),
+            };
+            let mut engine = engine.scope();
+            if let Some(item) = item {
+                engine.define(vars::target_level().join(Variable::single("item")), item);
+            }
+            let mut entry = Listed {
+                target: label,
+                from: target.path.source().to_owned(),
+                to: target.target_location.source().to_owned(),
+                deploy: deploy_kind(target, Path::new("")),
+                template: false,
+                skipped: skipped(cfg, target),
+                missing: false,
+                error: None,
+            };
+            let resolved = define_variables(
+                &mut engine,
+                &vars::target_level(),
+                Layer::Target,
+                target.shared.variables.iter(),
+                cfg.allow_shadowing,
+            )
+            .map_err(|e| format!("in its variables: {e}"))
+            .and_then(|()| resolve(&mut entry, target, &engine, config_dir));
+            entry.error = resolved.err();
+            listed.push(entry);
+        }
+    }
+    Ok(listed)
+}
+
+/// `listed` as a table, a line for each target saying where it goes, how
+/// and anything that stops it being deployed
+pub fn lines(listed: &[Listed]) -> Vec<String> {
+    let rows = listed
+        .iter()
+        .map(|l| {
+            let mut how = vec![l.deploy];
+            if l.template {
+                how.push("template");
+            }
+            let mut notes = Vec::new();
+            if let Some(why) = &l.skipped {
+                notes.push(format!("skipped, {why}"));
+            }
+            if l.missing {
+                notes.push("source missing".to_owned());
+            }
+            if let Some(e) = &l.error {
+                notes.push(format!("error {e}"));
+            }
+            vec![
+                l.target.clone(),
+                l.from.clone(),
+                "->".to_owned(),
+                l.to.clone(),
+                format!("[{}]", how.join(", ")),
+                notes.join("; "),
+            ]
+        })
+        .collect::<Vec<_>>();
+    display::table(&rows)
+        .into_iter()
+        .map(|cells| cells.join(" ").trim_end().to_owned())
+        .collect()
+}
+
+/// `listed` as JSON, an object with a `targets` array of one object per
+/// target with the fields of [`Listed`], `null` for those without a value
+pub fn json(listed: &[Listed]) -> String {
+    let optional = |s: &Option<String>| s.as_deref().map_or("null".to_owned(), json_string);
+    let targets = listed
+        .iter()
+        .map(|l| {
+            format!(
+                r#"{{"target":{},"from":{},"to":{},"deploy":{},"template":{},"skipped":{},"missing":{},"error":{}}}"#,
+                json_string(&l.target),
+                json_string(&l.from),
+                json_string(&l.to),
+                json_string(l.deploy),
+                l.template,
+                optional(&l.skipped),
+                l.missing,
+                optional(&l.error)
+            )
+        })
+        .join(",");
+    format!(r#"{{"targets":[{targets}]}}"#)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use dotloy::{
+        config::{DeployType, LinkType, OneOrMany, Root, Target, VariableValue},
+        default_parse_context,
+        template::Templated,
+    };
+
+    use super::{json, list};
+
+    #[test]
+    fn targets_are_rendered_and_missing_sources_are_only_flagged() {
+        let dir = TempDir::new("list").unwrap();
+        std::fs::create_dir(dir.path().join("nvim")).unwrap();
+        let mut nvim = Target::new("nvim".to_owned(), "/home/{{ config.user }}/nvim".to_owned());
+        nvim.shared.variables.insert(
+            "unused".to_owned(),
+            VariableValue::Single(Templated::new("x".to_owned())),
+        );
+        let mut gone = Target::new("gone.in".to_owned(), "/tmp/gone".to_owned());
+        gone.link_type = DeployType::Link(LinkType::Soft);
+        let mut never = Target::new("nvim".to_owned(), "/tmp/never".to_owned());
+        never.shared.runs_on = Some(OneOrMany::Many(vec![]));
+        let broken = Target::new("{{ nope }}".to_owned(), "/tmp/broken".to_owned());
+        let mut cfg = Root {
+            targets: vec![nvim, gone, never, broken],
+            ..Default::default()
+        };
+        cfg.shared.variables.insert(
+            "user".to_owned(),
+            VariableValue::Single(Templated::new("me".to_owned())),
+        );
+
+        let listed = list(&cfg, dir.path(), &default_parse_context()).unwrap();
+        let summary = listed
+            .iter()
+            .map(|l| (l.from.as_str(), l.deploy, l.template, l.missing))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("nvim", "soft link", false, false),
+                ("gone.in", "soft link", true, true),
+                ("nvim", "soft link", false, false),
+                ("{{ nope }}", "hard link", false, false),
+            ]
+        );
+        assert!(listed[0].to.ends_with("me/nvim"), "{}", listed[0].to);
+        assert!(listed[..2]
+            .iter()
+            .all(|l| l.skipped.is_none() && l.error.is_none()));
+        assert!(listed[2].skipped.is_some());
+        assert!(listed[3].error.as_deref().unwrap().contains("'from'"));
+        assert!(json(&listed[1..2])
+            .starts_with(r#"{"targets":[{"target":"gone.in","from":"gone.in","to":"#));
+        assert!(json(&listed[1..2]).ends_with(
+            r#","deploy":"soft link","template":true,"skipped":null,"missing":true,"error":null}]}"#
+        ));
+    }
+}
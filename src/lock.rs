@@ -0,0 +1,153 @@
+//! Lock files that stop two dotloy runs deploying the same config at once
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use fs_err as fs;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use dotloy::sys;
+
+/// How long to sleep between attempts when waiting for a lock
+const POLL: Duration = Duration::from_millis(250);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("another dotloy run is in progress (pid {pid}), it holds '{path}'", path = path.to_string_lossy())]
+    Held { pid: u32, path: PathBuf },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Where lock files go, the runtime dir if there is one as they don't need
+/// to outlive a reboot
+pub fn default_dir() -> PathBuf {
+    directories::BaseDirs::new()
+        .and_then(|d| {
+            d.runtime_dir()
+                .or(d.state_dir())
+                .map(Path::to_owned)
+                .or_else(|| Some(d.cache_dir().to_owned()))
+        })
+        .unwrap_or_else(std::env::temp_dir)
+        .join("dotloy")
+}
+
+/// A held lock on a config, released when dropped
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Lock `config`, identified by its canonical path or for stdin its
+    /// content id, with a lock file in `dir`. Fails straight away if another
+    /// process holds it
+    pub fn acquire(dir: &Path, config: &Path) -> Result<Self, Error> {
+        fs::create_dir_all(dir)?;
+        let hash = Sha256::digest(config.as_os_str().to_string_lossy().as_bytes());
+        let path = dir.join(format!("{}.lock", &format!("{hash:x}")[..16]));
+        loop {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut f) => {
+                    write!(f, "{}", std::process::id())?;
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+            let owner = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|s| s.trim().parse::<u32>().ok());
+            match owner {
+                Some(pid) if sys::process_alive(pid) => return Err(Error::Held { pid, path }),
+                // the owner crashed, or is only now writing its pid
+                Some(pid) => {
+                    log::warn!(
+                        "removing stale lock '{}' left by pid {pid}",
+                        path.to_string_lossy()
+                    );
+                    fs::remove_file(&path)?;
+                }
+                None if is_old(&path) => fs::remove_file(&path)?,
+                None => std::thread::sleep(POLL),
+            }
+        }
+    }
+    /// [`Lock::acquire`] but if another process holds it, call `waiting`
+    /// with its pid and wait for it to be released
+    pub fn wait(dir: &Path, config: &Path, waiting: impl FnOnce(u32)) -> Result<Self, Error> {
+        let mut waiting = Some(waiting);
+        loop {
+            match Self::acquire(dir, config) {
+                Err(Error::Held { pid, .. }) => {
+                    if let Some(f) = waiting.take() {
+                        f(pid);
+                    }
+                    std::thread::sleep(POLL);
+                }
+                r => return r,
+            }
+        }
+    }
+}
+
+/// A lock file without a pid is being written, unless it's been like that a
+/// while in which case it never will be
+fn is_old(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.elapsed().ok())
+        .is_none_or(|age| age > POLL * 4)
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!("failed to release lock: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Lock};
+    use assert_matches::assert_matches;
+    use std::path::Path;
+
+    #[test]
+    fn a_config_can_only_be_locked_once_at_a_time() {
+        let dir = tempdir::TempDir::new("dotloy-lock").unwrap();
+        let cfg = Path::new("/dots/dotloy.yaml");
+        let lock = Lock::acquire(dir.path(), cfg).unwrap();
+        assert_matches!(
+            Lock::acquire(dir.path(), cfg),
+            Err(Error::Held { pid, .. }) if pid == std::process::id()
+        );
+        let other = Lock::acquire(dir.path(), Path::new("/other/dotloy.yaml")).unwrap();
+        drop(lock);
+        let again = Lock::acquire(dir.path(), cfg).unwrap();
+        drop((again, other));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+    #[test]
+    fn stale_locks_are_taken_over() {
+        let dir = tempdir::TempDir::new("dotloy-lock").unwrap();
+        let cfg = Path::new("/dots/dotloy.yaml");
+        let path = Lock::acquire(dir.path(), cfg).unwrap().path.clone();
+        std::fs::write(&path, u32::MAX.to_string()).unwrap();
+        let lock = Lock::acquire(dir.path(), cfg).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&lock.path).unwrap(),
+            std::process::id().to_string()
+        );
+    }
+}
@@ -1,106 +1,55 @@
 #![deny(unused_must_use)]
-#![deny(unused_crate_dependencies)]
 use std::{
-    io::{BufReader, Write},
+    io::Write,
     path::{Path, PathBuf},
     process::exit,
+    time::{Duration, Instant},
 };
 
-use actions::Actions;
-use args::{Args, DeployCmd, ExpandCmd};
+use args::{
+    AddCmd, AddLink, AdoptCmd, Args, CheckCmd, DeployCmd, DiffCmd, DryRunFormat, ExpandCmd,
+    HistoryCmd, HistoryShow, ImportChezmoiCmd, ImportStowCmd, InitCmd, LintCmd, ListCmd, StatusCmd,
+};
 use clap::{CommandFactory, Parser};
 use colored::{Color, Colorize};
-use config::Root;
-use handybars::{Context, Object, Variable};
+use dotloy::{
+    abspath::{self, AbsPathBuf},
+    actions::{self, Actions},
+    apply_xdg_fallbacks,
+    config::{self, DeployType, DirtyGit, LinkType, Root, Target},
+    default_parse_context, define_config_location, define_stdin_config_location, define_variables,
+    override_variables, plan, resources,
+    template::{self, Context, Undefined},
+    timings::Timings,
+    vars::{self, Layer},
+};
+use failures::{Failures, Report};
+use fs_err as fs;
 use itertools::Itertools;
-use serde::{Deserialize, Serialize};
+use lock::Lock;
 use thiserror::Error;
 
-mod abspath;
-mod actions;
+mod add;
 mod args;
-mod config;
-pub(crate) mod resources;
-use fs_err as fs;
-
-use crate::abspath::AbsPathBuf;
-
-mod vars {
-    use handybars::Variable;
+mod doctor;
+mod failures;
+mod git;
+mod import;
+mod init;
+mod lint;
+mod list;
+mod lock;
+mod progress;
 
-    pub fn target_level() -> Variable<'static> {
-        Variable::single("target")
-    }
-    pub fn config_level() -> Variable<'static> {
-        Variable::single("config")
-    }
-}
-
-fn xdg_context() -> Context<'static> {
-    let dirs = directories::BaseDirs::new().expect("failed to get dirs on system");
-
-    let mut xdg = Object::new()
-        .with_property("home", dirs.home_dir().to_string_lossy().into_owned())
-        .with_property("config", dirs.config_dir().to_string_lossy().into_owned())
-        .with_property(
-            "local",
-            Object::new().with_property(
-                "config",
-                dirs.config_local_dir().to_string_lossy().into_owned(),
-            ),
-        );
-    // TODO: This will give an unhelpful variable not defined error on non-linux
-    // maybe intercept the error from handybars for this variable and provide our own?
-    if let Some(dir) = dirs.executable_dir() {
-        xdg.add_property("exec", dir.to_string_lossy().into_owned());
-    }
-    Context::new().with_define(Variable::single("xdg".to_owned()), xdg)
-}
-
-fn default_parse_context() -> Context<'static> {
-    let mut ctx = Context::new();
-    ctx.define(
-        Variable::single("cwd".to_string()),
-        std::env::current_dir()
-            .unwrap()
-            .to_string_lossy()
-            .into_owned(),
-    );
-    ctx.append(xdg_context());
-    ctx
-}
-
-#[repr(transparent)]
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct Templated<T>(T);
-impl<T> Templated<T> {
-    pub fn new(inner: T) -> Self {
-        Self(inner)
-    }
-}
-impl Templated<String> {
-    pub fn render(&self, ctx: &Context) -> Result<String, handybars::Error> {
-        ctx.render(&self.0)
-    }
-}
-
-fn define_variables<'a, 'b>(
-    on: &mut Context<'b>,
-    namespace: &Variable<'b>,
-    vars: impl Iterator<Item = (&'a String, &'a Templated<String>)>,
-) -> Result<(), handybars::Error> {
-    for (var, val) in vars {
-        on.define(
-            namespace.clone().join(var.parse()?),
-            handybars::Value::String(val.render(on)?.into()),
-        );
-    }
-    Ok(())
-}
+/// How many of the slowest actions `--timings` lists
+const SLOWEST_ACTIONS: usize = 10;
 
 fn handle_watch_updates(
     args: DeployCmd,
-    actions: Actions,
+    template_engine: Context,
+    mut actions: Actions,
+    mut skipped: Vec<String>,
+    watcher: &mut dyn notify::Watcher,
     rx: std::sync::mpsc::Receiver<notify::Result<notify::Event>>,
 ) {
     log::info!(
@@ -112,6 +61,15 @@ fn handle_watch_updates(
                 .into_owned())
             .join(", ")
     );
+    // failures are logged here instead, so the same one isn't logged in full
+    // on every change
+    actions.set_quiet_failures(true);
+    let mut failures = Failures::default();
+    let redeploy_failed = |failures: &mut Failures, e: &Error| {
+        if let Some(report) = failures.failed("redeploy", &e.to_string(), Instant::now()) {
+            log::error!("{}", report.line());
+        }
+    };
     for res in rx {
         match res {
             Ok(ev) => match ev.kind {
@@ -119,34 +77,103 @@ fn handle_watch_updates(
                 | notify::EventKind::Remove(_)
                 | notify::EventKind::Any
                 | notify::EventKind::Modify(_) => {
-                    log::info!("detected file changes");
                     log::debug!("notify event: {ev:#?}");
-                    let r = if ev.paths.is_empty() {
+                    let config_changed =
+                        args.from_plan.is_none() && is_config_change(&args.targets, &ev.paths);
+                    if config_changed {
+                        log::info!("detected config changes, planning again");
+                        match replan(&args, &template_engine, watcher) {
+                            Ok((replanned, now_skipped)) => {
+                                actions = replanned;
+                                skipped = now_skipped;
+                            }
+                            Err(e) => {
+                                redeploy_failed(&mut failures, &e);
+                                continue;
+                            }
+                        }
+                    }
+                    let summary = |failed| Summary {
+                        configs: args.targets.len(),
+                        skipped: skipped.clone(),
+                        failed,
+                    };
+                    // with every config skipped there's nothing to redeploy
+                    if actions.is_empty() {
+                        if config_changed {
+                            summary(Vec::new()).report();
+                        }
+                        continue;
+                    }
+                    // without paths there's no telling what changed, and a
+                    // config that changed may have changed anything
+                    let dependents = if ev.paths.is_empty() || config_changed {
                         None
                     } else {
-                        Some(ev.paths)
-                    }
-                    .map(|s| {
-                        actions.dependents_of(
-                            s.into_iter()
-                                .map(|p| {
-                                    AbsPathBuf::new(p)
-                                        .expect("failed to canonicalize path from notify")
-                                })
-                                .map(resources::ResourceLocation::Path)
-                                .collect(),
-                        )
-                    })
-                    .as_ref()
-                    .unwrap_or(&actions)
-                    .run(args.dry_run);
-                    match r {
-                        Err(actions::Error::NoActions) => {}
+                        match dependents_of_changes(&actions, &ev.paths) {
+                            Some(dependents) => Some(dependents),
+                            None => {
+                                log::debug!(
+                                    "nothing depends on [{}], not redeploying",
+                                    ev.paths.iter().map(|p| p.to_string_lossy()).join(", ")
+                                );
+                                continue;
+                            }
+                        }
+                    };
+                    log::info!("detected file changes");
+                    // wait for a deploy run by hand to finish rather than
+                    // lose the change
+                    let _locks = match lock_configs(&actions, args.dry_run, true) {
+                        Ok(locks) => locks,
                         Err(e) => {
-                            log::error!("failed to redeploy: {e}");
+                            redeploy_failed(&mut failures, &e);
+                            continue;
+                        }
+                    };
+                    actions.start_run();
+                    let mut changed = Vec::new();
+                    // failures that are new, rather than the same as last time
+                    let mut new_failures = Vec::new();
+                    let observe =
+                        |action: &actions::Action, outcome: actions::Outcome| match outcome {
+                            actions::Outcome::Done => {
+                                failures.succeeded(&action.to_string());
+                                if let Some(out) = action.written() {
+                                    changed.push(out.to_string());
+                                }
+                            }
+                            actions::Outcome::Failed(e) => {
+                                let error = e.to_string();
+                                match failures.failed(&action.to_string(), &error, Instant::now()) {
+                                    Some(Report::New(line)) => {
+                                        log::error!("{line}");
+                                        new_failures.push(format!("{action}: {error}"));
+                                    }
+                                    Some(Report::StillFailing(line)) => log::error!("{line}"),
+                                    None => {}
+                                }
+                            }
+                            actions::Outcome::Skipped => {}
+                        };
+                    let r = match dependents {
+                        Some(mut dependents) => dependents.run_observed(args.dry_run, observe),
+                        None => actions.run_observed(args.dry_run, observe),
+                    };
+                    match r {
+                        Err(e) => redeploy_failed(&mut failures, &e.into()),
+                        Ok(failed) => {
+                            failures.succeeded("redeploy");
+                            // run here rather than in the background so one
+                            // is done before the next redeploy starts
+                            if failed.is_empty() && !args.dry_run && !changed.is_empty() {
+                                run_on_change(&args.on_change, &changed);
+                            }
                         }
-                        _ => {}
                     }
+                    // configs stay skipped until they're fixed, so every
+                    // redeploy says which are
+                    summary(new_failures).report();
                 }
                 _ => {}
             },
@@ -155,8 +182,189 @@ fn handle_watch_updates(
     }
 }
 
-fn run_deploy(args: DeployCmd) -> Result<()> {
-    let template_engine = default_parse_context();
+/// Plan the configs of `args` again after one of them changed, along with
+/// why each config that was skipped was, and watch what they now read.
+/// Configs that fail to plan are skipped too, so one broken while watching
+/// doesn't stop the others redeploying
+fn replan(
+    args: &DeployCmd,
+    template_engine: &Context,
+    watcher: &mut dyn notify::Watcher,
+) -> Result<(Actions, Vec<String>)> {
+    let mut summary = Summary {
+        configs: args.targets.len(),
+        ..Default::default()
+    };
+    let (mut actions, errors) = plan_each_target(
+        &args.targets,
+        args.source_dir.as_deref(),
+        args.require_clean,
+        args.repoint,
+        template_engine,
+        &mut summary,
+        &mut Timings::new(false),
+    )?;
+    for e in errors {
+        summary.skip(e.to_string());
+    }
+    prepare_deploy(&mut actions, args);
+    actions.set_quiet_failures(true);
+    actions.configure_watcher(watcher)?;
+    watch_configs(&args.targets, watcher);
+    Ok((actions, summary.skipped))
+}
+
+/// Set up `actions` to be deployed as `args` asks
+fn prepare_deploy(actions: &mut Actions, args: &DeployCmd) {
+    actions.set_path_style(
+        directories::BaseDirs::new().map(|d| d.home_dir().to_owned()),
+        args.full_paths,
+    );
+    if !args.no_history {
+        actions.record_history(actions::default_history_dir());
+    }
+}
+
+/// Where each of `targets` is, as the watcher reports it
+fn config_paths(targets: &[PathBuf]) -> impl Iterator<Item = PathBuf> + '_ {
+    targets
+        .iter()
+        .filter(|t| !config::is_stdin(t))
+        .filter_map(|t| {
+            abspath::canonicalize(t)
+                .or_else(|_| AbsPathBuf::logical(t).map(|p| p.to_path_buf()))
+                .map_err(|e| log::error!("can't watch '{}': {e}", t.to_string_lossy()))
+                .ok()
+        })
+}
+
+/// Watch the configs of `targets`, so they're planned again when they
+/// change. Editors tend to replace a file rather than write to it, and
+/// targets may not exist yet, so it's their directories that are watched
+fn watch_configs(targets: &[PathBuf], watcher: &mut dyn notify::Watcher) {
+    for target in config_paths(targets) {
+        let dir = if target.is_dir() {
+            target.as_path()
+        } else {
+            target.parent().unwrap_or(&target)
+        };
+        if let Err(e) = watcher.watch(dir, notify::RecursiveMode::NonRecursive) {
+            log::warn!(
+                "can't watch '{}' for config changes: {e}",
+                dir.to_string_lossy()
+            );
+        }
+    }
+}
+
+/// Whether any of `paths` is a config, or one of `targets`
+fn is_config_change(targets: &[PathBuf], paths: &[PathBuf]) -> bool {
+    paths.iter().any(|p| {
+        p.file_name()
+            .is_some_and(|n| config::FILE_NAMES.iter().any(|f| n == *f))
+            || config_paths(targets).any(|t| t == *p)
+    })
+}
+
+/// The actions to redeploy because `paths` changed, `None` if none of them
+/// depend on them. Changes to files the actions write themselves don't
+/// count, they may well be in a watched directory
+fn dependents_of_changes(actions: &Actions, paths: &[PathBuf]) -> Option<Actions> {
+    let changed = actions.external_changes(
+        paths
+            .iter()
+            .filter_map(|p| {
+                AbsPathBuf::new(p)
+                    .map_err(|e| log::error!("ignoring change: {e}"))
+                    .ok()
+            })
+            .map(resources::ResourceLocation::Path)
+            .collect(),
+    );
+    let dependents = actions.dependents_of(changed);
+    (!dependents.is_empty()).then_some(dependents)
+}
+
+/// Run each of the `--on-change` commands after a redeploy updated `changed`.
+/// Those that fail are only logged, watching carries on
+fn run_on_change(commands: &[String], changed: &[String]) {
+    for command in commands {
+        log::info!("running `{command}`");
+        match resources::shell(command)
+            .env("DOTLOY_CHANGED", changed.join("\n"))
+            .stdin(std::process::Stdio::null())
+            .status()
+        {
+            Ok(status) if status.success() => {}
+            Ok(status) => log::error!("`{command}` failed: {status}"),
+            Err(e) => log::error!("failed to run `{command}`: {e}"),
+        }
+    }
+}
+
+/// A config along with where it came from
+struct Loaded {
+    root: Root,
+    /// Relative paths in the config are relative to this
+    dir: PathBuf,
+    /// File the config was read from, `None` if it came from stdin
+    file: Option<PathBuf>,
+    /// What the config is known by, its file or a hash of its content
+    id: PathBuf,
+}
+impl Loaded {
+    fn from_file(file: PathBuf, root: Root, dir: PathBuf) -> Self {
+        Self {
+            root,
+            dir,
+            id: file.clone(),
+            file: Some(file),
+        }
+    }
+    /// Read a config from stdin, its paths are relative to `source_dir` or
+    /// the cwd if there isn't one
+    fn stdin(source_dir: Option<&Path>) -> Result<Self> {
+        let (id, root) = config::read_stdin()?;
+        Ok(Self {
+            root,
+            dir: abspath::canonicalize(source_dir.unwrap_or(Path::new(".")))?,
+            file: None,
+            id: id.into(),
+        })
+    }
+    fn define_location(&self, on: &mut Context) {
+        match &self.file {
+            Some(file) => define_config_location(on, file),
+            None => define_stdin_config_location(on, &self.dir),
+        }
+    }
+}
+
+fn run_deploy(mut args: DeployCmd) -> Result<()> {
+    if let Some(list) = &args.targets_from {
+        if config::is_stdin(list) && args.targets.iter().any(|t| config::is_stdin(t)) {
+            return Err(Error::StdinListAndConfig);
+        }
+        let listed = read_target_list(list)?;
+        args.targets.extend(listed);
+    }
+    if args.recursive {
+        args.targets = find_configs_below(&args.targets)?;
+    }
+    match args.targets.iter().filter(|t| config::is_stdin(t)).count() {
+        0 => {}
+        1 if args.watch => return Err(Error::WatchStdin),
+        1 => {}
+        _ => return Err(Error::StdinTwice),
+    }
+    let mut template_engine = default_parse_context();
+    if args.xdg_on_macos {
+        dotloy::use_xdg_layout_on_macos(&mut template_engine);
+    }
+    // plans and scripts are run later, perhaps where commands can't be, so
+    // they get the real output even from a dry run
+    template_engine
+        .set_dry_run(args.dry_run && args.emit_plan.is_none() && args.emit_script.is_none());
     let (tx, rx) = std::sync::mpsc::channel();
     let mut actions = Actions::new();
     let mut watcher = if args.watch {
@@ -165,70 +373,808 @@ fn run_deploy(args: DeployCmd) -> Result<()> {
     } else {
         None
     };
-    let root_dir = fs::canonicalize(std::env::current_dir()?)?;
-    for target in args.targets.clone() {
-        let target_str = target.to_string_lossy();
-        if !target.exists() {
-            log::warn!("path '{target_str}' does not exist");
-            continue;
+    let mut summary = Summary {
+        configs: args.targets.len(),
+        ..Default::default()
+    };
+    let mut timings = Timings::new(args.timings);
+    match &args.from_plan {
+        Some(path) => {
+            let mut planned = timings.time("read plan", || -> Result<_> {
+                Ok(Actions::from_plan(plan::read(path)?)?)
+            })?;
+            log::info!("running plan '{}'", path.to_string_lossy());
+            actions.append(&mut planned);
+        }
+        None => actions.append(&mut plan_targets(
+            &args.targets,
+            args.source_dir.as_deref(),
+            args.require_clean,
+            args.repoint,
+            &template_engine,
+            &mut summary,
+            &mut timings,
+        )?),
+    }
+    prepare_deploy(&mut actions, &args);
+    if args.recursive {
+        for (nested, by) in actions.nested_configs() {
+            log::warn!(
+                "config '{}' is inside a directory '{}' deploys, so its files are deployed by both",
+                nested.to_string_lossy(),
+                by.to_string_lossy()
+            );
         }
-        let Ok(target) = fs::canonicalize(&target).map_err(|e| {
-            log::warn!("failed to canonicalize path '{target_str}': {e}, skipping...");
-        }) else {continue;};
-        let Ok(Some(cfg)) = read_config(&target).map_err(|e| {
-            log::warn!("failed to load config at '{target}': {e}", target = target.to_string_lossy());
-        }).map(|v| {
-            if v.is_none() {
-                log::warn!("failed to find config file for '{target}'", target = target.to_string_lossy());
-        } v}) else {
-            continue;
-        };
-        std::env::set_current_dir(root_dir.join(resolve_config_dir(&target).unwrap()))?;
-        let mut acts = Actions::from_config(&cfg, &template_engine)?;
-        actions.append(&mut acts);
-        std::env::set_current_dir(&root_dir)?;
     }
-    std::env::set_current_dir(&root_dir)?;
     if let Some(watcher) = &mut watcher {
         log::debug!("actions: {actions:#?}");
         actions.configure_watcher(watcher)?;
+        if args.from_plan.is_none() {
+            watch_configs(&args.targets, watcher);
+        }
+    }
+    if let Some(path) = &args.emit_script {
+        fs::write(path, actions.to_script()?)?;
+        log::info!("wrote the deploy to '{}'", path.to_string_lossy());
+        summary.report();
+        return Ok(());
+    }
+    if let Some(path) = &args.emit_plan {
+        plan::write(path, &actions.to_plan()?)?;
+        log::info!("wrote the plan to '{}'", path.to_string_lossy());
+        summary.report();
+        return Ok(());
     }
-    actions.run(args.dry_run)?;
-    if watcher.is_some() {
-        handle_watch_updates(args, actions, rx);
+    if args.format == DryRunFormat::Json && args.dry_run {
+        println!(
+            "{}",
+            actions.groups_json(timings.is_enabled().then_some(&timings))
+        );
+        return Ok(());
+    }
+    let locks = timings.time("lock configs", || {
+        lock_configs(&actions, args.dry_run, args.wait)
+    })?;
+    // only the first deploy gets a bar, redeploys in watch mode are small
+    let bar = progress::wanted(args.progress, actions.len())
+        .then(|| progress::Shown::start(actions.len()));
+    let started = Instant::now();
+    // each action is reported once it's done, so it took the time since the
+    // one before it was
+    let mut last = started;
+    // verifications that failed (rather than only warned), the target they
+    // check is failed even though its files were written
+    let mut failed_verifications = 0;
+    summary.failed = actions.run_observed(args.dry_run, |action, outcome| {
+        if let Some(bar) = &bar {
+            bar.advance(action);
+        }
+        if matches!(
+            (action, outcome),
+            (actions::Action::Verify { .. }, actions::Outcome::Failed(_))
+        ) {
+            failed_verifications += 1;
+        }
+        timings.action(action, last.elapsed());
+        last = Instant::now();
+    })?;
+    timings.phase("run", started.elapsed());
+    drop((bar, locks));
+    summary.report();
+    if args.format == DryRunFormat::Json {
+        println!(
+            "{}",
+            actions.groups_json(timings.is_enabled().then_some(&timings))
+        );
+    } else if timings.is_enabled() {
+        for line in timings.lines(SLOWEST_ACTIONS) {
+            log::info!("{line}");
+        }
+    }
+    if args.strict && !summary.is_clean() {
+        return Err(Error::Strict);
+    }
+    // watching carries on, a redeploy may well fix it
+    if failed_verifications > 0 && watcher.is_none() {
+        return Err(Error::VerificationsFailed(failed_verifications));
+    }
+    if let Some(watcher) = &mut watcher {
+        handle_watch_updates(args, template_engine, actions, summary.skipped, watcher, rx);
     }
 
     Ok(())
 }
-fn run_expand(cmd: ExpandCmd, cfg: Option<&Root>) -> Result<()> {
+
+/// `targets` with each directory replaced by the configs in and below it.
+/// Directories without any are kept, to be skipped like any other
+fn find_configs_below(targets: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    for target in targets {
+        let below = if target.is_dir() {
+            config::find_below(target)?
+        } else {
+            Vec::new()
+        };
+        if below.is_empty() {
+            found.push(target.clone());
+        } else {
+            log::debug!(
+                "found {} configs below '{}'",
+                below.len(),
+                target.to_string_lossy()
+            );
+            found.extend(below);
+        }
+    }
+    Ok(found)
+}
+
+/// Targets listed one per line in `list`, or stdin if it's `-`
+fn read_target_list(list: &Path) -> Result<Vec<PathBuf>> {
+    if config::is_stdin(list) {
+        let text = std::io::read_to_string(std::io::stdin())?;
+        Ok(parse_target_list(&text, Path::new("")))
+    } else {
+        let text = fs::read_to_string(list)?;
+        Ok(parse_target_list(
+            &text,
+            config::dir_of(list).unwrap_or(Path::new("")),
+        ))
+    }
+}
+
+/// Paths in a target list, skipping blank lines and `#` comments. Relative
+/// ones are relative to `dir`
+fn parse_target_list(text: &str, dir: &Path) -> Vec<PathBuf> {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| dir.join(l))
+        .collect()
+}
+
+/// Check the sources of the configs in `args` against their `sha256`,
+/// without deploying anything
+fn run_check(args: CheckCmd) -> Result<()> {
+    if args.targets.iter().filter(|t| config::is_stdin(t)).count() > 1 {
+        return Err(Error::StdinTwice);
+    }
+    let mut template_engine = default_parse_context();
+    template_engine.set_dry_run(true);
+    let mut summary = Summary {
+        configs: args.targets.len(),
+        ..Default::default()
+    };
+    let mut checks = plan_targets(
+        &args.targets,
+        args.source_dir.as_deref(),
+        false,
+        false,
+        &template_engine,
+        &mut summary,
+        &mut Timings::default(),
+    )?
+    .hash_checks();
+    if checks.is_empty() {
+        log::info!("no targets have a sha256 to check");
+    } else {
+        checks.set_path_style(
+            directories::BaseDirs::new().map(|d| d.home_dir().to_owned()),
+            false,
+        );
+        summary.failed = checks.run(false)?;
+    }
+    summary.report();
+    if !summary.is_clean() {
+        return Err(Error::ChecksFailed);
+    }
+    Ok(())
+}
+
+/// Show whether what each action of the configs in `args` deploys is in
+/// place, failing unless all of it is
+fn run_status(args: StatusCmd) -> Result<()> {
+    if args.targets.iter().filter(|t| config::is_stdin(t)).count() > 1 {
+        return Err(Error::StdinTwice);
+    }
+    let mut summary = Summary {
+        configs: args.targets.len(),
+        ..Default::default()
+    };
+    let mut actions = plan_targets(
+        &args.targets,
+        args.source_dir.as_deref(),
+        false,
+        false,
+        &default_parse_context(),
+        &mut summary,
+        &mut Timings::default(),
+    )?;
+    actions.set_path_style(
+        directories::BaseDirs::new().map(|d| d.home_dir().to_owned()),
+        args.full_paths,
+    );
+    let statuses = actions.status();
+    let width = statuses
+        .iter()
+        .map(|(_, s)| s.name().len())
+        .max()
+        .unwrap_or_default();
+    for (shown, status) in &statuses {
+        let name = format!("{:width$}", status.name());
+        let name = match status {
+            actions::Status::Deployed => name.green(),
+            actions::Status::Missing => name.yellow(),
+            actions::Status::Conflicting(_) | actions::Status::Unknown(_) => name.red(),
+        };
+        match status.detail() {
+            Some(detail) => println!("[{name}] {shown} {}", format!("({detail})").dimmed()),
+            None => println!("[{name}] {shown}"),
+        }
+    }
+    let counts = statuses.iter().map(|(_, s)| s.name()).counts();
+    let counts = ["deployed", "missing", "conflicting", "unknown"]
+        .into_iter()
+        .filter_map(|name| Some(format!("{} {name}", counts.get(name)?)))
+        .join(", ");
+    if !counts.is_empty() {
+        println!("{counts}");
+    }
+    summary.report();
+    let undeployed = statuses
+        .iter()
+        .filter(|(_, s)| *s != actions::Status::Deployed)
+        .count();
+    if undeployed > 0 {
+        return Err(Error::NotDeployed(undeployed));
+    }
+    if !summary.is_clean() {
+        return Err(Error::ConfigsSkipped(summary.skipped.len()));
+    }
+    Ok(())
+}
+
+/// Print what deploying the configs in `args` would change, a diff for each
+/// action writing text and a line saying what for the rest
+fn run_diff(args: DiffCmd) -> Result<()> {
+    if args.targets.iter().filter(|t| config::is_stdin(t)).count() > 1 {
+        return Err(Error::StdinTwice);
+    }
+    let mut summary = Summary {
+        configs: args.targets.len(),
+        ..Default::default()
+    };
+    let mut actions = plan_targets(
+        &args.targets,
+        args.source_dir.as_deref(),
+        false,
+        false,
+        &default_parse_context(),
+        &mut summary,
+        &mut Timings::default(),
+    )?;
+    actions.set_path_style(
+        directories::BaseDirs::new().map(|d| d.home_dir().to_owned()),
+        args.full_paths,
+    );
+    for (shown, difference) in actions.differences() {
+        match difference {
+            actions::Difference::Same if args.verbose => {
+                println!("{}", format!("{shown}: unchanged").dimmed())
+            }
+            actions::Difference::Same => {}
+            actions::Difference::Other(what) => println!("{}: {what}", shown.bold()),
+            actions::Difference::Text(lines) => {
+                println!("{}", shown.bold());
+                for line in lines {
+                    let line = match line.chars().next() {
+                        _ if line.starts_with("---") || line.starts_with("+++") => line.bold(),
+                        Some('@') => line.cyan(),
+                        Some('-') => line.red(),
+                        Some('+') => line.green(),
+                        _ => line.normal(),
+                    };
+                    println!("{line}");
+                }
+            }
+        }
+    }
+    summary.report();
+    Ok(())
+}
+
+/// List the latest deploys, or with `show` everything one of them did
+fn run_history(cmd: HistoryCmd) -> Result<()> {
+    let dir = actions::default_history_dir();
+    let records = actions::read_history(&dir)?;
+    // which files the records came from, so they can be found by hand
+    let read_from = |records: &[&actions::Record]| {
+        for config in records.iter().map(|r| &r.config).unique() {
+            log::info!(
+                "history of '{config}' is in '{}'",
+                actions::history_file(&dir, config).to_string_lossy()
+            );
+        }
+    };
+    match cmd.show {
+        Some(HistoryShow::Show { id }) => {
+            let record = records
+                .iter()
+                .find(|r| r.id == id)
+                .ok_or(Error::NoSuchDeploy(id))?;
+            read_from(&[record]);
+            println!("{}", history_line(record));
+            for entry in &record.entries {
+                println!("  {:8} {}", entry.change.name(), entry.output);
+            }
+        }
+        None if records.is_empty() => log::info!(
+            "nothing has been deployed yet, no history in '{}'",
+            dir.to_string_lossy()
+        ),
+        None => {
+            let latest = records.iter().rev().take(cmd.limit).rev().collect_vec();
+            read_from(&latest);
+            for record in latest {
+                println!("{}", history_line(record));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn history_line(record: &actions::Record) -> String {
+    format!(
+        "{}  {}  {}  {}{}",
+        record.id.bold(),
+        record.time,
+        record.config,
+        record.summary(),
+        if record.dry { " (dry run)" } else { "" }
+    )
+}
+
+/// Warn about anything in the configs of `cmd` that is probably a mistake
+fn run_lint(cmd: LintCmd) -> Result<()> {
+    let targets = if cmd.targets.is_empty() {
+        vec![PathBuf::from(".")]
+    } else {
+        cmd.targets
+    };
+    let mut warnings = 0;
+    for target in &targets {
+        let (file, cfg) = config::read(target)?
+            .ok_or_else(|| Error::TargetDoesNotExist(target.to_string_lossy().into_owned()))?;
+        let dir = file.parent().unwrap_or(Path::new("")).to_owned();
+        for warning in lint::lint(&cfg, &file, &dir) {
+            log::warn!("{warning}");
+            warnings += 1;
+        }
+    }
+    if warnings == 0 {
+        log::info!("no problems found");
+    } else if cmd.deny_warnings {
+        return Err(Error::LintWarnings(warnings));
+    }
+    Ok(())
+}
+
+/// Print each target of the config of `cmd` as it resolves here
+fn run_list(cmd: ListCmd) -> Result<()> {
+    let target = cmd.config.unwrap_or_else(|| PathBuf::from("."));
+    let (file, root) = config::read(&target)?
+        .ok_or_else(|| Error::TargetDoesNotExist(target.to_string_lossy().into_owned()))?;
+    let dir = file.parent().unwrap_or(Path::new("")).to_owned();
+    let cfg = Loaded::from_file(file, root, dir);
+    let mut engine = default_parse_context();
+    engine.set_dry_run(true);
+    cfg.define_location(&mut engine);
+    let listed = list::list(&cfg.root, &cfg.dir, &engine).map_err(|source| Error::Config {
+        path: target.to_string_lossy().into_owned(),
+        source: Box::new(source),
+    })?;
+    match cmd.format {
+        DryRunFormat::Text => {
+            for line in list::lines(&listed) {
+                println!("{line}");
+            }
+        }
+        DryRunFormat::Json => println!("{}", list::json(&listed)),
+    }
+    Ok(())
+}
+
+/// Print how each environment check went, failing if any did
+fn run_doctor() -> Result<()> {
+    let checks = doctor::run(&std::env::current_dir()?);
+    let width = checks
+        .iter()
+        .map(|c| c.name.len())
+        .max()
+        .unwrap_or_default();
+    for check in &checks {
+        let status = match check.status {
+            doctor::Status::Pass => "pass".green(),
+            doctor::Status::Warn => "warn".yellow(),
+            doctor::Status::Fail => "fail".red(),
+        };
+        println!("[{status}] {:width$}  {}", check.name, check.detail);
+        if let Some(hint) = check.hint {
+            println!("       {:width$}  {}", "", hint.dimmed());
+        }
+    }
+    let failed = checks
+        .iter()
+        .filter(|c| c.status == doctor::Status::Fail)
+        .count();
+    if failed > 0 {
+        return Err(Error::DoctorFailed(failed));
+    }
+    Ok(())
+}
+
+/// Load and plan the configs of `targets`, as given on the command line.
+/// Configs that can't be loaded are skipped and noted in `summary`
+fn plan_targets(
+    targets: &[PathBuf],
+    source_dir: Option<&Path>,
+    require_clean: bool,
+    repoint: bool,
+    template_engine: &Context,
+    summary: &mut Summary,
+    timings: &mut Timings,
+) -> Result<Actions> {
+    let (actions, mut errors) = plan_each_target(
+        targets,
+        source_dir,
+        require_clean,
+        repoint,
+        template_engine,
+        summary,
+        timings,
+    )?;
+    match errors.len() {
+        0 => Ok(actions),
+        1 => Err(errors.remove(0)),
+        n => {
+            for e in errors {
+                log::error!("{e}");
+            }
+            Err(Error::PlansFailed(n))
+        }
+    }
+}
+
+/// [`plan_targets`], but with the actions of the configs that did plan
+/// alongside why each of the others didn't
+fn plan_each_target(
+    targets: &[PathBuf],
+    source_dir: Option<&Path>,
+    require_clean: bool,
+    repoint: bool,
+    template_engine: &Context,
+    summary: &mut Summary,
+    timings: &mut Timings,
+) -> Result<(Actions, Vec<Error>)> {
+    let root_dir = abspath::canonicalize(std::env::current_dir()?)?;
+    let started = Instant::now();
+    // each config along with what it's called in errors
+    let mut loaded = Vec::new();
+    for target in targets {
+        let target_str = target.to_string_lossy();
+        if config::is_stdin(target) {
+            match Loaded::stdin(source_dir) {
+                Ok(cfg) => loaded.push((cfg, "<stdin>".to_owned())),
+                Err(e) => summary.skip(format!("failed to load config from stdin: {e}")),
+            }
+            continue;
+        }
+        if !target.exists() {
+            summary.skip(format!("path '{target_str}' does not exist"));
+            continue;
+        }
+        let target = match abspath::canonicalize(target) {
+            Ok(t) => t,
+            Err(e) => {
+                summary.skip(e.to_string());
+                continue;
+            }
+        };
+        let (cfg_file, cfg) = match config::read(&target) {
+            Ok(Some(cfg)) => cfg,
+            Ok(None) => {
+                summary.skip(format!("failed to find config file for '{target_str}'"));
+                continue;
+            }
+            Err(e) => {
+                summary.skip(format!("failed to load config at '{target_str}': {e}"));
+                continue;
+            }
+        };
+        let config_dir = root_dir.join(config::dir_of(&target).unwrap());
+        let cfg = Loaded::from_file(cfg_file, cfg, config_dir);
+        loaded.push((cfg, target_str.into_owned()));
+    }
+    timings.phase("read configs", started.elapsed());
+    // planning renders templates and runs commands, so each config gets a
+    // thread. It's named after the config so its logs say where they're from
+    let planned = std::thread::scope(|s| {
+        let threads = loaded
+            .iter()
+            .map(|(cfg, name)| {
+                std::thread::Builder::new()
+                    .name(name.clone())
+                    .spawn_scoped(s, move || {
+                        let started = Instant::now();
+                        let acts = plan_config(cfg, name, template_engine, repoint);
+                        let planned = started.elapsed();
+                        let acts = acts.and_then(|acts| {
+                            check_git(cfg, &acts, name, require_clean)?;
+                            Ok(acts)
+                        });
+                        (acts, planned, started.elapsed() - planned)
+                    })
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok::<_, Error>(
+            threads
+                .into_iter()
+                .map(|t| t.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+                .collect_vec(),
+        )
+    })?;
+    let mut actions = Actions::new();
+    let mut errors = Vec::new();
+    let mut one_by_one = Duration::ZERO;
+    for ((acts, planned, checked), (_, name)) in planned.into_iter().zip(&loaded) {
+        one_by_one += planned + checked;
+        timings.phase(format!("plan '{name}'"), planned);
+        timings.phase("check git", checked);
+        match acts {
+            Ok(mut acts) => actions.append(&mut acts),
+            Err(e) => errors.push(e),
+        }
+    }
+    log::debug!(
+        "planned {} configs in {:.2?}, {one_by_one:.2?} if planned one by one",
+        loaded.len(),
+        started.elapsed()
+    );
+    Ok((actions, errors))
+}
+
+/// Lock the configs of `actions` so no other dotloy run deploys them at the
+/// same time, if `wait` then waiting for one that does. Dry runs don't change
+/// anything so they don't need to
+fn lock_configs(actions: &Actions, dry: bool, wait: bool) -> Result<Vec<Lock>> {
+    if dry {
+        return Ok(Vec::new());
+    }
+    let dir = lock::default_dir();
+    let mut configs = actions.configs();
+    // the same order everywhere, so two runs can't each hold what the other
+    // is waiting for
+    configs.sort();
+    configs
+        .into_iter()
+        .map(|cfg| {
+            if wait {
+                Lock::wait(&dir, cfg, |pid| {
+                    log::info!("waiting for another dotloy run (pid {pid}) to finish...")
+                })
+            } else {
+                Lock::acquire(&dir, cfg)
+            }
+            .map_err(Error::from)
+        })
+        .collect()
+}
+
+/// The actions to deploy `cfg`, which is called `name` in errors. If
+/// `repoint` its links repoint stale symlinks into its directory
+fn plan_config(
+    cfg: &Loaded,
+    name: &str,
+    template_engine: &Context,
+    repoint: bool,
+) -> Result<Actions> {
+    let mut engine = template_engine.clone();
+    cfg.define_location(&mut engine);
+    let mut acts =
+        Actions::from_config_in(&cfg.root, &cfg.dir, &engine).map_err(|source| Error::Config {
+            path: name.to_owned(),
+            source: Box::new(source),
+        })?;
+    if repoint {
+        acts.repoint_links(&cfg.dir)?;
+    }
+    acts.set_config(&cfg.id);
+    Ok(acts)
+}
+
+/// Check the sources of `acts` have no uncommitted changes, if `cfg` asks
+/// for that or `required`
+fn check_git(cfg: &Loaded, acts: &Actions, name: &str, required: bool) -> Result<()> {
+    let mode = if required {
+        Some(DirtyGit::Refuse)
+    } else {
+        cfg.root.require_clean_git.mode()
+    };
+    let Some(mode) = mode else {
+        return Ok(());
+    };
+    let Some(top) = git::work_tree(&cfg.dir) else {
+        return Ok(());
+    };
+    let sources = acts.file_roots().collect::<Vec<_>>();
+    let dirty = git::uncommitted(&top, &sources.iter().map(|p| p.as_ref()).collect_vec())?;
+    if dirty.is_empty() {
+        return Ok(());
+    }
+    let files = dirty.iter().map(|p| p.to_string_lossy()).join("', '");
+    match mode {
+        DirtyGit::Warn => {
+            log::warn!("'{name}' has uncommitted changes to sources: '{files}'");
+            Ok(())
+        }
+        DirtyGit::Refuse => Err(Error::UncommittedChanges {
+            config: name.to_owned(),
+            files,
+        }),
+    }
+}
+
+/// Problems a deploy carries on past, reported together once it's done
+#[derive(Debug, Default)]
+struct Summary {
+    /// How many configs were asked for
+    configs: usize,
+    /// Why each config that was skipped was skipped
+    skipped: Vec<String>,
+    /// Actions that failed, with why
+    failed: Vec<String>,
+}
+impl Summary {
+    fn skip(&mut self, why: String) {
+        log::warn!("{why}, skipping...");
+        self.skipped.push(why);
+    }
+    fn is_clean(&self) -> bool {
+        self.skipped.is_empty() && self.failed.is_empty()
+    }
+    fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if !self.skipped.is_empty() {
+            lines.push(format!(
+                "{} of {} configs skipped: {}",
+                self.skipped.len(),
+                self.configs,
+                self.skipped.join("; ")
+            ));
+        }
+        if !self.failed.is_empty() {
+            lines.push(format!(
+                "{} action{} failed: {}",
+                self.failed.len(),
+                if self.failed.len() == 1 { "" } else { "s" },
+                self.failed.join("; ")
+            ));
+        }
+        lines
+    }
+    fn report(&self) {
+        for line in self.lines() {
+            log::warn!("{line}");
+        }
+    }
+}
+fn run_expand(cmd: ExpandCmd, cfg: Option<&Loaded>) -> Result<()> {
     let target = &cmd.target;
     if !target.exists() {
         return Err(Error::TargetDoesNotExist(
             target.to_string_lossy().into_owned(),
         ));
     }
+    let file = AbsPathBuf::new(target)?;
+    let from_files = config::read_variables(&cmd.var_files)?;
     let mut engine = default_parse_context();
-    if let Some(cfg) = cfg {
+    let mut undefined = None;
+    let mut render_depth = 1;
+    if let Some(loaded) = cfg {
+        let cfg = &loaded.root;
+        let config_dir = loaded.dir.as_path();
+        let in_config = |source| Error::Config {
+            path: loaded
+                .file
+                .as_ref()
+                .map_or("<stdin>".into(), |f| f.to_string_lossy().into_owned()),
+            source: Box::new(source),
+        };
+        loaded.define_location(&mut engine);
+        if cfg.allow_shell {
+            engine.enable_shell();
+        }
+        if let Some(dir) = &cfg.partials {
+            engine.set_partials_dir(config_dir.join(dir));
+        }
+        engine.set_base_dir(config_dir.to_owned());
+        apply_xdg_fallbacks(&mut engine, cfg)?;
+        let mut variables = cfg.shared.variables.clone();
+        override_variables(&mut variables, from_files, cfg.allow_shadowing);
         define_variables(
             &mut engine,
             &vars::config_level(),
-            cfg.shared.variables.iter(),
-        )?;
-        if let Some(target) = cfg.targets.iter().find(|t| {
-            t.path
-                .render(&engine)
-                .map(|p| p == cmd.target.to_string_lossy().as_ref())
-                .unwrap_or(false)
-        }) {
+            Layer::Config,
+            variables.iter(),
+            cfg.allow_shadowing,
+        )
+        .map_err(|e| in_config(actions::variables_error(None, e)))?;
+        let selected = match &cmd.as_target {
+            Some(name) => Some(named_target(cfg, &engine, config_dir, name)?),
+            None => expand_target(cfg, &engine, config_dir, target, &file)?,
+        };
+        if let Some(target) = selected {
             define_variables(
                 &mut engine,
                 &vars::target_level(),
+                Layer::Target,
                 target.shared.variables.iter(),
-            )?;
+                cfg.allow_shadowing,
+            )
+            .map_err(|e| in_config(actions::variables_error(Some(target.label()), e)))?;
+            let to = target
+                .target_location
+                .render(&engine)
+                .and_then(|to| engine.path(&to).map(|_| to))
+                .map_err(|source| {
+                    in_config(actions::Error::TemplateField {
+                        target: target.label().to_owned(),
+                        field: "to",
+                        source,
+                    })
+                })?;
+            actions::define_target_builtins(&mut engine, target, &cmd.target, to.as_ref())?;
+            undefined = Some(target.undefined);
+            render_depth = target.render_depth;
         }
+    } else {
+        define_variables(
+            &mut engine,
+            &vars::config_level(),
+            Layer::CommandLine,
+            from_files.iter(),
+            false,
+        )
+        .map_err(|e| actions::variables_error(None, e))?;
+    }
+    engine.set_undefined(
+        cmd.undefined
+            .map(Undefined::from)
+            .or(undefined)
+            .unwrap_or_default(),
+    );
+    engine.set_render_depth(render_depth);
+    let content = std::fs::read_to_string(&file)?;
+    if cmd.check {
+        engine
+            .check(&content)
+            .map_err(|source| actions::Error::TemplateIn {
+                file: target.to_string_lossy().into_owned(),
+                source,
+            })?;
+        log::info!(
+            "all variables used by '{}' are defined",
+            target.to_string_lossy()
+        );
+        return Ok(());
     }
-    let content = std::fs::read_to_string(target)?;
-    let rendered = engine.render(&content)?;
+    let rendered =
+        engine
+            .render_file(&content, &file)
+            .map_err(|source| actions::Error::TemplateIn {
+                file: target.to_string_lossy().into_owned(),
+                source,
+            })?;
     match cmd.output {
         Some(p) => {
             write!(std::fs::File::create(p)?, "{}", rendered)?;
@@ -245,24 +1191,78 @@ enum Error {
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
-    Parse(#[from] serde_yaml::Error),
+    Path(#[from] abspath::Error),
+    #[error(transparent)]
+    Read(#[from] config::ReadError),
+    #[error(transparent)]
+    Lock(#[from] lock::Error),
+    #[error(transparent)]
+    Import(#[from] import::Error),
+    #[error(transparent)]
+    Git(#[from] git::Error),
+    #[error(transparent)]
+    Plan(#[from] plan::Error),
+    #[error("'{config}' has uncommitted changes to sources, commit or stash them before deploying: '{files}'")]
+    UncommittedChanges { config: String, files: String },
+    #[error("'{0}' already exists, move it out of the way or use --dry-run to print the config")]
+    ImportedConfigExists(String),
+    #[error("'{0}' already exists, use --force to overwrite it")]
+    ConfigExists(String),
+    #[error(transparent)]
+    Add(#[from] add::Error),
     #[error(transparent)]
     Action(#[from] actions::Error),
+    #[error("in config '{path}': {source}")]
+    Config {
+        path: String,
+        source: Box<actions::Error>,
+    },
     #[error(transparent)]
-    Template(#[from] handybars::Error),
+    Template(#[from] template::Error),
+    #[error(
+        "'{file}' is deployed by several targets ('{targets}'), name it the way one of them does"
+    )]
+    AmbiguousExpandTarget { file: String, targets: String },
+    #[error("config has no target '{name}', it has '{available}'")]
+    UnknownExpandTarget { name: String, available: String },
     #[error("Target does not exist '{0}'")]
     TargetDoesNotExist(String),
     #[error("Shell is not supported for completions")]
     UnsupportedShell,
+    #[error("some configs were skipped or actions failed, and --strict was given")]
+    Strict,
+    #[error("{0} verifications failed")]
+    VerificationsFailed(usize),
+    #[error("no deploy with id '{0}' in the history")]
+    NoSuchDeploy(String),
+    #[error("{0} lint warnings, and --deny-warnings was given")]
+    LintWarnings(usize),
+    #[error("deploying '{0}' failed")]
+    DeployFailed(String),
+    #[error("{0} checks failed")]
+    DoctorFailed(usize),
+    #[error("some sources don't match their sha256 or couldn't be checked")]
+    ChecksFailed,
+    #[error("{0} actions aren't deployed")]
+    NotDeployed(usize),
+    #[error("{0} configs were skipped, so not everything could be checked")]
+    ConfigsSkipped(usize),
+    #[error("a config read from stdin can't be watched, write it to a file to use --watch")]
+    WatchStdin,
+    #[error("stdin (`-`) was given as a config more than once, it can only be read once")]
+    StdinTwice,
+    #[error(
+        "`--targets-from -` reads the targets from stdin, so a config can't be read from it too"
+    )]
+    StdinListAndConfig,
+    #[error("{0} configs failed to plan")]
+    PlansFailed(usize),
     #[error("Watch error '{0}'")]
     Watch(#[from] notify::Error),
 }
-#[cfg(test)]
-fn test_data_path() -> &'static std::path::Path {
-    "./test_data".as_ref()
-}
-
-fn init_logging(level: log::LevelFilter) {
+/// Set up logging at `level`, errors to stderr and the rest to stdout
+/// unless it's `all_to_stderr`, as when stdout is for JSON
+fn init_logging(level: log::LevelFilter, all_to_stderr: bool) {
     fn colour_for_level(level: log::Level) -> Color {
         match level {
             log::Level::Error => Color::Red,
@@ -276,80 +1276,347 @@ fn init_logging(level: log::LevelFilter) {
         .level_for(env!("CARGO_PKG_NAME"), level)
         .level(log::LevelFilter::Off)
         .format(|out, msg, record| {
+            // configs are planned on threads named after them
+            let thread = std::thread::current();
+            let msg = match thread.name().filter(|n| *n != "main") {
+                Some(config) => format!("{config}: {msg}"),
+                None => msg.to_string(),
+            };
             if record.target() == "dotloy::actions" {
                 out.finish(format_args!(
                     "{}",
-                    msg.to_string().color(colour_for_level(record.level()))
+                    msg.color(colour_for_level(record.level()))
                 ))
             } else {
                 out.finish(format_args!(
                     "[{src}]: {msg}",
                     src = record.target(),
-                    msg = msg.to_string().color(colour_for_level(record.level()))
+                    msg = msg.color(colour_for_level(record.level()))
                 ))
             }
         })
         .chain(
             fern::Dispatch::new()
                 .level(log::LevelFilter::Error)
-                .chain(std::io::stderr()),
+                .chain(Box::new(progress::AroundBar(std::io::stderr())) as Box<dyn Write + Send>),
         )
         .chain(
             fern::Dispatch::new()
                 .filter(|m| m.level() > log::Level::Error)
-                .chain(std::io::stdout()),
+                .chain(if all_to_stderr {
+                    Box::new(progress::AroundBar(std::io::stderr())) as Box<dyn Write + Send>
+                } else {
+                    Box::new(progress::AroundBar(std::io::stdout()))
+                }),
         )
         .apply()
         .expect("failed to init logging");
 }
 
-const DOTLOY_CFG_NAMES: [&str; 2] = ["dotloy.yaml", "dotloy.yml"];
-fn find_config_in_dir(dir: &Path) -> Option<PathBuf> {
-    assert!(dir.is_dir(), "tried to find config in non-directory");
-    DOTLOY_CFG_NAMES
-        .into_iter()
-        .map(Path::new)
-        .map(|p| dir.join(p))
-        .find(|c| c.exists())
+/// The target of `cfg` that deploys `file`, which was given as `arg`. If there
+/// are several then the one whose `from` is written the same way as `arg` is
+/// picked
+fn expand_target<'c>(
+    cfg: &'c Root,
+    engine: &Context,
+    config_dir: &Path,
+    arg: &Path,
+    file: &AbsPathBuf,
+) -> Result<Option<&'c config::Target>> {
+    let matches = cfg
+        .targets
+        .iter()
+        .filter_map(|t| {
+            let from = t.path.render(engine).ok().filter(|p| !p.is_empty())?;
+            let resolved = AbsPathBuf::new_in(config_dir, &from).ok()?;
+            (resolved == *file).then_some((t, from))
+        })
+        .collect::<Vec<_>>();
+    match matches.as_slice() {
+        [] => Ok(None),
+        [(t, _)] => Ok(Some(t)),
+        _ => {
+            let exact = matches
+                .iter()
+                .filter(|(_, from)| Path::new(from) == arg)
+                .collect::<Vec<_>>();
+            match exact.as_slice() {
+                [(t, _)] => Ok(Some(t)),
+                _ => Err(Error::AmbiguousExpandTarget {
+                    file: arg.to_string_lossy().into_owned(),
+                    targets: matches.iter().map(|(t, _)| t.label()).join("', '"),
+                }),
+            }
+        }
+    }
 }
-fn resolve_config_dir(p: &Path) -> Option<&Path> {
-    if p.is_dir() {
-        Some(p)
-    } else {
-        p.parent()
+
+/// The target of `cfg` picked by `expand --target`, by its `name`, or by its
+/// `from` as written or as the file it resolves to
+fn named_target<'c>(
+    cfg: &'c Root,
+    engine: &Context,
+    config_dir: &Path,
+    name: &str,
+) -> Result<&'c config::Target> {
+    cfg.targets
+        .iter()
+        .find(|t| t.name.as_deref() == Some(name))
+        .or_else(|| cfg.targets.iter().find(|t| t.path.source() == name))
+        .or_else(|| {
+            let file = AbsPathBuf::new_in(config_dir, name).ok()?;
+            expand_target(cfg, engine, config_dir, Path::new(name), &file)
+                .ok()
+                .flatten()
+        })
+        .ok_or_else(|| Error::UnknownExpandTarget {
+            name: name.to_owned(),
+            available: cfg
+                .targets
+                .iter()
+                .map(|t| t.name.as_deref().unwrap_or(t.label()))
+                .join("', '"),
+        })
+}
+
+/// Write a starter config to the directory of `cmd`, with a target for each
+/// of its `from`s
+fn run_init(cmd: InitCmd) -> Result<()> {
+    let dir = cmd.dir.unwrap_or_else(|| PathBuf::from("."));
+    let existing = config::FILE_NAMES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|file| file.exists());
+    if let Some(file) = existing.as_ref().filter(|_| !cmd.force) {
+        return Err(Error::ConfigExists(file.to_string_lossy().into_owned()));
+    }
+    let targets = cmd
+        .from
+        .iter()
+        .map(|from| match from.split_once('=') {
+            Some((from, to)) => (from.to_owned(), Some(to.to_owned())),
+            None => (from.to_owned(), None),
+        })
+        .collect::<Vec<_>>();
+    for (from, _) in &targets {
+        if !dir.join(from).exists() {
+            log::warn!("'{from}' doesn't exist in '{}' yet", dir.to_string_lossy());
+        }
     }
+    let text = init::starter(&targets)?;
+    fs::create_dir_all(&dir)?;
+    // overwriting a dotloy.yml rather than writing a dotloy.yaml next to it
+    let file = existing.unwrap_or_else(|| dir.join(config::FILE_NAMES[0]));
+    fs::write(&file, text)?;
+    log::info!(
+        "wrote a config with {} targets to '{}'",
+        targets.len(),
+        file.to_string_lossy()
+    );
+    Ok(())
 }
 
-fn read_config(p: &Path) -> Result<Option<Root>> {
-    let p = if p.is_dir() {
-        find_config_in_dir(p)
-    } else {
-        Some(p.to_owned())
+/// Add the target `cmd` describes to the end of its config, and deploy it if
+/// it asks
+fn run_add(cmd: AddCmd) -> Result<()> {
+    let at = cmd.config.unwrap_or_else(|| PathBuf::from("."));
+    let (file, cfg) = config::read(&at)?
+        .ok_or_else(|| Error::TargetDoesNotExist(at.to_string_lossy().into_owned()))?;
+    let dir = file.parent().unwrap_or(Path::new("")).to_owned();
+    let source = dir.join(&cmd.from);
+    // templated sources can only be checked once they're rendered
+    if !cmd.from.contains("{{") && !source.exists() {
+        return Err(actions::Error::SourceDoesNotExist {
+            path: source.to_string_lossy().into_owned(),
+        }
+        .into());
+    }
+    let mut target = Target::new(cmd.from, cmd.to);
+    if let Some(link) = cmd.link {
+        target.link_type = match link {
+            AddLink::Soft => DeployType::Link(LinkType::Soft),
+            AddLink::Hard => DeployType::Link(LinkType::Hard),
+            AddLink::Copy => DeployType::Copy,
+        };
+    }
+    if cmd.template {
+        target.is_template = Some(true);
+    }
+    if cfg.targets.contains(&target) {
+        log::warn!(
+            "'{}' already has a target deploying '{}' to '{}' like this",
+            file.to_string_lossy(),
+            target.path.source(),
+            target.target_location.source()
+        );
+    }
+    let text = add::with_target(&fs::read_to_string(&file)?, cfg, target.clone())?;
+    fs::write(&file, text)?;
+    log::info!("added '{}' to '{}'", target.label(), file.to_string_lossy());
+    if !cmd.deploy {
+        return Ok(());
+    }
+    deploy_added(&file, &target)
+}
+
+/// Deploy `target`, just added to the config `file`
+fn deploy_added(file: &Path, target: &Target) -> Result<()> {
+    // read again, so what's deployed is what was written
+    let (file, mut cfg) = config::read(file)?
+        .ok_or_else(|| Error::TargetDoesNotExist(file.to_string_lossy().into_owned()))?;
+    cfg.targets.retain(|t| t == target);
+    let name = file.to_string_lossy().into_owned();
+    let dir = file.parent().unwrap_or(Path::new("")).to_owned();
+    let loaded = Loaded::from_file(file, cfg, dir);
+    let mut actions = plan_config(&loaded, &name, &default_parse_context(), false)?;
+    actions.set_path_style(
+        directories::BaseDirs::new().map(|d| d.home_dir().to_owned()),
+        false,
+    );
+    actions.record_history(actions::default_history_dir());
+    let locks = lock_configs(&actions, false, false)?;
+    let mut summary = Summary {
+        configs: 1,
+        ..Default::default()
     };
-    p.map(|p| {
-        let cfg = serde_yaml::from_reader(BufReader::new(fs::File::open(p)?))?;
-        Ok(cfg)
-    })
-    .transpose()
+    summary.failed = actions.run(false)?;
+    drop(locks);
+    summary.report();
+    if !summary.failed.is_empty() {
+        return Err(Error::DeployFailed(target.label().to_owned()));
+    }
+    Ok(())
+}
+
+/// Move what's deployed at `cmd.deployed` into the config's directory, add
+/// a target deploying it back there and deploy it
+fn run_adopt(cmd: AdoptCmd) -> Result<()> {
+    let at = cmd.config.unwrap_or_else(|| PathBuf::from("."));
+    let (file, cfg) = config::read(&at)?
+        .ok_or_else(|| Error::TargetDoesNotExist(at.to_string_lossy().into_owned()))?;
+    let dir = file.parent().unwrap_or(Path::new("")).to_owned();
+    let deployed = AbsPathBuf::logical(&cmd.deployed)?;
+    let name = deployed
+        .file_name()
+        .ok_or_else(|| Error::TargetDoesNotExist(deployed.to_string_lossy().into_owned()))?;
+    let from = cmd.into.unwrap_or_default().join(name);
+    // written the way a config would, so it deploys on other machines too
+    let ctx = default_parse_context();
+    let to = ["xdg.config", "xdg.home"]
+        .into_iter()
+        .find_map(|var| {
+            let base = ctx.render(&format!("{{{{ {var} }}}}")).ok()?;
+            let rel = deployed.strip_prefix(base).ok()?;
+            Some(format!("{{{{ {var} }}}}/{}", import::slashed(rel)))
+        })
+        .unwrap_or_else(|| deployed.to_string_lossy().into_owned());
+    let target = Target::new(import::slashed(&from), to);
+    let old = fs::read_to_string(&file)?;
+    let text = add::with_target(&old, cfg, target.clone())?;
+    // checked before anything is moved, so a failure leaves everything as it was
+    config::parse(&text).map_err(|source| config::ReadError::Parse {
+        path: file.clone(),
+        source,
+    })?;
+    let into = dir.join(&from);
+    let moved = add::move_into(&deployed, &into)?;
+    log::info!(
+        "moved '{}' to '{}'",
+        deployed.to_string_lossy(),
+        into.to_string_lossy()
+    );
+    let adopted = fs::write(&file, &text).map_err(Error::from).and_then(|()| {
+        log::info!("added '{}' to '{}'", target.label(), file.to_string_lossy());
+        deploy_added(&file, &target)
+    });
+    if let Err(e) = adopted {
+        log::warn!(
+            "couldn't deploy '{}', moving it back to '{}'",
+            into.to_string_lossy(),
+            deployed.to_string_lossy()
+        );
+        add::move_back(&deployed, &into, moved)?;
+        fs::write(&file, old)?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn run_import_stow(cmd: ImportStowCmd) -> Result<()> {
+    let home = directories::BaseDirs::new().map(|d| d.home_dir().to_owned());
+    let (to, existing) = match &cmd.target {
+        Some(dir) => (dir.to_owned(), Some(PathBuf::from(dir))),
+        None => ("{{ xdg.home }}".to_owned(), home),
+    };
+    let cfg = import::stow::import(&cmd.stow_dir, &cmd.packages, &to, existing.as_deref())?;
+    write_imported(&cfg, &cmd.stow_dir.join(config::FILE_NAMES[0]), cmd.dry_run)
+}
+
+fn run_import_chezmoi(cmd: ImportChezmoiCmd) -> Result<()> {
+    let imported = import::chezmoi::import(&cmd.source_dir, "{{ xdg.home }}")?;
+    write_imported(
+        &imported.config,
+        &cmd.source_dir.join(config::FILE_NAMES[0]),
+        cmd.dry_run,
+    )?;
+    if !imported.not_imported.is_empty() {
+        log::warn!("not imported:\n  {}", imported.not_imported.join("\n  "));
+    }
+    Ok(())
+}
+
+/// Write a config made by an import to `file`, or print it if `dry_run`
+fn write_imported(cfg: &Root, file: &Path, dry_run: bool) -> Result<()> {
+    let text = import::to_yaml(cfg)?;
+    if dry_run {
+        print!("{text}");
+        return Ok(());
+    }
+    if file.exists() {
+        return Err(Error::ImportedConfigExists(
+            file.to_string_lossy().into_owned(),
+        ));
+    }
+    fs::write(file, text)?;
+    log::info!(
+        "wrote {} targets to '{}'",
+        cfg.targets.len(),
+        file.to_string_lossy()
+    );
+    Ok(())
 }
 
 fn run() -> Result<()> {
     let args = Args::parse();
-    init_logging(args.log_level);
+    init_logging(
+        args.log_level,
+        matches!(&args.cmd, args::Command::Deploy(d) if d.format == DryRunFormat::Json),
+    );
     match args.cmd {
         args::Command::Expand(cmd) => {
-            let cfg = cmd
-                .config
-                .as_ref()
-                .map(|c| read_config(c))
-                .transpose()?
-                .flatten();
-            if let Some(p) = &cmd.config {
-                std::env::set_current_dir(resolve_config_dir(p).unwrap())?;
-            }
+            let cfg = match &cmd.config {
+                Some(c) if config::is_stdin(c) => Some(Loaded::stdin(cmd.source_dir.as_deref())?),
+                Some(c) => config::read(c)?.map(|(file, root)| {
+                    let dir = file.parent().unwrap_or(Path::new("")).to_owned();
+                    Loaded::from_file(file, root, dir)
+                }),
+                None => None,
+            };
             run_expand(cmd, cfg.as_ref())
         }
         args::Command::Deploy(cmd) => run_deploy(cmd),
+        args::Command::Check(cmd) => run_check(cmd),
+        args::Command::Status(cmd) => run_status(cmd),
+        args::Command::Diff(cmd) => run_diff(cmd),
+        args::Command::Lint(cmd) => run_lint(cmd),
+        args::Command::List(cmd) => run_list(cmd),
+        args::Command::Doctor => run_doctor(),
+        args::Command::History(cmd) => run_history(cmd),
+        args::Command::Init(cmd) => run_init(cmd),
+        args::Command::Add(cmd) => run_add(cmd),
+        args::Command::Adopt(cmd) => run_adopt(cmd),
+        args::Command::ImportStow(cmd) => run_import_stow(cmd),
+        args::Command::ImportChezmoi(cmd) => run_import_chezmoi(cmd),
         args::Command::GenerateShellCompletions => {
             let shell = clap_complete::Shell::from_env().ok_or(Error::UnsupportedShell)?;
             let mut cmd = Args::command();
@@ -368,3 +1635,172 @@ fn main() {
         exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use std::path::Path;
+
+    use fs_err as fs;
+    use tempdir::TempDir;
+
+    use crate::{
+        abspath::{self, AbsPathBuf},
+        config::{Root, Target},
+        default_parse_context, dependents_of_changes, expand_target, is_config_change,
+        named_target, parse_target_list, run_on_change, Actions, Context, Error, Summary,
+    };
+
+    #[test]
+    fn expand_finds_the_target_however_the_file_is_written() {
+        let dir = AbsPathBuf::new(".").unwrap();
+        let engine = Context::new().with_define("config.data".parse().unwrap(), "test_data");
+        let mut cfg = Root::default();
+        cfg.targets.push(Target::new(
+            "./test_data/actions_with_test_data.in".to_owned(),
+            "a".to_owned(),
+        ));
+        let find = |cfg: &Root, arg: &str| {
+            let file = AbsPathBuf::new(arg).unwrap();
+            expand_target(cfg, &engine, &dir, Path::new(arg), &file)
+                .map(|t| t.map(|t| t.target_location.source().to_owned()))
+        };
+        let abs = dir.join("test_data/actions_with_test_data.in");
+        for arg in [
+            "test_data/actions_with_test_data.in",
+            abs.to_str().unwrap(),
+            "src/../test_data/actions_with_test_data.in",
+        ] {
+            assert_eq!(find(&cfg, arg).unwrap().as_deref(), Some("a"), "{arg}");
+        }
+        assert_eq!(find(&cfg, "test_data/copying.yaml").unwrap(), None);
+
+        cfg.targets.push(Target::new(
+            "{{ config.data }}/actions_with_test_data.in".to_owned(),
+            "b".to_owned(),
+        ));
+        assert_eq!(
+            find(&cfg, "./test_data/actions_with_test_data.in")
+                .unwrap()
+                .as_deref(),
+            Some("a")
+        );
+        assert_matches!(
+            find(&cfg, abs.to_str().unwrap()),
+            Err(Error::AmbiguousExpandTarget { .. })
+        );
+    }
+
+    #[test]
+    fn expand_can_pick_a_target_by_name_or_from() {
+        let dir = AbsPathBuf::new(".").unwrap();
+        let engine = Context::new();
+        let mut cfg = Root::default();
+        let mut named = Target::new("{{ config.x }}".to_owned(), "a".to_owned());
+        named.name = Some("work-laptop".to_owned());
+        cfg.targets.push(named);
+        cfg.targets.push(Target::new(
+            "./test_data/copying.yaml".to_owned(),
+            "b".to_owned(),
+        ));
+        let pick = |name| {
+            named_target(&cfg, &engine, &dir, name).map(|t| t.target_location.source().to_owned())
+        };
+        assert_eq!(pick("work-laptop").unwrap(), "a");
+        assert_eq!(pick("{{ config.x }}").unwrap(), "a");
+        assert_eq!(pick("./test_data/copying.yaml").unwrap(), "b");
+        assert_eq!(pick("test_data/../test_data/copying.yaml").unwrap(), "b");
+        assert_eq!(
+            pick("home-desktop").unwrap_err().to_string(),
+            "config has no target 'home-desktop', it has 'work-laptop', './test_data/copying.yaml'"
+        );
+    }
+
+    #[test]
+    fn watched_changes_nothing_depends_on_are_not_redeployed() {
+        let dir = TempDir::new("watch").unwrap();
+        fs::write(dir.path().join("colors.conf.in"), "bg = black").unwrap();
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+        let mut cfg = Root::default();
+        cfg.targets.push(Target::new(
+            "colors.conf.in".to_owned(),
+            dir.path()
+                .join("colors.conf")
+                .to_string_lossy()
+                .into_owned(),
+        ));
+        let actions = Actions::from_config_in(&cfg, dir.path(), &default_parse_context()).unwrap();
+        let changed = |name: &str| dependents_of_changes(&actions, &[dir.path().join(name)]);
+        assert!(changed("notes.txt").is_none());
+        assert!(changed("colors.conf").is_none());
+        assert!(!changed("colors.conf.in").unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn on_change_commands_see_what_changed_and_failures_dont_stop_them() {
+        let dir = TempDir::new("on_change").unwrap();
+        let out = dir.path().join("changed");
+        run_on_change(
+            &[
+                "exit 1".to_owned(),
+                format!("printf '%s' \"$DOTLOY_CHANGED\" > '{}'", out.display()),
+            ],
+            &["/a".to_owned(), "/b".to_owned()],
+        );
+        assert_eq!(fs::read_to_string(out).unwrap(), "/a\n/b");
+    }
+
+    #[test]
+    fn target_lists_skip_comments_and_are_relative_to_the_list() {
+        let list = "# work laptop\napps/nvim\n\n  apps/kitty/dotloy.yaml  \n/etc/dotloy\n";
+        assert_eq!(
+            parse_target_list(list, Path::new("/repo/roles")),
+            [
+                Path::new("/repo/roles/apps/nvim"),
+                Path::new("/repo/roles/apps/kitty/dotloy.yaml"),
+                Path::new("/etc/dotloy"),
+            ]
+        );
+    }
+
+    #[test]
+    fn changes_to_configs_and_targets_are_config_changes() {
+        let dir = TempDir::new("config_change").unwrap();
+        let root = abspath::canonicalize(dir.path()).unwrap();
+        let targets = [root.join("app"), root.join("other.yaml")];
+        assert!(is_config_change(&targets, &[root.join("app/dotloy.yaml")]));
+        assert!(is_config_change(&targets, &[root.join("dotloy.yml")]));
+        assert!(is_config_change(&targets, &[root.join("other.yaml")]));
+        // a target created after watching started
+        assert!(is_config_change(&targets, &[root.join("app")]));
+        assert!(!is_config_change(&targets, &[root.join("app/zshrc")]));
+    }
+
+    #[test]
+    fn summary_counts_skipped_configs_and_failed_actions() {
+        let mut summary = Summary {
+            configs: 5,
+            ..Default::default()
+        };
+        assert!(summary.is_clean());
+        assert!(summary.lines().is_empty());
+        summary.skip("path 'a' does not exist".to_owned());
+        summary.skip("failed to find config file for 'b'".to_owned());
+        summary.failed.push("mkdir c: denied".to_owned());
+        assert!(!summary.is_clean());
+        assert_eq!(
+            summary.lines(),
+            [
+                "2 of 5 configs skipped: path 'a' does not exist; failed to find config file for 'b'",
+                "1 action failed: mkdir c: denied",
+            ]
+        );
+        summary.failed.push("link d: exists".to_owned());
+        assert_eq!(
+            summary.lines()[1],
+            "2 actions failed: mkdir c: denied; link d: exists"
+        );
+    }
+}
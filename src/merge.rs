@@ -0,0 +1,432 @@
+//! Merging a JSON or YAML document into one that already exists, for files
+//! other programs also write to
+use std::borrow::Cow;
+
+use serde_yaml::Value;
+use thiserror::Error;
+
+use crate::config::{ArrayMerge, MergeFormat};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to parse '{file}': {source}")]
+    Parse {
+        file: String,
+        source: serde_yaml::Error,
+    },
+    #[error("'{file}' can't be written as JSON: {reason}")]
+    NotJson { file: String, reason: String },
+    #[error("failed to write '{file}': {source}")]
+    Write {
+        file: String,
+        source: serde_yaml::Error,
+    },
+}
+
+/// A document as it was and as it is with something merged into it
+pub struct Merged {
+    pub before: Value,
+    pub after: Value,
+    /// Whether the existing document is JSON with comments, which writing
+    /// it drops
+    pub had_comments: bool,
+}
+
+impl Merged {
+    /// Merge `source`, the text of `source_name`, into `existing`, the text
+    /// of `file`. JSON is parsed as the YAML it is, once it has no comments
+    pub fn new(
+        existing: &str,
+        file: &str,
+        source: &str,
+        source_name: &str,
+        arrays: ArrayMerge,
+    ) -> Result<Self, Error> {
+        let parse = |text: &str, file: &str| {
+            if text.trim().is_empty() {
+                return Ok((Value::Null, false));
+            }
+            let (text, had_comments) = plain_json(text);
+            let value = serde_yaml::from_str(&text).map_err(|source| Error::Parse {
+                file: file.to_owned(),
+                source,
+            })?;
+            Ok((value, had_comments))
+        };
+        let (before, had_comments) = parse(existing, file)?;
+        let mut after = before.clone();
+        merge(&mut after, parse(source, source_name)?.0, arrays);
+        Ok(Self {
+            before,
+            after,
+            had_comments,
+        })
+    }
+    pub fn changed(&self) -> bool {
+        self.before != self.after
+    }
+    /// The merged document as `format`, laid out like `existing` where
+    /// possible
+    pub fn write(&self, existing: &str, file: &str, format: MergeFormat) -> Result<String, Error> {
+        let mut text = match format {
+            MergeFormat::Json => {
+                json(&self.after, Some(&indent_of(existing)), 0).map_err(|reason| {
+                    Error::NotJson {
+                        file: file.to_owned(),
+                        reason,
+                    }
+                })?
+            }
+            MergeFormat::Yaml => {
+                serde_yaml::to_string(&self.after).map_err(|source| Error::Write {
+                    file: file.to_owned(),
+                    source,
+                })?
+            }
+        };
+        if !text.ends_with('\n') && (existing.is_empty() || existing.ends_with('\n')) {
+            text.push('\n');
+        }
+        Ok(text)
+    }
+    /// What merging changes, a line per key that is added or set to
+    /// something else
+    pub fn changes(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        changes(&self.before, &self.after, "", &mut lines);
+        lines
+    }
+}
+
+/// Merge `from` into `into`, key by key for mappings. Anything else in
+/// `from` replaces what's in `into`, except sequences when `arrays` says
+/// otherwise
+pub fn merge(into: &mut Value, from: Value, arrays: ArrayMerge) {
+    match (into, from) {
+        (Value::Mapping(into), Value::Mapping(from)) => {
+            for (key, value) in from {
+                match into.get_mut(&key) {
+                    Some(existing) => merge(existing, value, arrays),
+                    None => {
+                        into.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(into), Value::Sequence(from)) if arrays == ArrayMerge::Union => {
+            for item in from {
+                if !into.contains(&item) {
+                    into.push(item);
+                }
+            }
+        }
+        (into, from) => *into = from,
+    }
+}
+
+fn changes(before: &Value, after: &Value, path: &str, lines: &mut Vec<String>) {
+    let shown = |v: &Value| json(v, None, 0).unwrap_or_else(|_| format!("{v:?}"));
+    let Value::Mapping(after) = after else {
+        if before != after {
+            lines.push(format!(
+                "~ {}: {} -> {}",
+                display_path(path),
+                shown(before),
+                shown(after)
+            ));
+        }
+        return;
+    };
+    let Value::Mapping(before) = before else {
+        lines.push(format!(
+            "~ {}: {} -> {}",
+            display_path(path),
+            shown(before),
+            shown(&Value::Mapping(after.clone()))
+        ));
+        return;
+    };
+    for (key, value) in after {
+        let key_name = key.as_str().map_or_else(|| shown(key), str::to_owned);
+        let path = match path {
+            "" => key_name,
+            _ => format!("{path}.{key_name}"),
+        };
+        match before.get(key) {
+            Some(old) => changes(old, value, &path, lines),
+            None => lines.push(format!("+ {path}: {}", shown(value))),
+        }
+    }
+}
+
+/// `text` without what JSON with comments, as VSCode writes it, has that
+/// YAML doesn't allow: `//` and `/* */` comments, commas before a closing
+/// bracket and tabs between tokens. Each is replaced by spaces, so errors
+/// point at the same place. Also whether there were any comments. Text that
+/// doesn't start like JSON is left alone, as YAML has comments of its own
+fn plain_json(text: &str) -> (Cow<'_, str>, bool) {
+    if !text.trim_start().starts_with(['{', '[']) {
+        return (Cow::Borrowed(text), false);
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut comments = false;
+    let mut chars = text.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                '\\' => out.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        // not in the middle of a token, like the unquoted URL YAML allows
+        let between = out
+            .chars()
+            .next_back()
+            .is_none_or(|p| p.is_whitespace() || "{[,".contains(p));
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                out.push(c);
+            }
+            ('/', Some('/')) if between => {
+                comments = true;
+                out.push(' ');
+                while chars.next_if(|c| *c != '\n').is_some() {
+                    out.push(' ');
+                }
+            }
+            ('/', Some('*')) if between => {
+                comments = true;
+                chars.next();
+                out.push_str("  ");
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    out.push(if c == '\n' { c } else { ' ' });
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            ('}' | ']', _) => {
+                let end = out.trim_end().len();
+                if out[..end].ends_with(',') {
+                    out.replace_range(end - 1..end, " ");
+                }
+                out.push(c);
+            }
+            ('\t', _) => out.push(' '),
+            _ => out.push(c),
+        }
+    }
+    (Cow::Owned(out), comments)
+}
+
+fn display_path(path: &str) -> &str {
+    match path {
+        "" => "(document)",
+        _ => path,
+    }
+}
+
+/// Indentation of the first indented line of `text`, two spaces if none is
+fn indent_of(text: &str) -> String {
+    text.lines()
+        .map(|l| &l[..l.len() - l.trim_start().len()])
+        .find(|i| !i.is_empty())
+        .unwrap_or("  ")
+        .to_owned()
+}
+
+/// `value` as JSON, over several lines indented by `indent` or on one line
+/// if there's no indent
+fn json(value: &Value, indent: Option<&str>, depth: usize) -> Result<String, String> {
+    let (newline, inner, outer) = match indent {
+        Some(indent) => ("\n", indent.repeat(depth + 1), indent.repeat(depth)),
+        None => ("", String::new(), String::new()),
+    };
+    let sep = if indent.is_some() { ": " } else { ":" };
+    Ok(match value {
+        Value::Null => "null".to_owned(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) if n.as_f64().is_some_and(|f| !f.is_finite()) => {
+            return Err(format!("{n} isn't a JSON number"))
+        }
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => json_string(s),
+        Value::Sequence(items) if items.is_empty() => "[]".to_owned(),
+        Value::Sequence(items) => {
+            let items = items
+                .iter()
+                .map(|i| Ok(format!("{inner}{}", json(i, indent, depth + 1)?)))
+                .collect::<Result<Vec<_>, String>>()?;
+            format!(
+                "[{newline}{}{newline}{outer}]",
+                items.join(&format!(",{newline}"))
+            )
+        }
+        Value::Mapping(map) if map.is_empty() => "{}".to_owned(),
+        Value::Mapping(map) => {
+            let entries = map
+                .iter()
+                .map(|(k, v)| {
+                    let key = match k {
+                        Value::String(s) => s.clone(),
+                        Value::Bool(_) | Value::Number(_) => json(k, None, 0)?,
+                        _ => {
+                            return Err("only strings, numbers and booleans can be keys".to_owned())
+                        }
+                    };
+                    Ok(format!(
+                        "{inner}{}{sep}{}",
+                        json_string(&key),
+                        json(v, indent, depth + 1)?
+                    ))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            format!(
+                "{{{newline}{}{newline}{outer}}}",
+                entries.join(&format!(",{newline}"))
+            )
+        }
+        Value::Tagged(tagged) => return Err(format!("JSON has no tags like {}", tagged.tag)),
+    })
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Merged;
+    use crate::config::{ArrayMerge, MergeFormat};
+
+    const SETTINGS: &str = "{\n    \"editor.fontSize\": 12,\n    \"files.exclude\": [\"*.o\"]\n}\n";
+
+    #[test]
+    fn merges_keep_existing_keys_and_layout() {
+        let merged = Merged::new(
+            SETTINGS,
+            "settings.json",
+            "editor.fontSize: 14\nfiles.exclude: ['*.o', target]\nnew: {a: null}\n",
+            "settings.yaml",
+            ArrayMerge::Union,
+        )
+        .unwrap();
+        assert_eq!(
+            merged.changes(),
+            [
+                "~ editor.fontSize: 12 -> 14",
+                "~ files.exclude: [\"*.o\"] -> [\"*.o\",\"target\"]",
+                "+ new: {\"a\":null}",
+            ]
+        );
+        let written = merged
+            .write(SETTINGS, "settings.json", MergeFormat::Json)
+            .unwrap();
+        assert_eq!(
+            written,
+            "{\n    \"editor.fontSize\": 14,\n    \"files.exclude\": [\n        \"*.o\",\n        \"target\"\n    ],\n    \"new\": {\n        \"a\": null\n    }\n}\n"
+        );
+
+        let again = Merged::new(
+            &written,
+            "settings.json",
+            "files.exclude: [target]",
+            "settings.yaml",
+            ArrayMerge::Union,
+        )
+        .unwrap();
+        assert!(!again.changed());
+        let replaced = Merged::new(
+            &written,
+            "settings.json",
+            "files.exclude: [target]",
+            "settings.yaml",
+            ArrayMerge::Replace,
+        )
+        .unwrap();
+        assert_eq!(
+            replaced.changes(),
+            ["~ files.exclude: [\"*.o\",\"target\"] -> [\"target\"]"]
+        );
+    }
+
+    #[test]
+    fn json_with_comments_can_be_merged_into() {
+        let settings = "{\n\t// how big\n\t\"editor.fontSize\": 12, /* \"a\": 1, */\n\t\"url\": \"http://x\",\n}\n";
+        let merged = Merged::new(
+            settings,
+            "settings.json",
+            "editor.fontSize: 14",
+            "settings.yaml",
+            ArrayMerge::Replace,
+        )
+        .unwrap();
+        assert!(merged.had_comments);
+        assert_eq!(merged.changes(), ["~ editor.fontSize: 12 -> 14"]);
+        assert_eq!(
+            merged
+                .write(settings, "settings.json", MergeFormat::Json)
+                .unwrap(),
+            "{\n\t\"editor.fontSize\": 14,\n\t\"url\": \"http://x\"\n}\n"
+        );
+        let plain = Merged::new(
+            SETTINGS,
+            "settings.json",
+            "a: 1",
+            "settings.yaml",
+            ArrayMerge::Replace,
+        )
+        .unwrap();
+        assert!(!plain.had_comments);
+        let yaml = Merged::new(
+            "{url: http://x}",
+            "settings.yaml",
+            "a: 1",
+            "settings.yaml",
+            ArrayMerge::Replace,
+        )
+        .unwrap();
+        assert!(!yaml.had_comments);
+        assert_eq!(yaml.changes(), ["+ a: 1"]);
+    }
+
+    #[test]
+    fn parse_failures_name_the_file() {
+        let error = Merged::new(
+            "{ \"a\": ",
+            "settings.json",
+            "a: 1",
+            "settings.yaml",
+            ArrayMerge::Replace,
+        )
+        .err()
+        .unwrap();
+        assert!(
+            error
+                .to_string()
+                .starts_with("failed to parse 'settings.json': "),
+            "{error}"
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Deploys worked out ahead of time, written by `deploy --emit-plan` and run
+//! as they are by `deploy --from-plan`
+use std::path::{Path, PathBuf};
+
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    abspath::AbsPathBuf,
+    archive,
+    block::Block,
+    config::{ArrayMerge, LinkType, MergeFormat, SymlinkFallback, VerifyFailure},
+    remote::Remote,
+};
+
+/// Version of the plan format, plans of any other version are refused
+pub const VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("failed to parse plan '{path}': {source}", path = path.to_string_lossy())]
+    Parse {
+        path: PathBuf,
+        source: serde_yaml::Error,
+    },
+    #[error("failed to write plan: {0}")]
+    Write(serde_yaml::Error),
+    #[error("plan '{path}' is version {found} (written by dotloy {dotloy}), this dotloy only runs version {VERSION}", path = path.to_string_lossy())]
+    Incompatible {
+        path: PathBuf,
+        found: u32,
+        dotloy: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub version: u32,
+    /// Version of dotloy that wrote it
+    pub dotloy: String,
+    pub steps: Vec<Step>,
+}
+
+impl Plan {
+    pub fn new(steps: Vec<Step>) -> Self {
+        Self {
+            version: VERSION,
+            dotloy: env!("CARGO_PKG_VERSION").to_owned(),
+            steps,
+        }
+    }
+}
+
+/// One action of the plan, along with where it came from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub action: Planned,
+}
+
+/// Where a planned action writes to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Destination {
+    Path(AbsPathBuf),
+    Remote(Remote),
+}
+
+/// An action with nothing left to render
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Planned {
+    MkDir {
+        path: AbsPathBuf,
+    },
+    EnsureDir {
+        path: AbsPathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<u32>,
+    },
+    Link {
+        ty: LinkType,
+        from: AbsPathBuf,
+        to: AbsPathBuf,
+        #[serde(default, skip_serializing_if = "crate::config::is_default")]
+        fallback: SymlinkFallback,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        repoint: Option<AbsPathBuf>,
+        #[serde(default, skip_serializing_if = "crate::config::is_default")]
+        adopt: bool,
+    },
+    Copy {
+        from: AbsPathBuf,
+        to: Destination,
+    },
+    /// A rendered template or command output
+    Write {
+        content: String,
+        /// Unix permissions of what it was rendered from
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<u32>,
+        to: Destination,
+    },
+    /// Content put in a managed block of `to`
+    Block {
+        content: String,
+        to: AbsPathBuf,
+        block: Block,
+        #[serde(default, skip_serializing_if = "crate::config::is_default")]
+        create: bool,
+    },
+    /// Content merged into the document at `to`
+    Merge {
+        content: String,
+        to: AbsPathBuf,
+        format: MergeFormat,
+        #[serde(default, skip_serializing_if = "crate::config::is_default")]
+        arrays: ArrayMerge,
+    },
+    Extract {
+        archive: AbsPathBuf,
+        format: archive::Format,
+        to: AbsPathBuf,
+        strip: usize,
+    },
+    CheckHash {
+        file: AbsPathBuf,
+        sha256: String,
+    },
+    Verify {
+        command: String,
+        dir: PathBuf,
+        target: Destination,
+        on_failure: VerifyFailure,
+    },
+}
+
+/// Just enough of a plan to tell whether the rest can be read
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+    #[serde(default)]
+    dotloy: String,
+}
+
+pub fn write(path: &Path, plan: &Plan) -> Result<(), Error> {
+    fs::write(path, serde_yaml::to_string(plan).map_err(Error::Write)?)?;
+    Ok(())
+}
+
+/// Read the plan at `path`, refusing it if it's another version
+pub fn read(path: &Path) -> Result<Plan, Error> {
+    let text = fs::read_to_string(path)?;
+    let parse_error = |source| Error::Parse {
+        path: path.to_owned(),
+        source,
+    };
+    let header: Header = serde_yaml::from_str(&text).map_err(parse_error)?;
+    if header.version != VERSION {
+        return Err(Error::Incompatible {
+            path: path.to_owned(),
+            found: header.version,
+            dotloy: header.dotloy,
+        });
+    }
+    serde_yaml::from_str(&text).map_err(parse_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use fs_err as fs;
+    use tempdir::TempDir;
+
+    use super::{read, Error};
+
+    #[test]
+    fn plans_of_other_versions_are_refused() {
+        let dir = TempDir::new("plan").unwrap();
+        let path = dir.path().join("plan.yaml");
+        fs::write(
+            &path,
+            "version: 99\ndotloy: 9.0.0\nsteps: [{action: {kind: teleport}}]\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            read(&path),
+            Err(Error::Incompatible { found: 99, dotloy, .. }) if dotloy == "9.0.0"
+        ));
+    }
+}
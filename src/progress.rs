@@ -0,0 +1,134 @@
+//! Progress bar for deploys with lots of actions. It's drawn on the last line
+//! of stderr and log lines are written above it
+use std::{
+    fmt::Display,
+    io::{self, IsTerminal, Write},
+    sync::{Mutex, MutexGuard},
+};
+
+/// Plans with more actions than this get a bar without asking for one
+const THRESHOLD: usize = 200;
+/// Width of the bar itself, without the counts and current item
+const WIDTH: usize = 30;
+/// Longest the current item gets before it's cut short
+const MAX_ITEM: usize = 60;
+
+static BAR: Mutex<Option<Bar>> = Mutex::new(None);
+
+fn bar() -> MutexGuard<'static, Option<Bar>> {
+    BAR.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Whether a plan of `total` actions should show a bar, `forced` if it was
+/// asked for
+pub fn wanted(forced: bool, total: usize) -> bool {
+    forced || (total > THRESHOLD && io::stderr().is_terminal())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Bar {
+    done: usize,
+    total: usize,
+    current: String,
+}
+impl Bar {
+    fn line(&self) -> String {
+        let filled = WIDTH * self.done.min(self.total) / self.total.max(1);
+        let mut current = self.current.chars().take(MAX_ITEM).collect::<String>();
+        if self.current.chars().count() > MAX_ITEM {
+            current.push_str("...");
+        }
+        format!(
+            "[{}{}] {}/{} {current}",
+            "#".repeat(filled),
+            "-".repeat(WIDTH - filled),
+            self.done,
+            self.total
+        )
+    }
+}
+
+fn clear() {
+    let _ = write!(io::stderr(), "\r\x1b[2K");
+}
+fn draw(bar: &Bar) {
+    let mut err = io::stderr();
+    let _ = write!(err, "\r\x1b[2K{}", bar.line());
+    let _ = err.flush();
+}
+
+/// The bar while it's shown, it's cleared when this is dropped
+pub struct Shown(());
+impl Shown {
+    pub fn start(total: usize) -> Self {
+        let new = Bar {
+            done: 0,
+            total,
+            current: String::new(),
+        };
+        draw(&new);
+        *bar() = Some(new);
+        Self(())
+    }
+    /// Count another action as done, `item` being what it was
+    pub fn advance(&self, item: impl Display) {
+        if let Some(bar) = bar().as_mut() {
+            bar.done += 1;
+            bar.current = item.to_string();
+            draw(bar);
+        }
+    }
+}
+impl Drop for Shown {
+    fn drop(&mut self) {
+        if bar().take().is_some() {
+            clear();
+        }
+    }
+}
+
+/// Writes to `W` without mangling the bar, it's cleared before each write
+/// and drawn again once a line is finished
+pub struct AroundBar<W>(pub W);
+impl<W: Write> Write for AroundBar<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bar = bar();
+        if bar.is_some() {
+            clear();
+        }
+        let n = self.0.write(buf)?;
+        if let Some(bar) = bar.as_ref().filter(|_| buf[..n].ends_with(b"\n")) {
+            self.0.flush()?;
+            draw(bar);
+        }
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bar, MAX_ITEM};
+
+    #[test]
+    fn bar_shows_counts_and_the_current_item() {
+        let mut bar = Bar {
+            done: 0,
+            total: 4,
+            current: String::new(),
+        };
+        assert_eq!(bar.line(), format!("[{}] 0/4 ", "-".repeat(30)));
+        bar.done = 1;
+        bar.current = "mkdir ~/.config".to_owned();
+        assert_eq!(
+            bar.line(),
+            format!("[{}{}] 1/4 mkdir ~/.config", "#".repeat(7), "-".repeat(23))
+        );
+        bar.current = "x".repeat(100);
+        assert!(bar
+            .line()
+            .ends_with(&format!("{}...", "x".repeat(MAX_ITEM))));
+    }
+}
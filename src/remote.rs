@@ -0,0 +1,124 @@
+//! Destinations on other machines, `[user@]host:/path`, written to over ssh
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{resources::Error, script};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Remote {
+    /// Host as ssh takes it, with the user if there is one
+    pub host: String,
+    pub path: String,
+}
+
+impl Remote {
+    /// `s` if it's a remote destination. Paths on it have to be absolute or
+    /// start at `~` so windows paths like `C:\x` aren't mistaken for one
+    pub fn parse(s: &str) -> Option<Self> {
+        let (host, path) = s.split_once(':')?;
+        let valid_host =
+            host.chars().count() > 1 && !host.contains(['/', '\\']) && !host.starts_with('-');
+        (valid_host && (path.starts_with('/') || path.starts_with('~'))).then(|| Self {
+            host: host.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+    /// The remote shell command writing its stdin to the path, creating the
+    /// directories above it
+    pub fn write_command(&self) -> String {
+        let quoted = |p: &str| match p.strip_prefix('~') {
+            Some("") => "\"$HOME\"".to_owned(),
+            Some(rest) if rest.starts_with('/') => format!("\"$HOME\"{}", script::quote(rest)),
+            _ => script::quote(p),
+        };
+        let file = quoted(&self.path);
+        match self.path.rsplit_once('/') {
+            Some((dir, _)) if !dir.is_empty() && dir != "~" => {
+                format!("mkdir -p {} && cat > {file}", quoted(dir))
+            }
+            _ => format!("cat > {file}"),
+        }
+    }
+    /// `ssh` command writing to the path, as it would be typed
+    pub fn ssh_command(&self) -> String {
+        format!(
+            "ssh {} {}",
+            script::quote(&self.host),
+            script::quote(&self.write_command())
+        )
+    }
+    pub fn write(&self, content: &[u8]) -> Result<(), Error> {
+        log::debug!("running `{}`", self.ssh_command());
+        let failed = |stderr: String| Error::Remote {
+            host: self.host.clone(),
+            path: self.path.clone(),
+            stderr,
+        };
+        let mut child = Command::new("ssh")
+            .arg(&self.host)
+            .arg(self.write_command())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| failed(format!("failed to run ssh: {e}")))?;
+        let written = child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(content);
+        let out = child.wait_with_output()?;
+        if !out.status.success() {
+            return Err(failed(
+                String::from_utf8_lossy(&out.stderr).trim_end().to_owned(),
+            ));
+        }
+        Ok(written?)
+    }
+}
+
+impl std::fmt::Display for Remote {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Remote;
+
+    #[test]
+    fn only_host_colon_path_is_remote() {
+        assert_eq!(
+            Remote::parse("root@router:/etc/dropbear/config"),
+            Some(Remote {
+                host: "root@router".to_owned(),
+                path: "/etc/dropbear/config".to_owned(),
+            })
+        );
+        assert!(Remote::parse("media:~/compose.yaml").is_some());
+        assert_eq!(Remote::parse(r"C:\Users\me"), None);
+        assert_eq!(Remote::parse("C:/Users/me"), None);
+        assert_eq!(Remote::parse("/home/me/.vimrc"), None);
+        assert_eq!(Remote::parse("host:relative"), None);
+        assert_eq!(Remote::parse("./a:/b"), None);
+    }
+    #[test]
+    fn writes_create_the_directory_above() {
+        let remote = |s| Remote::parse(s).unwrap().write_command();
+        assert_eq!(
+            remote("pi:/etc/it's/conf"),
+            r"mkdir -p '/etc/it'\''s' && cat > '/etc/it'\''s/conf'"
+        );
+        assert_eq!(
+            remote("pi:~/.config/app.yaml"),
+            r#"mkdir -p "$HOME"'/.config' && cat > "$HOME"'/.config/app.yaml'"#
+        );
+        assert_eq!(remote("pi:~/.vimrc"), r#"cat > "$HOME"'/.vimrc'"#);
+        assert_eq!(remote("pi:/x"), "cat > '/x'");
+    }
+}
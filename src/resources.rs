@@ -1,23 +1,85 @@
 use crate::abspath::AbsPathBuf;
-use std::io::Write;
-use std::{collections::HashMap};
+pub use crate::remote::Remote;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
 
 use fs_err as fs;
 use serde::Deserialize;
+use thiserror::Error;
 use uuid::Uuid;
 
-#[derive(Deserialize, Debug, Eq, PartialEq, Clone)]
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("unknown in-memory resource @{id}")]
+    UnknownResource { id: Uuid },
+    #[error("failed to run `{command}`: {source}")]
+    CommandSpawn {
+        command: String,
+        source: std::io::Error,
+    },
+    #[error("failed to write '{path}' on '{host}': {stderr}")]
+    Remote {
+        host: String,
+        path: String,
+        stderr: String,
+    },
+    #[error("'{0}' is on another machine, it can only be written to")]
+    RemoteRead(Remote),
+    #[error("command `{command}` failed ({status}): {stderr}")]
+    CommandFailed {
+        command: String,
+        status: String,
+        stderr: String,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum ResourceLocation {
-    InMemory { id: Uuid },
+    InMemory {
+        id: Uuid,
+        /// What the resource is for, shown instead of the id when displayed
+        #[serde(default)]
+        label: Option<String>,
+    },
     Path(AbsPathBuf),
+    /// Somewhere on another machine
+    Remote(Remote),
 }
+/// Labels are only for display, in-memory resources are the same if their
+/// ids are
+impl PartialEq for ResourceLocation {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::InMemory { id: a, .. }, Self::InMemory { id: b, .. }) => a == b,
+            (Self::Path(a), Self::Path(b)) => a == b,
+            (Self::Remote(a), Self::Remote(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+impl Eq for ResourceLocation {}
 impl From<AbsPathBuf> for ResourceLocation {
     fn from(value: AbsPathBuf) -> Self {
         Self::Path(value)
     }
 }
 
+impl From<Remote> for ResourceLocation {
+    fn from(value: Remote) -> Self {
+        Self::Remote(value)
+    }
+}
+
 impl ResourceLocation {
     #[must_use]
     pub fn as_path(&self) -> Option<&AbsPathBuf> {
@@ -32,38 +94,142 @@ impl ResourceLocation {
 impl std::fmt::Display for ResourceLocation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ResourceLocation::InMemory { id } => write!(f, "@{id}"),
+            ResourceLocation::InMemory {
+                label: Some(label), ..
+            } => write!(f, "({label})"),
+            ResourceLocation::InMemory { id, label: None } => write!(f, "@{id}"),
             ResourceLocation::Path(p) => write!(f, "{}", p.to_string_lossy()),
+            ResourceLocation::Remote(r) => write!(f, "{r}"),
         }
     }
 }
 
+/// Metadata of the file a resource came from, applied to wherever it ends
+/// up being written
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ResourceMeta {
+    pub permissions: Option<std::fs::Permissions>,
+    pub modified: Option<SystemTime>,
+}
+impl ResourceMeta {
+    pub fn of(path: &Path) -> std::io::Result<Self> {
+        let meta = fs::metadata(path)?;
+        Ok(Self {
+            permissions: Some(meta.permissions()),
+            modified: meta.modified().ok(),
+        })
+    }
+}
+
+/// Content of a resource, kept as bytes so binary files survive being copied
+/// through the store
 #[derive(Debug, Clone)]
 pub enum ResourceHandle {
-    MemStr(String),
+    Mem(Vec<u8>, ResourceMeta),
     File(AbsPathBuf),
+    /// Standard output of a shell command run in `dir`, the command is run
+    /// every time the content is needed
+    Command {
+        command: String,
+        dir: PathBuf,
+    },
 }
 impl ResourceHandle {
-    fn content(&self) -> std::io::Result<String> {
+    fn content(&self) -> Result<Vec<u8>> {
+        match self {
+            ResourceHandle::Mem(s, _) => Ok(s.clone()),
+            ResourceHandle::File(f) => Ok(fs::read(f)?),
+            ResourceHandle::Command { command, dir } => run_command(command, dir),
+        }
+    }
+    fn meta(&self) -> Result<ResourceMeta> {
         match self {
-            ResourceHandle::MemStr(s) => Ok(s.clone()),
-            ResourceHandle::File(f) => fs::read_to_string(f),
+            ResourceHandle::Mem(_, meta) => Ok(meta.clone()),
+            ResourceHandle::File(f) => Ok(ResourceMeta::of(f)?),
+            ResourceHandle::Command { .. } => Ok(ResourceMeta::default()),
         }
     }
 }
 
+/// `command` run by the platform's shell
+pub fn shell(command: &str) -> Command {
+    let mut cmd = if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.arg("/C");
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.arg("-c");
+        c
+    };
+    cmd.arg(command);
+    cmd
+}
+
+fn run_command(command: &str, dir: &Path) -> Result<Vec<u8>> {
+    log::debug!("running `{command}` for its output");
+    let output = shell(command)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|source| Error::CommandSpawn {
+            command: command.to_owned(),
+            source,
+        })?;
+    if !output.status.success() {
+        return Err(Error::CommandFailed {
+            command: command.to_owned(),
+            status: output.status.to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr)
+                .trim_end()
+                .to_owned(),
+        });
+    }
+    Ok(output.stdout)
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct ResourceStore {
     handles: HashMap<Uuid, ResourceHandle>,
+    /// Shared with subsets, which watch mode runs rather than the whole
+    extracted: Arc<Mutex<HashSet<PathBuf>>>,
 }
 impl ResourceStore {
+    /// Files extracted from archives so far, which extracting again may
+    /// replace
+    pub fn extracted(&self) -> MutexGuard<'_, HashSet<PathBuf>> {
+        self.extracted.lock().unwrap()
+    }
     pub fn define(&mut self, handle: ResourceHandle) -> ResourceLocation {
+        self.define_with_label(handle, None)
+    }
+    /// Define `handle`, displayed as `label` rather than its id
+    pub fn define_labeled(
+        &mut self,
+        handle: ResourceHandle,
+        label: impl Into<String>,
+    ) -> ResourceLocation {
+        self.define_with_label(handle, Some(label.into()))
+    }
+    fn define_with_label(
+        &mut self,
+        handle: ResourceHandle,
+        label: Option<String>,
+    ) -> ResourceLocation {
         let id = Uuid::new_v4();
         self.handles.insert(id, handle);
-        ResourceLocation::InMemory { id }
+        ResourceLocation::InMemory { id, label }
     }
     pub fn define_mem(&mut self) -> ResourceLocation {
-        self.define(ResourceHandle::MemStr("".to_owned()))
+        self.define(ResourceHandle::Mem(Vec::new(), ResourceMeta::default()))
+    }
+    /// Like [`define_mem`](Self::define_mem) but displayed as `label` rather
+    /// than its id
+    pub fn define_mem_labeled(&mut self, label: impl Into<String>) -> ResourceLocation {
+        self.define_labeled(
+            ResourceHandle::Mem(Vec::new(), ResourceMeta::default()),
+            label,
+        )
     }
     pub fn set(&mut self, target: Uuid, value: ResourceHandle) {
         self.handles.insert(target, value);
@@ -72,32 +238,192 @@ impl ResourceStore {
     pub fn test_handles(&self) -> &HashMap<Uuid, ResourceHandle> {
         &self.handles
     }
-    pub fn set_content(
-        &mut self,
-        target: &ResourceLocation,
-        value: ResourceHandle,
-    ) -> std::io::Result<()> {
+    pub fn set_content(&mut self, target: &ResourceLocation, value: ResourceHandle) -> Result<()> {
         match target {
-            ResourceLocation::InMemory { id } => {
+            ResourceLocation::InMemory { id, .. } => {
                 self.set(*id, value);
                 Ok(())
             }
             ResourceLocation::Path(p) => {
-                write!(fs::File::create(p.as_os_str())?, "{}", value.content()?)
+                let meta = value.meta()?;
+                match value {
+                    ResourceHandle::Mem(bytes, _) => {
+                        write_atomic(p, &meta, |tmp| fs::write(tmp, bytes))
+                    }
+                    // Streams rather than reading the whole file in first
+                    ResourceHandle::File(f) => {
+                        write_atomic(p, &meta, |tmp| fs::copy(f, tmp).map(|_| ()))
+                    }
+                    command @ ResourceHandle::Command { .. } => {
+                        let bytes = command.content()?;
+                        write_atomic(p, &meta, |tmp| fs::write(tmp, bytes))
+                    }
+                }
             }
+            ResourceLocation::Remote(r) => r.write(&value.content()?),
         }
     }
 
-    pub fn get(&self, target: Uuid) -> &ResourceHandle {
-        &self.handles[&target]
+    pub fn get(&self, target: Uuid) -> Result<&ResourceHandle> {
+        self.handles
+            .get(&target)
+            .ok_or(Error::UnknownResource { id: target })
     }
-    pub fn get_content(&self, target: &ResourceLocation) -> std::io::Result<String> {
+    pub fn get_content(&self, target: &ResourceLocation) -> Result<Vec<u8>> {
+        Ok(match target {
+            ResourceLocation::InMemory { id, .. } => self.get(*id)?.content()?,
+            ResourceLocation::Path(p) => fs::read(p)?,
+            ResourceLocation::Remote(r) => return Err(Error::RemoteRead(r.clone())),
+        })
+    }
+    /// The command producing `target`, if it is produced by one
+    pub fn command(&self, target: &ResourceLocation) -> Option<&str> {
         match target {
-            ResourceLocation::InMemory { id } => self.get(*id).content(),
-            ResourceLocation::Path(p) => fs::read_to_string(p),
+            ResourceLocation::InMemory { id, .. } => match self.handles.get(id)? {
+                ResourceHandle::Command { command, .. } => Some(command),
+                _ => None,
+            },
+            ResourceLocation::Path(_) | ResourceLocation::Remote(_) => None,
+        }
+    }
+    pub fn get_meta(&self, target: &ResourceLocation) -> Result<ResourceMeta> {
+        Ok(match target {
+            ResourceLocation::InMemory { id, .. } => self.get(*id)?.meta()?,
+            ResourceLocation::Path(p) => ResourceMeta::of(p)?,
+            ResourceLocation::Remote(r) => return Err(Error::RemoteRead(r.clone())),
+        })
+    }
+    /// Copy of this store with only the resources in `ids`
+    pub fn subset(&self, ids: impl IntoIterator<Item = Uuid>) -> Self {
+        Self {
+            handles: ids
+                .into_iter()
+                .filter_map(|id| Some((id, self.handles.get(&id)?.clone())))
+                .collect(),
+            extracted: self.extracted.clone(),
         }
     }
     pub fn append(&mut self, other: &mut ResourceStore) {
         self.handles.extend(other.handles.drain());
     }
 }
+
+/// Write `dest` by having `fill` write a temporary file next to it, then
+/// renaming that over `dest`. If anything fails `dest` is left as it was.
+/// `dest` gets the permissions in `meta`, or keeps its own if `meta` has none
+fn write_atomic(
+    dest: &Path,
+    meta: &ResourceMeta,
+    fill: impl FnOnce(&Path) -> std::io::Result<()>,
+) -> Result<()> {
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = dest.with_file_name(format!(".{name}.{}.dotloy-tmp", Uuid::new_v4()));
+    let written = fill(&tmp).and_then(|()| {
+        let permissions = meta
+            .permissions
+            .clone()
+            .or_else(|| fs::metadata(dest).ok().map(|m| m.permissions()));
+        if let Some(permissions) = permissions {
+            fs::set_permissions(&tmp, permissions)?;
+        }
+        if let Some(modified) = meta.modified {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&tmp)?
+                .set_modified(modified)?;
+        }
+        replace(&tmp, dest)
+    });
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    Ok(written?)
+}
+
+fn replace(tmp: &Path, dest: &Path) -> std::io::Result<()> {
+    match fs::rename(tmp, dest) {
+        Ok(()) => Ok(()),
+        // Windows refuses to replace files that are open elsewhere, which
+        // copying over them can still do
+        #[cfg(windows)]
+        Err(_) if dest.exists() => {
+            fs::copy(tmp, dest)?;
+            fs::remove_file(tmp)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fs_err as fs;
+    use tempdir::TempDir;
+
+    use crate::abspath::AbsPathBuf;
+
+    use super::{ResourceHandle, ResourceLocation, ResourceStore};
+
+    const BLOB: &[u8] = &[0x89, b'P', b'N', b'G', 0x00, 0xff, 0xfe, b'\r', b'\n', 0xc3];
+
+    #[test]
+    fn binary_content_round_trips_through_the_store() {
+        let dir = TempDir::new("binary_resources").unwrap();
+        let src = dir.path().join("src.png");
+        fs::write(&src, BLOB).unwrap();
+        let mut store = ResourceStore::default();
+
+        let from_file = store.define(ResourceHandle::File(AbsPathBuf::new(&src).unwrap()));
+        assert_eq!(store.get_content(&from_file).unwrap(), BLOB);
+        let dst = ResourceLocation::Path(AbsPathBuf::new(dir.path().join("dst.png")).unwrap());
+        store
+            .set_content(&dst, ResourceHandle::File(AbsPathBuf::new(&src).unwrap()))
+            .unwrap();
+        assert_eq!(store.get_content(&dst).unwrap(), BLOB);
+
+        let from_mem = store.define(ResourceHandle::Mem(BLOB.to_vec(), Default::default()));
+        assert_eq!(store.get_content(&from_mem).unwrap(), BLOB);
+        store
+            .set_content(&dst, ResourceHandle::Mem(BLOB.to_vec(), Default::default()))
+            .unwrap();
+        assert_eq!(fs::read(dst.as_path().unwrap()).unwrap(), BLOB);
+    }
+
+    #[test]
+    fn failed_writes_leave_the_destination_untouched() {
+        let dir = TempDir::new("atomic_writes").unwrap();
+        let dst = dir.path().join("config");
+        fs::write(&dst, "original").unwrap();
+        let mut store = ResourceStore::default();
+        let missing = AbsPathBuf::new(dir.path().join("missing")).unwrap();
+        let target = ResourceLocation::Path(AbsPathBuf::new(&dst).unwrap());
+        assert!(store
+            .set_content(&target, ResourceHandle::File(missing))
+            .is_err());
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "original");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn writes_keep_the_destination_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("atomic_perms").unwrap();
+        let dst = dir.path().join("config");
+        fs::write(&dst, "original").unwrap();
+        fs::set_permissions(&dst, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let mut store = ResourceStore::default();
+        let target = ResourceLocation::Path(AbsPathBuf::new(&dst).unwrap());
+        store
+            .set_content(
+                &target,
+                ResourceHandle::Mem(b"new".to_vec(), Default::default()),
+            )
+            .unwrap();
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "new");
+        assert_eq!(
+            fs::metadata(&dst).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
+}
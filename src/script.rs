@@ -0,0 +1,52 @@
+//! Plans written out as POSIX shell scripts, for machines that can't run
+//! dotloy
+
+/// Start of every script. `pipefail` isn't POSIX so it's only set where the
+/// shell has it
+pub const HEADER: &str = "#!/bin/sh
+# generated by dotloy
+set -eu
+if (set -o pipefail) 2>/dev/null; then set -o pipefail; fi
+";
+
+/// `s` as a single shell word
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Command writing exactly `content` to the already quoted `dest`
+pub fn write_file(content: &str, dest: &str) -> String {
+    let mut delim = "DOTLOY_EOF".to_owned();
+    while content.lines().any(|l| l == delim) {
+        delim.push('_');
+    }
+    match content.strip_suffix('\n') {
+        Some(body) => format!("cat > {dest} <<'{delim}'\n{body}\n{delim}"),
+        // a heredoc always ends in a newline, which `$(...)` strips again
+        None if !content.is_empty() => {
+            format!("printf '%s' \"$(cat <<'{delim}'\n{content}\n{delim}\n)\" > {dest}")
+        }
+        None => format!(": > {dest}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quote, write_file};
+
+    #[test]
+    fn words_are_quoted() {
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote("$HOME `x`"), "'$HOME `x`'");
+    }
+    #[test]
+    fn heredocs_dont_end_early() {
+        assert_eq!(
+            write_file("a\nDOTLOY_EOF\n", "'f'"),
+            "cat > 'f' <<'DOTLOY_EOF_'\na\nDOTLOY_EOF\nDOTLOY_EOF_"
+        );
+        assert_eq!(write_file("", "'f'"), ": > 'f'");
+        assert!(write_file("no newline", "'f'").starts_with("printf '%s'"));
+    }
+}
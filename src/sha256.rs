@@ -0,0 +1,44 @@
+//! SHA-256, for checking sources against the hash a config gives for them
+
+use std::io::Read;
+
+use sha2::{Digest, Sha256};
+
+/// Lowercase hex SHA-256 of everything `from` reads, a chunk at a time
+pub fn hex_digest(mut from: impl Read) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut from, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// Whether `s` looks like a hex SHA-256
+pub fn is_hex_digest(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hex_digest, is_hex_digest};
+
+    #[test]
+    fn digests_match_the_standard_vectors() {
+        assert_eq!(
+            hex_digest(&b""[..]).unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex_digest(&b"abc"[..]).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex_digest(&vec![b'a'; 1_000_000][..]).unwrap(),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+        assert!(is_hex_digest(&hex_digest(&b"abc"[..]).unwrap()));
+        assert!(!is_hex_digest("abc"));
+    }
+}
@@ -0,0 +1,52 @@
+/// Furthest a candidate can be from what was written to still be suggested
+const MAX_DISTANCE: usize = 2;
+
+/// Number of single character insertions, deletions and substitutions to get
+/// from `a` to `b`
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + usize::from(ca != *cb);
+            curr.push(sub.min(prev[j + 1] + 1).min(curr[j] + 1));
+        }
+        prev = curr;
+    }
+    prev[b.len()]
+}
+
+/// Candidates close enough to `word` to be what was meant, closest first
+pub fn closest<'a>(word: &str, candidates: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut found = candidates
+        .into_iter()
+        .map(|c| (edit_distance(word, c), c))
+        .filter(|(d, c)| *d <= MAX_DISTANCE && *d < c.chars().count())
+        .collect::<Vec<_>>();
+    found.sort();
+    found.dedup();
+    found.into_iter().map(|(_, c)| c).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{closest, edit_distance};
+
+    #[test]
+    fn distances_count_single_edits() {
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("from", "from"), 0);
+        assert_eq!(edit_distance("form", "from"), 2);
+        assert_eq!(edit_distance("runs_om", "runs_on"), 1);
+        assert_eq!(edit_distance("variable", "variables"), 1);
+    }
+    #[test]
+    fn only_close_candidates_are_suggested() {
+        assert_eq!(
+            closest("templat", ["template", "to", "from_command"]),
+            ["template"]
+        );
+        assert!(closest("xyz", ["to", "from"]).is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+//! Facts about the machine dotloy is running on
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+use fs_err as fs;
+
+use crate::config::Session;
+
+fn non_empty(s: String) -> Option<String> {
+    let s = s.trim();
+    (!s.is_empty()).then(|| s.to_owned())
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().and_then(non_empty)
+}
+
+fn command_output(cmd: &str, args: &[&str]) -> Option<String> {
+    let out = Command::new(cmd).args(args).output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    String::from_utf8(out.stdout).ok().and_then(non_empty)
+}
+
+fn full_hostname() -> Option<String> {
+    env("HOSTNAME")
+        .or_else(|| env("COMPUTERNAME"))
+        .or_else(|| fs::read_to_string("/etc/hostname").ok().and_then(non_empty))
+        .or_else(|| command_output("hostname", &[]))
+}
+
+/// Hostname without any domain part
+pub fn hostname() -> Result<String, &'static str> {
+    full_hostname()
+        .map(|h| h.split('.').next().unwrap_or_default().to_owned())
+        .ok_or("could not determine the hostname of this machine")
+}
+
+/// Fully qualified domain name, which is just the hostname if there is no domain
+pub fn fqdn() -> Result<String, &'static str> {
+    let fqdn = if cfg!(windows) {
+        env("COMPUTERNAME").map(|host| match env("USERDNSDOMAIN") {
+            Some(domain) => format!("{host}.{domain}"),
+            None => host,
+        })
+    } else {
+        command_output("hostname", &["-f"])
+    };
+    fqdn.or_else(full_hostname)
+        .ok_or("could not determine the hostname of this machine")
+}
+
+pub fn user() -> Result<String, &'static str> {
+    env("USER")
+        .or_else(|| env("USERNAME"))
+        .or_else(|| env("LOGNAME"))
+        .ok_or("could not determine the current user")
+}
+
+/// Whether this is Linux running under WSL, worked out once per run
+pub fn is_wsl() -> bool {
+    static WSL: OnceLock<bool> = OnceLock::new();
+    *WSL.get_or_init(|| {
+        cfg!(target_os = "linux")
+            && (env("WSL_DISTRO_NAME").is_some()
+                || fs::read_to_string("/proc/sys/kernel/osrelease")
+                    .is_ok_and(|release| is_wsl_release(&release)))
+    })
+}
+
+/// Whether `release`, the kernel's osrelease, is that of a WSL kernel
+fn is_wsl_release(release: &str) -> bool {
+    let release = release.to_lowercase();
+    release.contains("microsoft") || release.contains("wsl")
+}
+
+/// Kind of session this is running in, only told apart on linux
+pub fn session() -> Result<Session, &'static str> {
+    if cfg!(target_os = "linux") {
+        Ok(session_from(env))
+    } else {
+        Err("sessions are only told apart on linux")
+    }
+}
+
+/// The session `env` describes. Without a display it's a tty, so headless
+/// machines are too
+fn session_from(env: impl Fn(&str) -> Option<String>) -> Session {
+    match env("XDG_SESSION_TYPE").as_deref() {
+        Some("wayland") => Session::Wayland,
+        Some("x11") => Session::X11,
+        Some("tty") => Session::Tty,
+        // unset, or something like `unspecified` under ssh
+        _ if env("WAYLAND_DISPLAY").is_some() => Session::Wayland,
+        _ if env("DISPLAY").is_some() => Session::X11,
+        _ => Session::Tty,
+    }
+}
+
+/// Whether a process with id `pid` is running
+pub fn process_alive(pid: u32) -> bool {
+    if cfg!(target_os = "linux") {
+        std::path::Path::new("/proc").join(pid.to_string()).exists()
+    } else if cfg!(windows) {
+        command_output("tasklist", &["/NH", "/FI", &format!("PID eq {pid}")])
+            .is_some_and(|out| out.split_whitespace().any(|w| w == pid.to_string()))
+    } else {
+        Command::new("kill")
+            .args(["-0", &pid.to_string()])
+            .output()
+            .is_ok_and(|out| out.status.success())
+    }
+}
+
+/// Where `name` would be run from, searching `PATH` like a shell would but
+/// without running anything
+pub fn find_command(name: &str) -> Option<PathBuf> {
+    find_command_in(
+        name,
+        &std::env::var_os("PATH").unwrap_or_default(),
+        std::env::var_os("PATHEXT").as_deref(),
+    )
+}
+
+/// [`find_command`] with the given `PATH`, and `PATHEXT` on windows
+fn find_command_in(name: &str, path: &OsStr, pathext: Option<&OsStr>) -> Option<PathBuf> {
+    // windows runs `tmux` as `tmux.exe` and so on, and `tmux.exe` as is
+    let extensions = if cfg!(windows) {
+        let pathext = pathext
+            .and_then(OsStr::to_str)
+            .unwrap_or(".COM;.EXE;.BAT;.CMD");
+        std::iter::once(String::new())
+            .chain(
+                pathext
+                    .split(';')
+                    .filter(|e| !e.is_empty())
+                    .map(str::to_owned),
+            )
+            .collect()
+    } else {
+        vec![String::new()]
+    };
+    let candidates = |file: &Path| {
+        extensions
+            .iter()
+            .map(|ext| {
+                let mut p = file.as_os_str().to_owned();
+                p.push(ext);
+                PathBuf::from(p)
+            })
+            .collect::<Vec<_>>()
+    };
+    if name.contains(['/', std::path::MAIN_SEPARATOR]) {
+        return candidates(Path::new(name))
+            .into_iter()
+            .find(|p| is_executable(p));
+    }
+    std::env::split_paths(path)
+        .flat_map(|dir| candidates(&dir.join(name)))
+        .find(|p| is_executable(p))
+}
+
+fn is_executable(path: &Path) -> bool {
+    let Ok(meta) = std::fs::metadata(path) else {
+        return false;
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        meta.is_file() && meta.permissions().mode() & 0o111 != 0
+    }
+    #[cfg(not(unix))]
+    meta.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use fs_err as fs;
+    use tempdir::TempDir;
+
+    use super::{find_command_in, is_wsl_release, session_from};
+    use crate::config::Session;
+
+    #[test]
+    fn sessions_fall_back_on_the_displays_set() {
+        let session = |vars: &[(&str, &str)]| {
+            session_from(|name| {
+                vars.iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, v)| v.to_string())
+            })
+        };
+        assert_eq!(
+            session(&[
+                ("XDG_SESSION_TYPE", "x11"),
+                ("WAYLAND_DISPLAY", "wayland-0")
+            ]),
+            Session::X11
+        );
+        assert_eq!(
+            session(&[("XDG_SESSION_TYPE", "wayland")]),
+            Session::Wayland
+        );
+        assert_eq!(
+            session(&[("XDG_SESSION_TYPE", "tty"), ("DISPLAY", ":0")]),
+            Session::Tty
+        );
+        assert_eq!(
+            session(&[
+                ("XDG_SESSION_TYPE", "unspecified"),
+                ("WAYLAND_DISPLAY", "wayland-0"),
+                ("DISPLAY", ":0")
+            ]),
+            Session::Wayland
+        );
+        assert_eq!(session(&[("DISPLAY", ":0")]), Session::X11);
+        assert_eq!(session(&[]), Session::Tty);
+    }
+
+    #[test]
+    fn wsl_kernels_are_recognised() {
+        assert!(is_wsl_release("5.15.133.1-microsoft-standard-WSL2\n"));
+        assert!(is_wsl_release("4.4.0-19041-Microsoft"));
+        assert!(!is_wsl_release("6.6.10-arch1-1"));
+    }
+
+    #[test]
+    fn commands_are_found_on_path_without_running_them() {
+        let dirs = [
+            TempDir::new("path_a").unwrap(),
+            TempDir::new("path_b").unwrap(),
+        ];
+        let name = if cfg!(windows) { "tmux.exe" } else { "tmux" };
+        let tmux = dirs[1].path().join(name);
+        fs::write(&tmux, "exit 1").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::write(dirs[0].path().join("tmux"), "not executable").unwrap();
+            fs::set_permissions(&tmux, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let path = std::env::join_paths(dirs.iter().map(|d| d.path())).unwrap();
+        let find = |name| find_command_in(name, &path, Some(&OsString::from(".EXE")));
+        assert_eq!(find("tmux"), Some(tmux.clone()));
+        assert_eq!(find("nvim"), None);
+        assert_eq!(find(&tmux.to_string_lossy()), Some(tmux.clone()));
+    }
+}
@@ -0,0 +1,149 @@
+use super::parse::Invalid;
+
+/// Transformation applied to the value of an expression, e.g. `{{ x | upper }}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Upper,
+    Lower,
+    Trim,
+    Replace {
+        from: String,
+        to: String,
+    },
+    /// Quoted JSON string
+    Json,
+    /// Double quoted YAML string
+    YamlStr,
+    /// Single argument to a POSIX shell
+    ShellQuote,
+    /// Regex matching the value literally
+    RegexEscape,
+}
+
+impl Filter {
+    pub fn new(name: &str, args: Vec<String>) -> Result<Self, Invalid> {
+        let arity = |expected| Invalid::check_arity(&args, expected);
+        match name {
+            "upper" => arity(0).map(|_| Self::Upper),
+            "lower" => arity(0).map(|_| Self::Lower),
+            "trim" => arity(0).map(|_| Self::Trim),
+            "json" => arity(0).map(|_| Self::Json),
+            "yaml_str" => arity(0).map(|_| Self::YamlStr),
+            "shell_quote" => arity(0).map(|_| Self::ShellQuote),
+            "regex_escape" => arity(0).map(|_| Self::RegexEscape),
+            "replace" => {
+                arity(2)?;
+                let mut args = args.into_iter();
+                Ok(Self::Replace {
+                    from: args.next().unwrap(),
+                    to: args.next().unwrap(),
+                })
+            }
+            _ => Err(Invalid::Unknown),
+        }
+    }
+
+    pub fn apply(&self, input: String) -> String {
+        match self {
+            Filter::Upper => input.to_uppercase(),
+            Filter::Lower => input.to_lowercase(),
+            Filter::Trim => input.trim().to_owned(),
+            Filter::Replace { from, to } => input.replace(from.as_str(), to),
+            Filter::Json => double_quoted(&input, |c| format!("\\u{:04x}", c as u32)),
+            Filter::YamlStr => double_quoted(&input, |c| format!("\\x{:02x}", c as u32)),
+            Filter::ShellQuote => format!("'{}'", input.replace('\'', r"'\''")),
+            Filter::RegexEscape => {
+                let mut out = String::with_capacity(input.len());
+                for c in input.chars() {
+                    if "\\.+*?()|[]{}^$#&-~".contains(c) {
+                        out.push('\\');
+                    }
+                    out.push(c);
+                }
+                out
+            }
+        }
+    }
+}
+
+/// Wrap `input` in double quotes with the escapes shared by JSON and YAML,
+/// using `control` for control characters without a short escape
+fn double_quoted(input: &str, control: impl Fn(char) -> String) -> String {
+    let mut out = String::with_capacity(input.len() + 2);
+    out.push('"');
+    for c in input.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&control(c)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+
+    const TRICKY: [&str; 5] = ["", "it's \"fine\"", "a\nb\\c", "héllo ✓", "\t\u{7}"];
+
+    fn apply_all(filter: Filter) -> Vec<String> {
+        TRICKY.iter().map(|s| filter.apply(s.to_string())).collect()
+    }
+
+    #[test]
+    fn json_produces_valid_string_literals() {
+        assert_eq!(
+            apply_all(Filter::Json),
+            [
+                r#""""#,
+                r#""it's \"fine\"""#,
+                r#""a\nb\\c""#,
+                r#""héllo ✓""#,
+                r#""\t\u0007""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn yaml_str_produces_double_quoted_scalars() {
+        assert_eq!(
+            apply_all(Filter::YamlStr),
+            [
+                r#""""#,
+                r#""it's \"fine\"""#,
+                r#""a\nb\\c""#,
+                r#""héllo ✓""#,
+                r#""\t\x07""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn shell_quote_produces_a_single_word() {
+        assert_eq!(
+            apply_all(Filter::ShellQuote),
+            [
+                "''",
+                r#"'it'\''s "fine"'"#,
+                "'a\nb\\c'",
+                "'héllo ✓'",
+                "'\t\u{7}'",
+            ]
+        );
+    }
+
+    #[test]
+    fn regex_escape_escapes_metacharacters() {
+        assert_eq!(
+            Filter::RegexEscape.apply(r"a.b*c+(d)[e]{1,2}^$|?\#&-~ é".to_owned()),
+            r"a\.b\*c\+\(d\)\[e\]\{1,2\}\^\$\|\?\\\#\&\-\~ é"
+        );
+        assert_eq!(Filter::RegexEscape.apply(String::new()), "");
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Local,
+};
+
+use super::{parse::Invalid, Error, Value, Variable};
+
+/// Variables provided by helpers rather than defined in a context
+pub const VARIABLES: [&str; 3] = ["now.date", "now.datetime", "now.year"];
+
+/// Function callable from a template, e.g. `{{ shell "hostname" }}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Helper {
+    Shell(String),
+    /// `now.format "<strftime>"`
+    NowFormat(String),
+    /// `lookup "<file>" "<dotted.key>"`
+    Lookup {
+        file: String,
+        key: String,
+    },
+}
+
+impl Helper {
+    pub fn new(name: &str, args: Vec<String>) -> Result<Self, Invalid> {
+        let single = |args: Vec<String>| {
+            Invalid::check_arity(&args, 1)?;
+            Ok(args.into_iter().next().unwrap())
+        };
+        match name {
+            "shell" => single(args).map(Self::Shell),
+            "now.format" => single(args).map(Self::NowFormat),
+            "lookup" => {
+                Invalid::check_arity(&args, 2)?;
+                let mut args = args.into_iter();
+                Ok(Self::Lookup {
+                    file: args.next().unwrap(),
+                    key: args.next().unwrap(),
+                })
+            }
+            _ => Err(Invalid::Unknown),
+        }
+    }
+}
+
+/// Output of commands run by `shell`, by the directory they ran in and the
+/// command
+type ShellCache = HashMap<(Option<PathBuf>, String), String>;
+
+/// Settings and state for helpers. Clones share the same caches so that a
+/// command used by many targets is only run once per plan, and the same clock
+/// so that every output of a run has the same timestamp
+#[derive(Debug, Clone)]
+pub struct Helpers {
+    allow_shell: bool,
+    dry_run: bool,
+    /// Directory relative paths given to helpers are resolved against
+    base_dir: Option<PathBuf>,
+    shell_cache: Arc<Mutex<ShellCache>>,
+    lookup_cache: Arc<Mutex<HashMap<PathBuf, serde_yaml::Value>>>,
+    clock: Arc<Mutex<DateTime<Local>>>,
+}
+
+impl Default for Helpers {
+    fn default() -> Self {
+        Self {
+            allow_shell: false,
+            dry_run: false,
+            base_dir: None,
+            shell_cache: Default::default(),
+            lookup_cache: Default::default(),
+            clock: Arc::new(Mutex::new(Local::now())),
+        }
+    }
+}
+
+impl PartialEq for Helpers {
+    fn eq(&self, other: &Self) -> bool {
+        self.allow_shell == other.allow_shell && self.dry_run == other.dry_run
+    }
+}
+impl Eq for Helpers {}
+
+impl Helpers {
+    pub fn enable_shell(&mut self) {
+        self.allow_shell = true;
+    }
+    pub fn shell_enabled(&self) -> bool {
+        self.allow_shell
+    }
+    pub fn set_dry_run(&mut self, dry: bool) {
+        self.dry_run = dry;
+    }
+
+    pub fn set_base_dir(&mut self, dir: PathBuf) {
+        self.base_dir = Some(dir);
+    }
+    /// Path a helper argument refers to
+    pub fn resolve(&self, path: &str) -> PathBuf {
+        match &self.base_dir {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    /// Set the time used by `now` to the current time and forget files read
+    /// by `lookup` and output of `shell`, for everything sharing these caches
+    pub fn start_run(&self) {
+        *self.clock.lock().unwrap() = Local::now();
+        self.lookup_cache.lock().unwrap().clear();
+        self.shell_cache.lock().unwrap().clear();
+    }
+
+    /// Builtin variables that are computed rather than defined
+    pub fn variable(&self, var: &Variable) -> Option<Value> {
+        let mut segments = var.segments();
+        let now = *self.clock.lock().unwrap();
+        let value = match (segments.next()?, segments.next()?) {
+            ("now", "date") => now.format("%Y-%m-%d").to_string(),
+            ("now", "datetime") => now.format("%Y-%m-%dT%H:%M:%S%:z").to_string(),
+            ("now", "year") => now.format("%Y").to_string(),
+            _ => return None,
+        };
+        segments.next().is_none().then_some(Value::String(value))
+    }
+
+    pub fn call(&self, helper: &Helper) -> Result<String, Error> {
+        match helper {
+            Helper::Shell(cmd) => self.shell(cmd),
+            Helper::NowFormat(fmt) => {
+                let items = StrftimeItems::new(fmt);
+                if items.clone().any(|i| i == Item::Error) {
+                    return Err(Error::InvalidTimeFormat(fmt.to_owned()));
+                }
+                Ok(self
+                    .clock
+                    .lock()
+                    .unwrap()
+                    .format_with_items(items)
+                    .to_string())
+            }
+            Helper::Lookup { file, key } => self.lookup(&self.resolve(file), key),
+        }
+    }
+
+    fn lookup(&self, path: &Path, key: &str) -> Result<String, Error> {
+        let mut cache = self.lookup_cache.lock().unwrap();
+        if !cache.contains_key(path) {
+            cache.insert(path.to_owned(), load_lookup(path)?);
+        }
+        let value = key
+            .split('.')
+            .try_fold(&cache[path], |value, segment| match value {
+                serde_yaml::Value::Sequence(items) => items.get(segment.parse::<usize>().ok()?),
+                value => value.get(segment),
+            });
+        let (path, key) = (path.display().to_string(), key.to_owned());
+        match value {
+            Some(serde_yaml::Value::String(s)) => Ok(s.clone()),
+            Some(serde_yaml::Value::Number(n)) => Ok(n.to_string()),
+            Some(serde_yaml::Value::Bool(b)) => Ok(b.to_string()),
+            Some(_) => Err(Error::LookupNotScalar { path, key }),
+            None => Err(Error::LookupMissingKey { path, key }),
+        }
+    }
+
+    fn shell(&self, cmd: &str) -> Result<String, Error> {
+        if !self.allow_shell {
+            return Err(Error::ShellNotAllowed(cmd.to_owned()));
+        }
+        if self.dry_run {
+            log::info!("would run shell command `{cmd}`");
+            return Ok(format!("$({cmd})"));
+        }
+        let key = (self.base_dir.clone(), cmd.to_owned());
+        if let Some(out) = self.shell_cache.lock().unwrap().get(&key) {
+            return Ok(out.clone());
+        }
+        let out = run_shell(cmd, self.base_dir.as_deref())?;
+        self.shell_cache.lock().unwrap().insert(key, out.clone());
+        Ok(out)
+    }
+}
+
+/// Parse a file for `lookup`. JSON is parsed as YAML, which it is a subset of
+fn load_lookup(path: &Path) -> Result<serde_yaml::Value, Error> {
+    let display = || path.display().to_string();
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    if !matches!(ext, "yaml" | "yml" | "json") {
+        return Err(Error::LookupFormat(display()));
+    }
+    let content = std::fs::read_to_string(path).map_err(|e| Error::LookupRead {
+        path: display(),
+        reason: e.to_string(),
+    })?;
+    serde_yaml::from_str(&content).map_err(|e| Error::LookupParse {
+        path: display(),
+        reason: e.to_string(),
+    })
+}
+
+/// Run `cmd` in `dir`, or the cwd if there isn't one
+fn run_shell(cmd: &str, dir: Option<&Path>) -> Result<String, Error> {
+    log::debug!("running shell command `{cmd}`");
+    let mut command = if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.arg("/C");
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.arg("-c");
+        c
+    };
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+    let output = command
+        .arg(cmd)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| Error::ShellSpawn {
+            command: cmd.to_owned(),
+            reason: e.to_string(),
+        })?;
+    if !output.status.success() {
+        return Err(Error::ShellFailed {
+            command: cmd.to_owned(),
+            status: output.status.to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr)
+                .trim_end()
+                .to_owned(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end()
+        .to_owned())
+}
@@ -0,0 +1,1604 @@
+//! Template engine used for both config fields and file expansion. Config
+//! fields are wrapped in [`Templated`] and everything is rendered against a
+//! single [`Context`] type, the expand command included.
+//!
+//! The syntax is handlebars-like: `{{ some.var }}` expands to the value of
+//! `some.var`, optionally piped through filters (`{{ some.var | trim | upper }}`).
+//! `\{{` produces a literal `{{`. Lists can be iterated with
+//! `{{#each some.list}} {{ this }} {{ @index }} {{/each}}`. Helpers are called
+//! with quoted arguments, e.g. `{{ shell "hostname" }}` or
+//! `{{ lookup "theme.yaml" "colors.bg" }}`. `{{> path }}` splices
+//! in another file rendered with the same variables. A tag opened with `{{-`
+//! or closed with `-}}` strips the whitespace before or after it. Everything
+//! between `{{raw}}` and `{{/raw}}` (or `{{{{raw}}}}` and `{{{{/raw}}}}`) is
+//! output as is.
+//!
+//! Some variables are built into the engine rather than defined in a context:
+//! `now.date`, `now.datetime` and `now.year`, plus the `now.format` helper.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use fs_err as fs;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+mod filter;
+mod helper;
+pub mod parse;
+
+use crate::{abspath::AbsPathBuf, suggest, vars::Layer};
+use helper::{Helper, Helpers};
+use parse::{Expr, Location, Node, Source, Span};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error(transparent)]
+    Parse(#[from] parse::Error),
+    #[error("unknown variable '{var}' at {loc}{hint}", hint = did_you_mean(suggestions))]
+    UndefinedVariable {
+        var: Variable,
+        loc: Location,
+        /// Defined variables close to it, which may be what was meant
+        suggestions: Vec<String>,
+    },
+    #[error("{}", list(.0))]
+    Multiple(Vec<Error>),
+    #[error("variable '{0}' is not a string")]
+    NotAString(Variable),
+    #[error("variable '{0}' is not a list and cannot be iterated with #each")]
+    NotAList(Variable),
+    #[error("variable '{var}' is not available: {reason}")]
+    Unavailable { var: Variable, reason: String },
+    #[error("tried to run shell command `{0}` but the shell helper is disabled, set `allow_shell: true` in the config to enable it")]
+    ShellNotAllowed(String),
+    #[error("failed to run shell command `{command}`: {reason}")]
+    ShellSpawn { command: String, reason: String },
+    #[error("invalid time format '{0}'")]
+    InvalidTimeFormat(String),
+    #[error("shell command `{command}` failed ({status}): {stderr}")]
+    ShellFailed {
+        command: String,
+        status: String,
+        stderr: String,
+    },
+    #[error("partial '{path}' included from {from} does not exist")]
+    MissingPartial { path: String, from: String },
+    #[error("failed to read partial '{path}': {reason}")]
+    ReadPartial { path: String, reason: String },
+    #[error("partials include each other in a cycle: {0}")]
+    PartialCycle(String),
+    #[error("partials are nested too deeply: {0}")]
+    PartialDepth(String),
+    #[error("failed to read '{path}' for lookup: {reason}")]
+    LookupRead { path: String, reason: String },
+    #[error("failed to parse '{path}' for lookup: {reason}")]
+    LookupParse { path: String, reason: String },
+    #[error("can only look up values in yaml or json files, not '{0}'")]
+    LookupFormat(String),
+    #[error("key '{key}' not found in '{path}'")]
+    LookupMissingKey { path: String, key: String },
+    #[error("key '{key}' in '{path}' is not a string, number or bool")]
+    LookupNotScalar { path: String, key: String },
+    #[error("template syntax is left after rendering {depth} times: `{remaining}`")]
+    RenderDepth { depth: usize, remaining: String },
+    #[error("variable cycle: {0}")]
+    VariableCycle(String),
+    #[error("in variable '{var}': {source}")]
+    InVariable { var: Variable, source: Box<Error> },
+    #[error("in partial '{path}': {source}")]
+    InPartial { path: String, source: Box<Error> },
+    #[error("rendered to an empty path")]
+    EmptyPath,
+    #[error("rendered to a path that is only whitespace ('{0}')")]
+    BlankPath(String),
+    #[error("invalid path '{path}': {reason}")]
+    InvalidPath { path: String, reason: String },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// How many partials can be nested inside each other
+const MAX_PARTIAL_DEPTH: usize = 32;
+/// Most variables suggested for one that isn't defined
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Single error from all those found rendering a template, if there were any
+fn combine(mut errors: Vec<Error>) -> Option<Error> {
+    match errors.len() {
+        0 => None,
+        1 => errors.pop(),
+        _ => Some(Error::Multiple(errors)),
+    }
+}
+
+fn did_you_mean(suggestions: &[String]) -> String {
+    match suggestions {
+        [] => String::new(),
+        [only] => format!(", did you mean `{only}`?"),
+        [rest @ .., last] => format!(
+            ", did you mean {} or `{last}`?",
+            rest.iter().map(|s| format!("`{s}`")).join(", ")
+        ),
+    }
+}
+
+fn list(errors: &[Error]) -> String {
+    let mut out = format!("{} errors:", errors.len());
+    for e in errors {
+        out += &format!("\n  {e}");
+    }
+    out
+}
+
+/// Dot separated path to a value, e.g. `xdg.local.config`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Variable {
+    segments: Vec<String>,
+}
+
+impl Variable {
+    pub fn single(name: impl Into<String>) -> Self {
+        Self {
+            segments: vec![name.into()],
+        }
+    }
+    pub fn join(mut self, other: Variable) -> Self {
+        self.segments.extend(other.segments);
+        self
+    }
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().map(String::as_str)
+    }
+    /// Parse a name for a variable defined by the config, which unlike those
+    /// used in templates must be made up only of identifiers
+    pub fn definable(name: &str) -> Result<Self, parse::Error> {
+        let valid = name
+            .split('.')
+            .all(|s| !s.is_empty() && s.chars().all(parse::is_ident_char));
+        if !valid {
+            return Err(parse::Error::InvalidVariable(name.to_owned()));
+        }
+        name.parse()
+    }
+    /// Whether `prefix` is this variable or one of its parents
+    pub fn starts_with(&self, prefix: &Variable) -> bool {
+        self.segments.starts_with(&prefix.segments)
+    }
+}
+
+impl std::str::FromStr for Variable {
+    type Err = parse::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let segments = s.split('.').map(str::to_owned).collect::<Vec<_>>();
+        if segments.iter().any(String::is_empty) {
+            return Err(parse::Error::InvalidVariable(s.to_owned()));
+        }
+        Ok(Self { segments })
+    }
+}
+
+impl std::fmt::Display for Variable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.segments.join("."))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    List(Vec<Value>),
+    Object(Object),
+    /// Variable that could not be determined, using it is an error
+    /// mentioning the reason
+    Unavailable(String),
+}
+
+impl Value {
+    fn get_path<'s>(&self, mut segments: impl Iterator<Item = &'s str>) -> Option<&Value> {
+        segments.try_fold(self, |value, seg| match value {
+            Value::Object(obj) => obj.get(seg),
+            _ => None,
+        })
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_owned())
+    }
+}
+impl From<Object> for Value {
+    fn from(value: Object) -> Self {
+        Self::Object(value)
+    }
+}
+/// Values as they'd be written in yaml flow style, for messages
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{s}"),
+            Value::List(items) => write!(f, "[{}]", items.iter().join(", ")),
+            Value::Object(obj) => write!(
+                f,
+                "{{{}}}",
+                obj.properties
+                    .iter()
+                    .map(|(k, v)| format!("{k}: {v}"))
+                    .join(", ")
+            ),
+            Value::Unavailable(_) => write!(f, "(unavailable)"),
+        }
+    }
+}
+impl From<Vec<Value>> for Value {
+    fn from(value: Vec<Value>) -> Self {
+        Self::List(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Object {
+    properties: BTreeMap<String, Value>,
+}
+
+impl Object {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_property(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.add_property(name, value);
+        self
+    }
+    pub fn add_property(&mut self, name: impl Into<String>, value: impl Into<Value>) {
+        self.properties.insert(name.into(), value.into());
+    }
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.properties.get(name)
+    }
+    /// Path to every value in the object, nested ones included, below `prefix`
+    fn paths(&self, prefix: &Variable, out: &mut Vec<Variable>) {
+        for (name, value) in &self.properties {
+            let mut path = prefix.clone();
+            path.segments.push(name.clone());
+            if let Value::Object(obj) = value {
+                obj.paths(&path, out);
+            }
+            out.push(path);
+        }
+    }
+    /// Merge `other` into this object, with `other` taking priority.
+    /// Objects present in both are merged recursively
+    fn merge(&mut self, other: Object) {
+        for (name, value) in other.properties {
+            match (self.properties.get_mut(&name), value) {
+                (Some(Value::Object(ours)), Value::Object(theirs)) => ours.merge(theirs),
+                (_, value) => {
+                    self.properties.insert(name, value);
+                }
+            }
+        }
+    }
+}
+
+impl FromIterator<(String, Value)> for Object {
+    fn from_iter<T: IntoIterator<Item = (String, Value)>>(iter: T) -> Self {
+        Self {
+            properties: iter.into_iter().collect(),
+        }
+    }
+}
+
+/// A config value that is rendered with a [`Context`] before it is used
+#[repr(transparent)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Templated<T>(T);
+impl<T> Templated<T> {
+    pub fn new(inner: T) -> Self {
+        Self(inner)
+    }
+}
+impl Templated<String> {
+    pub fn render(&self, ctx: &Context) -> Result<String, Error> {
+        ctx.render(&self.0)
+    }
+    /// Render as a path, see [`Context::path`]
+    pub fn render_path(&self, ctx: &Context) -> Result<AbsPathBuf, Error> {
+        ctx.path(&self.render(ctx)?)
+    }
+    /// The template as written, before rendering
+    pub fn source(&self) -> &str {
+        &self.0
+    }
+    /// See [`referenced_vars`]
+    pub fn referenced_vars(&self) -> Result<Vec<Reference>, Error> {
+        referenced_vars(&self.0)
+    }
+}
+
+/// What to do when a template references a variable that isn't defined
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Undefined {
+    /// Fail to render
+    #[default]
+    Error,
+    /// Leave the expression in the output exactly as it was written
+    Passthrough,
+    /// Replace the expression with nothing
+    Empty,
+}
+
+/// Set of variables available to a template
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    root: Object,
+    helpers: Helpers,
+    undefined: Undefined,
+    /// Where to look for partials not found next to the including file
+    partials_dir: Option<PathBuf>,
+    /// Times to render output again while it still contains template syntax
+    rerenders: usize,
+    /// Context this is a [`scope`](Self::scope) of, which variables not
+    /// defined here are looked up in
+    parent: Option<Arc<Context>>,
+    /// Layer of each variable defined with [`Context::define_in`], the rest
+    /// are built in
+    layers: BTreeMap<Variable, Layer>,
+}
+
+impl PartialEq for Context {
+    fn eq(&self, other: &Self) -> bool {
+        self.helpers == other.helpers
+            && self.undefined == other.undefined
+            && self.partials_dir == other.partials_dir
+            && self.rerenders == other.rerenders
+            // scopes of the same context only differ in what they define,
+            // flattening copies every variable of the context
+            && (matches!((&self.parent, &other.parent), (Some(a), Some(b)) if Arc::ptr_eq(a, b))
+                && self.root == other.root
+                || self.flattened() == other.flattened())
+    }
+}
+impl Eq for Context {}
+
+impl Context {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// New context with the same settings and variables as this one. The
+    /// variables are shared rather than copied, so this is cheap however
+    /// many there are. Defining a variable in the scope doesn't affect this
+    /// context
+    pub fn scope(self: &Arc<Self>) -> Self {
+        Self {
+            root: Object::new(),
+            helpers: self.helpers.clone(),
+            undefined: self.undefined,
+            partials_dir: self.partials_dir.clone(),
+            rerenders: self.rerenders,
+            parent: Some(self.clone()),
+            layers: BTreeMap::new(),
+        }
+    }
+    /// Whether both contexts are scopes of the same context
+    #[cfg(test)]
+    pub fn shares_parent_with(&self, other: &Context) -> bool {
+        matches!((&self.parent, &other.parent), (Some(a), Some(b)) if Arc::ptr_eq(a, b))
+    }
+    /// Every variable visible from this context, including those of the
+    /// contexts it is a scope of
+    fn flattened(&self) -> Cow<'_, Object> {
+        match &self.parent {
+            None => Cow::Borrowed(&self.root),
+            Some(parent) => {
+                let mut all = parent.flattened().into_owned();
+                all.merge(self.root.clone());
+                Cow::Owned(all)
+            }
+        }
+    }
+    pub fn with_define(mut self, var: Variable, value: impl Into<Value>) -> Self {
+        self.define(var, value);
+        self
+    }
+    /// Define `var` as `value`, creating any intermediate objects needed and
+    /// replacing whatever was there before
+    pub fn define(&mut self, var: Variable, value: impl Into<Value>) {
+        let mut segments = var.segments;
+        let last = segments.pop().expect("variables always have a segment");
+        let mut obj = &mut self.root;
+        for seg in segments {
+            let entry = obj
+                .properties
+                .entry(seg)
+                .or_insert_with(|| Value::Object(Object::new()));
+            if !matches!(entry, Value::Object(_)) {
+                *entry = Value::Object(Object::new());
+            }
+            let Value::Object(next) = entry else {
+                unreachable!()
+            };
+            obj = next;
+        }
+        obj.properties.insert(last, value.into());
+    }
+    /// [`Context::define`], noting that `var` comes from `layer`
+    pub fn define_in(&mut self, layer: Layer, var: Variable, value: impl Into<Value>) {
+        self.layers.insert(var.clone(), layer);
+        self.define(var, value);
+    }
+    /// Layer `var` was defined in, if it is
+    pub fn layer_of(&self, var: &Variable) -> Option<Layer> {
+        let mut segments = var.segments();
+        let here = segments
+            .next()
+            .and_then(|first| self.root.get(first))
+            .and_then(|v| v.get_path(segments))
+            .is_some();
+        if here {
+            Some(self.layers.get(var).copied().unwrap_or(Layer::Builtin))
+        } else {
+            self.parent.as_deref()?.layer_of(var)
+        }
+    }
+    pub fn append(&mut self, other: Context) {
+        self.root.merge(other.root);
+        self.layers.extend(other.layers);
+    }
+    /// Allow templates to use the `shell` helper
+    pub fn enable_shell(&mut self) {
+        self.helpers.enable_shell();
+    }
+    pub fn shell_enabled(&self) -> bool {
+        self.helpers.shell_enabled()
+    }
+    pub fn set_undefined(&mut self, undefined: Undefined) {
+        self.undefined = undefined;
+    }
+    pub fn set_partials_dir(&mut self, dir: PathBuf) {
+        self.partials_dir = Some(dir);
+    }
+    /// Render output again while it contains template syntax, up to `depth`
+    /// times in total. Past 1 it is an error for syntax to be left over
+    pub fn set_render_depth(&mut self, depth: usize) {
+        self.rerenders = depth.saturating_sub(1);
+    }
+    /// Directory relative paths given to helpers like `lookup` are resolved
+    /// against, normally the one containing the config
+    pub fn set_base_dir(&mut self, dir: PathBuf) {
+        self.helpers.set_base_dir(dir);
+    }
+    /// A rendered config field as the path it names, relative to the base
+    /// directory and with `.` and `..` removed. Empty or blank renderings are
+    /// errors rather than the current directory
+    pub fn path(&self, rendered: &str) -> Result<AbsPathBuf> {
+        if rendered.is_empty() {
+            return Err(Error::EmptyPath);
+        }
+        if rendered.trim().is_empty() {
+            return Err(Error::BlankPath(rendered.to_owned()));
+        }
+        AbsPathBuf::logical(self.helpers.resolve(rendered)).map_err(|e| Error::InvalidPath {
+            path: rendered.to_owned(),
+            reason: e.to_string(),
+        })
+    }
+    /// Update the time used by `now`, reread files used by `lookup` and rerun
+    /// `shell` commands, for this context and all its clones
+    pub fn start_run(&self) {
+        self.helpers.start_run();
+    }
+    /// In dry run mode helpers with side effects log what they would do
+    /// rather than doing it
+    pub fn set_dry_run(&mut self, dry: bool) {
+        self.helpers.set_dry_run(dry);
+    }
+    /// Every variable that can be used from this context, objects included
+    /// and built-ins like `now.date`
+    pub fn variables(&self) -> impl Iterator<Item = Variable> {
+        let mut found = Vec::new();
+        self.flattened().paths(
+            &Variable {
+                segments: Vec::new(),
+            },
+            &mut found,
+        );
+        found.into_iter().chain(
+            helper::VARIABLES
+                .iter()
+                .map(|v| v.parse().expect("built-in variables are valid")),
+        )
+    }
+    /// `err` with the variables it might have meant, if it is about an
+    /// undefined variable
+    fn with_suggestions(&self, err: Error) -> Error {
+        let Error::UndefinedVariable { var, loc, .. } = err else {
+            return err;
+        };
+        let written = var.to_string();
+        let names = self.variables().map(|v| v.to_string()).collect_vec();
+        // short names are close to too much to suggest as freely as long ones
+        let max_distance = (written.chars().count() / 3).max(1);
+        let suggestions = suggest::closest(&written, names.iter().map(String::as_str))
+            .into_iter()
+            .filter(|name| suggest::edit_distance(&written, name) <= max_distance)
+            .take(MAX_SUGGESTIONS)
+            .map(str::to_owned)
+            .collect();
+        Error::UndefinedVariable {
+            var,
+            loc,
+            suggestions,
+        }
+    }
+    pub fn lookup(&self, var: &Variable) -> Option<&Value> {
+        let mut segments = var.segments();
+        self.root
+            .get(segments.next()?)
+            .and_then(|v| v.get_path(segments))
+            .or_else(|| self.parent.as_deref()?.lookup(var))
+    }
+
+    /// Check that every variable `input` uses is defined and available,
+    /// without rendering it
+    pub fn check(&self, input: &str) -> Result<()> {
+        let errors = referenced_vars(input)?
+            .into_iter()
+            .filter_map(|Reference { var, loc }| match self.lookup(&var) {
+                Some(Value::Unavailable(reason)) => Some(Error::Unavailable {
+                    var,
+                    reason: reason.to_owned(),
+                }),
+                Some(_) => None,
+                None if self.helpers.variable(&var).is_some() => None,
+                None => Some(self.with_suggestions(Error::UndefinedVariable {
+                    var,
+                    loc,
+                    suggestions: Vec::new(),
+                })),
+            })
+            .collect();
+        combine(errors).map_or(Ok(()), Err)
+    }
+    /// Render `input`, with partials resolved relative to the working directory
+    pub fn render(&self, input: &str) -> Result<String> {
+        self.render_from(input, None)
+    }
+    /// Render `input`, the contents of `file`, with partials resolved
+    /// relative to `file`
+    pub fn render_file(&self, input: &str, file: &Path) -> Result<String> {
+        self.render_from(input, Some(file))
+    }
+
+    fn render_from(&self, input: &str, file: Option<&Path>) -> Result<String> {
+        let mut out = self.render_once(input, file)?;
+        if self.rerenders == 0 {
+            return Ok(out);
+        }
+        for _ in 0..self.rerenders {
+            if remaining_tag(&out).is_none() {
+                return Ok(out);
+            }
+            out = self.render_once(&out, file)?;
+        }
+        match remaining_tag(&out) {
+            Some(span) => Err(Error::RenderDepth {
+                depth: self.rerenders + 1,
+                remaining: out[span.start..span.end].to_owned(),
+            }),
+            None => Ok(out),
+        }
+    }
+
+    fn render_once(&self, input: &str, file: Option<&Path>) -> Result<String> {
+        let mut out = String::with_capacity(input.len());
+        let mut scope = Scope {
+            ctx: self,
+            input,
+            file,
+            chain: file
+                .map(|f| fs::canonicalize(f).unwrap_or_else(|_| f.to_owned()))
+                .into_iter()
+                .collect(),
+            frames: Vec::new(),
+            errors: Vec::new(),
+        };
+        scope.render(&parse::parse(input)?, &mut out)?;
+        match combine(scope.errors) {
+            Some(e) => Err(e),
+            None => Ok(out),
+        }
+    }
+
+    /// File that `{{> path }}` in `from` refers to. Looked for next to `from`
+    /// (or in the working directory if there isn't one) and then in the
+    /// partials directory
+    fn find_partial(&self, path: &str, from: Option<&Path>) -> Option<PathBuf> {
+        let beside = match from {
+            Some(from) => from.parent().unwrap_or(Path::new("")).join(path),
+            None => PathBuf::from(path),
+        };
+        std::iter::once(beside)
+            .chain(self.partials_dir.as_ref().map(|dir| dir.join(path)))
+            .find(|p| p.is_file())
+    }
+
+    /// Every file other than `file` itself that rendering `input`, the
+    /// contents of `file`, would read: partials, directly or through other
+    /// partials, and files used by `lookup`. Partials that can't be found or
+    /// parsed are skipped since rendering will report them
+    pub fn dependencies(&self, input: &str, file: Option<&Path>) -> Vec<PathBuf> {
+        fn walk<'n>(nodes: &'n [Node], partials: &mut Vec<&'n str>, lookups: &mut Vec<&'n str>) {
+            for node in nodes {
+                match node {
+                    Node::Partial { path, .. } => partials.push(path),
+                    Node::Expr(Expr {
+                        source: Source::Helper(Helper::Lookup { file, .. }),
+                        ..
+                    }) => lookups.push(file),
+                    Node::Each { body, .. } => walk(body, partials, lookups),
+                    Node::Text(_) | Node::Expr(_) => {}
+                }
+            }
+        }
+        let mut found: Vec<PathBuf> = Vec::new();
+        let mut todo = vec![(input.to_owned(), file.map(Path::to_owned))];
+        while let Some((input, file)) = todo.pop() {
+            let Ok(nodes) = parse::parse(&input) else {
+                continue;
+            };
+            let mut paths = Vec::new();
+            let mut lookups = Vec::new();
+            walk(&nodes, &mut paths, &mut lookups);
+            for lookup in lookups {
+                let lookup = self.helpers.resolve(lookup);
+                if !found.contains(&lookup) {
+                    found.push(lookup);
+                }
+            }
+            for path in paths {
+                let Some(partial) = self.find_partial(path, file.as_deref()) else {
+                    continue;
+                };
+                if found.contains(&partial) {
+                    continue;
+                }
+                if let Ok(content) = std::fs::read_to_string(&partial) {
+                    todo.push((content, Some(partial.clone())));
+                }
+                found.push(partial);
+            }
+        }
+        found
+    }
+}
+
+/// Iteration of an `#each` block currently being rendered
+struct Frame<'c> {
+    this: &'c Value,
+    index: usize,
+}
+
+/// Variables visible at a point during rendering, the context plus the
+/// `this`/`@index` of the innermost `#each`
+struct Scope<'c, 'i> {
+    ctx: &'c Context,
+    /// Source being rendered, which spans refer to
+    input: &'i str,
+    /// File `input` was read from, if any
+    file: Option<&'i Path>,
+    /// Files currently being rendered, outermost first, to detect partials
+    /// that include themselves
+    chain: Vec<PathBuf>,
+    frames: Vec<Frame<'c>>,
+    /// Undefined variables found so far, collected so they can all be
+    /// reported at once
+    errors: Vec<Error>,
+}
+
+impl<'c, 'i> Scope<'c, 'i> {
+    fn resolve(&self, var: &Variable) -> Option<Cow<'c, Value>> {
+        let mut segments = var.segments();
+        match (segments.next()?, self.frames.last()) {
+            ("this", Some(frame)) => frame.this.get_path(segments).map(Cow::Borrowed),
+            ("@index", Some(frame)) if segments.next().is_none() => {
+                Some(Cow::Owned(Value::String(frame.index.to_string())))
+            }
+            _ => self
+                .ctx
+                .lookup(var)
+                .map(Cow::Borrowed)
+                .or_else(|| self.ctx.helpers.variable(var).map(Cow::Owned)),
+        }
+    }
+
+    fn render(&mut self, nodes: &[Node], out: &mut String) -> Result<()> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Expr(expr) => match self.eval(expr) {
+                    Ok(value) => out.push_str(&value),
+                    Err(e @ Error::UndefinedVariable { .. }) => self.undefined(expr.span, e, out),
+                    Err(e) => return Err(e),
+                },
+                Node::Each { var, body, span } => {
+                    let items = match self.resolve(var) {
+                        Some(Cow::Borrowed(Value::List(items))) => items,
+                        Some(Cow::Borrowed(Value::Unavailable(reason))) => {
+                            return Err(Error::Unavailable {
+                                var: var.clone(),
+                                reason: reason.clone(),
+                            })
+                        }
+                        Some(_) => return Err(Error::NotAList(var.clone())),
+                        None => {
+                            let e = Error::UndefinedVariable {
+                                var: var.clone(),
+                                loc: Location::of(self.input, span.start),
+                                suggestions: Vec::new(),
+                            };
+                            self.undefined(*span, e, out);
+                            continue;
+                        }
+                    };
+                    for (index, this) in items.iter().enumerate() {
+                        self.frames.push(Frame { this, index });
+                        let r = self.render(body, out);
+                        self.frames.pop();
+                        r?;
+                    }
+                }
+                Node::Partial { path, span } => self.partial(path, *span, out)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Render the partial at `path` in place, with the same variables in scope
+    fn partial(&mut self, path: &str, span: Span, out: &mut String) -> Result<()> {
+        let Some(file) = self.ctx.find_partial(path, self.file) else {
+            let loc = Location::of(self.input, span.start);
+            return Err(Error::MissingPartial {
+                path: path.to_owned(),
+                from: match self.file {
+                    Some(f) => format!("'{}' at {loc}", f.display()),
+                    None => loc.to_string(),
+                },
+            });
+        };
+        let canonical = fs::canonicalize(&file).unwrap_or_else(|_| file.clone());
+        let cycle = self.chain.contains(&canonical);
+        if cycle || self.chain.len() >= MAX_PARTIAL_DEPTH {
+            let chain = self
+                .chain
+                .iter()
+                .chain([&canonical])
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(if cycle {
+                Error::PartialCycle(chain)
+            } else {
+                Error::PartialDepth(chain)
+            });
+        }
+        let in_partial = |source| Error::InPartial {
+            path: file.display().to_string(),
+            source: Box::new(source),
+        };
+        let content = std::fs::read_to_string(&file).map_err(|e| Error::ReadPartial {
+            path: file.display().to_string(),
+            reason: e.to_string(),
+        })?;
+        let nodes = parse::parse(&content).map_err(|e| in_partial(e.into()))?;
+        self.chain.push(canonical);
+        let mut inner = Scope {
+            ctx: self.ctx,
+            input: &content,
+            file: Some(&file),
+            chain: std::mem::take(&mut self.chain),
+            frames: std::mem::take(&mut self.frames),
+            errors: Vec::new(),
+        };
+        let r = inner.render(&nodes, out);
+        self.chain = inner.chain;
+        self.chain.pop();
+        self.frames = inner.frames;
+        r.map_err(in_partial)?;
+        if let Some(e) = combine(inner.errors) {
+            self.errors.push(in_partial(e));
+        }
+        Ok(())
+    }
+
+    /// Handle `err`, caused by an undefined variable in the source at `span`,
+    /// according to the context's policy
+    fn undefined(&mut self, span: Span, err: Error, out: &mut String) {
+        match self.ctx.undefined {
+            Undefined::Error => self.errors.push(self.ctx.with_suggestions(err)),
+            Undefined::Passthrough => out.push_str(&self.input[span.start..span.end]),
+            Undefined::Empty => {}
+        }
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<String> {
+        let value = match &expr.source {
+            Source::Variable(var) => match self.resolve(var).as_deref() {
+                Some(Value::String(s)) => s.clone(),
+                Some(Value::Unavailable(reason)) => {
+                    return Err(Error::Unavailable {
+                        var: var.clone(),
+                        reason: reason.clone(),
+                    })
+                }
+                Some(_) => return Err(Error::NotAString(var.clone())),
+                None => {
+                    return Err(Error::UndefinedVariable {
+                        var: var.clone(),
+                        loc: Location::of(self.input, expr.span.start),
+                        suggestions: Vec::new(),
+                    })
+                }
+            },
+            Source::Helper(helper) => self.ctx.helpers.call(helper)?,
+        };
+        Ok(expr
+            .filters
+            .iter()
+            .fold(value, |value, filter| filter.apply(value)))
+    }
+}
+
+/// First tag in `input` that rendering would replace, if it is valid
+/// template syntax at all
+fn remaining_tag(input: &str) -> Option<Span> {
+    parse::parse(input)
+        .ok()?
+        .iter()
+        .find_map(|node| match node {
+            Node::Text(_) => None,
+            Node::Expr(Expr { span, .. })
+            | Node::Each { span, .. }
+            | Node::Partial { span, .. } => Some(*span),
+        })
+}
+
+/// Use of a context variable by a template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub var: Variable,
+    pub loc: Location,
+}
+
+/// Every use of a context variable in `input`, in order, not including the
+/// `this` and `@index` of `#each` blocks. Nothing is rendered, so this
+/// finds all of them even when some are undefined
+pub fn referenced_vars(input: &str) -> Result<Vec<Reference>> {
+    struct Walk<'i> {
+        input: &'i str,
+        out: Vec<Reference>,
+    }
+    impl Walk<'_> {
+        fn add(&mut self, var: &Variable, span: Span, in_each: bool) {
+            if !(in_each && matches!(var.segments().next(), Some("this" | "@index"))) {
+                self.out.push(Reference {
+                    var: var.clone(),
+                    loc: Location::of(self.input, span.start),
+                });
+            }
+        }
+        fn walk(&mut self, nodes: &[Node], in_each: bool) {
+            for node in nodes {
+                match node {
+                    Node::Expr(Expr {
+                        source: Source::Variable(var),
+                        span,
+                        ..
+                    }) => self.add(var, *span, in_each),
+                    Node::Each { var, body, span } => {
+                        self.add(var, *span, in_each);
+                        self.walk(body, true);
+                    }
+                    Node::Text(_) | Node::Expr(_) | Node::Partial { .. } => {}
+                }
+            }
+        }
+    }
+    let mut walk = Walk {
+        input,
+        out: Vec::new(),
+    };
+    walk.walk(&parse::parse(input)?, false);
+    Ok(walk.out)
+}
+
+/// Variables from the context that rendering `input` would use, each listed
+/// once
+pub fn variables(input: &str) -> Result<Vec<Variable>> {
+    Ok(referenced_vars(input)?
+        .into_iter()
+        .map(|r| r.var)
+        .unique()
+        .collect())
+}
+
+/// Shell commands that rendering `input` would run
+pub fn shell_commands(input: &str) -> Result<Vec<String>> {
+    fn walk(nodes: &[Node], out: &mut Vec<String>) {
+        for node in nodes {
+            match node {
+                Node::Expr(Expr {
+                    source: Source::Helper(Helper::Shell(cmd)),
+                    ..
+                }) => out.push(cmd.to_owned()),
+                Node::Each { body, .. } => walk(body, out),
+                Node::Text(_) | Node::Expr(_) | Node::Partial { .. } => {}
+            }
+        }
+    }
+    let mut out = Vec::new();
+    walk(&parse::parse(input)?, &mut out);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use fs_err as fs;
+    use tempdir::TempDir;
+
+    use std::{path::Path, sync::Arc};
+
+    use super::{shell_commands, Context, Error, Location, Object, Undefined, Value, Variable};
+
+    fn ctx() -> Context {
+        Context::new()
+            .with_define(Variable::single("name"), "  Hello World ")
+            .with_define(
+                Variable::single("xdg"),
+                Object::new().with_property("local", Object::new().with_property("config", "/c")),
+            )
+    }
+
+    #[test]
+    fn nested_variables_are_looked_up() {
+        assert_eq!(
+            ctx().render("at {{ xdg.local.config }}/x").unwrap(),
+            "at /c/x"
+        );
+    }
+
+    #[test]
+    fn filters_apply_left_to_right() {
+        assert_eq!(
+            ctx()
+                .render(r#"{{ name | trim | lower | replace:" ":"-" }}"#)
+                .unwrap(),
+            "hello-world"
+        );
+        assert_eq!(ctx().render("{{name|upper}}").unwrap(), "  HELLO WORLD ");
+    }
+
+    #[test]
+    fn escaping_filters_compose_with_others() {
+        assert_eq!(
+            ctx()
+                .render(r#"{"name": {{ name | trim | replace:"o":"'" | json }}}"#)
+                .unwrap(),
+            r#"{"name": "Hell' W'rld"}"#
+        );
+        assert_eq!(
+            ctx()
+                .render("echo {{ name | shell_quote | upper }}")
+                .unwrap(),
+            "echo '  HELLO WORLD '"
+        );
+    }
+
+    #[test]
+    fn undefined_variables_are_an_error() {
+        assert_eq!(
+            ctx().render("{{ xdg.nope }}"),
+            Err(Error::UndefinedVariable {
+                var: "xdg.nope".parse().unwrap(),
+                loc: Location { line: 1, column: 1 },
+                suggestions: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn undefined_variables_suggest_close_ones() {
+        let ctx = Arc::new(ctx());
+        let mut scope = ctx.scope();
+        scope.define("target.font_size".parse().unwrap(), "12");
+        assert_eq!(
+            scope.render("{{ xdg.locl.config }}").unwrap_err().to_string(),
+            "unknown variable 'xdg.locl.config' at line 1, column 1, did you mean `xdg.local.config`?"
+        );
+        assert_matches!(
+            scope.check("{{ target.font_sise }}"),
+            Err(Error::UndefinedVariable { suggestions, .. }) if suggestions == ["target.font_size"]
+        );
+        assert_matches!(
+            scope.render("{{ now.dat }}"),
+            Err(Error::UndefinedVariable { suggestions, .. }) if suggestions == ["now.date"]
+        );
+    }
+
+    #[test]
+    fn every_undefined_variable_is_reported_with_its_position() {
+        let err = ctx()
+            .render("{{ name }}\n  {{ a }} {{#each b}}{{ c }}{{/each}}\n{{ a }}")
+            .unwrap_err();
+        let undefined = |var: &str, line, column| Error::UndefinedVariable {
+            var: var.parse().unwrap(),
+            loc: Location { line, column },
+            suggestions: Vec::new(),
+        };
+        assert_eq!(
+            err,
+            Error::Multiple(vec![
+                undefined("a", 2, 3),
+                undefined("b", 2, 11),
+                undefined("a", 3, 1),
+            ])
+        );
+        assert_eq!(
+            err.to_string(),
+            "3 errors:\n  unknown variable 'a' at line 2, column 3\n  unknown variable 'b' at line 2, column 11\n  unknown variable 'a' at line 3, column 1"
+        );
+    }
+
+    #[test]
+    fn passthrough_reproduces_undefined_expressions_exactly() {
+        let mut ctx = ctx();
+        ctx.set_undefined(Undefined::Passthrough);
+        assert_eq!(
+            ctx.render("{{name|trim}} {{  bar.baz |upper}} {{#each  list }}x{{/each}}")
+                .unwrap(),
+            "Hello World {{  bar.baz |upper}} {{#each  list }}x{{/each}}"
+        );
+        assert_matches!(ctx.render("{{ xdg.local }}"), Err(Error::NotAString(_)));
+    }
+
+    #[test]
+    fn empty_removes_undefined_expressions() {
+        let mut ctx = ctx();
+        ctx.set_undefined(Undefined::Empty);
+        assert_eq!(
+            ctx.render("a{{ bar }}b{{#each list}}x{{/each}}c").unwrap(),
+            "abc"
+        );
+    }
+
+    #[test]
+    fn trim_markers_remove_lines_of_tags_alone() {
+        assert_eq!(
+            hosts()
+                .render("Hosts:\n{{#each config.hosts -}}\n  {{ this.name }}\n{{- /each }}\nend")
+                .unwrap(),
+            "Hosts:\nab\nend"
+        );
+        assert_eq!(
+            hosts()
+                .render("{{#each config.hosts}}\n{{- this.name }}\n{{/each -}}\nend")
+                .unwrap(),
+            "a\nb\nend"
+        );
+    }
+
+    #[test]
+    fn consecutive_trimmed_tags_join() {
+        assert_eq!(
+            ctx().render("{{ xdg.local.config -}}  \n  {{- xdg.local.config -}} \n {{ xdg.local.config }}").unwrap(),
+            "/c/c/c"
+        );
+        assert_eq!(ctx().render("a {{- name-}} b").unwrap(), "a  Hello World b");
+    }
+
+    #[test]
+    fn trim_markers_at_the_ends_of_the_input() {
+        assert_eq!(
+            ctx().render("\n\n {{- xdg.local.config -}} \n").unwrap(),
+            "/c"
+        );
+        assert_eq!(ctx().render("{{- xdg.local.config -}}").unwrap(), "/c");
+        assert_eq!(ctx().render(" \\{{- x -}} ").unwrap(), " {{- x -}} ");
+    }
+
+    #[test]
+    fn partials_are_rendered_in_place_with_the_same_variables() {
+        let dir = TempDir::new("partials").unwrap();
+        fs::create_dir(dir.path().join("partials")).unwrap();
+        fs::write(
+            dir.path().join("partials/host.conf"),
+            "{{ this.name }}:{{#each this.ports}}{{> port.conf }}{{/each}}",
+        )
+        .unwrap();
+        fs::write(dir.path().join("partials/port.conf"), "{{ this }},").unwrap();
+        let file = dir.path().join("main.in");
+        let input = "{{#each config.hosts}}{{> partials/host.conf }} {{/each}}";
+        assert_eq!(hosts().render_file(input, &file).unwrap(), "a:1,2, b: ");
+        assert_eq!(
+            hosts().dependencies(input, Some(&file)),
+            vec![
+                dir.path().join("partials/host.conf"),
+                dir.path().join("partials/port.conf")
+            ]
+        );
+    }
+
+    #[test]
+    fn partials_fall_back_to_the_partials_dir() {
+        let dir = TempDir::new("partials_dir").unwrap();
+        fs::write(dir.path().join("colors.conf"), "{{ name | trim }}").unwrap();
+        let mut ctx = ctx();
+        ctx.set_partials_dir(dir.path().to_owned());
+        assert_eq!(
+            ctx.render_file("{{> colors.conf }}!", Path::new("nowhere/main.in"))
+                .unwrap(),
+            "Hello World!"
+        );
+    }
+
+    #[test]
+    fn missing_partials_name_the_including_file() {
+        let err = ctx()
+            .render_file("\n {{> nope.conf }}", Path::new("dir/main.in"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "partial 'nope.conf' included from 'dir/main.in' at line 2, column 2 does not exist"
+        );
+    }
+
+    #[test]
+    fn recursive_partials_are_an_error_naming_the_chain() {
+        let dir = TempDir::new("partial_cycle").unwrap();
+        fs::write(dir.path().join("a"), "{{> b }}").unwrap();
+        fs::write(dir.path().join("b"), "{{ name }}{{> a }}").unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let err = ctx()
+            .render_file("{{> a }}", &dir.path().join("main.in"))
+            .unwrap_err();
+        let in_partial = |path: &str, source| Error::InPartial {
+            path: dir.path().join(path).display().to_string(),
+            source: Box::new(source),
+        };
+        let chain = ["main.in", "a", "b", "a"]
+            .map(|p| root.join(p).display().to_string())
+            .join(" -> ");
+        assert_eq!(
+            err,
+            in_partial("a", in_partial("b", Error::PartialCycle(chain)))
+        );
+    }
+
+    #[test]
+    fn definable_variables_are_identifiers() {
+        assert_eq!(
+            Variable::definable("a.b_c.d-1"),
+            Ok("a.b_c.d-1".parse().unwrap())
+        );
+        for name in ["", "a.", "my var", "{{ x }}", "@index", "a..b"] {
+            assert_eq!(
+                Variable::definable(name),
+                Err(super::parse::Error::InvalidVariable(name.to_owned()))
+            );
+        }
+    }
+
+    #[test]
+    fn variables_lists_each_context_variable_once() {
+        assert_eq!(
+            super::variables(
+                "{{ a }} {{ this }} {{#each b.c}}{{ this.d }}{{ @index }}{{ a | upper }}{{/each}}"
+            )
+            .unwrap(),
+            ["a", "this", "b.c"].map(|v| v.parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn referenced_vars_have_positions_and_skip_escapes() {
+        let refs = super::referenced_vars(
+            "\\{{ a }} {{ b | upper }}\n{{#each c}}{{ this }} {{ b }}{{/each}}",
+        )
+        .unwrap();
+        assert_eq!(
+            refs.iter()
+                .map(|r| (r.var.to_string(), r.loc.to_string()))
+                .collect::<Vec<_>>(),
+            [
+                ("b", "line 1, column 10"),
+                ("c", "line 2, column 1"),
+                ("b", "line 2, column 23"),
+            ]
+            .map(|(v, l)| (v.to_owned(), l.to_owned()))
+        );
+    }
+
+    #[test]
+    fn check_reports_every_missing_variable_without_rendering() {
+        let ctx = ctx();
+        assert_eq!(ctx.check("{{ xdg.local.config }} {{ now.year }}"), Ok(()));
+        assert_eq!(
+            ctx.check("{{ nope }}\n{{ name }} {{#each also.nope}}{{/each}}")
+                .unwrap_err()
+                .to_string(),
+            "2 errors:\n  unknown variable 'nope' at line 1, column 1\n  unknown variable 'also.nope' at line 2, column 12"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn rendered_paths_are_relative_to_the_base_dir_and_never_empty() {
+        let mut ctx = ctx();
+        ctx.define(Variable::single("empty"), "");
+        ctx.define(Variable::single("dir"), "conf/../dots");
+        ctx.set_base_dir(Path::new("/base").to_owned());
+        let path = |s: &str| super::Templated::new(s.to_owned()).render_path(&ctx);
+        assert_eq!(
+            path("{{ dir }}/vimrc").unwrap().as_ref(),
+            Path::new("/base/dots/vimrc")
+        );
+        assert_eq!(
+            path("/etc/hosts").unwrap().as_ref(),
+            Path::new("/etc/hosts")
+        );
+        assert_eq!(path("{{ empty }}"), Err(Error::EmptyPath));
+        assert_eq!(
+            path(" {{ empty }}\t"),
+            Err(Error::BlankPath(" \t".to_owned()))
+        );
+    }
+
+    fn lookup_ctx() -> (Context, TempDir) {
+        let dir = TempDir::new("lookup").unwrap();
+        fs::write(
+            dir.path().join("theme.yaml"),
+            "colors:\n  bg: '#282828'\n  size: 12\n  list: [a, b]\nenabled: true\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("theme.json"),
+            r##"{"colors": {"fg": "#ebdbb2"}}"##,
+        )
+        .unwrap();
+        fs::write(dir.path().join("theme.toml"), "").unwrap();
+        let mut ctx = ctx();
+        ctx.set_base_dir(dir.path().to_owned());
+        (ctx, dir)
+    }
+
+    #[test]
+    fn lookup_reads_scalars_from_yaml_and_json() {
+        let (ctx, dir) = lookup_ctx();
+        let tmpl = r#"{{ lookup "theme.yaml" "colors.bg" }} {{ lookup "theme.yaml" "colors.size" }} {{ lookup "theme.yaml" "colors.list.1" | upper }} {{ lookup "theme.yaml" "enabled" }} {{ lookup "theme.json" "colors.fg" }}"#;
+        assert_eq!(ctx.render(tmpl).unwrap(), "#282828 12 B true #ebdbb2");
+        assert_eq!(
+            ctx.dependencies(tmpl, None),
+            vec![dir.path().join("theme.yaml"), dir.path().join("theme.json")]
+        );
+    }
+
+    #[test]
+    fn lookup_errors_are_distinct() {
+        let (ctx, _dir) = lookup_ctx();
+        let lookup =
+            |file: &str, key: &str| ctx.render(&format!(r#"{{{{ lookup "{file}" "{key}" }}}}"#));
+        assert_matches!(lookup("nope.yaml", "a"), Err(Error::LookupRead { .. }));
+        assert_matches!(lookup("theme.toml", "a"), Err(Error::LookupFormat(_)));
+        assert_matches!(
+            lookup("theme.yaml", "colors.nope"),
+            Err(Error::LookupMissingKey { key, .. }) if key == "colors.nope"
+        );
+        assert_matches!(
+            lookup("theme.yaml", "colors.list.2"),
+            Err(Error::LookupMissingKey { .. })
+        );
+        assert_matches!(
+            lookup("theme.yaml", "colors"),
+            Err(Error::LookupNotScalar { key, .. }) if key == "colors"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restarting_rereads_lookups_and_reruns_shell_commands() {
+        let (mut ctx, dir) = lookup_ctx();
+        ctx.enable_shell();
+        fs::write(dir.path().join("value"), "old").unwrap();
+        let tmpl = r#"{{ lookup "theme.json" "colors.fg" }} {{ shell "cat value" }}"#;
+        assert_eq!(ctx.render(tmpl).unwrap(), "#ebdbb2 old");
+
+        fs::write(
+            dir.path().join("theme.json"),
+            r##"{"colors": {"fg": "#000000"}}"##,
+        )
+        .unwrap();
+        fs::write(dir.path().join("value"), "new").unwrap();
+        assert_eq!(ctx.clone().render(tmpl).unwrap(), "#ebdbb2 old");
+        ctx.start_run();
+        assert_eq!(ctx.render(tmpl).unwrap(), "#000000 new");
+    }
+
+    fn nested() -> Context {
+        ctx()
+            .with_define("a".parse().unwrap(), "{{ b }}")
+            .with_define("b".parse().unwrap(), "{{ name | trim }}")
+    }
+
+    #[test]
+    fn output_is_rendered_once_by_default() {
+        assert_eq!(nested().render("{{ a }}").unwrap(), "{{ b }}");
+    }
+
+    #[test]
+    fn render_depth_expands_syntax_produced_by_variables() {
+        let mut ctx = nested();
+        ctx.set_render_depth(3);
+        assert_eq!(ctx.render("{{ a }}!").unwrap(), "Hello World!");
+        assert_eq!(ctx.render("{{ b }}").unwrap(), "Hello World");
+        ctx.set_render_depth(2);
+        assert_eq!(
+            ctx.render("x {{ a }}"),
+            Err(Error::RenderDepth {
+                depth: 2,
+                remaining: "{{ name | trim }}".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn rendering_an_object_is_an_error() {
+        assert_matches!(ctx().render("{{ xdg.local }}"), Err(Error::NotAString(_)));
+    }
+
+    fn hosts() -> Context {
+        let host = |name: &str, ports: &[&str]| {
+            Object::new().with_property("name", name).with_property(
+                "ports",
+                ports.iter().map(|&p| p.into()).collect::<Vec<Value>>(),
+            )
+        };
+        ctx()
+            .with_define(
+                "config.hosts".parse().unwrap(),
+                vec![host("a", &["1", "2"]).into(), host("b", &[]).into()],
+            )
+            .with_define("config.none".parse().unwrap(), Vec::new())
+    }
+
+    #[test]
+    fn each_renders_body_per_item_with_this_and_index() {
+        assert_eq!(
+            hosts()
+                .render(
+                    "{{#each config.hosts}}Host {{ this.name | upper }} #{{ @index }}\n{{/each}}"
+                )
+                .unwrap(),
+            "Host A #0\nHost B #1\n"
+        );
+    }
+
+    #[test]
+    fn nested_each_binds_innermost_item() {
+        assert_eq!(
+            hosts()
+                .render("{{#each config.hosts}}{{#each this.ports}}{{ this }}@{{ @index }} {{/each}}{{/each}}")
+                .unwrap(),
+            "1@0 2@1 "
+        );
+    }
+
+    #[test]
+    fn each_over_empty_list_renders_nothing() {
+        assert_eq!(
+            hosts().render("a{{#each config.none}}x{{/each}}b").unwrap(),
+            "ab"
+        );
+    }
+
+    #[test]
+    fn each_over_non_lists_is_an_error() {
+        assert_matches!(
+            hosts().render("{{#each name}}{{/each}}"),
+            Err(Error::NotAList(_))
+        );
+        assert_matches!(
+            hosts().render("{{#each config.missing}}{{/each}}"),
+            Err(Error::UndefinedVariable { .. })
+        );
+    }
+
+    #[test]
+    fn this_outside_each_is_a_normal_variable() {
+        assert_matches!(
+            ctx().render("{{ this }}"),
+            Err(Error::UndefinedVariable { .. })
+        );
+    }
+
+    #[test]
+    fn unavailable_variables_error_with_their_reason() {
+        let ctx = ctx().with_define(
+            "sys.hostname".parse().unwrap(),
+            Value::Unavailable("no hostname".to_owned()),
+        );
+        assert_eq!(
+            ctx.render("{{ sys.hostname }}"),
+            Err(Error::Unavailable {
+                var: "sys.hostname".parse().unwrap(),
+                reason: "no hostname".to_owned()
+            })
+        );
+        assert_eq!(ctx.render("{{ name | trim }}").unwrap(), "Hello World");
+    }
+
+    #[test]
+    fn shell_is_disabled_by_default() {
+        assert_matches!(
+            ctx().render(r#"{{ shell "echo hi" }}"#),
+            Err(Error::ShellNotAllowed(_))
+        );
+    }
+
+    #[test]
+    fn shell_output_is_substituted_without_trailing_whitespace() {
+        let mut ctx = ctx();
+        ctx.enable_shell();
+        assert_eq!(
+            ctx.render(r#"[{{ shell "echo hi" | upper }}]"#).unwrap(),
+            "[HI]"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shell_results_are_cached_between_clones() {
+        let mut ctx = ctx();
+        ctx.enable_shell();
+        let tmpl = r#"{{ shell "date +%s%N" }}"#;
+        let first = ctx.render(tmpl).unwrap();
+        assert_eq!(ctx.clone().render(tmpl).unwrap(), first);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shell_commands_run_in_the_base_dir() {
+        let (a, b) = (
+            TempDir::new("shell_a").unwrap(),
+            TempDir::new("shell_b").unwrap(),
+        );
+        let mut ctx = ctx();
+        ctx.enable_shell();
+        let mut in_dir = |dir: &TempDir| {
+            ctx.set_base_dir(dir.path().to_owned());
+            ctx.render(r#"{{ shell "pwd -P" }}"#).unwrap()
+        };
+        // the same command elsewhere isn't answered from the cache
+        assert_eq!(
+            [in_dir(&a), in_dir(&b)],
+            [&a, &b].map(|d| d
+                .path()
+                .canonicalize()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failing_shell_commands_report_status_and_stderr() {
+        let mut ctx = ctx();
+        ctx.enable_shell();
+        assert_matches!(
+            ctx.render(r#"{{ shell "echo oops >&2; exit 3" }}"#),
+            Err(Error::ShellFailed { status, stderr, .. }) if status.contains('3') && stderr == "oops"
+        );
+    }
+
+    #[test]
+    fn dry_run_does_not_run_shell_commands() {
+        let mut ctx = ctx();
+        ctx.enable_shell();
+        ctx.set_dry_run(true);
+        assert_eq!(ctx.render(r#"{{ shell "exit 1" }}"#).unwrap(), "$(exit 1)");
+        assert_eq!(
+            shell_commands(r#"a {{#each x}}{{ shell "b" }}{{/each}} {{ y }}"#).unwrap(),
+            vec!["b".to_owned()]
+        );
+    }
+
+    #[test]
+    fn now_is_the_same_for_every_render_until_restarted() {
+        let ctx = ctx();
+        let tmpl =
+            r#"{{ now.datetime }} {{ now.date }} {{ now.year }} {{ now.format "%H:%M:%S%.f" }}"#;
+        let first = ctx.render(tmpl).unwrap();
+        assert_eq!(ctx.clone().render(tmpl).unwrap(), first);
+        assert_eq!(
+            ctx.render(r#"{{ now.format "%Y" }}"#).unwrap(),
+            ctx.render("{{ now.year }}").unwrap()
+        );
+    }
+
+    #[test]
+    fn scopes_see_but_do_not_change_their_parent() {
+        let parent = Arc::new(ctx().with_define("config.a".parse().unwrap(), "a"));
+        let mut scope = parent.scope();
+        scope.define("config.b".parse().unwrap(), "b");
+        scope.define("name".parse().unwrap(), "shadowed");
+        assert_eq!(
+            scope
+                .render("{{ config.a }}{{ config.b }} {{ name }} {{ xdg.local.config }}")
+                .unwrap(),
+            "ab shadowed /c"
+        );
+        assert_matches!(
+            parent.render("{{ config.b }}"),
+            Err(Error::UndefinedVariable { .. })
+        );
+        assert_eq!(
+            scope,
+            ctx()
+                .with_define("config.a".parse().unwrap(), "a")
+                .with_define("config.b".parse().unwrap(), "b")
+                .with_define("name".parse().unwrap(), "shadowed")
+        );
+        let mut sibling = parent.scope();
+        assert_ne!(scope, sibling);
+        sibling.define("config.b".parse().unwrap(), "b");
+        sibling.define("name".parse().unwrap(), "shadowed");
+        assert_eq!(scope, sibling);
+        // same variables however they're defined
+        sibling.define("config.a".parse().unwrap(), "a");
+        assert_eq!(scope, sibling);
+    }
+
+    #[test]
+    fn now_can_be_shadowed_by_user_variables() {
+        let ctx = ctx().with_define("now.date".parse().unwrap(), "today");
+        assert_eq!(ctx.render("{{ now.date }}").unwrap(), "today");
+    }
+
+    #[test]
+    fn invalid_time_formats_are_an_error() {
+        assert_matches!(
+            ctx().render(r#"{{ now.format "%Q" }}"#),
+            Err(Error::InvalidTimeFormat(_))
+        );
+    }
+
+    #[test]
+    fn define_merges_into_existing_objects() {
+        let mut ctx = ctx();
+        ctx.define("xdg.home".parse().unwrap(), "/h");
+        assert_eq!(
+            ctx.render("{{ xdg.home }} {{ xdg.local.config }}").unwrap(),
+            "/h /c"
+        );
+    }
+
+    #[test]
+    fn append_merges_objects_with_other_taking_priority() {
+        let mut ctx = ctx();
+        ctx.append(Context::new().with_define("xdg.local.config".parse().unwrap(), "/o"));
+        assert_eq!(ctx.render("{{ xdg.local.config }}").unwrap(), "/o");
+    }
+}
@@ -0,0 +1,659 @@
+use thiserror::Error;
+
+use super::{filter::Filter, helper::Helper, Variable};
+
+/// Byte range of a construct within the template source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Human readable position within the template source, both are 1-based
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    pub fn of(input: &str, offset: usize) -> Self {
+        let before = &input[..offset];
+        let line = before.matches('\n').count() + 1;
+        let column = before
+            .rfind('\n')
+            .map(|nl| &before[nl + 1..])
+            .unwrap_or(before)
+            .chars()
+            .count()
+            + 1;
+        Self { line, column }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("unterminated template expression starting at {0}")]
+    Unterminated(Location),
+    #[error("expected {expected} at {loc}")]
+    Expected {
+        expected: &'static str,
+        loc: Location,
+    },
+    #[error("unknown filter '{name}' at {loc}")]
+    UnknownFilter { name: String, loc: Location },
+    #[error("filter '{name}' at {loc} takes {expected} argument(s) but was given {found}")]
+    FilterArity {
+        name: String,
+        expected: usize,
+        found: usize,
+        loc: Location,
+    },
+    #[error("unknown helper '{name}' at {loc}")]
+    UnknownHelper { name: String, loc: Location },
+    #[error("helper '{name}' at {loc} takes {expected} argument(s) but was given {found}")]
+    HelperArity {
+        name: String,
+        expected: usize,
+        found: usize,
+        loc: Location,
+    },
+    #[error("invalid variable name '{0}', names must be identifiers separated by '.'")]
+    InvalidVariable(String),
+    #[error("unknown block '#{name}' at {loc}")]
+    UnknownBlock { name: String, loc: Location },
+    #[error("block '#{name}' opened at {loc} is never closed")]
+    UnclosedBlock { name: String, loc: Location },
+    #[error("unexpected '{{{{/{name}}}}}' at {loc}")]
+    UnexpectedClose { name: String, loc: Location },
+    #[error("raw block opened at {0} is never closed")]
+    UnclosedRaw(Location),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Reason a filter or helper could not be constructed, turned into an
+/// [`Error`] once the location is known
+#[derive(Debug)]
+pub enum Invalid {
+    Unknown,
+    Arity { expected: usize, found: usize },
+}
+
+impl Invalid {
+    pub fn check_arity(args: &[String], expected: usize) -> Result<(), Self> {
+        if args.len() == expected {
+            Ok(())
+        } else {
+            Err(Self::Arity {
+                expected,
+                found: args.len(),
+            })
+        }
+    }
+    fn filter(self, name: &str, loc: Location) -> Error {
+        let name = name.to_owned();
+        match self {
+            Invalid::Unknown => Error::UnknownFilter { name, loc },
+            Invalid::Arity { expected, found } => Error::FilterArity {
+                name,
+                expected,
+                found,
+                loc,
+            },
+        }
+    }
+    fn helper(self, name: &str, loc: Location) -> Error {
+        let name = name.to_owned();
+        match self {
+            Invalid::Unknown => Error::UnknownHelper { name, loc },
+            Invalid::Arity { expected, found } => Error::HelperArity {
+                name,
+                expected,
+                found,
+                loc,
+            },
+        }
+    }
+}
+
+/// Where the value of an expression comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Variable(Variable),
+    /// `{{ name "arg" ... }}`
+    Helper(Helper),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    pub source: Source,
+    pub filters: Vec<Filter>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node<'a> {
+    Text(&'a str),
+    Expr(Expr),
+    /// `{{#each var}} body {{/each}}`
+    Each {
+        var: Variable,
+        body: Vec<Node<'a>>,
+        span: Span,
+    },
+    /// `{{> path/to/partial }}`
+    Partial {
+        path: String,
+        span: Span,
+    },
+}
+
+/// Anything that can appear between `{{` and `}}`
+enum Tag<'a> {
+    Node(Node<'a>),
+    Close { name: &'a str, start: usize },
+}
+
+/// Opening and closing tags of raw blocks, longest first so that
+/// `{{{{raw}}}}` isn't mistaken for `{{raw}}`
+const RAW_TAGS: [(&str, &str); 2] = [("{{{{raw}}}}", "{{{{/raw}}}}"), ("{{raw}}", "{{/raw}}")];
+
+/// Block currently being parsed, along with where it was opened
+struct Block {
+    name: &'static str,
+    start: usize,
+}
+
+pub(super) fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+fn is_variable_char(c: char) -> bool {
+    is_ident_char(c) || c == '.' || c == '@'
+}
+
+pub fn parse(input: &str) -> Result<Vec<Node<'_>>> {
+    Parser {
+        input,
+        pos: 0,
+        trim_next: false,
+    }
+    .nodes(None)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    /// Whether the last tag ended with `-}}`, so the whitespace after it
+    /// should be dropped
+    trim_next: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+    fn loc(&self, offset: usize) -> Location {
+        Location::of(self.input, offset)
+    }
+    fn eat(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+    fn skip_ws(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+    /// Consume characters matching `f`, stopping before a `-}}` since that
+    /// always ends the tag
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let mut len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        if rest[..len].ends_with('-') && rest[len..].starts_with("}}") {
+            len -= 1;
+        }
+        self.pos += len;
+        &rest[..len]
+    }
+    /// Consume the end of a tag, either `}}` or `-}}`
+    fn eat_close(&mut self) -> bool {
+        if self.eat("-}}") {
+            self.trim_next = true;
+            true
+        } else {
+            self.eat("}}")
+        }
+    }
+    /// Position text following the current position starts at, skipping
+    /// whitespace if the previous tag asked for it
+    fn text_start(&mut self) -> usize {
+        if std::mem::take(&mut self.trim_next) {
+            self.skip_ws();
+        }
+        self.pos
+    }
+    /// Error for when something other than `expected` was found, or the
+    /// input ran out before the expression opened at `start` was closed
+    fn expected(&self, expected: &'static str, start: usize) -> Error {
+        if self.rest().is_empty() {
+            Error::Unterminated(self.loc(start))
+        } else {
+            Error::Expected {
+                expected,
+                loc: self.loc(self.pos),
+            }
+        }
+    }
+
+    /// Parse nodes until the end of input, or until the closing tag of `block`
+    fn nodes(&mut self, block: Option<Block>) -> Result<Vec<Node<'a>>> {
+        let mut nodes = Vec::new();
+        let mut text_start = self.text_start();
+        while let Some(i) = self.rest().find(['{', '\\']) {
+            let at = self.pos + i;
+            let rest = &self.input[at..];
+            if let Some((open, close)) = RAW_TAGS.iter().find(|(open, _)| rest.starts_with(open)) {
+                nodes.push(Node::Text(&self.input[text_start..at]));
+                let body = at + open.len();
+                let len = self.input[body..]
+                    .find(close)
+                    .ok_or_else(|| Error::UnclosedRaw(self.loc(at)))?;
+                nodes.push(Node::Text(&self.input[body..body + len]));
+                self.pos = body + len + close.len();
+                text_start = self.pos;
+            } else if rest.starts_with("\\{{") {
+                nodes.push(Node::Text(&self.input[text_start..at]));
+                nodes.push(Node::Text("{{"));
+                self.pos = at + 3;
+                text_start = self.pos;
+            } else if rest.starts_with("{{") {
+                let text = &self.input[text_start..at];
+                nodes.push(Node::Text(if rest.starts_with("{{-") {
+                    text.trim_end()
+                } else {
+                    text
+                }));
+                self.pos = at;
+                match self.tag()? {
+                    Tag::Node(node) => nodes.push(node),
+                    Tag::Close { name, .. } if block.as_ref().map(|b| b.name) == Some(name) => {
+                        nodes.retain(|n| !matches!(n, Node::Text("")));
+                        return Ok(nodes);
+                    }
+                    Tag::Close { name, start } => {
+                        return Err(Error::UnexpectedClose {
+                            name: name.to_owned(),
+                            loc: self.loc(start),
+                        })
+                    }
+                }
+                text_start = self.text_start();
+            } else {
+                self.pos = at + 1;
+            }
+        }
+        if let Some(block) = block {
+            return Err(Error::UnclosedBlock {
+                name: block.name.to_owned(),
+                loc: self.loc(block.start),
+            });
+        }
+        nodes.push(Node::Text(&self.input[text_start..]));
+        nodes.retain(|n| !matches!(n, Node::Text("")));
+        Ok(nodes)
+    }
+
+    fn tag(&mut self) -> Result<Tag<'a>> {
+        let start = self.pos;
+        self.pos += 2;
+        self.eat("-");
+        self.skip_ws();
+        if self.eat("#") {
+            let name = self.take_while(is_ident_char);
+            let name = match name {
+                "each" => "each",
+                _ => {
+                    return Err(Error::UnknownBlock {
+                        name: name.to_owned(),
+                        loc: self.loc(start),
+                    })
+                }
+            };
+            self.skip_ws();
+            let var = self.variable(start)?;
+            self.close(start)?;
+            let body = self.nodes(Some(Block { name, start }))?;
+            Ok(Tag::Node(Node::Each {
+                var,
+                body,
+                span: Span {
+                    start,
+                    end: self.pos,
+                },
+            }))
+        } else if self.eat("/") {
+            let name = self.take_while(is_ident_char);
+            self.close(start)?;
+            Ok(Tag::Close { name, start })
+        } else if self.eat(">") {
+            self.skip_ws();
+            let path = if self.eat("\"") {
+                self.quoted(start)?
+            } else {
+                self.take_while(|c| !c.is_whitespace() && c != '}')
+                    .to_owned()
+            };
+            if path.is_empty() {
+                return Err(self.expected("partial path", start));
+            }
+            self.close(start)?;
+            Ok(Tag::Node(Node::Partial {
+                path,
+                span: Span {
+                    start,
+                    end: self.pos,
+                },
+            }))
+        } else {
+            self.expr(start).map(Node::Expr).map(Tag::Node)
+        }
+    }
+
+    /// Consume the `}}` ending a tag opened at `start`
+    fn close(&mut self, start: usize) -> Result<()> {
+        self.skip_ws();
+        if self.eat_close() {
+            Ok(())
+        } else {
+            Err(self.expected("'}}'", start))
+        }
+    }
+
+    fn expr(&mut self, start: usize) -> Result<Expr> {
+        let at = self.pos;
+        let var = self.variable(start)?;
+        self.skip_ws();
+        let source = if self.rest().starts_with('"') {
+            let mut args = Vec::new();
+            while self.eat("\"") {
+                args.push(self.quoted(start)?);
+                self.skip_ws();
+            }
+            let name = var.to_string();
+            Source::Helper(Helper::new(&name, args).map_err(|e| e.helper(&name, self.loc(at)))?)
+        } else {
+            Source::Variable(var)
+        };
+        let mut filters = Vec::new();
+        loop {
+            self.skip_ws();
+            if self.eat_close() {
+                break;
+            } else if self.eat("|") {
+                self.skip_ws();
+                filters.push(self.filter(start)?);
+            } else {
+                return Err(self.expected("'|' or '}}'", start));
+            }
+        }
+        Ok(Expr {
+            source,
+            filters,
+            span: Span {
+                start,
+                end: self.pos,
+            },
+        })
+    }
+
+    fn variable(&mut self, start: usize) -> Result<Variable> {
+        let at = self.pos;
+        let name = self.take_while(is_variable_char);
+        if name.is_empty() {
+            return Err(self.expected("variable name", start));
+        }
+        // a name cut short like `some.` is most likely still being typed
+        name.parse().map_err(|_| match self.rest() {
+            "" => Error::Unterminated(self.loc(start)),
+            _ => Error::Expected {
+                expected: "variable name",
+                loc: self.loc(at),
+            },
+        })
+    }
+
+    fn filter(&mut self, start: usize) -> Result<Filter> {
+        let at = self.pos;
+        let name = self.take_while(is_ident_char);
+        if name.is_empty() {
+            return Err(self.expected("filter name", start));
+        }
+        let mut args = Vec::new();
+        while self.eat(":") {
+            args.push(self.argument(start)?);
+        }
+        Filter::new(name, args).map_err(|e| e.filter(name, self.loc(at)))
+    }
+
+    fn argument(&mut self, start: usize) -> Result<String> {
+        if self.eat("\"") {
+            return self.quoted(start);
+        }
+        let word = self.take_while(|c| !c.is_whitespace() && !matches!(c, '|' | ':' | '}'));
+        if word.is_empty() {
+            return Err(self.expected("filter argument", start));
+        }
+        Ok(word.to_owned())
+    }
+
+    /// Rest of a quoted string whose opening `"` has already been consumed
+    fn quoted(&mut self, start: usize) -> Result<String> {
+        let mut out = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(out);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => out.push('\n'),
+                    Some((_, 't')) => out.push('\t'),
+                    Some((_, c)) => out.push(c),
+                    None => break,
+                },
+                c => out.push(c),
+            }
+        }
+        Err(Error::Unterminated(self.loc(start)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::{parse, Error, Location, Node, Source};
+    use crate::template::{filter::Filter, helper::Helper};
+
+    #[test]
+    fn plain_text_is_a_single_node() {
+        assert_eq!(
+            parse("hello world").unwrap(),
+            vec![Node::Text("hello world")]
+        );
+    }
+
+    #[test]
+    fn expressions_are_split_from_text() {
+        let nodes = parse("a {{ x.y }} b").unwrap();
+        assert_matches!(nodes.as_slice(), [Node::Text("a "), Node::Expr(e), Node::Text(" b")] => {
+            assert_eq!(e.source, Source::Variable("x.y".parse().unwrap()));
+            assert_eq!((e.span.start, e.span.end), (2, 11));
+        });
+    }
+
+    #[test]
+    fn escaped_braces_are_literal() {
+        assert_eq!(
+            parse("\\{{ x }}").unwrap(),
+            vec![Node::Text("{{"), Node::Text(" x }}")]
+        );
+    }
+
+    #[test]
+    fn filters_are_parsed_in_order_with_arguments() {
+        let nodes = parse(r#"{{ x | trim | replace:" ":"-" | upper }}"#).unwrap();
+        assert_matches!(nodes.as_slice(), [Node::Expr(e)] => {
+            assert_eq!(e.filters, vec![
+                Filter::Trim,
+                Filter::Replace { from: " ".to_owned(), to: "-".to_owned() },
+                Filter::Upper,
+            ]);
+        });
+    }
+
+    #[test]
+    fn helpers_take_quoted_arguments() {
+        let nodes = parse(r#"{{ shell "echo \"hi\"" | trim }}"#).unwrap();
+        assert_matches!(nodes.as_slice(), [Node::Expr(e)] => {
+            assert_eq!(e.source, Source::Helper(Helper::Shell("echo \"hi\"".to_owned())));
+            assert_eq!(e.filters, vec![Filter::Trim]);
+        });
+    }
+
+    #[test]
+    fn unknown_helpers_are_an_error() {
+        assert_matches!(parse(r#"{{ nope "a" }}"#), Err(Error::UnknownHelper { .. }));
+        assert_matches!(
+            parse(r#"{{ shell "a" "b" }}"#),
+            Err(Error::HelperArity {
+                expected: 1,
+                found: 2,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_filters_report_name_and_location() {
+        assert_eq!(
+            parse("ab\n  {{ x | lower | shout }}"),
+            Err(Error::UnknownFilter {
+                name: "shout".to_owned(),
+                loc: Location {
+                    line: 2,
+                    column: 18
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn wrong_filter_arity_is_an_error() {
+        assert_matches!(
+            parse("{{ x | replace:a }}"),
+            Err(Error::FilterArity {
+                expected: 2,
+                found: 1,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn each_blocks_nest() {
+        let nodes = parse("{{#each a}}x{{#each this.b}}{{ this }}{{/each}}{{/each}}").unwrap();
+        assert_matches!(nodes.as_slice(), [Node::Each { body, .. }] => {
+            assert_matches!(body.as_slice(), [Node::Text("x"), Node::Each { body, .. }] => {
+                assert_matches!(body.as_slice(), [Node::Expr(_)]);
+            });
+        });
+    }
+
+    #[test]
+    fn partials_take_bare_or_quoted_paths() {
+        let nodes = parse(r#"{{> partials/colors.conf }}{{>"with space.conf"}}"#).unwrap();
+        assert_matches!(nodes.as_slice(), [Node::Partial { path: a, .. }, Node::Partial { path: b, span }] => {
+            assert_eq!(a, "partials/colors.conf");
+            assert_eq!(b, "with space.conf");
+            assert_eq!((span.start, span.end), (27, 49));
+        });
+        assert_matches!(parse("{{> }}"), Err(Error::Expected { .. }));
+    }
+
+    #[test]
+    fn raw_blocks_are_literal_until_their_exact_close() {
+        assert_eq!(
+            parse("a{{raw}}{{ x }}{{#each}}{{/raw}}b").unwrap(),
+            vec![
+                Node::Text("a"),
+                Node::Text("{{ x }}{{#each}}"),
+                Node::Text("b")
+            ]
+        );
+        assert_eq!(
+            parse("{{{{raw}}}}{{raw}}{{ x }}{{/raw}}{{{{/raw}}}}").unwrap(),
+            vec![Node::Text("{{raw}}{{ x }}{{/raw}}")]
+        );
+        assert_eq!(
+            parse("x\n {{raw}} {{ y }}"),
+            Err(Error::UnclosedRaw(Location { line: 2, column: 2 }))
+        );
+    }
+
+    #[test]
+    fn unbalanced_blocks_are_an_error() {
+        assert_matches!(
+            parse("{{#each a}} no end"),
+            Err(Error::UnclosedBlock {
+                loc: Location { line: 1, column: 1 },
+                ..
+            })
+        );
+        assert_matches!(
+            parse("{{ a }}{{/each}}"),
+            Err(Error::UnexpectedClose { .. })
+        );
+        assert_matches!(
+            parse("{{#loop a}}{{/loop}}"),
+            Err(Error::UnknownBlock { .. })
+        );
+    }
+
+    #[test]
+    fn unclosed_expression_is_an_error() {
+        assert_matches!(parse("{{ x | upper"), Err(Error::Unterminated(_)));
+    }
+
+    #[test]
+    fn truncated_templates_are_errors_not_panics() {
+        for input in [
+            "{{", "{{.", "{{ a.b", "{{ some.", "{{-", "{{>", "{{#each", "{{/",
+        ] {
+            assert_eq!(
+                parse(input),
+                Err(Error::Unterminated(Location { line: 1, column: 1 })),
+                "{input}"
+            );
+        }
+        assert_eq!(parse("a {").unwrap(), vec![Node::Text("a {")]);
+        // a file cut short anywhere, e.g. saved mid-edit, never panics
+        let whole = "é {{ a.b | replace \"x\" \"y\" }}\n{{#each xs}}{{ this }}{{/each}}\
+                     {{> p.conf }}{{raw}}{{ z }}{{/raw}} \\{{ {{~ c -}} }";
+        for (end, _) in whole.char_indices() {
+            let _ = parse(&whole[..end]);
+        }
+    }
+}
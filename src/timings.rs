@@ -0,0 +1,150 @@
+//! How long the parts of a deploy took, to find out where a slow one spends
+//! its time
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use itertools::Itertools;
+
+use crate::display::json_string;
+
+/// Durations of the phases of a deploy and each action it ran. Nothing is
+/// recorded unless it's enabled, so it can always be passed around
+#[derive(Debug, Default, Clone)]
+pub struct Timings {
+    enabled: bool,
+    /// In the order they finished
+    phases: Vec<(String, Duration)>,
+    /// In the order they ran
+    actions: Vec<(String, Duration)>,
+}
+
+impl Timings {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Default::default()
+        }
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+    /// Record that the phase `name` took `took`. Phases recorded more than
+    /// once are added up
+    pub fn phase(&mut self, name: impl Into<String>, took: Duration) {
+        if !self.enabled {
+            return;
+        }
+        let name = name.into();
+        match self.phases.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, total)) => *total += took,
+            None => self.phases.push((name, took)),
+        }
+    }
+    /// Run `f` as the phase `name`
+    pub fn time<T>(&mut self, name: impl Into<String>, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let out = f();
+        self.phase(name, started.elapsed());
+        out
+    }
+    /// Record that `action` took `took` to run
+    pub fn action(&mut self, action: impl Display, took: Duration) {
+        if self.enabled {
+            self.actions.push((action.to_string(), took));
+        }
+    }
+    /// The `n` slowest actions, slowest first
+    fn slowest(&self, n: usize) -> impl Iterator<Item = &(String, Duration)> {
+        self.actions
+            .iter()
+            .sorted_by(|(_, a), (_, b)| b.cmp(a))
+            .take(n)
+    }
+    /// The breakdown to print, phases then the `n` slowest actions
+    pub fn lines(&self, n: usize) -> Vec<String> {
+        let mut lines = vec!["phases:".to_owned()];
+        lines.extend(
+            self.phases
+                .iter()
+                .map(|(name, took)| format!("  {took:>10.2?}  {name}")),
+        );
+        lines.push(format!(
+            "  {:>10.2?}  total",
+            self.phases.iter().map(|(_, t)| *t).sum::<Duration>()
+        ));
+        if !self.actions.is_empty() {
+            lines.push(format!(
+                "slowest {} of {} actions:",
+                n.min(self.actions.len()),
+                self.actions.len()
+            ));
+            lines.extend(
+                self.slowest(n)
+                    .map(|(action, took)| format!("  {took:>10.2?}  {action}")),
+            );
+        }
+        lines
+    }
+    /// Every phase and action with how many seconds it took, as JSON
+    pub fn json(&self) -> String {
+        let entries = |items: &mut dyn Iterator<Item = &(String, Duration)>, key: &str| {
+            items
+                .map(|(name, took)| {
+                    format!(
+                        r#"{{{}:{},"seconds":{}}}"#,
+                        json_string(key),
+                        json_string(name),
+                        took.as_secs_f64()
+                    )
+                })
+                .join(",")
+        };
+        format!(
+            r#"{{"phases":[{}],"actions":[{}]}}"#,
+            entries(&mut self.phases.iter(), "phase"),
+            entries(&mut self.slowest(self.actions.len()), "action")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Timings;
+
+    #[test]
+    fn phases_add_up_and_actions_are_slowest_first() {
+        let ms = Duration::from_millis;
+        let mut timings = Timings::new(true);
+        timings.phase("read configs", ms(3));
+        timings.phase("plan", ms(1));
+        timings.phase("plan", ms(2));
+        timings.action("copy a", ms(1));
+        timings.action("link \"b\"", ms(5));
+        timings.action("copy c", ms(2));
+        assert_eq!(
+            timings.lines(2),
+            [
+                "phases:",
+                "      3.00ms  read configs",
+                "      3.00ms  plan",
+                "      6.00ms  total",
+                "slowest 2 of 3 actions:",
+                "      5.00ms  link \"b\"",
+                "      2.00ms  copy c",
+            ]
+        );
+        assert_eq!(
+            timings.json(),
+            r#"{"phases":[{"phase":"read configs","seconds":0.003},{"phase":"plan","seconds":0.003}],"actions":[{"action":"link \"b\"","seconds":0.005},{"action":"copy c","seconds":0.002},{"action":"copy a","seconds":0.001}]}"#
+        );
+
+        let mut off = Timings::new(false);
+        off.phase("plan", ms(1));
+        off.action("copy a", ms(1));
+        assert_eq!(off.lines(2), ["phases:", "      0.00ns  total"]);
+    }
+}
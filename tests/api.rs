@@ -0,0 +1,88 @@
+use std::path::Path;
+
+use dotloy::{
+    actions::{ActionKind, Actions, Outcome},
+    config::{LinkType, Root, Target},
+    resources::ResourceLocation,
+    template::Templated,
+};
+use tempdir::TempDir;
+
+fn plan(dir: &Path) -> Actions {
+    std::fs::write(dir.join("vimrc"), "set number").unwrap();
+    std::fs::write(
+        dir.join("gitconfig.in"),
+        "[user]\n\tname = {{ config.name }}",
+    )
+    .unwrap();
+    let mut cfg = Root::default();
+    cfg.targets
+        .push(Target::new("vimrc".to_owned(), "out/.vimrc".to_owned()));
+    cfg.targets.push(Target::new(
+        "gitconfig.in".to_owned(),
+        "out/.gitconfig".to_owned(),
+    ));
+    cfg.shared
+        .variables
+        .insert("name".to_owned(), Templated::new("me".to_owned()).into());
+    let mut ctx = dotloy::default_parse_context();
+    dotloy::define_config_location(&mut ctx, &dir.join("dotloy.yaml"));
+    Actions::from_config_in(&cfg, dir, &ctx).unwrap()
+}
+
+#[test]
+fn plans_can_be_inspected_before_they_run() {
+    let dir = TempDir::new("dotloy-api").unwrap();
+    let plan = plan(dir.path());
+    assert_eq!(
+        plan.iter().map(|a| a.kind()).collect::<Vec<_>>(),
+        [
+            ActionKind::MkDir,
+            ActionKind::Link(LinkType::Hard),
+            ActionKind::MkDir,
+            ActionKind::TemplateExpand,
+            ActionKind::Copy,
+        ]
+    );
+    let link = plan.iter().nth(1).unwrap();
+    assert_eq!(
+        link.dependency().unwrap().as_path().unwrap().file_name(),
+        Some("vimrc".as_ref())
+    );
+    assert_eq!(
+        link.output().as_path().unwrap().as_ref(),
+        dir.path().join("out").join(".vimrc")
+    );
+    assert!(matches!(
+        plan.iter().nth(3).unwrap().output(),
+        ResourceLocation::InMemory { .. }
+    ));
+}
+
+#[test]
+fn runs_report_each_action() {
+    let dir = TempDir::new("dotloy-api").unwrap();
+    let mut plan = plan(dir.path());
+
+    let mut skipped = 0;
+    plan.run_with(true, |_, outcome| {
+        assert!(matches!(outcome, Outcome::Skipped));
+        skipped += 1;
+    })
+    .unwrap();
+    assert_eq!(skipped, plan.len());
+    assert!(!dir.path().join("out").exists());
+
+    let mut failures = Vec::new();
+    plan.run_with(false, |action, outcome| {
+        if let Outcome::Failed(e) = outcome {
+            failures.push(format!("{action}: {e}"));
+        }
+    })
+    .unwrap();
+    assert_eq!(failures, Vec::<String>::new());
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("out").join(".gitconfig")).unwrap(),
+        "[user]\n\tname = me"
+    );
+}
@@ -0,0 +1,66 @@
+#![cfg(unix)]
+use std::{path::Path, process::Command};
+
+use tempdir::TempDir;
+
+/// A config with a template rendering the output of a shell command
+fn config(dir: &Path) {
+    std::fs::write(
+        dir.join("dotloy.yaml"),
+        "allow_shell: true\ntargets:\n- from: greeting.in\n  to: out/greeting\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("greeting.in"), "hello {{ shell \"echo real\" }}\n").unwrap();
+}
+
+fn dotloy(dir: &Path, args: &[&str]) {
+    let out = Command::new(env!("CARGO_BIN_EXE_dotloy"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+}
+
+#[test]
+fn dry_run_plans_have_the_output_of_commands() {
+    let dir = TempDir::new("emit-plan").unwrap();
+    config(dir.path());
+    dotloy(
+        dir.path(),
+        &["deploy", "--emit-plan", "plan.yaml", "--dry-run", "."],
+    );
+    assert!(!dir.path().join("out").exists());
+    dotloy(
+        dir.path(),
+        &["deploy", "--from-plan", "plan.yaml", "--no-history"],
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("out/greeting")).unwrap(),
+        "hello real\n"
+    );
+}
+
+#[test]
+fn dry_run_scripts_have_the_output_of_commands() {
+    let dir = TempDir::new("emit-script").unwrap();
+    config(dir.path());
+    dotloy(
+        dir.path(),
+        &["deploy", "--emit-script", "deploy.sh", "--dry-run", "."],
+    );
+    let status = Command::new("sh")
+        .arg("deploy.sh")
+        .current_dir(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("out/greeting")).unwrap(),
+        "hello real\n"
+    );
+}
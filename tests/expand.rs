@@ -0,0 +1,31 @@
+use std::process::Command;
+
+use tempdir::TempDir;
+
+#[test]
+fn expand_resolves_paths_from_where_it_was_run() {
+    let dir = TempDir::new("expand").unwrap();
+    let (work, cfg) = (dir.path().join("work"), dir.path().join("cfg"));
+    std::fs::create_dir_all(work.join("sub")).unwrap();
+    std::fs::create_dir(&cfg).unwrap();
+    std::fs::write(cfg.join("dotloy.yaml"), "targets: []\n").unwrap();
+    std::fs::write(work.join("sub/main.tmpl"), "main {{> part.txt }}").unwrap();
+    std::fs::write(work.join("sub/part.txt"), "part").unwrap();
+
+    let out = Command::new(env!("CARGO_BIN_EXE_dotloy"))
+        .current_dir(&work)
+        .args(["expand", "sub/main.tmpl", "--config", "../cfg/dotloy.yaml"])
+        .args(["-o", "out.txt"])
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert_eq!(
+        std::fs::read_to_string(work.join("out.txt")).unwrap(),
+        "main part"
+    );
+    assert!(!cfg.join("out.txt").exists());
+}
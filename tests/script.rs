@@ -0,0 +1,82 @@
+#![cfg(unix)]
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use dotloy::{
+    actions::Actions,
+    config::{DeployType, LinkType, Root, Target},
+    template::Templated,
+};
+use tempdir::TempDir;
+
+fn plan(dir: &Path) -> Actions {
+    std::fs::write(dir.join("vimrc"), "set number\n").unwrap();
+    std::fs::write(dir.join("it's a file"), "no newline").unwrap();
+    std::fs::write(dir.join("theme.in"), "bg = {{ config.bg }}\nDOTLOY_EOF\n").unwrap();
+    std::fs::create_dir(dir.join("nvim")).unwrap();
+    std::fs::write(dir.join("nvim").join("init.lua"), "").unwrap();
+    let mut cfg = Root::default();
+    cfg.shared.variables.insert(
+        "bg".to_owned(),
+        Templated::new("$(rm -rf /) 'dark'".to_owned()).into(),
+    );
+    let target = |from: &str, to: &str, ty| {
+        let mut t = Target::new(from.to_owned(), to.to_owned());
+        t.link_type = ty;
+        t
+    };
+    cfg.targets = vec![
+        target("vimrc", "out/.vimrc", DeployType::Auto),
+        target("it's a file", "out/a dir/copied", DeployType::Copy),
+        target("theme.in", "out/theme", DeployType::Auto),
+        target("nvim", "out/config/nvim", DeployType::Link(LinkType::Soft)),
+    ];
+    let mut ctx = dotloy::default_parse_context();
+    dotloy::define_config_location(&mut ctx, &dir.join("dotloy.yaml"));
+    Actions::from_config_in(&cfg, dir, &ctx).unwrap()
+}
+
+/// Contents of every file under `dir`, following links
+fn snapshot(dir: &Path) -> BTreeMap<PathBuf, String> {
+    let mut files = BTreeMap::new();
+    let mut todo = vec![dir.to_owned()];
+    while let Some(d) = todo.pop() {
+        for entry in std::fs::read_dir(d).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                todo.push(path);
+            } else {
+                let content = std::fs::read_to_string(&path).unwrap();
+                files.insert(path.strip_prefix(dir).unwrap().to_owned(), content);
+            }
+        }
+    }
+    files
+}
+
+#[test]
+fn scripts_deploy_the_same_as_dotloy() {
+    let dir = TempDir::new("dotloy-script").unwrap();
+    let mut plan = plan(dir.path());
+    let out = dir.path().join("out");
+
+    assert_eq!(plan.run(false).unwrap(), Vec::<String>::new());
+    let deployed = snapshot(&out);
+    assert_eq!(deployed.len(), 4);
+    std::fs::remove_dir_all(&out).unwrap();
+
+    let script = dir.path().join("deploy.sh");
+    std::fs::write(&script, plan.to_script().unwrap()).unwrap();
+    let status = Command::new("sh").arg(&script).status().unwrap();
+    assert!(status.success());
+    assert_eq!(snapshot(&out), deployed);
+    assert!(out.join("config").join("nvim").is_symlink());
+
+    // over what it deployed itself, as when provisioning again
+    let status = Command::new("sh").arg(&script).status().unwrap();
+    assert!(status.success());
+    assert_eq!(snapshot(&out), deployed);
+}
@@ -0,0 +1,40 @@
+use std::process::Command;
+
+use tempdir::TempDir;
+
+#[test]
+fn deploys_report_their_timings_as_json() {
+    let dir = TempDir::new("timings").unwrap();
+    std::fs::write(dir.path().join("rc"), "").unwrap();
+    std::fs::write(
+        dir.path().join("dotloy.yaml"),
+        "targets:\n- from: rc\n  to: out/rc\n",
+    )
+    .unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_dotloy"))
+        .current_dir(dir.path())
+        .args([
+            "deploy",
+            "--no-history",
+            "--timings",
+            "--format",
+            "json",
+            ".",
+        ])
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert!(dir.path().join("out/rc").exists());
+    // logs go to stderr, so stdout is only the report
+    let json = String::from_utf8(out.stdout).unwrap();
+    assert!(
+        json.starts_with(r#"{"count":2,"#) && json.trim_end().ends_with('}'),
+        "{json}"
+    );
+    assert!(json.contains(r#""phase":"run""#), "{json}");
+    assert_eq!(json.matches(r#""action":"#).count(), 2, "{json}");
+}
@@ -0,0 +1,37 @@
+#![cfg(unix)]
+use std::{path::Path, process::Command};
+
+use tempdir::TempDir;
+
+/// Deploy a config whose target is verified by `false`, failing as `on_failure`
+/// says, and return whether dotloy succeeded
+fn deploy_failing_verification(dir: &Path, on_failure: &str) -> bool {
+    std::fs::write(dir.join("rc"), "").unwrap();
+    std::fs::write(
+        dir.join("dotloy.yaml"),
+        format!(
+            "targets:\n- from: rc\n  to: out/rc\n  verify_command: 'false'\n  \
+             verify_failure: {on_failure}\n"
+        ),
+    )
+    .unwrap();
+    Command::new(env!("CARGO_BIN_EXE_dotloy"))
+        .current_dir(dir)
+        .args(["deploy", "--no-history", "."])
+        .output()
+        .unwrap()
+        .status
+        .success()
+}
+
+#[test]
+fn failed_verifications_fail_the_deploy() {
+    for (on_failure, succeeds) in [("error", false), ("rollback", false), ("warn", true)] {
+        let dir = TempDir::new("verify").unwrap();
+        assert_eq!(
+            deploy_failing_verification(dir.path(), on_failure),
+            succeeds,
+            "{on_failure}"
+        );
+    }
+}